@@ -1,5 +1,6 @@
 // Types for the Download API
 import type { Ref } from "vue";
+import { fetchAllPages } from "./pagination";
 
 // Possible download statuses
 export type DownloadStatus =
@@ -45,6 +46,9 @@ export interface DownloadItemWithProgress {
   url: string;
   output_path: string;
   created_at?: string;
+  priority: number;
+  // Position among the downloads waiting to start, 1 being the next one
+  queue_position: number | null;
   progress: Progress;
 }
 
@@ -100,11 +104,10 @@ export const getStatusString = (
 export const fetchDownloads = async (): Promise<
   Record<string, DownloadItemWithProgress>
 > => {
-  const response = await fetch("/api/downloads/");
-  if (!response.ok) {
-    throw new Error("Failed to fetch downloads");
-  }
-  return await response.json();
+  const pages = await fetchAllPages<Record<string, DownloadItemWithProgress>>(
+    "/api/downloads/"
+  );
+  return Object.assign({}, ...pages);
 };
 
 export const fetchStats = async (): Promise<DownloadStats> => {
@@ -124,6 +127,24 @@ export const cancelDownload = async (id: string): Promise<void> => {
   }
 };
 
+export const pauseDownload = async (id: string): Promise<void> => {
+  const response = await fetch(`/api/downloads/${id}/pause`, {
+    method: "POST",
+  });
+  if (!response.ok) {
+    throw new Error("Failed to pause download");
+  }
+};
+
+export const resumeDownload = async (id: string): Promise<void> => {
+  const response = await fetch(`/api/downloads/${id}/resume`, {
+    method: "POST",
+  });
+  if (!response.ok) {
+    throw new Error("Failed to resume download");
+  }
+};
+
 export const cleanupDownloads = async (): Promise<{ count: number }> => {
   const response = await fetch(`/api/downloads/cleanup`, {
     method: "GET",
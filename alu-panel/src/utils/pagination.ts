@@ -0,0 +1,37 @@
+/**
+ * Page size the panel asks for, the server clamps it to its configured maximum
+ */
+const PAGE_SIZE = 500;
+
+/**
+ * Fetches every page of a paginated list endpoint, which reports the number of items
+ * across all pages in the `X-Total-Count` header
+ * @param url The list endpoint, with or without query parameters
+ * @returns Promise resolving to the pages in order, either arrays or objects keyed by ID
+ */
+export async function fetchAllPages<T extends object>(url: string): Promise<T[]> {
+  const separator = url.includes("?") ? "&" : "?";
+  const pages: T[] = [];
+  let fetched = 0;
+
+  while (true) {
+    const response = await fetch(
+      `${url}${separator}limit=${PAGE_SIZE}&offset=${fetched}`
+    );
+    if (!response.ok) {
+      throw new Error(
+        `Failed to fetch ${url}: ${response.status} ${response.statusText}`
+      );
+    }
+
+    const page: T = await response.json();
+    pages.push(page);
+
+    const count = Array.isArray(page) ? page.length : Object.keys(page).length;
+    fetched += count;
+    const total = Number(response.headers.get("X-Total-Count") ?? fetched);
+    if (count === 0 || fetched >= total) {
+      return pages;
+    }
+  }
+}
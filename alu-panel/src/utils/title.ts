@@ -1,3 +1,5 @@
+import { fetchAllPages } from "./pagination";
+
 /**
  * Interface representing game/title metadata from Nintendo eShop
  */
@@ -116,15 +118,8 @@ export namespace TitleMetadata {
    * @returns Promise resolving to an array of TitleMetadata
    */
   export async function fetchBaseGames(): Promise<TitleMetadata[]> {
-    const response = await fetch("/api/base_games");
-
-    if (!response.ok) {
-      throw new Error(
-        `Failed to fetch games: ${response.status} ${response.statusText}`
-      );
-    }
-
-    return await response.json();
+    const pages = await fetchAllPages<TitleMetadata[]>("/api/base_games");
+    return pages.flat();
   }
 
   /**
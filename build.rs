@@ -23,6 +23,9 @@ pub fn main() -> Result<(), std::io::Error> {
         "cargo:rerun-if-changed={}",
         frontend_dir.join("pnpm-lock.yaml").display()
     );
+
+    emit_build_info(&current_dir);
+
     Command::new("pnpm")
         .arg("install")
         .current_dir(&frontend_dir)
@@ -36,3 +39,38 @@ pub fn main() -> Result<(), std::io::Error> {
     // tracing::info!("frontend built");
     Ok(())
 }
+
+/// Expose build metadata (git sha, build time, enabled features) to the crate as env vars
+fn emit_build_info(current_dir: &std::path::Path) {
+    let git_dir = current_dir.join(".git");
+    println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+    println!("cargo:rerun-if-changed={}", git_dir.join("refs").display());
+
+    // Building from a source tarball without git is fine, just report it as unknown
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .current_dir(current_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .filter(|sha| !sha.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ALUMULEMU_GIT_SHA={git_sha}");
+
+    let build_time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=ALUMULEMU_BUILD_TIME={build_time}");
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=ALUMULEMU_FEATURES={}", features.join(","));
+}
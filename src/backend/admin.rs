@@ -3,15 +3,11 @@ use axum::{
     extract::{Path, Query}, response::IntoResponse, Json
 };
 use http::StatusCode;
-use once_cell::sync::Lazy;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::{
-    games_dir,
     import::registry,
     index::TinfoilResponse,
-    router::{AlumRes, RescanOptions, update_metadata_from_filesystem},
+    router::{AlumRes, RescanOptions},
 };
 
 // Define response types for API endpoints
@@ -26,9 +22,13 @@ pub struct ApiResponse<T> {
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct ImporterInfo {
+    /// ID the importer is registered under, used in the import routes
     pub id: String,
+    pub name: String,
     pub display_name: String,
     pub description: String,
+    /// JSON schema of the importer's requests, empty if it doesn't describe them
+    pub request_schema: serde_json::Value,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -41,47 +41,8 @@ pub struct ImportStartResponse {
     pub importer: String,
 }
 
-// Global flag to track if a rescan job is already running
-static RESCAN_IN_PROGRESS: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
-
 pub async fn trigger_rescan(options: RescanOptions) -> color_eyre::Result<()> {
-    // Try to set the flag - returns false if already set
-    if RESCAN_IN_PROGRESS
-        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-        .is_err()
-    {
-        tracing::info!("Rescan already in progress, ignoring new request");
-        return Ok(());
-    }
-
-    tracing::info!("Starting games directory rescan as async background job");
-
-    // Clone the flag for use in the background task
-    let rescan_flag = RESCAN_IN_PROGRESS.clone();
-    tracing::debug!(?options, "Rescan flag cloned for background task");
-
-    // Spawn a background task to handle the rescan
-    tokio::spawn(async move {
-        let result = update_metadata_from_filesystem(&games_dir(), options).await;
-
-        match result {
-            Ok(_) => {
-                tracing::info!("Background rescan job completed successfully");
-                // Uncomment if you want to add metaview creation back
-                // tracing::info!("(re)Creating precomputed metaview");
-                // if let Err(e) = create_precomputed_metaview().await {
-                //     tracing::warn!("Failed to create precomputed metaview: {}", e);
-                // }
-            }
-            Err(e) => {
-                tracing::error!("Background rescan job failed: {}", e);
-            }
-        }
-
-        // Reset the flag when the job completes
-        rescan_flag.store(false, Ordering::SeqCst);
-    });
-
+    super::api::rescan::spawn_rescan(options);
     Ok(())
 }
 
@@ -123,10 +84,12 @@ pub async fn list_importers() -> (StatusCode, Json<ApiResponse<ImportersResponse
         // This ensures the RwLockReadGuard is dropped before the function returns
         registry::get_all_importers()
             .into_iter()
-            .map(|importer| ImporterInfo {
-                id: importer.name().to_string(),
+            .map(|(id, importer)| ImporterInfo {
+                id,
+                name: importer.name().to_string(),
                 display_name: importer.display_name().to_string(),
                 description: importer.description().to_string(),
+                request_schema: importer.request_schema(),
             })
             .collect::<Vec<_>>()
     };
@@ -0,0 +1,235 @@
+//! TitleDB name backfill
+//!
+//! Files imported before TitleDB finished importing end up without a proper title name,
+//! either `None` or the name guessed from the filename. The backfill job looks those names
+//! up again in TitleDB and fills them in, without re-reading any CNMTs. Titles TitleDB
+//! doesn't know, like homebrew, are named from the NACP in their file instead if
+//! `ALU_NACP_NAMES` is enabled.
+
+use std::{
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{Json, Router, response::IntoResponse, routing::post};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    db::NspMetadata,
+    titledb::{GameFileDataNaive, Title, nacp_name},
+};
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct BackfillProgress {
+    pub running: bool,
+    pub cancelled: bool,
+    /// Number of metadata rows that were missing a name when the job started
+    pub total: usize,
+    pub processed: usize,
+    /// Number of rows that got a name from TitleDB or their NACP
+    pub updated: usize,
+    pub error: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct BackfillState {
+    progress: BackfillProgress,
+    cancel: Option<CancellationToken>,
+}
+
+static BACKFILL: LazyLock<Mutex<BackfillState>> =
+    LazyLock::new(|| Mutex::new(BackfillState::default()));
+
+fn update_progress(f: impl FnOnce(&mut BackfillProgress)) {
+    f(&mut BACKFILL.lock().unwrap().progress);
+}
+
+/// Check if a metadata row has no name from TitleDB, only a missing or filename-derived one
+fn needs_name(metadata: &NspMetadata) -> bool {
+    let Some(name) = metadata.title_name.as_deref() else {
+        return true;
+    };
+    let filename = Path::new(&metadata.path)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    // `scan_file` stores the filename-derived name, minus the extension, when TitleDB has
+    // no entry for the file yet
+    let guessed = GameFileDataNaive::parse_from_filename(&filename).name;
+
+    name.trim().is_empty() || name == guessed || name == guessed.trim().trim_end_matches(".nsp")
+}
+
+/// Read the NACP name of a file, which needs its CNMT first
+async fn name_from_nacp(path: &str) -> Option<String> {
+    if !crate::config::config().backend_config.nacp_names {
+        return None;
+    }
+    match crate::nsp::read_cnmt_limited(path).await {
+        Ok(cnmt) => nacp_name(path, &cnmt).await,
+        Err(e) => {
+            tracing::warn!("Failed to read CNMT for {}: {}", path, e);
+            None
+        }
+    }
+}
+
+async fn run_backfill(token: CancellationToken) -> color_eyre::Result<()> {
+    let locale = crate::config::config().backend_config.get_locale_string();
+    let unnamed: Vec<NspMetadata> = NspMetadata::get_all()
+        .await?
+        .into_iter()
+        .filter(needs_name)
+        .collect();
+
+    tracing::info!("Backfilling TitleDB names for {} files", unnamed.len());
+    update_progress(|p| p.total = unnamed.len());
+
+    for mut metadata in unnamed {
+        if token.is_cancelled() {
+            tracing::info!("Name backfill cancelled");
+            update_progress(|p| p.cancelled = true);
+            break;
+        }
+
+        let name = match Title::get_from_title_id(&locale, &metadata.title_id).await {
+            Ok(Some(Title {
+                name: Some(name), ..
+            })) => Some(name),
+            Ok(_) => {
+                tracing::debug!("No TitleDB entry for {}", metadata.title_id);
+                name_from_nacp(&metadata.path).await
+            }
+            Err(e) => {
+                tracing::warn!("Failed to look up {}: {}", metadata.title_id, e);
+                None
+            }
+        };
+
+        if let Some(name) = name {
+            metadata.title_name = Some(name);
+            match metadata.save().await {
+                Ok(_) => update_progress(|p| p.updated += 1),
+                Err(e) => tracing::warn!("Failed to save name for {}: {}", metadata.path, e),
+            }
+        }
+
+        update_progress(|p| p.processed += 1);
+    }
+
+    Ok(())
+}
+
+/// Start backfilling names in the background, or report progress if it's already running
+pub async fn start_backfill() -> impl IntoResponse {
+    let token = CancellationToken::new();
+    {
+        let mut state = BACKFILL.lock().unwrap();
+        if state.progress.running {
+            return (StatusCode::CONFLICT, Json(state.progress.clone()));
+        }
+        state.progress = BackfillProgress {
+            running: true,
+            started_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        state.cancel = Some(token.clone());
+    }
+
+    tokio::spawn(async move {
+        let result = run_backfill(token).await;
+
+        let mut state = BACKFILL.lock().unwrap();
+        state.progress.running = false;
+        state.progress.finished_at = Some(Utc::now());
+        state.cancel = None;
+        match result {
+            Ok(()) => tracing::info!(
+                "Name backfill finished, updated {} of {} files",
+                state.progress.updated,
+                state.progress.total
+            ),
+            Err(e) => {
+                tracing::error!("Name backfill failed: {}", e);
+                state.progress.error = Some(e.to_string());
+            }
+        }
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(BACKFILL.lock().unwrap().progress.clone()),
+    )
+}
+
+/// Get the progress of the current or last backfill
+pub async fn get_backfill() -> impl IntoResponse {
+    Json(BACKFILL.lock().unwrap().progress.clone())
+}
+
+/// Cancel the running backfill, rows already updated keep their names
+pub async fn cancel_backfill() -> impl IntoResponse {
+    let state = BACKFILL.lock().unwrap();
+    match &state.cancel {
+        Some(token) => {
+            token.cancel();
+            (StatusCode::OK, Json(state.progress.clone()))
+        }
+        None => (StatusCode::NOT_FOUND, Json(state.progress.clone())),
+    }
+}
+
+pub fn backfill_api() -> Router {
+    Router::new()
+        .route(
+            "/",
+            post(start_backfill)
+                .get(get_backfill)
+                .delete(cancel_backfill),
+        )
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_editor,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(path: &str, title_name: Option<&str>) -> NspMetadata {
+        NspMetadata {
+            path: path.to_string(),
+            title_id: "0100000000010000".to_string(),
+            version: "0".to_string(),
+            title_name: title_name.map(str::to_string),
+            download_id: "0100000000010000_v0.nsp".to_string(),
+            unidentified: false,
+            required_system_version: None,
+        }
+    }
+
+    #[test]
+    fn test_needs_name() {
+        let path = "/games/Some Game [0100000000010000][v0].nsp";
+        let guessed =
+            GameFileDataNaive::parse_from_filename("Some Game [0100000000010000][v0].nsp");
+
+        assert!(needs_name(&metadata(path, None)));
+        assert!(needs_name(&metadata(path, Some(""))));
+        assert!(needs_name(&metadata(path, Some(&guessed.name))));
+        assert!(needs_name(&metadata(
+            path,
+            Some(guessed.name.trim_end_matches(".nsp"))
+        )));
+        assert!(!needs_name(&metadata(
+            path,
+            Some("Some Game: Deluxe Edition")
+        )));
+    }
+}
@@ -0,0 +1,236 @@
+//! Access log of served downloads, for bandwidth accounting
+//!
+//! When `ALU_ACCESS_LOG` is set, every game download is appended to that JSON Lines file
+//! once its response body is dropped, so the entry has the bytes that were actually sent. Aborted
+//! transfers are logged too, with `completed` unset. `GET /api/bandwidth` sums the log up
+//! over a time window.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{Json, Router, extract::Query, response::IntoResponse, routing::get};
+use chrono::{DateTime, Duration, Utc};
+use futures::Stream;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use super::since::{invalid_since, parse_since};
+
+/// Window used when no `since` is given
+const DEFAULT_BANDWIDTH_DAYS: i64 = 30;
+
+/// Appends are serialized so concurrent entries never interleave
+static LOG_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub download_id: String,
+    pub bytes_sent: u64,
+    /// Whether the whole file was sent
+    pub completed: bool,
+    pub client_ip: Option<String>,
+    pub username: Option<String>,
+}
+
+/// Path of the access log, `None` if it's disabled
+fn access_log_path() -> Option<String> {
+    Some(crate::config::config().backend_config.access_log).filter(|path| !path.is_empty())
+}
+
+/// Append an entry to the access log
+pub async fn append_entry(entry: &AccessLogEntry) -> std::io::Result<()> {
+    let Some(path) = access_log_path() else {
+        return Ok(());
+    };
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    let _guard = LOG_LOCK.lock().await;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await
+}
+
+/// Wraps a response body stream, logging how much of it was sent once it's dropped
+pub struct CountingStream<S> {
+    inner: S,
+    entry: Option<AccessLogEntry>,
+    expected: u64,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(
+        inner: S,
+        download_id: String,
+        expected: u64,
+        client_ip: Option<String>,
+        username: Option<String>,
+    ) -> Self {
+        Self {
+            inner,
+            entry: Some(AccessLogEntry {
+                timestamp: Utc::now(),
+                download_id,
+                bytes_sent: 0,
+                completed: false,
+                client_ip,
+                username,
+            }),
+            expected,
+        }
+    }
+}
+
+impl<S, B, E> Stream for CountingStream<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    type Item = Result<B, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let len = chunk.as_ref().len() as u64;
+            if let Some(entry) = &mut self.entry {
+                entry.bytes_sent += len;
+            }
+        }
+        poll
+    }
+}
+
+impl<S> Drop for CountingStream<S> {
+    fn drop(&mut self) {
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        entry.completed = entry.bytes_sent >= self.expected;
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(e) = append_entry(&entry).await {
+                    tracing::warn!("Failed to write access log entry: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BandwidthSummary {
+    pub since: DateTime<Utc>,
+    pub downloads: u64,
+    pub completed: u64,
+    pub bytes_sent: u64,
+    /// Bytes sent per user, anonymous downloads are under `anonymous`
+    pub by_user: BTreeMap<String, u64>,
+    /// Bytes sent per download ID
+    pub by_download: BTreeMap<String, u64>,
+}
+
+impl BandwidthSummary {
+    fn add(&mut self, entry: AccessLogEntry) {
+        self.downloads += 1;
+        if entry.completed {
+            self.completed += 1;
+        }
+        self.bytes_sent += entry.bytes_sent;
+        let username = entry.username.unwrap_or_else(|| "anonymous".to_string());
+        *self.by_user.entry(username).or_default() += entry.bytes_sent;
+        *self.by_download.entry(entry.download_id).or_default() += entry.bytes_sent;
+    }
+}
+
+/// Sum up the access log from the given time on
+pub async fn summarize(since: DateTime<Utc>) -> std::io::Result<BandwidthSummary> {
+    let mut summary = BandwidthSummary {
+        since,
+        ..Default::default()
+    };
+    let Some(path) = access_log_path() else {
+        return Ok(summary);
+    };
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(summary),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines = tokio::io::BufReader::new(file).lines();
+    while let Some(line) = lines.next_line().await? {
+        // A line cut short by a crash shouldn't hide the rest of the log
+        match serde_json::from_str::<AccessLogEntry>(&line) {
+            Ok(entry) if entry.timestamp >= since => summary.add(entry),
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Skipping malformed access log line: {}", e),
+        }
+    }
+    Ok(summary)
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BandwidthQuery {
+    /// Only count downloads from this time on, either `YYYY-MM-DD` or RFC 3339
+    pub since: Option<String>,
+}
+
+/// Summarize the bandwidth used by downloads over a time window
+pub async fn get_bandwidth(Query(query): Query<BandwidthQuery>) -> impl IntoResponse {
+    let since = match query.since.as_deref() {
+        Some(since) => match parse_since(since) {
+            Some(since) => since,
+            None => return invalid_since(since),
+        },
+        None => Utc::now() - Duration::days(DEFAULT_BANDWIDTH_DAYS),
+    };
+
+    match summarize(since).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read access log: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub fn bandwidth_api() -> Router {
+    Router::new()
+        .route("/", get(get_bandwidth))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_counting_stream() {
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(vec![0; 3]), Ok(vec![0; 4])];
+        let mut stream = CountingStream::new(
+            futures::stream::iter(chunks),
+            "0100000000010000_v0.nsp".to_string(),
+            10,
+            None,
+            None,
+        );
+
+        // Only what was actually pulled from the stream is counted
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(stream.entry.as_ref().unwrap().bytes_sent, 3);
+        while stream.next().await.is_some() {}
+        assert_eq!(stream.entry.as_ref().unwrap().bytes_sent, 7);
+        // Don't write the entry anywhere
+        stream.entry = None;
+    }
+}
@@ -0,0 +1,259 @@
+//! Downloading a whole title as a single archive
+//!
+//! A bundle is a zip of the base game, its updates and DLC, written on the fly while it's
+//! sent so it's never built in memory or on disk. Game files don't compress, so entries are
+//! stored as they are. The size of the archive isn't known up front and ranges aren't
+//! supported, an interrupted bundle has to be downloaded again.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+};
+
+use async_zip::{Compression, ZipEntryBuilder, tokio::write::ZipFileWriter};
+use axum::{
+    Extension,
+    body::Body,
+    extract::{ConnectInfo, Path},
+    response::Response,
+};
+use http::{HeaderMap, StatusCode, header};
+use tokio::io::AsyncWrite;
+use tokio_util::{compat::TokioAsyncReadCompatExt, io::ReaderStream};
+
+use super::{bandwidth, popular};
+use crate::{
+    backend::{kv_config::FilenameConfig, user::User},
+    db::NspMetadata,
+    title_kind::TitleKind,
+    titledb::{Metaview, title_group_prefix},
+    util::format_game_name,
+};
+
+/// Size of the buffer between the archive writer and the response
+const BUNDLE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// A file to put into a bundle
+struct BundleEntry {
+    name: String,
+    path: PathBuf,
+}
+
+/// Make an entry name unique within a bundle, numbering repeated names like `Game (2).nsp`
+fn unique_entry_name(name: &str, used: &mut HashSet<String>) -> String {
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !used.insert(candidate.clone()) {
+        let path = FsPath::new(name);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        candidate = match path.extension() {
+            Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+            None => format!("{stem} ({n})"),
+        };
+        n += 1;
+    }
+    candidate
+}
+
+/// Write the bundle's files into a zip archive
+async fn write_bundle<W: AsyncWrite + Unpin>(
+    writer: W,
+    entries: Vec<BundleEntry>,
+) -> color_eyre::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for entry in entries {
+        let file = tokio::fs::File::open(&entry.path).await?;
+        let builder = ZipEntryBuilder::new(entry.name.into(), Compression::Stored);
+        let mut entry_writer = zip.write_entry_stream(builder).await?;
+        futures::io::copy(&mut file.compat(), &mut entry_writer).await?;
+        entry_writer.close().await?;
+    }
+    zip.close().await?;
+    Ok(())
+}
+
+/// Handler for downloading a title with its updates and DLC as a zip
+pub async fn get_title_bundle(
+    Path(title_id): Path<String>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    super::check_referer(&headers).await?;
+    if title_group_prefix(&title_id).is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let download_ids = Metaview::get_download_ids(&title_id).await.map_err(|e| {
+        tracing::error!("Failed to get download IDs of {}: {}", title_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut files = Vec::new();
+    for download_id in download_ids {
+        match NspMetadata::get_from_download_id(&download_id).await {
+            Ok(Some(metadata)) => files.push(metadata),
+            Ok(None) => tracing::warn!("No metadata found for download ID: {}", download_id),
+            Err(e) => {
+                tracing::error!("Failed to get metadata of {}: {}", download_id, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    // Files hidden from the user's index aren't bundled either
+    let mut files = super::visible_files(user.as_ref().map(|Extension(user)| user), files)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to apply the index filter: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if files.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    // Base game first, then updates and DLC in title ID order
+    files.sort_by_key(|metadata| {
+        (
+            !TitleKind::is_base(&metadata.title_id),
+            metadata.download_id.clone(),
+        )
+    });
+
+    let filename_template = FilenameConfig::configured_template().await;
+    let mut used_names = HashSet::new();
+    let mut entries = Vec::with_capacity(files.len());
+    let mut total_size = 0;
+    for metadata in &files {
+        let path = FsPath::new(&metadata.path);
+        let file_metadata = tokio::fs::metadata(path).await.map_err(|e| {
+            tracing::error!("Failed to read file metadata for {}: {}", metadata.path, e);
+            StatusCode::NOT_FOUND
+        })?;
+        total_size += file_metadata.len();
+
+        let raw_filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("nsp");
+        let name = format_game_name(
+            metadata,
+            &raw_filename,
+            extension,
+            filename_template.as_ref(),
+        );
+        entries.push(BundleEntry {
+            name: unique_entry_name(&name, &mut used_names),
+            path: path.to_path_buf(),
+        });
+    }
+
+    let base = &files[0];
+    let name = base.title_name.as_deref().unwrap_or(&base.title_id);
+    let bundle_name = format!("{name} [{}].zip", base.title_id);
+    let safe_filename = bundle_name.replace(['"', '\\', '\n', '\r', '\t'], "_");
+    tracing::info!(
+        "Serving bundle {} with {} files",
+        safe_filename,
+        entries.len()
+    );
+
+    popular::spawn_record_download(base.title_id.clone());
+
+    let (writer, reader) = tokio::io::duplex(BUNDLE_BUFFER_SIZE);
+    let bundle_title_id = title_id.clone();
+    tokio::spawn(async move {
+        // The client gets a truncated archive if this fails, the status was already sent
+        if let Err(e) = write_bundle(writer, entries).await {
+            tracing::error!("Failed to write bundle of {}: {}", bundle_title_id, e);
+        }
+    });
+
+    let stream = bandwidth::CountingStream::new(
+        ReaderStream::new(reader),
+        format!("{title_id}.zip"),
+        total_size,
+        connect_info.map(|Extension(ConnectInfo(addr))| addr.ip().to_string()),
+        user.map(|Extension(user)| user.username),
+    );
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{safe_filename}\""),
+        )
+        .header(header::ACCEPT_RANGES, "none")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from_stream(stream))
+        .map_err(|e| {
+            tracing::error!("Failed to build response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unique_entry_name() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_entry_name("Game.nsp", &mut used), "Game.nsp");
+        assert_eq!(unique_entry_name("Game.nsp", &mut used), "Game (2).nsp");
+        assert_eq!(unique_entry_name("Game.nsp", &mut used), "Game (3).nsp");
+        assert_eq!(unique_entry_name("Game", &mut used), "Game");
+        assert_eq!(unique_entry_name("Game", &mut used), "Game (2)");
+    }
+
+    #[tokio::test]
+    async fn test_write_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.nsp");
+        let update = dir.path().join("update.nsp");
+        tokio::fs::write(&base, b"base game").await.unwrap();
+        tokio::fs::write(&update, b"update").await.unwrap();
+
+        let mut archive = Vec::new();
+        write_bundle(
+            &mut archive,
+            vec![
+                BundleEntry {
+                    name: "Game [0100000000010000][v0].nsp".to_string(),
+                    path: base,
+                },
+                BundleEntry {
+                    name: "Game [0100000000010800][v65536].nsp".to_string(),
+                    path: update,
+                },
+            ],
+        )
+        .await
+        .unwrap();
+
+        let reader = async_zip::base::read::mem::ZipFileReader::new(archive)
+            .await
+            .unwrap();
+        let names: Vec<String> = reader
+            .file()
+            .entries()
+            .iter()
+            .map(|entry| entry.filename().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Game [0100000000010000][v0].nsp",
+                "Game [0100000000010800][v65536].nsp"
+            ]
+        );
+
+        let mut contents = String::new();
+        let mut entry = reader.reader_with_entry(1).await.unwrap();
+        entry.read_to_string_checked(&mut contents).await.unwrap();
+        assert_eq!(contents, "update");
+    }
+}
@@ -1,11 +1,20 @@
 use axum::{
     Json, Router,
     extract::Path,
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
+use http::StatusCode;
 use serde_json::Value;
 
-use crate::{backend::kv_config::KVConfig, router::AlumRes};
+use crate::{
+    backend::{
+        admin::ApiResponse,
+        api::invalidate_index_cache,
+        kv_config::{FilenameConfig, KVConfig, KvOptExt, TinfoilIndexConfig, WebhookConfig},
+    },
+    router::AlumRes,
+};
 
 pub async fn get_key(Path(key): Path<String>) -> AlumRes<Json<Option<KVConfig>>> {
     tracing::trace!("Getting key: {}", key);
@@ -13,19 +22,50 @@ pub async fn get_key(Path(key): Path<String>) -> AlumRes<Json<Option<KVConfig>>>
     Ok(Json(config))
 }
 
-pub async fn set_key(Path(key): Path<String>, Json(config): Json<Value>) -> AlumRes<Json<Value>> {
+/// Check a setting that can't be checked when it's used, as it's used while serving files
+fn validate_setting(key: &str, value: &Value) -> Result<(), String> {
+    if key == FilenameConfig::KEY_NAME {
+        let config: FilenameConfig =
+            serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+        config.template().map_err(|e| e.to_string())?;
+    }
+    if key == WebhookConfig::KEY_NAME {
+        let config: WebhookConfig =
+            serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+        config.url()?;
+    }
+    Ok(())
+}
+
+pub async fn set_key(Path(key): Path<String>, Json(config): Json<Value>) -> AlumRes<Response> {
     tracing::trace!("Setting key: {}", key);
+    if let Err(e) = validate_setting(&key, &config) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<Value> {
+                status: "error".to_string(),
+                message: Some(e),
+                data: None,
+            }),
+        )
+            .into_response());
+    }
+
     // Pass the key and a mutable reference to the config value
     let mut kv = KVConfig::new(key.clone(), None);
     kv.set(config.clone()).await?;
-    Ok(Json(config))
+    // File names and the index settings change the index, or how long it's cached
+    if key == FilenameConfig::KEY_NAME || key == TinfoilIndexConfig::KEY_NAME {
+        invalidate_index_cache();
+    }
+    Ok(Json(config).into_response())
 }
 
 pub fn config_router() -> Router {
     Router::new()
-        .layer(axum::middleware::from_fn(
-            crate::backend::user::auth_require_admin,
-        ))
         .route("/get/{key}", get(get_key))
         .route("/set/{key}", post(set_key))
+        .route_layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
 }
@@ -1,17 +1,39 @@
 //! Downloader API module
+//!
+//! Progress can also be streamed as server-sent events, from `/stream` for every download
+//! in the queue or `/{id}/stream` for one of them. The stream of all downloads starts with
+//! a `progress` event of everything in the queue, after which `progress` events only carry
+//! the downloads that changed, by ID, and `removed` events the IDs of downloads that left
+//! the queue. The stream of one download ends once it's finished.
 
-use crate::import::downloader::{DOWNLOAD_QUEUE, DownloadStatus, Progress}; // Remove DownloadQueueItem
+use super::pagination::{Pagination, page_response};
+use crate::import::downloader::{
+    DOWNLOAD_QUEUE, DownloadQueueItem, DownloadStatus, Progress, RetryError, SetPriorityError,
+};
+use crate::index::TinfoilResponse;
+use crate::titledb::title_group_prefix;
+use axum::extract::Query;
 use axum::response::IntoResponse;
-use axum::routing::get;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::{get, post};
 use axum::{Json, Router};
 use chrono::{DateTime, Utc}; // Add imports for chrono types
 use color_eyre::Result;
+use futures::{Stream, future::select_all, stream};
 use http::StatusCode;
 use serde_json::json;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf; // Add import for PathBuf
+use std::time::Duration;
+use tokio::sync::watch;
 use ulid::Ulid;
 
+/// Shortest time between two progress events of a stream
+const STREAM_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest time between two checks of the queue, so new downloads are noticed
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Combined DownloadQueueItem with its current Progress, excluding sensitive headers
 #[derive(Debug, serde::Serialize)]
 pub struct DownloadItemWithProgress {
@@ -19,12 +41,131 @@ pub struct DownloadItemWithProgress {
     pub url: String,
     pub output_path: PathBuf,
     pub created_at: Option<DateTime<Utc>>,
+    pub import_job_id: Option<Ulid>,
+    pub title_id: Option<String>,
+    pub priority: i32,
+    /// Position among the downloads waiting to start, 1 being the next one
+    pub queue_position: Option<usize>,
     // Keep Progress nested
     pub progress: Progress,
 }
 
-/// Get all active downloads and their current status
-pub async fn get_downloads() -> Result<BTreeMap<Ulid, DownloadItemWithProgress>> {
+impl DownloadItemWithProgress {
+    fn new(item: DownloadQueueItem, progress: Progress, queue_position: Option<usize>) -> Self {
+        Self {
+            url: item.url,
+            output_path: item.output_path,
+            created_at: item.created_at,
+            import_job_id: item.import_job_id,
+            title_id: item.title_id,
+            priority: item.priority,
+            queue_position,
+            progress,
+        }
+    }
+}
+
+/// What admins get to debug a download, with the values of sensitive headers redacted
+#[derive(Debug, serde::Serialize)]
+pub struct DownloadDebugInfo {
+    pub url: String,
+    pub output_path: PathBuf,
+    pub headers: BTreeMap<String, String>,
+    pub expected_sha256: Option<String>,
+    pub import_job_id: Option<Ulid>,
+    pub progress: Progress,
+}
+
+/// Header names whose values are secrets, matched case-insensitively as part of the name
+const SENSITIVE_HEADER_PARTS: &[&str] = &["auth", "cookie", "token", "key", "secret", "session"];
+
+/// Characters kept at each end of a redacted header value
+const REDACTED_KEEP: usize = 4;
+
+/// Redact a header value if it's a secret, keeping its first and last few characters so
+/// admins can still tell values apart. Short values are hidden completely
+fn redact_header(name: &str, value: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if !SENSITIVE_HEADER_PARTS
+        .iter()
+        .any(|part| name.contains(part))
+    {
+        return value.to_string();
+    }
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= REDACTED_KEEP * 3 {
+        return "***".to_string();
+    }
+    let start: String = chars[..REDACTED_KEEP].iter().collect();
+    let end: String = chars[chars.len() - REDACTED_KEEP..].iter().collect();
+    format!("{start}***{end}")
+}
+
+impl DownloadDebugInfo {
+    fn new(item: DownloadQueueItem, progress: Progress) -> Self {
+        let headers = item
+            .headers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| {
+                let value = redact_header(&name, &value);
+                (name, value)
+            })
+            .collect();
+        Self {
+            url: item.url,
+            output_path: item.output_path,
+            headers,
+            expected_sha256: item.expected_sha256,
+            import_job_id: item.import_job_id,
+            progress,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PriorityRequest {
+    pub priority: i32,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct DownloadsQuery {
+    /// Only downloads of this title, including its updates and DLC
+    pub title_id: Option<String>,
+    /// Only downloads queued by this import job
+    pub import_job_id: Option<Ulid>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl DownloadsQuery {
+    fn matches(&self, item: &DownloadQueueItem) -> bool {
+        let title_matches = match self.title_id.as_deref().map(str::trim) {
+            Some(title_id) if !title_id.is_empty() => item
+                .title_id
+                .as_deref()
+                .is_some_and(|item_title_id| same_title(item_title_id, title_id)),
+            _ => true,
+        };
+        title_matches
+            && self
+                .import_job_id
+                .is_none_or(|job_id| item.import_job_id == Some(job_id))
+    }
+}
+
+/// Check if two title IDs belong to the same title, such as a base game and its update
+fn same_title(a: &str, b: &str) -> bool {
+    match (title_group_prefix(a), title_group_prefix(b)) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+/// Get the active downloads matching the query and their current status
+pub async fn get_downloads(
+    query: &DownloadsQuery,
+) -> Result<BTreeMap<Ulid, DownloadItemWithProgress>> {
     // Create a scope to ensure the lock is dropped after getting the data
     let downloads_vec = {
         // Lock is acquired here, and any potential PoisonError is immediately converted
@@ -40,10 +181,12 @@ pub async fn get_downloads() -> Result<BTreeMap<Ulid, DownloadItemWithProgress>>
         };
 
         // Call list_downloads and collect the data into a new vector that doesn't depend on queue
+        let positions = queue.queue_positions();
         queue
             .list_downloads()
             .into_iter()
-            .map(|(id, item, progress)| (id, item.clone(), progress))
+            .filter(|(_, item, _)| query.matches(item))
+            .map(|(id, item, progress)| (id, item.clone(), progress, positions.get(&id).copied()))
             .collect::<Vec<_>>()
 
         // Lock is automatically dropped here when queue goes out of scope
@@ -52,17 +195,9 @@ pub async fn get_downloads() -> Result<BTreeMap<Ulid, DownloadItemWithProgress>>
     // Process the vector outside of the MutexGuard's scope and use BTreeMap instead of HashMap
     let downloads = downloads_vec
         .into_iter()
-        .map(|(id, item, progress)| {
-            (
-                id, // Keep Ulid as the key
-                DownloadItemWithProgress {
-                    // Construct the new struct without headers
-                    url: item.url,
-                    output_path: item.output_path,
-                    created_at: item.created_at,
-                    progress,
-                },
-            )
+        // Keep Ulid as the key, and leave out the headers
+        .map(|(id, item, progress, position)| {
+            (id, DownloadItemWithProgress::new(item, progress, position))
         })
         .collect::<BTreeMap<Ulid, DownloadItemWithProgress>>();
 
@@ -83,16 +218,13 @@ pub async fn get_download(id: &Ulid) -> Result<Option<DownloadItemWithProgress>>
         };
 
         // Find the specific download item by ID
+        let position = queue.queue_positions().get(id).copied();
         queue
             .list_downloads()
             .into_iter()
             .find(|(item_id, _, _)| item_id == id)
-            .map(|(_, item, progress)| DownloadItemWithProgress {
-                // Construct the new struct without headers
-                url: item.url.clone(),
-                output_path: item.output_path.clone(),
-                created_at: item.created_at,
-                progress,
+            .map(|(_, item, progress)| {
+                DownloadItemWithProgress::new(item.clone(), progress, position)
             })
     };
 
@@ -101,7 +233,7 @@ pub async fn get_download(id: &Ulid) -> Result<Option<DownloadItemWithProgress>>
 
 /// Get a summary of download status statistics
 pub async fn get_download_stats() -> Result<DownloadStats> {
-    let downloads_vec = {
+    let (downloads_vec, max_concurrent) = {
         let queue = match DOWNLOAD_QUEUE.lock() {
             Ok(guard) => guard,
             Err(poison_err) => {
@@ -111,14 +243,18 @@ pub async fn get_download_stats() -> Result<DownloadStats> {
                 ));
             }
         };
-        queue
+        let downloads = queue
             .list_downloads()
             .into_iter()
             .map(|(_, _, progress)| progress)
-            .collect::<Vec<Progress>>()
+            .collect::<Vec<Progress>>();
+        (downloads, queue.max_concurrent())
     };
 
-    let mut stats = DownloadStats::default();
+    let mut stats = DownloadStats {
+        max_concurrent,
+        ..Default::default()
+    };
 
     for progress in downloads_vec {
         match progress.status {
@@ -159,6 +295,53 @@ pub async fn cancel_download(id: &Ulid) -> Result<bool> {
     Ok(result)
 }
 
+/// Cancel every unfinished download, returning how many were cancelled
+pub async fn cancel_all_downloads() -> Result<usize> {
+    let mut queue = DOWNLOAD_QUEUE
+        .lock()
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to lock download queue: {}", e))?;
+    Ok(queue.cancel_all())
+}
+
+/// Get what admins need to debug a download, from the queue or the database once it's gone
+/// from the queue
+pub async fn get_download_debug(id: &Ulid) -> Result<Option<DownloadDebugInfo>> {
+    let queued = {
+        let queue = DOWNLOAD_QUEUE
+            .lock()
+            .map_err(|e| color_eyre::eyre::eyre!("Failed to lock download queue: {}", e))?;
+        queue.get_item(id).cloned().zip(queue.get_progress(id))
+    };
+    if let Some((item, progress)) = queued {
+        return Ok(Some(DownloadDebugInfo::new(item, progress)));
+    }
+
+    let item: Option<DownloadQueueItem> = crate::db::DB
+        .select(("download_queue", id.to_string()))
+        .await?;
+    Ok(item.map(|item| {
+        let progress = item.progress.clone();
+        DownloadDebugInfo::new(item, progress)
+    }))
+}
+
+/// Change the priority of a queued download
+pub async fn set_download_priority(id: &Ulid, priority: i32) -> Result<(), SetPriorityError> {
+    DOWNLOAD_QUEUE.lock().unwrap().set_priority(id, priority)
+}
+
+/// Pause or resume a download, `None` if there's no such download and `false` if it
+/// can't be paused or resumed in its current state
+pub async fn set_download_paused(id: &Ulid, paused: bool) -> Option<bool> {
+    let mut queue = DOWNLOAD_QUEUE.lock().unwrap();
+    queue.get_item(id)?;
+    Some(if paused {
+        queue.pause(id)
+    } else {
+        queue.resume(id)
+    })
+}
+
 /// Clean up completed and aborted downloads from the queue
 pub async fn cleanup_downloads() -> Result<usize> {
     // Acquire the lock, perform cleanup, get the count, then drop the lock
@@ -204,11 +387,20 @@ pub struct DownloadStats {
     pub completed: usize,
     pub cancelled: usize,
     pub failed: usize,
+    /// Most downloads running at once, `None` for no limit
+    pub max_concurrent: Option<usize>,
 }
 
-pub async fn get_downloads_handler() -> Result<impl IntoResponse, StatusCode> {
-    match get_downloads().await {
-        Ok(downloads) => Ok(Json(downloads).into_response()),
+/// Handler for listing downloads, oldest first
+pub async fn get_downloads_handler(
+    Query(query): Query<DownloadsQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match get_downloads(&query).await {
+        Ok(downloads) => {
+            let pagination = Pagination::new(query.limit, query.offset);
+            let (page, total): (BTreeMap<_, _>, _) = pagination.paginate(downloads);
+            Ok(page_response(page, total))
+        }
         Err(e) => {
             tracing::error!("Failed to get downloads: {}", e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -230,6 +422,20 @@ pub async fn get_download_handler(
     }
 }
 
+/// Handler for debugging a download, admin-only as it shows request headers
+pub async fn get_download_debug_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match get_download_debug(&id).await {
+        Ok(Some(info)) => Ok(Json(info).into_response()),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to get download {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 /// Handler for getting download stats
 pub async fn get_download_stats_handler() -> Result<impl IntoResponse, StatusCode> {
     match get_download_stats().await {
@@ -241,6 +447,47 @@ pub async fn get_download_stats_handler() -> Result<impl IntoResponse, StatusCod
     }
 }
 
+/// Handler for queueing a failed download again, returning the new download's ID
+pub async fn retry_download_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match crate::import::retry_download(&id).await {
+        Ok(new_id) => Ok(Json(json!({ "id": new_id })).into_response()),
+        Err(RetryError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e @ RetryError::NotFailed) => Ok((
+            StatusCode::CONFLICT,
+            Json(TinfoilResponse::Failure(e.to_string())),
+        )
+            .into_response()),
+        Err(e @ RetryError::Database(_)) => {
+            tracing::error!("Failed to retry download {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handler for queueing every failed download again, returning the new downloads' IDs
+pub async fn retry_failed_downloads_handler() -> Result<impl IntoResponse, StatusCode> {
+    match crate::import::retry_failed_downloads().await {
+        Ok(ids) => Ok(Json(json!({ "retried": ids }))),
+        Err(e) => {
+            tracing::error!("Failed to retry failed downloads: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handler for cancelling every unfinished download
+pub async fn cancel_all_downloads_handler() -> Result<impl IntoResponse, StatusCode> {
+    match cancel_all_downloads().await {
+        Ok(cancelled) => Ok(Json(json!({ "cancelled": cancelled }))),
+        Err(e) => {
+            tracing::error!("Failed to cancel downloads: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 /// Handler for cancelling a download
 pub async fn cancel_download_handler(
     axum::extract::Path(id): axum::extract::Path<Ulid>,
@@ -260,20 +507,301 @@ pub async fn cancel_download_handler(
     }
 }
 
+/// Handler for changing the priority of a queued download
+pub async fn set_download_priority_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+    Json(request): Json<PriorityRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match set_download_priority(&id, request.priority).await {
+        Ok(()) => match get_download(&id).await {
+            Ok(Some(item)) => Ok(Json(item).into_response()),
+            Ok(None) => Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                tracing::error!("Failed to get download {}: {}", id, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+        Err(SetPriorityError::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e @ SetPriorityError::Started) => Ok((
+            StatusCode::CONFLICT,
+            Json(TinfoilResponse::Failure(e.to_string())),
+        )
+            .into_response()),
+    }
+}
+
+async fn set_paused_response(id: Ulid, paused: bool) -> Result<impl IntoResponse, StatusCode> {
+    match set_download_paused(&id, paused).await {
+        Some(true) => match get_download(&id).await {
+            Ok(Some(item)) => Ok(Json(item).into_response()),
+            Ok(None) => Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                tracing::error!("Failed to get download {}: {}", id, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+        Some(false) => {
+            let message = if paused {
+                "Only queued or running downloads can be paused"
+            } else {
+                "Only paused downloads can be resumed"
+            };
+            Ok((
+                StatusCode::CONFLICT,
+                Json(TinfoilResponse::Failure(message.to_string())),
+            )
+                .into_response())
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Handler for pausing a download
+pub async fn pause_download_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    set_paused_response(id, true).await
+}
+
+/// Handler for resuming a paused download
+pub async fn resume_download_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    set_paused_response(id, false).await
+}
+
+/// Changes since the progress last sent to a client
+#[derive(Debug, Default, PartialEq)]
+struct ProgressDelta {
+    changed: BTreeMap<Ulid, Progress>,
+    removed: Vec<Ulid>,
+}
+
+/// Compare the current progress of the downloads with what was last sent, and remember the
+/// current progress as sent
+fn progress_delta(
+    sent: &mut HashMap<Ulid, Progress>,
+    current: impl IntoIterator<Item = (Ulid, Progress)>,
+) -> ProgressDelta {
+    let current: HashMap<_, _> = current.into_iter().collect();
+    let mut removed: Vec<Ulid> = sent
+        .keys()
+        .filter(|id| !current.contains_key(id))
+        .copied()
+        .collect();
+    removed.sort();
+    for id in &removed {
+        sent.remove(id);
+    }
+    let changed: BTreeMap<_, _> = current
+        .into_iter()
+        .filter(|(id, progress)| sent.get(id) != Some(progress))
+        .collect();
+    sent.extend(changed.iter().map(|(id, progress)| (*id, progress.clone())));
+    ProgressDelta { changed, removed }
+}
+
+/// Wait until one of the downloads changed, or the poll interval passed
+async fn wait_for_progress_change(receivers: &mut [watch::Receiver<Progress>]) {
+    let changed = async {
+        if receivers.is_empty() {
+            return std::future::pending().await;
+        }
+        let _ = select_all(receivers.iter_mut().map(|rx| Box::pin(rx.changed()))).await;
+    };
+    tokio::select! {
+        _ = changed => {}
+        _ = tokio::time::sleep(STREAM_POLL_INTERVAL) => {}
+    }
+}
+
+/// Stream the changes to the progress of every download in the queue
+fn downloads_progress_stream() -> impl Stream<Item = Result<Event, axum::Error>> {
+    struct State {
+        sent: HashMap<Ulid, Progress>,
+        pending: Option<Event>,
+        started: bool,
+    }
+
+    let state = State {
+        sent: HashMap::new(),
+        pending: None,
+        started: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        if let Some(event) = state.pending.take() {
+            return Some((Ok(event), state));
+        }
+        loop {
+            let mut receivers = DOWNLOAD_QUEUE.lock().unwrap().subscribe_all();
+            let current = receivers
+                .iter_mut()
+                .map(|(id, rx)| (*id, rx.borrow_and_update().clone()));
+            let delta = progress_delta(&mut state.sent, current);
+
+            let removed = (!delta.removed.is_empty())
+                .then(|| Event::default().event("removed").json_data(&delta.removed));
+            // Clients get the whole queue first, even if it's empty
+            let progress = (!delta.changed.is_empty() || !state.started)
+                .then(|| Event::default().event("progress").json_data(&delta.changed));
+            state.started = true;
+
+            let mut events = removed.into_iter().chain(progress);
+            if let Some(event) = events.next() {
+                state.pending = events.next().and_then(Result::ok);
+                return Some((event, state));
+            }
+
+            let mut receivers: Vec<_> = receivers.into_iter().map(|(_, rx)| rx).collect();
+            wait_for_progress_change(&mut receivers).await;
+            tokio::time::sleep(STREAM_EVENT_INTERVAL).await;
+        }
+    })
+}
+
+/// Stream the progress of a download whenever it changes, until it's finished
+fn download_progress_stream(
+    rx: watch::Receiver<Progress>,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    stream::unfold((rx, false), |(mut rx, finished)| async move {
+        if finished {
+            return None;
+        }
+        let progress = rx.borrow_and_update().clone();
+        let event = Event::default().event("progress").json_data(&progress);
+        if !progress.is_complete() {
+            tokio::time::sleep(STREAM_EVENT_INTERVAL).await;
+            // Nothing more to wait for once the download left the queue
+            let removed = rx.changed().await.is_err();
+            return Some((event, (rx, removed)));
+        }
+        Some((event, (rx, true)))
+    })
+}
+
+/// Handler for following the progress of every download as server-sent events
+pub async fn downloads_stream_handler() -> impl IntoResponse {
+    Sse::new(downloads_progress_stream()).keep_alive(KeepAlive::default())
+}
+
+/// Handler for following the progress of a download as server-sent events
+pub async fn download_stream_handler(
+    axum::extract::Path(id): axum::extract::Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let rx = DOWNLOAD_QUEUE
+        .lock()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .subscribe(&id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Sse::new(download_progress_stream(rx)).keep_alive(KeepAlive::default()))
+}
+
 pub fn dl_write_router() -> Router {
     Router::new()
         .route("/{id}/cancel", get(cancel_download_handler))
+        .route("/{id}/priority", post(set_download_priority_handler))
+        .route("/{id}/pause", post(pause_download_handler))
+        .route("/{id}/resume", post(resume_download_handler))
+        .route("/{id}/retry", post(retry_download_handler))
+        .route("/cancel_all", post(cancel_all_downloads_handler))
+        .route("/retry_failed", post(retry_failed_downloads_handler))
         .route("/cleanup", get(cleanup_downloads_handler))
         .layer(axum::middleware::from_fn(
             crate::backend::user::auth_require_editor,
         ))
 }
 
+/// Routes that show what downloads were requested with, admins only
+pub fn dl_admin_router() -> Router {
+    Router::new()
+        .route("/{id}/debug", get(get_download_debug_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
+
 pub fn downloader_api() -> Router {
     Router::new()
         .route("/", get(get_downloads_handler))
         .route("/stats", get(get_download_stats_handler))
+        .route("/stream", get(downloads_stream_handler))
         .route("/{id}", get(get_download_handler))
+        .route("/{id}/stream", get(download_stream_handler))
         .merge(dl_write_router())
+        .merge(dl_admin_router())
     // .nest("/{id}/cancel", get(cancel_download_router))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downloads_query_title_filter() {
+        let job_id = Ulid::new();
+        let item = DownloadQueueItem::new("https://example.com/update.nsp", "/tmp", None)
+            .with_import_job(job_id, Some("0100000000010000".to_string()));
+        let untagged = DownloadQueueItem::new("https://example.com/other.nsp", "/tmp", None);
+
+        let query = |title_id: &str| DownloadsQuery {
+            title_id: Some(title_id.to_string()),
+            ..Default::default()
+        };
+        // Updates and DLC of the title count as the title
+        assert!(query("0100000000010000").matches(&item));
+        assert!(query("0100000000010800").matches(&item));
+        assert!(query("0100000000011001").matches(&item));
+        assert!(!query("0100000000020000").matches(&item));
+        assert!(!query("0100000000010000").matches(&untagged));
+
+        assert!(DownloadsQuery::default().matches(&untagged));
+        let by_job = DownloadsQuery {
+            import_job_id: Some(job_id),
+            ..Default::default()
+        };
+        assert!(by_job.matches(&item));
+        assert!(!by_job.matches(&untagged));
+    }
+
+    #[test]
+    fn test_redact_header() {
+        assert_eq!(
+            redact_header("Cookie", "session=0123456789abcdef"),
+            "sess***cdef"
+        );
+        assert_eq!(
+            redact_header("authorization", "Basic dXNlcjpwYXNz"),
+            "Basi***YXNz"
+        );
+        assert_eq!(redact_header("X-Api-Key", "short"), "***");
+        assert_eq!(redact_header("User-Agent", "Tinfoil/17.0"), "Tinfoil/17.0");
+    }
+
+    #[test]
+    fn test_progress_delta() {
+        let progress = |downloaded| Progress {
+            downloaded,
+            ..Default::default()
+        };
+        let (a, b) = (Ulid::from_parts(1, 0), Ulid::from_parts(2, 0));
+        let mut sent = HashMap::new();
+
+        let first = progress_delta(&mut sent, [(a, progress(0)), (b, progress(0))]);
+        assert_eq!(first.changed.len(), 2);
+        assert!(first.removed.is_empty());
+
+        // Only what changed is sent again
+        let delta = progress_delta(&mut sent, [(a, progress(10)), (b, progress(0))]);
+        assert_eq!(delta.changed, BTreeMap::from([(a, progress(10))]));
+        assert_eq!(
+            progress_delta(&mut sent, [(a, progress(10)), (b, progress(0))]),
+            ProgressDelta::default()
+        );
+
+        let delta = progress_delta(&mut sent, [(a, progress(10))]);
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.removed, vec![b]);
+        assert_eq!(sent.len(), 1);
+    }
+}
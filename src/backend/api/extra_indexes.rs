@@ -0,0 +1,164 @@
+//! Extra index management
+//!
+//! Extra indexes are other Tinfoil shops merged into ours. Besides the ones configured
+//! with `ALU_MERGE_INDEXES`, they can be added here, optionally with headers for private
+//! shops that require authentication. Headers are stored encrypted and never returned,
+//! only their names are listed.
+
+use std::collections::HashMap;
+
+use axum::{Json, Router, response::IntoResponse, routing::get};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{admin::ApiResponse, api::invalidate_index_cache},
+    index::{ExtraIndexesImport, Index},
+    secrets::SecretError,
+};
+
+/// An extra index as listed by the API
+#[derive(Debug, Serialize)]
+pub struct ExtraIndexInfo {
+    pub url: String,
+    /// Names of the headers sent with the request
+    pub headers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddExtraIndexRequest {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteExtraIndexRequest {
+    pub url: String,
+}
+
+fn error_response(status: StatusCode, message: String) -> axum::response::Response {
+    (
+        status,
+        Json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: Some(message),
+            data: None,
+        }),
+    )
+        .into_response()
+}
+
+/// List the extra indexes
+pub async fn list_extra_indexes() -> impl IntoResponse {
+    let indexes = match ExtraIndexesImport::list().await {
+        Ok(indexes) => indexes,
+        Err(e) => {
+            tracing::error!("Failed to list extra indexes: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let indexes: Vec<_> = indexes
+        .into_iter()
+        .map(|index| {
+            let mut headers: Vec<_> = match index.headers() {
+                Ok(headers) => headers.unwrap_or_default().into_keys().collect(),
+                Err(e) => {
+                    tracing::warn!(url = %index.url, "Failed to read extra index headers: {}", e);
+                    Vec::new()
+                }
+            };
+            headers.sort();
+            ExtraIndexInfo {
+                url: index.url,
+                headers,
+            }
+        })
+        .collect();
+
+    Json(indexes).into_response()
+}
+
+/// Add an extra index, or replace the headers of an existing one, and load it
+pub async fn add_extra_index(Json(request): Json<AddExtraIndexRequest>) -> impl IntoResponse {
+    let url = request.url.trim().to_string();
+    if !matches!(
+        reqwest::Url::parse(&url)
+            .map(|u| u.scheme().to_string())
+            .as_deref(),
+        Ok("http" | "https")
+    ) {
+        return error_response(StatusCode::BAD_REQUEST, format!("Invalid URL '{url}'"));
+    }
+
+    let index = match ExtraIndexesImport::new(url).with_headers(request.headers) {
+        Ok(index) => index,
+        Err(e @ SecretError::NoKey) => {
+            return error_response(StatusCode::BAD_REQUEST, e.to_string());
+        }
+        Err(e) => {
+            tracing::error!("Failed to encrypt extra index headers: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if let Err(e) = index.add().await {
+        tracing::error!("Failed to add extra index: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    // Don't wait for the next scheduled download to serve it
+    let url = index.url.clone();
+    tokio::spawn(async move {
+        match index.load().await {
+            Ok(()) => invalidate_index_cache(),
+            Err(e) => tracing::error!(url = %index.url, "Failed to load extra index: {}", e),
+        }
+    });
+
+    (
+        StatusCode::CREATED,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: None,
+            data: Some(url),
+        }),
+    )
+        .into_response()
+}
+
+/// Remove an extra index along with the files it added
+pub async fn delete_extra_index(Json(request): Json<DeleteExtraIndexRequest>) -> impl IntoResponse {
+    if ExtraIndexesImport::new(request.url.clone())
+        .delete()
+        .await
+        .is_err()
+    {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("Extra index '{}' not found", request.url),
+        );
+    }
+
+    if let Err(e) = Index::delete_extra_index(&request.url).await {
+        tracing::error!("Failed to delete saved extra index: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    invalidate_index_cache();
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub fn extra_indexes_api() -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(list_extra_indexes)
+                .post(add_extra_index)
+                .delete(delete_extra_index),
+        )
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
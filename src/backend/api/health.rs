@@ -0,0 +1,40 @@
+//! Health check endpoint
+
+use axum::Json;
+use serde::Serialize;
+
+use crate::{
+    nsp::{self, CnmtReadStats},
+    storage::{self, StorageReport},
+};
+
+#[derive(Serialize, Debug)]
+pub struct HealthResponse {
+    /// `ok` if everything is fine, `degraded` if the server is up but something needs attention
+    pub status: &'static str,
+    pub warnings: Vec<String>,
+    /// Result of the most recent free-space check
+    pub storage: Option<StorageReport>,
+    /// Files being scanned for metadata, a high number waiting means scans are backed up
+    pub cnmt_reads: CnmtReadStats,
+}
+
+pub async fn get_health() -> Json<HealthResponse> {
+    let storage = storage::last_report();
+    let mut warnings = Vec::new();
+
+    if storage.as_ref().is_some_and(|report| report.low_space) {
+        warnings.push(storage::LOW_SPACE_WARNING.to_string());
+    }
+
+    Json(HealthResponse {
+        status: if warnings.is_empty() {
+            "ok"
+        } else {
+            "degraded"
+        },
+        warnings,
+        storage,
+        cnmt_reads: nsp::cnmt_read_stats(),
+    })
+}
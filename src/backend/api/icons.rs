@@ -0,0 +1,112 @@
+//! Icons of many titles at once
+//!
+//! Frontends showing a page of titles can get all of their icons with a single request
+//! instead of one per title. Icons come from TitleDB, and updates use the icon of their
+//! base game since TitleDB has no entries for them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{Json, Router, response::IntoResponse, routing::post};
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::{index::TinfoilResponse, title_kind::TitleKind, titledb::Title};
+
+/// Most title IDs a single batch may ask for
+const MAX_ICON_BATCH: usize = 200;
+
+#[derive(Deserialize, Debug)]
+pub struct IconBatchRequest {
+    pub title_ids: Vec<String>,
+}
+
+/// Title ID whose TitleDB entry has the icon of a title
+fn icon_title_id(title_id: &str) -> String {
+    let title_id = title_id.trim().to_uppercase();
+    if TitleKind::is_update(&title_id) {
+        TitleKind::base_title_id(&title_id).unwrap_or(title_id)
+    } else {
+        title_id
+    }
+}
+
+/// Map every requested title ID to its icon URL, or `None` if it has none
+fn resolve_icons(
+    title_ids: &[String],
+    icons: &HashMap<String, String>,
+) -> BTreeMap<String, Option<String>> {
+    title_ids
+        .iter()
+        .map(|title_id| {
+            let icon = icons.get(&icon_title_id(title_id)).cloned();
+            (title_id.clone(), icon)
+        })
+        .collect()
+}
+
+/// Handler for getting the icon URLs of a batch of titles
+pub async fn icon_batch_handler(Json(request): Json<IconBatchRequest>) -> impl IntoResponse {
+    if request.title_ids.len() > MAX_ICON_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(TinfoilResponse::Failure(format!(
+                "Too many title IDs, at most {MAX_ICON_BATCH} can be requested at once"
+            ))),
+        )
+            .into_response();
+    }
+
+    let mut lookup: Vec<String> = request
+        .title_ids
+        .iter()
+        .map(|title_id| icon_title_id(title_id))
+        .collect();
+    lookup.sort();
+    lookup.dedup();
+
+    let locale = crate::config::config().backend_config.get_locale_string();
+    match Title::get_icon_urls(&locale, &lookup).await {
+        Ok(icons) => Json(resolve_icons(&request.title_ids, &icons)).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get icons: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub fn icons_api() -> Router {
+    Router::new().route("/batch", post(icon_batch_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_icons() {
+        let icons = HashMap::from([(
+            "0100000000010000".to_string(),
+            "https://example.com/icon.jpg".to_string(),
+        )]);
+        let title_ids = [
+            "0100000000010000".to_string(),
+            // Updates use the icon of the base game
+            "0100000000010800".to_string(),
+            // Keys stay as they were requested
+            "0100000000010000 ".to_string(),
+            "0100000000011001".to_string(),
+        ];
+
+        let resolved = resolve_icons(&title_ids, &icons);
+        assert_eq!(resolved.len(), 4);
+        assert_eq!(
+            resolved["0100000000010800"].as_deref(),
+            Some("https://example.com/icon.jpg")
+        );
+        assert_eq!(
+            resolved["0100000000010000 "].as_deref(),
+            Some("https://example.com/icon.jpg")
+        );
+        assert_eq!(resolved["0100000000011001"], None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Import API
+//!
+//! Shortcuts for starting imports without going through an importer's own request format.
+//! `POST /api/import/url` queues a single file, such as an NSP or an archive of them, and
+//! answers with the IDs of the download and of the import job that follows it.
+//! `POST /api/import/tinfoil_index` imports the files of a Tinfoil index pasted as the
+//! request body, for indexes that aren't hosted anywhere. Its body may be as large as an
+//! index fetched by the repository importer, rather than the global `max_body_size`.
+//! `GET /api/import/importers` lists the registered importers and the schemas of their
+//! requests, and `POST /api/import/{importer_id}` runs one of them with the JSON body as
+//! its request. The shortcuts above take precedence over importers of the same ID.
+
+use std::collections::HashMap;
+
+use axum::{
+    Json, Router,
+    extract::DefaultBodyLimit,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{
+    backend::admin::ApiResponse,
+    import::{
+        ImportSource, host_policy,
+        import_utils::run_import_job,
+        job::{ImportJob, ImportJobStatus},
+        repository::{self, IndexFileResult},
+    },
+    index::Index,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct UrlImportApiRequest {
+    pub url: String,
+    /// Headers sent along with the download, such as an `Authorization` header
+    #[serde(default)]
+    pub headers: Option<HashMap<String, String>>,
+    /// Whether to extract the file as an archive, detected from its extension when unset
+    #[serde(default)]
+    pub extract: Option<bool>,
+    /// Delete older versions of imported updates, defaults to the import job config
+    #[serde(default)]
+    pub clean_superseded_updates: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UrlImportResponse {
+    pub importer: String,
+    pub job_id: Ulid,
+    pub download_id: Ulid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TinfoilIndexImportRequest {
+    #[serde(flatten)]
+    pub index: Index,
+    /// URL relative file URLs of the index are resolved against
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Delete older versions of imported updates, defaults to the import job config
+    #[serde(default)]
+    pub clean_superseded_updates: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TinfoilIndexImportResponse {
+    pub importer: String,
+    /// Import job of the queued files, if any could be queued
+    pub job_id: Option<Ulid>,
+    pub files: Vec<IndexFileResult>,
+}
+
+fn error_response(status: StatusCode, message: String) -> axum::response::Response {
+    (
+        status,
+        Json(ApiResponse::<()> {
+            status: "error".to_string(),
+            message: Some(message),
+            data: None,
+        }),
+    )
+        .into_response()
+}
+
+/// Queue a download of a single URL and import it once it's done
+pub async fn import_url(Json(request): Json<UrlImportApiRequest>) -> impl IntoResponse {
+    let url = request.url.trim().to_string();
+    // Rejected URLs don't get an import job
+    if let Err(e) = host_policy::check_url(&url).await {
+        return error_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    // The job comes first, so the download is tagged with it
+    let job_id = ImportJob::create("url_importer", None);
+    let queued = ImportSource::enqueue_http(&url, request.headers, None, Some(job_id)).await;
+    let download = match queued {
+        Ok(download) => download,
+        Err(e) => {
+            tracing::error!(url, "Failed to queue download: {}", e);
+            ImportJob::set_status(&job_id, ImportJobStatus::Failed(e.to_string()));
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let download_id = download.id;
+
+    let source = ImportSource::QueuedHttp {
+        url,
+        download,
+        extract: request.extract,
+    };
+    run_import_job(
+        job_id,
+        "url_importer",
+        source,
+        request.clean_superseded_updates,
+    );
+
+    (
+        StatusCode::ACCEPTED,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: Some("Import started".to_string()),
+            data: Some(UrlImportResponse {
+                importer: "url_importer".to_string(),
+                job_id,
+                download_id,
+            }),
+        }),
+    )
+        .into_response()
+}
+
+/// Import the files of a Tinfoil index given as the request body
+pub async fn import_tinfoil_index(
+    Json(request): Json<TinfoilIndexImportRequest>,
+) -> impl IntoResponse {
+    const IMPORTER: &str = "tinfoil_index_importer";
+
+    let base_url = match request.base_url.as_deref().map(str::trim) {
+        Some(base_url) if !base_url.is_empty() => match url::Url::parse(base_url) {
+            Ok(base_url) => Some(base_url),
+            Err(e) => {
+                return error_response(StatusCode::BAD_REQUEST, format!("Invalid base URL: {e}"));
+            }
+        },
+        _ => None,
+    };
+
+    let mut files = repository::resolve_index_files(&request.index, base_url.as_ref());
+    // Rejected files are reported along with the others, the rest is still imported
+    for file in &mut files {
+        let Some(url) = &file.download_url else {
+            continue;
+        };
+        if let Err(e) = host_policy::check_url(url).await {
+            file.download_url = None;
+            file.error = Some(e.to_string());
+        }
+    }
+    let urls: Vec<String> = files
+        .iter()
+        .filter_map(|file| file.download_url.clone())
+        .collect();
+
+    if urls.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: Some("None of the files in the index can be imported".to_string()),
+                data: Some(TinfoilIndexImportResponse {
+                    importer: IMPORTER.to_string(),
+                    job_id: None,
+                    files,
+                }),
+            }),
+        )
+            .into_response();
+    }
+
+    tracing::info!(
+        files = urls.len(),
+        "Importing files of a pasted Tinfoil index"
+    );
+    let job_id = ImportJob::create(IMPORTER, None);
+    let source = ImportSource::RemoteHttpAutoList {
+        urls,
+        headers: repository::index_headers(&request.index),
+    };
+    run_import_job(job_id, IMPORTER, source, request.clean_superseded_updates);
+
+    (
+        StatusCode::ACCEPTED,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: Some("Import started".to_string()),
+            data: Some(TinfoilIndexImportResponse {
+                importer: IMPORTER.to_string(),
+                job_id: Some(job_id),
+                files,
+            }),
+        }),
+    )
+        .into_response()
+}
+
+pub fn import_api() -> Router {
+    Router::new()
+        .route("/url", post(import_url))
+        .route(
+            "/tinfoil_index",
+            post(import_tinfoil_index).layer(DefaultBodyLimit::max(repository::MAX_INDEX_BYTES)),
+        )
+        .route("/importers", get(crate::backend::admin::list_importers))
+        .route(
+            "/{importer_id}",
+            post(crate::backend::admin::process_import),
+        )
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_editor,
+        ))
+}
@@ -0,0 +1,244 @@
+//! Import job API
+//!
+//! Editors can follow and cancel the jobs they started by ID, while the full history of
+//! past and current imports is admin-only.
+//!
+//! The progress of a job, over all of its downloads and extracted archives, can also be
+//! streamed as server-sent events from `/{id}/events`. Each `progress` event carries the
+//! current [`ImportJobProgress`], and the stream ends once the job is finished.
+
+use std::time::Duration;
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query},
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post},
+};
+use futures::{Stream, future::select_all, stream};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use ulid::Ulid;
+
+use super::{
+    pagination::Pagination,
+    since::{invalid_since, parse_since},
+};
+use crate::{
+    import::{
+        downloader::DOWNLOAD_QUEUE,
+        job::{ImportHistoryFilter, ImportJob, ImportJobProgress, ImportJobStatus},
+    },
+    index::TinfoilResponse,
+};
+
+/// Shortest time between two progress events of a job
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest time between two checks of a job, so downloads it queued later are noticed
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ImportHistoryQuery {
+    /// Only jobs with this status, such as `failed`
+    pub status: Option<String>,
+    /// Only jobs created by this importer
+    pub importer: Option<String>,
+    /// Only jobs created from this time on, either `YYYY-MM-DD` or RFC 3339
+    pub since: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+fn bad_request(message: String) -> axum::response::Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(TinfoilResponse::Failure(message)),
+    )
+        .into_response()
+}
+
+/// Handler for searching the import history, newest first
+pub async fn list_import_jobs_handler(
+    Query(query): Query<ImportHistoryQuery>,
+) -> impl IntoResponse {
+    let status = query
+        .status
+        .map(|status| status.trim().to_lowercase())
+        .filter(|status| !status.is_empty());
+    if let Some(status) = &status {
+        if !ImportJobStatus::NAMES.contains(&status.as_str()) {
+            return bad_request(format!(
+                "Invalid status '{status}', expected one of: {}",
+                ImportJobStatus::NAMES.join(", ")
+            ));
+        }
+    }
+
+    let since = match query.since.as_deref().filter(|since| !since.is_empty()) {
+        Some(since) => match parse_since(since) {
+            Some(since) => Some(since),
+            None => return invalid_since(since),
+        },
+        None => None,
+    };
+
+    let pagination = Pagination::new(query.limit, query.offset);
+    let filter = ImportHistoryFilter {
+        status,
+        importer: query.importer.filter(|importer| !importer.is_empty()),
+        since,
+        limit: pagination.limit(),
+        offset: pagination.offset(),
+    };
+
+    match ImportJob::history(&filter).await {
+        Ok(page) => Json(page).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get import history: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Handler for getting a specific import job by ID
+pub async fn get_import_job_handler(Path(id): Path<Ulid>) -> Result<impl IntoResponse, StatusCode> {
+    match ImportJob::load(&id).await {
+        Ok(Some(job)) => Ok(Json(job).into_response()),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to get import job {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handler for cancelling an import job and all of its unfinished downloads
+pub async fn cancel_import_job_handler(Path(id): Path<Ulid>) -> impl IntoResponse {
+    match ImportJob::load(&id).await {
+        Ok(Some(job)) if job.status.is_finished() => {
+            return (
+                StatusCode::CONFLICT,
+                Json(TinfoilResponse::Failure(format!(
+                    "Import job {id} is already {}",
+                    job.status.name()
+                ))),
+            )
+                .into_response();
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get import job {}: {}", id, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    match ImportJob::cancel(&id) {
+        Some(cancelled) => Json(json!({ "cancelled_downloads": cancelled })).into_response(),
+        // It finished in the meantime
+        None => StatusCode::CONFLICT.into_response(),
+    }
+}
+
+/// Wait until a job or one of its downloads changed, or the poll interval passed
+async fn wait_for_job_change(id: &Ulid, updates: &mut broadcast::Receiver<Ulid>) {
+    let mut downloads = DOWNLOAD_QUEUE.lock().unwrap().subscribe_import_job(id);
+    let download_changed = async {
+        if downloads.is_empty() {
+            return std::future::pending().await;
+        }
+        let _ = select_all(downloads.iter_mut().map(|rx| Box::pin(rx.changed()))).await;
+    };
+    let job_changed = async {
+        loop {
+            match updates.recv().await {
+                Ok(updated) if updated == *id => return,
+                Ok(_) => {}
+                // Missed some updates, which may have been this job's
+                Err(broadcast::error::RecvError::Lagged(_)) => return,
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = download_changed => {}
+        _ = job_changed => {}
+        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+    }
+}
+
+/// Stream the progress of a job whenever it changes, until the job is finished
+fn job_progress_stream(job: ImportJob) -> impl Stream<Item = Result<Event, axum::Error>> {
+    struct State {
+        job: ImportJob,
+        updates: broadcast::Receiver<Ulid>,
+        last: Option<ImportJobProgress>,
+    }
+
+    let state = State {
+        updates: ImportJob::subscribe(),
+        job,
+        last: None,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(last) = &state.last {
+                if last.status.is_finished() {
+                    return None;
+                }
+                tokio::time::sleep(EVENT_INTERVAL).await;
+                wait_for_job_change(&state.job.id, &mut state.updates).await;
+            }
+
+            if let Some(job) = ImportJob::get(&state.job.id) {
+                state.job = job;
+            }
+            let progress = state.job.progress();
+            if state.last.as_ref() == Some(&progress) {
+                continue;
+            }
+
+            let event = Event::default().event("progress").json_data(&progress);
+            state.last = Some(progress);
+            return Some((event, state));
+        }
+    })
+}
+
+/// Handler for following the progress of an import job as server-sent events
+pub async fn import_job_events_handler(
+    Path(id): Path<Ulid>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match ImportJob::load(&id).await {
+        Ok(Some(job)) => Ok(Sse::new(job_progress_stream(job)).keep_alive(KeepAlive::default())),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to get import job {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn imports_api() -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(list_import_jobs_handler).layer(axum::middleware::from_fn(
+                crate::backend::user::auth_require_admin,
+            )),
+        )
+        .route("/{id}", get(get_import_job_handler))
+        .route("/{id}/cancel", post(cancel_import_job_handler))
+        .route("/{id}/events", get(import_job_events_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_editor,
+        ))
+}
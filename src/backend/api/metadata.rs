@@ -1,26 +1,210 @@
 use axum::{
     Json, Router,
     extract::{Path, Query},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::get,
 };
-use http::StatusCode;
+use http::{StatusCode, header};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
+use super::pagination::{PagePolicy, Pagination, page_response};
 use crate::{
     backend::api::invalidate_index_cache,
     db::NspMetadata,
     index::Index,
+    locale::Locale,
     router::{AlumRes, index_from_existing_data},
-    titledb::{Metaview, Title},
+    title_kind::TitleKind,
+    titledb::{
+        Metaview, MissingContent, SortOrder, Title, TitleDbImportProgress, TitleSort,
+        TitleSuggestion, import_progress, last_import_time, title_group_prefix,
+    },
     util::format_game_name,
 };
 
+/// Results fetched from the database at a time when streaming a search
+const SEARCH_STREAM_PAGE_SIZE: usize = 100;
+
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct SearchQuery {
     #[serde(rename = "q")]
     pub query: String,
-    #[serde(rename = "limit")]
+    /// Maximum number of results, clamped to `ALU_MAX_PAGE_SIZE` unless streaming
+    #[serde(rename = "limit", alias = "page_size")]
+    pub limit: Option<usize>,
+    /// Number of results to skip
+    #[serde(default)]
+    pub offset: Option<usize>,
+    /// 1-based page of results, used when no offset is given
+    #[serde(default)]
+    pub page: Option<usize>,
+    /// Whether to include titles flagged as demos, defaults to `true`
+    #[serde(default)]
+    pub include_demos: Option<bool>,
+    /// Stream the results as JSON Lines, fetching them page by page. Without a limit, every
+    /// match is streamed
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// Only titles that support this language, such as `en`, see [`normalize_language`]
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Fall back to typo tolerant matching when the full-text search finds nothing,
+    /// defaults to `false`
+    #[serde(default)]
+    pub fuzzy: Option<bool>,
+}
+
+impl SearchQuery {
+    pub fn include_demos(&self) -> bool {
+        self.include_demos.unwrap_or(true)
+    }
+
+    pub fn fuzzy(&self) -> bool {
+        self.fuzzy.unwrap_or_default()
+    }
+
+    pub fn language(&self) -> Option<String> {
+        normalize_language(self.language.as_deref())
+    }
+
+    pub fn pagination(&self) -> Pagination {
+        Pagination::new(self.limit, self.offset).with_page(self.page)
+    }
+}
+
+/// Normalize a language filter, which is matched against the languages TitleDB lists for a
+/// title. That's what the game supports, not the locale its metadata was imported from, so
+/// `en` also matches titles of the `JP_ja` table that ship with English.
+fn normalize_language(language: Option<&str>) -> Option<String> {
+    language
+        .map(|language| language.trim().to_lowercase())
+        .filter(|language| !language.is_empty())
+}
+
+/// Trim an exact match filter, which is ignored when empty
+fn non_empty(filter: Option<&str>) -> Option<String> {
+    filter
+        .map(str::trim)
+        .filter(|filter| !filter.is_empty())
+        .map(str::to_string)
+}
+
+/// Respond to a search, either with a JSON array of a page of titles along with the total
+/// number of matches or, when `stream` is set, with JSON Lines fetched [`SEARCH_STREAM_PAGE_SIZE`] at a time so
+/// even a huge result set never has to fit in memory.
+async fn search_response<F, Fut, C, CFut>(
+    query: SearchQuery,
+    search: F,
+    count: C,
+) -> AlumRes<Response>
+where
+    F: Fn(Arc<SearchQuery>, usize, usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = color_eyre::Result<Vec<Title>>> + Send + 'static,
+    C: FnOnce(Arc<SearchQuery>) -> CFut,
+    CFut: Future<Output = color_eyre::Result<usize>>,
+{
+    let pagination = query.pagination();
+    if !query.stream.unwrap_or_default() {
+        let query = Arc::new(query);
+        let (titles, total) = tokio::try_join!(
+            search(query.clone(), pagination.offset(), pagination.limit()),
+            count(query)
+        )?;
+        return Ok(page_response(titles, total));
+    }
+
+    let remaining = query.limit.unwrap_or(usize::MAX);
+    let query = Arc::new(query);
+    let start = pagination.offset();
+    let pages = futures::stream::unfold((start, remaining), move |(start, remaining)| {
+        let page = search(query.clone(), start, remaining.min(SEARCH_STREAM_PAGE_SIZE));
+        async move {
+            if remaining == 0 {
+                return None;
+            }
+            let titles = match page.await {
+                Ok(titles) => titles,
+                Err(e) => {
+                    tracing::error!("Streamed search failed at result {}: {}", start, e);
+                    return None;
+                }
+            };
+            if titles.is_empty() {
+                return None;
+            }
+
+            let mut lines = Vec::new();
+            for title in &titles {
+                if let Err(e) = serde_json::to_writer(&mut lines, title) {
+                    tracing::error!("Failed to serialize search result: {}", e);
+                    return None;
+                }
+                lines.push(b'\n');
+            }
+            let next = (start + titles.len(), remaining.saturating_sub(titles.len()));
+            Some((Ok::<_, std::io::Error>(lines), next))
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::from_stream(pages),
+    )
+        .into_response())
+}
+
+/// Query parameters for title listings
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct ListQuery {
+    /// Whether to include titles flagged as demos, defaults to `true`
+    #[serde(default)]
+    pub include_demos: Option<bool>,
+    /// Only titles that support this language, such as `en`, see [`normalize_language`]
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default, alias = "page_size")]
     pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: Option<usize>,
+    /// 1-based page, used when no offset is given
+    #[serde(default)]
+    pub page: Option<usize>,
+    /// Sort by `name`, `release_date` or `size` instead of ID, titles without one come last
+    #[serde(default)]
+    pub sort: Option<TitleSort>,
+    /// `asc` (the default) or `desc`, when sorting
+    #[serde(default)]
+    pub order: Option<SortOrder>,
+    /// Only titles in this category (genre), as listed by `/categories`
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Only titles by this publisher
+    #[serde(default)]
+    pub publisher: Option<String>,
+}
+
+impl ListQuery {
+    pub fn include_demos(&self) -> bool {
+        self.include_demos.unwrap_or(true)
+    }
+
+    pub fn language(&self) -> Option<String> {
+        normalize_language(self.language.as_deref())
+    }
+
+    pub fn category(&self) -> Option<String> {
+        non_empty(self.category.as_deref())
+    }
+
+    pub fn publisher(&self) -> Option<String> {
+        non_empty(self.publisher.as_deref())
+    }
+
+    pub fn pagination(&self) -> Pagination {
+        Pagination::new(self.limit, self.offset).with_page(self.page)
+    }
 }
 
 #[derive(serde::Serialize, Debug)]
@@ -69,18 +253,15 @@ pub async fn title_meta_base_game(
         }
     };
 
-    // Validate title_id_param length before extracting substring
-    if title_id_param.len() < 12 {
+    // Find base game that shares the grouping prefix
+    let Some(base_game_id) = title_group_prefix(&title_id_param) else {
         tracing::error!("Invalid title ID format: {}", title_id_param);
         return Err(StatusCode::BAD_REQUEST);
-    }
-
-    // Find base game that matches first 12 chars and ends with 000
-    let base_game_id = &title_id_param[..12];
+    };
 
     let base_metadata = match nsp_metadata
         .iter()
-        .find(|m| m.title_id.starts_with(base_game_id) && m.title_id.ends_with("000"))
+        .find(|m| m.title_id.starts_with(base_game_id) && TitleKind::is_base(&m.title_id))
     {
         Some(metadata) => metadata,
         None => {
@@ -107,12 +288,43 @@ pub async fn title_meta_base_game(
 }
 
 /// Get all alternate (non-base) versions of a title
-pub async fn get_download_ids(Path(title_id): Path<String>) -> AlumRes<Json<Vec<String>>> {
+pub async fn get_download_ids(
+    Path(title_id): Path<String>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    if title_group_prefix(&title_id).is_none() {
+        tracing::error!("Invalid title ID format: {}", title_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     match Metaview::get_download_ids(&title_id).await {
         Ok(view) => Ok(Json(view)),
         Err(e) => {
             tracing::error!("Failed to get download IDs for {}: {}", title_id, e);
-            Err(e.into())
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// List the updates and DLC of a game in the library that TitleDB knows of but the library
+/// doesn't have, by any title ID of the game
+#[tracing::instrument]
+pub async fn title_missing_content(
+    Path(title_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if title_group_prefix(&title_id).is_none() {
+        tracing::error!("Invalid title ID format: {}", title_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match MissingContent::find(&title_id).await {
+        Ok(Some(missing)) => Ok(Json(missing)),
+        Ok(None) => {
+            tracing::warn!("Base game not in the library for ID: {}", title_id);
+            Err(StatusCode::NOT_FOUND)
+        }
+        Err(e) => {
+            tracing::error!("Failed to find missing content of {}: {}", title_id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
@@ -124,10 +336,10 @@ pub async fn list_grouped_by_titleid(
     Path(title_id_param): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
     // Validate title ID length
-    if title_id_param.len() < 12 {
+    let Some(base_game_id) = title_group_prefix(&title_id_param) else {
         tracing::error!("Invalid title ID format (too short): {}", title_id_param);
         return Err(StatusCode::BAD_REQUEST);
-    }
+    };
 
     let nsp_metadata = match NspMetadata::get_all().await {
         Ok(metadata) => metadata,
@@ -137,12 +349,10 @@ pub async fn list_grouped_by_titleid(
         }
     };
 
-    let base_game_id = title_id_param[..12].to_string();
-
     // First try to find the base game in our local metadata
     let base_game_metadata = match nsp_metadata
         .iter()
-        .find(|m| m.title_id.starts_with(&base_game_id) && m.title_id.ends_with("000"))
+        .find(|m| m.title_id.starts_with(base_game_id) && TitleKind::is_base(&m.title_id))
     {
         Some(metadata) => metadata,
         None => {
@@ -171,9 +381,9 @@ pub async fn list_grouped_by_titleid(
 
     for metadata in nsp_metadata
         .iter()
-        .filter(|m| m.title_id.starts_with(&base_game_id))
+        .filter(|m| m.title_id.starts_with(base_game_id))
     {
-        if !metadata.title_id.ends_with("000") {
+        if !TitleKind::is_base(&metadata.title_id) {
             match Title::get_from_metaview_cache(&metadata.title_id).await {
                 Ok(Some(title)) => versions.push(title),
                 Ok(None) => {
@@ -198,16 +408,16 @@ pub async fn list_grouped_by_titleid(
 
 /// List base games only (games that end with 000)
 #[tracing::instrument]
-pub async fn list_base_games() -> Result<impl IntoResponse, StatusCode> {
-    match Metaview::get_base_games().await {
-        Ok(base_games) => {
-            let filtered_games = base_games
-                .into_iter()
-                .filter_map(|meta| meta.title)
-                .collect::<Vec<_>>();
-
-            Ok(Json(filtered_games).into_response())
-        }
+pub async fn list_base_games(
+    Query(list_query): Query<ListQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let pagination = list_query.pagination();
+    let base_games = tokio::try_join!(
+        Metaview::get_base_games(&list_query, pagination.offset(), pagination.limit()),
+        Metaview::count_base_games(&list_query)
+    );
+    match base_games {
+        Ok((page, total)) => Ok(page_response(page, total)),
         Err(e) => {
             tracing::error!("Failed to get base games: {}", e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -215,42 +425,204 @@ pub async fn list_base_games() -> Result<impl IntoResponse, StatusCode> {
     }
 }
 
-pub async fn search_titledb(query: Query<SearchQuery>) -> AlumRes<Json<Vec<Title>>> {
-    tracing::debug!(?query, "Searching for title with query");
-
-    match Title::search(&query).await {
-        Ok(search) => Ok(Json(search)),
+/// List the languages supported by the local titles, for the `language` filter
+#[tracing::instrument]
+pub async fn list_languages() -> Result<impl IntoResponse, StatusCode> {
+    match Metaview::get_languages().await {
+        Ok(languages) => Ok(Json(languages).into_response()),
         Err(e) => {
-            tracing::error!("Search failed: {}", e);
-            Err(e.into())
+            tracing::error!("Failed to get languages: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-pub async fn search_base_game(query: Query<SearchQuery>) -> AlumRes<Json<Vec<Title>>> {
-    let query = query.0;
-    tracing::debug!(?query, "Searching for base game with query");
+/// List the categories (genres) of the local titles, for the `category` filter
+#[tracing::instrument]
+pub async fn list_categories() -> Result<impl IntoResponse, StatusCode> {
+    match Metaview::get_categories().await {
+        Ok(categories) => Ok(Json(categories).into_response()),
+        Err(e) => {
+            tracing::error!("Failed to get categories: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
 
-    match Metaview::search_base_game(&query).await {
-        Ok(search) => Ok(Json(search)),
+/// List files without a real title ID, such as homebrew, which are left out of the catalog
+#[tracing::instrument]
+pub async fn list_unidentified(
+    Query(pagination): Query<Pagination>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match NspMetadata::get_unidentified().await {
+        Ok(files) => {
+            let (page, total): (Vec<_>, _) = pagination.paginate(files);
+            Ok(page_response(page, total))
+        }
         Err(e) => {
-            tracing::error!("Base game search failed: {}", e);
-            Err(e.into())
+            tracing::error!("Failed to get unidentified files: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-pub async fn search_titles(query: Query<SearchQuery>) -> AlumRes<Json<Vec<Title>>> {
+pub async fn search_titledb(query: Query<SearchQuery>) -> AlumRes<Response> {
+    tracing::debug!(?query, "Searching for title with query");
+
+    search_response(
+        query.0,
+        |query, start, limit| async move {
+            Title::search(&query, start, limit)
+                .await
+                .inspect_err(|e| tracing::error!("Search failed: {}", e))
+        },
+        |query| async move {
+            Title::count_search(&query)
+                .await
+                .inspect_err(|e| tracing::error!("Counting search results failed: {}", e))
+        },
+    )
+    .await
+}
+
+pub async fn search_base_game(query: Query<SearchQuery>) -> AlumRes<Response> {
+    let query = query.0;
+    tracing::debug!(?query, "Searching for base game with query");
+
+    search_response(
+        query,
+        |query, start, limit| async move {
+            Metaview::search_base_game(&query, start, limit)
+                .await
+                .inspect_err(|e| tracing::error!("Base game search failed: {}", e))
+        },
+        |query| async move {
+            Metaview::count_base_game_search(&query)
+                .await
+                .inspect_err(|e| tracing::error!("Counting base game search results failed: {}", e))
+        },
+    )
+    .await
+}
+
+pub async fn search_titles(query: Query<SearchQuery>) -> AlumRes<Response> {
     let query = query.0;
     tracing::debug!(?query, "Searching for title with query");
 
-    match Title::search(&query).await {
-        Ok(search) => Ok(Json(search)),
-        Err(e) => {
-            tracing::error!("Title search failed: {}", e);
-            Err(e.into())
+    search_response(
+        query,
+        |query, start, limit| async move {
+            Title::search(&query, start, limit)
+                .await
+                .inspect_err(|e| tracing::error!("Title search failed: {}", e))
+        },
+        |query| async move {
+            Title::count_search(&query)
+                .await
+                .inspect_err(|e| tracing::error!("Counting title search results failed: {}", e))
+        },
+    )
+    .await
+}
+
+const SUGGEST_LIMITS: PagePolicy = PagePolicy {
+    default: 8,
+    max: 20,
+};
+/// Shortest prefix worth querying, the search index n-grams start at 2 characters
+const SUGGEST_MIN_PREFIX_LEN: usize = 2;
+const SUGGEST_CACHE_TTL: Duration = Duration::from_secs(60);
+const SUGGEST_CACHE_CAPACITY: usize = 512;
+
+/// Autocomplete results keyed by lowercased prefix and limit, with the time they were fetched
+type SuggestCache = HashMap<(String, usize), (Instant, Vec<TitleSuggestion>)>;
+
+static SUGGEST_CACHE: LazyLock<Mutex<SuggestCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(serde::Deserialize, Debug)]
+pub struct SuggestQuery {
+    #[serde(default)]
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+/// Autocomplete base game names, returning only their title IDs and names
+pub async fn suggest_titles(
+    Query(query): Query<SuggestQuery>,
+) -> AlumRes<Json<Vec<TitleSuggestion>>> {
+    let prefix = query.q.trim().to_lowercase();
+    if prefix.chars().count() < SUGGEST_MIN_PREFIX_LEN {
+        return Ok(Json(Vec::new()));
+    }
+    let limit = SUGGEST_LIMITS.clamp(query.limit);
+    let key = (prefix, limit);
+
+    let cached = SUGGEST_CACHE
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|(cached_at, _)| cached_at.elapsed() < SUGGEST_CACHE_TTL)
+        .map(|(_, suggestions)| suggestions.clone());
+    if let Some(suggestions) = cached {
+        return Ok(Json(suggestions));
+    }
+
+    let locale = crate::config::config().backend_config.get_locale_string();
+    let suggestions = Title::suggest(&locale, &key.0, limit).await?;
+
+    let mut cache = SUGGEST_CACHE.lock().unwrap();
+    if cache.len() >= SUGGEST_CACHE_CAPACITY {
+        cache.retain(|_, (cached_at, _)| cached_at.elapsed() < SUGGEST_CACHE_TTL);
+        if cache.len() >= SUGGEST_CACHE_CAPACITY {
+            cache.clear();
         }
     }
+    cache.insert(key, (Instant::now(), suggestions.clone()));
+
+    Ok(Json(suggestions))
+}
+
+/// Title count and import freshness of a single TitleDB locale
+#[derive(serde::Serialize, Debug)]
+pub struct LocaleTitleCount {
+    pub count: i64,
+    /// When the locale was last imported, if it was since the server started
+    pub last_import: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the cached TitleDB file for the locale was last downloaded
+    pub cache_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Get the number of titles imported for each configured locale
+pub async fn titledb_counts() -> AlumRes<Json<BTreeMap<String, LocaleTitleCount>>> {
+    let backend_config = crate::config::config().backend_config;
+    let locales = std::iter::once(backend_config.primary_locale())
+        .chain(backend_config.secondary_locales.iter().copied());
+
+    let mut counts = BTreeMap::new();
+    for Locale { region, language } in locales {
+        let locale = Locale::new(region, language).to_string();
+        let count = Title::count(&locale).await?;
+
+        let cache_updated_at = crate::util::find_titledb_cache(region, language)
+            .and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .map(chrono::DateTime::<chrono::Utc>::from);
+
+        counts.insert(
+            locale.clone(),
+            LocaleTitleCount {
+                count,
+                last_import: last_import_time(&locale),
+                cache_updated_at,
+            },
+        );
+    }
+
+    Ok(Json(counts))
+}
+
+/// Get the progress of the TitleDB imports that are running, empty if none is
+pub async fn titledb_import_status() -> Json<Vec<TitleDbImportProgress>> {
+    Json(import_progress())
 }
 
 /// Creates a router for all metadata-related endpoints
@@ -262,9 +634,16 @@ pub fn metadata_api() -> Router {
             get(title_meta_base_game),
         )
         .route("/title_meta/{title_id}/download_ids", get(get_download_ids))
+        .route("/title_meta/{title_id}/missing", get(title_missing_content))
         .route("/grouped/{title_id}", get(list_grouped_by_titleid))
         .route("/base_games", get(list_base_games))
         .route("/base_games/search", get(search_base_game))
+        .route("/unidentified", get(list_unidentified))
+        .route("/languages", get(list_languages))
+        .route("/categories", get(list_categories))
         .route("/titledb/search", get(search_titledb))
+        .route("/titledb/counts", get(titledb_counts))
+        .route("/titledb/import_status", get(titledb_import_status))
         .route("/search", get(search_titles))
+        .route("/suggest", get(suggest_titles))
 }
@@ -0,0 +1,261 @@
+//! Prometheus metrics endpoint
+//!
+//! Serves counters and gauges in the OpenMetrics text format. Request durations and index
+//! cache lookups are recorded as they happen, download and title counts are looked up
+//! when the metrics are scraped.
+
+use axum::response::{IntoResponse, Response};
+use http::{Method, StatusCode, header};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::downloader::get_download_stats;
+use crate::{locale::Locale, router::AlumRes, titledb::Title};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Upper bounds of the request duration buckets in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Outcome of looking up the Tinfoil index in its cache
+#[derive(Debug, Clone, Copy)]
+pub enum IndexCacheLookup {
+    /// Served straight from the cache
+    Hit,
+    /// Only the changed slices of the rom dir, or the expired rest of the index, were
+    /// regenerated
+    Partial,
+    /// Missing or invalidated, the whole index was regenerated
+    Miss,
+}
+
+static INDEX_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static INDEX_CACHE_PARTIAL: AtomicU64 = AtomicU64::new(0);
+static INDEX_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Request durations by method and status code
+static REQUEST_DURATIONS: Mutex<BTreeMap<(&'static str, u16), Histogram>> =
+    Mutex::new(BTreeMap::new());
+
+pub fn record_index_cache(lookup: IndexCacheLookup) {
+    let counter = match lookup {
+        IndexCacheLookup::Hit => &INDEX_CACHE_HITS,
+        IndexCacheLookup::Partial => &INDEX_CACHE_PARTIAL,
+        IndexCacheLookup::Miss => &INDEX_CACHE_MISSES,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Label of a request method. Clients can send any token as a method, so anything
+/// non-standard shares one label instead of growing the metrics without bound
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "OTHER",
+    }
+}
+
+pub fn record_request(method: &Method, status: StatusCode, duration: Duration) {
+    let mut durations = REQUEST_DURATIONS.lock().unwrap();
+    durations
+        .entry((method_label(method), status.as_u16()))
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Builds an OpenMetrics text exposition
+#[derive(Debug, Default)]
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# TYPE {name} {kind}");
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let _ = write!(self.out, "{{{labels}}}");
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket_name = format!("{name}_bucket");
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let bound = bound.to_string();
+            let labels = [labels, &[("le", bound.as_str())]].concat();
+            self.sample(&bucket_name, &labels, cumulative);
+        }
+        let labels_inf = [labels, &[("le", "+Inf")]].concat();
+        self.sample(&bucket_name, &labels_inf, histogram.count);
+        self.sample(&format!("{name}_count"), labels, histogram.count);
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render all the metrics
+async fn render() -> AlumRes<String> {
+    let mut writer = MetricsWriter::default();
+
+    let stats = get_download_stats().await?;
+    writer.family(
+        "alumulemu_downloads",
+        "gauge",
+        "Downloads in the queue by status",
+    );
+    for (status, count) in [
+        ("queued", stats.queued),
+        ("downloading", stats.downloading),
+        ("paused", stats.paused),
+        ("completed", stats.completed),
+        ("cancelled", stats.cancelled),
+        ("failed", stats.failed),
+    ] {
+        writer.sample("alumulemu_downloads", &[("status", status)], count);
+    }
+
+    let backend_config = crate::config::config().backend_config;
+    let locales = std::iter::once(backend_config.primary_locale())
+        .chain(backend_config.secondary_locales.iter().copied());
+    writer.family(
+        "alumulemu_titles",
+        "gauge",
+        "Titles imported from TitleDB by locale",
+    );
+    for Locale { region, language } in locales {
+        let locale = Locale::new(region, language).to_string();
+        let count = Title::count(&locale).await?;
+        writer.sample("alumulemu_titles", &[("locale", &locale)], count);
+    }
+
+    writer.family(
+        "alumulemu_index_cache_lookups",
+        "counter",
+        "Tinfoil index requests by how much of the cached index could be used",
+    );
+    for (result, counter) in [
+        ("hit", &INDEX_CACHE_HITS),
+        ("partial", &INDEX_CACHE_PARTIAL),
+        ("miss", &INDEX_CACHE_MISSES),
+    ] {
+        writer.sample(
+            "alumulemu_index_cache_lookups_total",
+            &[("result", result)],
+            counter.load(Ordering::Relaxed),
+        );
+    }
+
+    let durations = REQUEST_DURATIONS.lock().unwrap().clone();
+    writer.family(
+        "alumulemu_http_request_duration_seconds",
+        "histogram",
+        "Time taken to answer HTTP requests",
+    );
+    for ((method, status), histogram) in &durations {
+        let status = status.to_string();
+        writer.histogram(
+            "alumulemu_http_request_duration_seconds",
+            &[("method", method), ("status", &status)],
+            histogram,
+        );
+    }
+
+    Ok(writer.finish())
+}
+
+pub async fn get_metrics() -> AlumRes<Response> {
+    let body = render().await?;
+    Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_writer() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(60.0);
+
+        let mut writer = MetricsWriter::default();
+        writer.family("test_titles", "gauge", "Titles");
+        writer.sample("test_titles", &[("locale", "US_\"en\"")], 3);
+        writer.histogram("test_duration_seconds", &[("method", "GET")], &histogram);
+        let out = writer.finish();
+
+        assert!(out.starts_with("# TYPE test_titles gauge\n# HELP test_titles Titles\n"));
+        assert!(out.contains("test_titles{locale=\"US_\\\"en\\\"\"} 3\n"));
+        assert!(out.contains("test_duration_seconds_bucket{method=\"GET\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("test_duration_seconds_bucket{method=\"GET\",le=\"0.25\"} 2\n"));
+        assert!(out.contains("test_duration_seconds_bucket{method=\"GET\",le=\"10\"} 2\n"));
+        assert!(out.contains("test_duration_seconds_bucket{method=\"GET\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("test_duration_seconds_count{method=\"GET\"} 3\n"));
+        assert!(out.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "OTHER"
+        );
+    }
+}
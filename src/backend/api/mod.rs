@@ -1,50 +1,309 @@
 use crate::{
-    backend::kv_config::{KvOptExt, Motd}, // Add Motd import
+    backend::index_filter::{IndexFilter, TitleFacets},
+    backend::kv_config::{
+        FilenameConfig, IndexGrouping, KvOptExt, Motd, MotdRecipient, ThemeConfig,
+        TinfoilIndexConfig,
+    },
     db::NspMetadata,
-    index::{Index, TinfoilResponse},
-    router::{AlumRes, index_from_existing_data},
+    index::{Index, TinfoilFileEntry, TinfoilResponse, TinfoilTitleMeta},
+    index_encryption::{configured_public_key, encrypt_index},
+    nsp::SystemVersion,
+    router::{
+        AlumRes, IndexScope, TINFOIL_HEADERS, dedup_index_entries, index_entry_from_metadata,
+    },
+    title_kind::TitleKind,
+    titledb::{Metaview, Title, title_group_base_id, title_group_prefix},
     util::format_game_name,
 };
 use axum::{
-    Json, Router,
-    extract::Path,
+    Extension, Json, Router,
+    extract::{ConnectInfo, Path, Query},
+    handler::Handler,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
 };
-use http::{StatusCode, header};
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, StatusCode, header};
+use serde::Deserialize;
 use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 
-use super::{kv_config::ExtraSourcesConfig, user::user_router};
+use super::{
+    admin::ApiResponse,
+    kv_config::ExtraSourcesConfig,
+    user::{User, user_router},
+};
 
+pub mod backfill;
+pub mod bandwidth;
+pub mod bundle;
 pub mod downloader;
+pub mod extra_indexes;
+pub mod health;
+pub mod icons;
+pub mod import;
+pub mod imports;
 pub mod metadata;
+pub mod metrics;
+pub mod pagination;
+pub mod popular;
+pub mod quarantine;
+pub mod repair;
+pub mod rescan;
+pub mod since;
+pub mod stats;
+pub mod themes;
+pub mod validate;
 pub mod config;
+pub mod version;
+
+/// Game files rarely change once imported, let clients keep them for a day and revalidate
+/// against the ETag afterwards. Shared caches only get to store them when no login is
+/// needed, otherwise they could hand them to clients without credentials.
+fn game_file_cache_control(public: bool) -> &'static str {
+    match public {
+        true => "public, max-age=86400, must-revalidate",
+        false => "private, max-age=86400, must-revalidate",
+    }
+}
+
+// Where the directories files are grouped into are served
+const INDEX_DIRECTORY_ROUTE: &str = "/api/tinfoil/directory/";
+
+// Past this many changed slices of the rom dir, rebuilding the whole index is cheaper
+// than splicing each of them into the cached one
+const MAX_DIRTY_SCOPES: usize = 64;
 
-// Default cache lifetime in seconds (5 minutes)
-const CACHE_LIFETIME_SECONDS: u64 = 300;
+// The index changes whenever files do, clients have to check their copy is still current
+const INDEX_CACHE_CONTROL: &str = "no-cache";
+
+/// The index as it's served
+#[derive(Debug, Clone, Default)]
+struct ServedIndex {
+    index: Index,
+    /// Hash of the serialized index, the ETag of the response
+    hash: String,
+}
+
+impl ServedIndex {
+    fn new(index: Index) -> Self {
+        let json = serde_json::to_vec(&index).unwrap_or_default();
+        let hash = format!("{:x}", Sha256::digest(json));
+        Self { index, hash }
+    }
+
+    /// Put a MOTD in front of the index's message, without hashing the whole index again
+    fn with_motd(mut self, motd: Option<&str>) -> Self {
+        if let Some(motd) = motd {
+            self.index.success = add_motd(self.index.success.take(), Some(motd));
+            self.hash = format!("{:x}", Sha256::digest(format!("{}\n{motd}", self.hash)));
+        }
+        self
+    }
+}
+
+/// Put a MOTD in front of the message of an index. Anything already there is a warning,
+/// which goes after the MOTD
+fn add_motd(success: Option<String>, motd: Option<&str>) -> Option<String> {
+    match (motd, success) {
+        (Some(motd), Some(warning)) => Some(format!("{motd}\n\n{warning}")),
+        (motd, warning) => warning.or(motd.map(str::to_string)),
+    }
+}
+
+/// Index entry of a file in the rom dir
+#[derive(Debug, Clone)]
+struct LocalIndexEntry {
+    title_id: String,
+    entry: TinfoilFileEntry,
+    /// Titledb metadata of the file's title, if there's something to tell Tinfoil about it
+    title_meta: Option<TinfoilTitleMeta>,
+    /// Directory the file is listed in, `None` for the top level of the index
+    directory: Option<String>,
+    /// What index filters look at, `None` if TitleDB doesn't know the title
+    facets: Option<TitleFacets>,
+}
+
+impl LocalIndexEntry {
+    fn is_visible(&self, filter: Option<&IndexFilter>) -> bool {
+        filter.is_none_or(|filter| filter.allows(&self.title_id, self.facets.as_ref()))
+    }
+}
 
 // Structure to hold cached index data with timestamp
+#[derive(Default)]
 struct IndexCache {
-    data: Option<Index>,
+    /// Everything in the index except the files from the rom dir (extras, themes...)
+    base: Option<Index>,
+    /// Applied when the index is served, as it depends on the client
+    motd: Option<Motd>,
+    /// Index entries of the files in the rom dir, keyed by path
+    local_files: BTreeMap<String, LocalIndexEntry>,
+    /// Slices of the rom dir that changed since their entries were generated
+    dirty: Vec<IndexScope>,
+    /// When the base was generated
     last_updated: Option<Instant>,
+    /// How long the base is used before being regenerated, as configured when it was
+    lifetime: Duration,
+    /// Assembled index without a MOTD, until anything above changes
+    served: Option<ServedIndex>,
+}
+
+impl IndexCache {
+    /// Files from the rom dir a filter lets through
+    fn visible_files(
+        &self,
+        filter: Option<&IndexFilter>,
+    ) -> impl Iterator<Item = (&String, &LocalIndexEntry)> {
+        self.local_files
+            .iter()
+            .filter(move |(_, local)| local.is_visible(filter))
+    }
+
+    /// Assemble the top level of the index, files from the rom dir first, followed by the
+    /// directories the other files are grouped into. Only the files a filter lets through
+    /// are listed.
+    ///
+    /// Files from extra indexes are left out of filtered indexes, as there's nothing to
+    /// tell which titles they are.
+    fn assemble(&self, filter: Option<&IndexFilter>) -> Option<Index> {
+        let base = self.base.as_ref()?;
+        let mut index = base.clone();
+        index.files = dedup_index_entries(
+            self.visible_files(filter)
+                .filter(|(_, local)| local.directory.is_none())
+                .map(|(path, local)| (path.as_str(), &local.entry)),
+        );
+        if filter.is_none() {
+            index.files.extend(base.files.iter().cloned());
+        }
+
+        let directories: BTreeSet<&str> = self
+            .visible_files(filter)
+            .filter_map(|(_, local)| local.directory.as_deref())
+            .collect();
+        index.directories.extend(
+            directories
+                .into_iter()
+                .map(|name| format!("{INDEX_DIRECTORY_ROUTE}{}", urlencoding::encode(name))),
+        );
+
+        let mut title_metas: Vec<&TinfoilTitleMeta> = self
+            .visible_files(filter)
+            .filter_map(|(_, local)| local.title_meta.as_ref())
+            .collect();
+        // Files of the same title may need different firmware, the highest requirement wins
+        title_metas.sort_by_key(|meta| meta.required_system_version);
+        for meta in title_metas {
+            index.add_title_metadata(meta.clone());
+        }
+        Some(index)
+    }
+
+    /// Assemble the index of a directory files are grouped into, with only the files a
+    /// filter lets through. `None` if there's no such directory
+    fn assemble_directory(&self, name: &str, filter: Option<&IndexFilter>) -> Option<Index> {
+        self.base.as_ref()?;
+        let files: Vec<(&String, &LocalIndexEntry)> = self
+            .visible_files(filter)
+            .filter(|(_, local)| local.directory.as_deref() == Some(name))
+            .collect();
+        if files.is_empty() {
+            return None;
+        }
+        Some(Index {
+            files: dedup_index_entries(
+                files
+                    .into_iter()
+                    .map(|(path, local)| (path.as_str(), &local.entry)),
+            ),
+            ..Default::default()
+        })
+    }
+
+    /// The MOTD to show a client right now
+    fn motd_for(&self, recipient: &MotdRecipient) -> Option<&str> {
+        self.motd
+            .as_ref()
+            .and_then(|motd| motd.message_for(recipient, Utc::now()))
+    }
+
+    /// Assemble the full index for a client, with the MOTD if it targets the client
+    fn assemble_for(
+        &self,
+        recipient: &MotdRecipient,
+        filter: Option<&IndexFilter>,
+    ) -> Option<Index> {
+        let mut index = self.assemble(filter)?;
+        index.success = add_motd(index.success, self.motd_for(recipient));
+        Some(index)
+    }
+
+    /// The assembled index for a client along with its hash. The index without the MOTD is
+    /// only hashed again after it changed, filtered indexes are assembled every time
+    fn served_for(
+        &mut self,
+        recipient: &MotdRecipient,
+        filter: Option<&IndexFilter>,
+    ) -> ServedIndex {
+        let served = match (filter, &self.served) {
+            (Some(_), _) => ServedIndex::new(self.assemble(filter).unwrap_or_default()),
+            (None, Some(served)) => served.clone(),
+            (None, None) => {
+                let served = ServedIndex::new(self.assemble(None).unwrap_or_default());
+                self.served = Some(served.clone());
+                served
+            }
+        };
+        served.with_motd(self.motd_for(recipient))
+    }
+
+    /// Replace the entries of a slice of the rom dir with freshly generated ones
+    fn splice(&mut self, scope: &IndexScope, entries: BTreeMap<String, LocalIndexEntry>) {
+        self.served = Default::default();
+        self.local_files
+            .retain(|path, local| !scope.matches(path, &local.title_id));
+        self.local_files.extend(entries);
+    }
+
+    /// Whether the base can still be used as it is
+    fn is_fresh(&self) -> bool {
+        self.base.is_some()
+            && self
+                .last_updated
+                .is_some_and(|timestamp| timestamp.elapsed() < self.lifetime)
+    }
+
+    /// Take what has to be regenerated: whether the base expired, and the slices of the rom
+    /// dir. Entries carry TitleDB data that can change without their files changing, so
+    /// all of them are regenerated along with an expired base.
+    fn take_stale(&mut self) -> (bool, Vec<IndexScope>) {
+        let dirty = std::mem::take(&mut self.dirty);
+        match self.is_fresh() {
+            true => (false, dirty),
+            false => (true, vec![IndexScope::All]),
+        }
+    }
 }
 
 // Create a global cache using lazy_static
-static INDEX_CACHE: Lazy<Arc<Mutex<IndexCache>>> = Lazy::new(|| {
-    Arc::new(Mutex::new(IndexCache {
-        data: None,
-        last_updated: None,
-    }))
-});
+static INDEX_CACHE: Lazy<Arc<Mutex<IndexCache>>> =
+    Lazy::new(|| Arc::new(Mutex::new(IndexCache::default())));
+
+/// Generates the parts of the Tinfoil index that don't come from the rom dir:
+/// extras, sources and themes.
+async fn generate_index_base() -> AlumRes<Index> {
+    let index_config = TinfoilIndexConfig::get().await?.unwrap_or_default();
 
-/// Generates the Tinfoil index data, merging base data, extras, and Motd.
-async fn generate_tinfoil_index_data() -> AlumRes<Index> {
-    let mut games = index_from_existing_data().await?;
+    let mut games = Index::default();
 
-    // Now, merge it with the extras if possible
+    // Merge in the extra indexes if possible
     if let Ok(extras) = Index::get_extra_indexes().await {
         extras.iter().for_each(|e_idx| {
             games.merge_file_index(e_idx.clone());
@@ -53,83 +312,758 @@ async fn generate_tinfoil_index_data() -> AlumRes<Index> {
         });
     }
 
-    // Check for Motd and apply if set
-    // Check for Motd and apply if set and enabled
-    games.success = match Motd::get().await {
-        // Only assign the message if Motd is fetched successfully, enabled, and has a message.
-        Ok(Some(motd)) if motd.enabled && motd.message.is_some() => motd.message,
-        // In all other cases (error, no Motd, disabled, or empty message), assign None.
-        _ => None,
-    };
+    // The MOTD is added in front of it when the index is served
+    if crate::storage::is_low_on_space() {
+        games.success = Some(crate::storage::LOW_SPACE_WARNING.to_string());
+    }
 
     games.locations = ExtraSourcesConfig::get()
         .await?
-        .map(|config| config.sources) // Extract the sources Vec if Some(config)
-        .unwrap_or_default(); // Use an empty Vec if None
+        .map(|config| config.sources)
+        .unwrap_or_default();
+
+    if let Some(themes) = ThemeConfig::get().await? {
+        games.theme_blacklist = themes.blacklist;
+        games.theme_whitelist = themes.whitelist;
+        games.theme_error = themes.error;
+    }
+
+    games.referrer = index_config.referrer().map(str::to_string);
+    games.version = index_config
+        .min_client_version
+        .filter(|version| !version.trim().is_empty());
 
     Ok(games)
 }
 
-#[axum::debug_handler]
-pub async fn tinfoil_index() -> AlumRes<Json<Index>> {
-    // Try to get cached version first
-    {
-        let cache = INDEX_CACHE.lock().unwrap();
-        if let (Some(cached_data), Some(timestamp)) = (&cache.data, cache.last_updated) {
-            // Check if cache is still valid
-            if timestamp.elapsed() < Duration::from_secs(CACHE_LIFETIME_SECONDS) {
-                tracing::debug!(
-                    "Serving tinfoil index from cache (age: {}s)",
-                    timestamp.elapsed().as_secs()
-                );
-                // Return a clone of the cached data
-                return Ok(Json(cached_data.clone()));
-            } else {
-                tracing::debug!(
-                    "Cache expired after {}s (max: {}s), regenerating",
-                    timestamp.elapsed().as_secs(),
-                    CACHE_LIFETIME_SECONDS
-                );
-                // Cache expired, proceed to regenerate below
-            }
-        } else {
+/// Get the MOTD, `None` if it's unset or can't be read
+async fn current_motd() -> Option<Motd> {
+    Motd::get().await.unwrap_or_else(|e| {
+        tracing::warn!("Failed to get the MOTD: {}", e);
+        None
+    })
+}
+
+/// Titledb metadata telling Tinfoil the firmware a file needs, `None` if it's unknown
+fn title_meta_from_metadata(
+    metadata: &NspMetadata,
+    title: Option<&Title>,
+) -> Option<TinfoilTitleMeta> {
+    let required = SystemVersion(metadata.required_system_version?);
+    let mut meta = title
+        .and_then(|title| TinfoilTitleMeta::try_from(title.clone()).ok())
+        .unwrap_or_else(|| TinfoilTitleMeta {
+            name: metadata.title_name.clone().unwrap_or_default(),
+            ..Default::default()
+        });
+    meta.title_id = metadata.title_id.clone();
+    if let Ok(version) = metadata.version.trim_start_matches('v').parse() {
+        meta.version = version;
+    }
+    Some(meta.with_required_system_version(required))
+}
+
+/// Get the TitleDB entries of the titles of files with a known firmware requirement,
+/// updates getting their base game's
+async fn titles_with_requirements(metadata: &[NspMetadata]) -> HashMap<String, Title> {
+    let mut title_ids: Vec<String> = metadata
+        .iter()
+        .filter(|m| m.required_system_version.is_some())
+        .map(|m| titledb_title_id(&m.title_id))
+        .collect();
+    if title_ids.is_empty() {
+        return HashMap::new();
+    }
+    title_ids.sort();
+    title_ids.dedup();
+
+    let locale = crate::config::config().backend_config.get_locale_string();
+    // The index works without them, Tinfoil has its own metadata
+    Title::get_from_title_ids(&locale, &title_ids)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Failed to get titles for the index metadata: {}", e);
+            HashMap::new()
+        })
+}
+
+/// Title ID whose TitleDB entry describes a title, updates have none of their own
+fn titledb_title_id(title_id: &str) -> String {
+    let title_id = title_id.to_uppercase();
+    if TitleKind::is_update(&title_id) {
+        TitleKind::base_title_id(&title_id).unwrap_or(title_id)
+    } else {
+        title_id
+    }
+}
+
+/// Get the TitleDB entries of the base games of files, by title group prefix, for
+/// grouping and filtering the files
+async fn base_game_titles(metadata: &[NspMetadata]) -> HashMap<String, Title> {
+    let mut title_ids: Vec<String> = metadata
+        .iter()
+        .filter(|m| !m.unidentified)
+        .filter_map(|m| title_group_base_id(&m.title_id))
+        .collect();
+    if title_ids.is_empty() {
+        return HashMap::new();
+    }
+    title_ids.sort();
+    title_ids.dedup();
+
+    let locale = crate::config::config().backend_config.get_locale_string();
+    // Files that can't be grouped are listed at the top level
+    let titles = Title::get_from_title_ids(&locale, &title_ids)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Failed to get the base games of the index files: {}", e);
+            HashMap::new()
+        });
+    titles
+        .into_iter()
+        .filter_map(|(title_id, title)| {
+            Some((title_group_prefix(&title_id)?.to_uppercase(), title))
+        })
+        .collect()
+}
+
+/// Get the TitleDB entry of the base game of a file
+fn base_game_of<'a>(
+    metadata: &NspMetadata,
+    base_games: &'a HashMap<String, Title>,
+) -> Option<&'a Title> {
+    if metadata.unidentified {
+        return None;
+    }
+    let prefix = title_group_prefix(&metadata.title_id)?.to_uppercase();
+    base_games.get(&prefix)
+}
+
+/// Drop the files a user's index filter hides from them, so they can't be downloaded by
+/// guessing their download IDs either
+pub(super) async fn visible_files(
+    user: Option<&User>,
+    files: Vec<NspMetadata>,
+) -> AlumRes<Vec<NspMetadata>> {
+    let Some(user) = user else {
+        return Ok(files);
+    };
+    let Some(filter) = IndexFilter::for_user(&user.username).await? else {
+        return Ok(files);
+    };
+    let base_games = base_game_titles(&files).await;
+    Ok(files
+        .into_iter()
+        .filter(|m| {
+            let facets = base_game_of(m, &base_games).map(TitleFacets::from);
+            filter.allows(&m.title_id, facets.as_ref())
+        })
+        .collect())
+}
+
+/// Directory a file is listed in, `None` if it's listed at the top level
+fn index_directory(
+    grouping: IndexGrouping,
+    metadata: &NspMetadata,
+    base_games: &HashMap<String, Title>,
+) -> Option<String> {
+    let base_game = || base_game_of(metadata, base_games);
+    let non_empty = |name: &str| Some(name.trim().to_string()).filter(|name| !name.is_empty());
+    match grouping {
+        IndexGrouping::Flat => None,
+        IndexGrouping::FirstLetter => {
+            let name = metadata.title_name.as_deref().unwrap_or_default();
+            let first = name.trim_start().chars().next()?;
+            Some(match first.is_ascii_alphabetic() {
+                true => first.to_ascii_uppercase().to_string(),
+                false => "#".to_string(),
+            })
+        }
+        IndexGrouping::Publisher => base_game()?.publisher.as_deref().and_then(non_empty),
+        IndexGrouping::BaseGame if metadata.unidentified => None,
+        IndexGrouping::BaseGame => base_game()
+            .and_then(|title| title.name.as_deref())
+            .and_then(non_empty)
+            // Files of the same game without a TitleDB entry still belong together
+            .or_else(|| title_group_base_id(&metadata.title_id)),
+    }
+}
+
+/// Generates the index entries of the files in a slice of the rom dir
+async fn generate_local_entries(scope: &IndexScope) -> AlumRes<BTreeMap<String, LocalIndexEntry>> {
+    let index_config = TinfoilIndexConfig::get().await?.unwrap_or_default();
+
+    let mut metadata = NspMetadata::get_in_scope(scope)
+        .await
+        .map_err(color_eyre::Report::from)?;
+    if index_config.exclude_demos {
+        let demo_ids = Metaview::get_demo_title_ids().await?;
+        metadata.retain(|m| !demo_ids.contains(&m.title_id));
+    }
+    let titles = titles_with_requirements(&metadata).await;
+    let base_games = base_game_titles(&metadata).await;
+    let filename_template = FilenameConfig::configured_template().await;
+
+    Ok(metadata
+        .into_iter()
+        .filter_map(|m| {
+            let entry = index_entry_from_metadata(&m, filename_template.as_ref())?;
+            let title_meta =
+                title_meta_from_metadata(&m, titles.get(&titledb_title_id(&m.title_id)));
+            let directory = index_directory(index_config.grouping, &m, &base_games);
+            let facets = base_game_of(&m, &base_games).map(TitleFacets::from);
+            Some((
+                m.path,
+                LocalIndexEntry {
+                    title_id: m.title_id,
+                    entry,
+                    title_meta,
+                    directory,
+                    facets,
+                },
+            ))
+        })
+        .collect())
+}
+
+/// Parts of the index that don't come from the rom dir, and how long to keep them
+struct IndexBase {
+    base: Index,
+    motd: Option<Motd>,
+    lifetime: Duration,
+}
+
+impl IndexBase {
+    async fn generate() -> AlumRes<Self> {
+        Ok(Self {
+            base: generate_index_base().await?,
+            motd: current_motd().await,
+            lifetime: TinfoilIndexConfig::get()
+                .await?
+                .unwrap_or_default()
+                .cache_lifetime(),
+        })
+    }
+
+    fn apply(self, cache: &mut IndexCache) {
+        cache.served = Default::default();
+        cache.base = Some(self.base);
+        cache.motd = self.motd;
+        cache.lifetime = self.lifetime;
+        cache.last_updated = Some(Instant::now());
+    }
+}
+
+/// Get the Tinfoil index from the cache.
+///
+/// The entries of the files in the rom dir are kept up to date as files are scanned or
+/// deleted: slices of the rom dir that changed are regenerated and spliced into the cached
+/// index. Once the cache expires, the rest of the index and all file entries are
+/// regenerated, picking up TitleDB changes. The cache is also dropped entirely when it was
+/// invalidated, such as after a TitleDB import, or too much changed at once.
+async fn cached_tinfoil_index(
+    recipient: &MotdRecipient,
+    filter: Option<&IndexFilter>,
+) -> AlumRes<ServedIndex> {
+    let pending = {
+        let mut cache = INDEX_CACHE.lock().unwrap();
+        if cache.base.is_none() {
             tracing::debug!("No cached index available, generating new index");
-            // No cache, proceed to regenerate below
+            cache.dirty.clear();
+            None
+        } else if cache.dirty.len() > MAX_DIRTY_SCOPES {
+            tracing::debug!(
+                "{} slices of the index changed, regenerating all of it",
+                cache.dirty.len()
+            );
+            cache.dirty.clear();
+            None
+        } else if cache.is_fresh() && cache.dirty.is_empty() {
+            tracing::debug!("Serving tinfoil index from cache");
+            metrics::record_index_cache(metrics::IndexCacheLookup::Hit);
+            return Ok(cache.served_for(recipient, filter));
+        } else {
+            Some(cache.take_stale())
         }
         // Lock is dropped here
+    };
+
+    if let Some((expired, scopes)) = pending {
+        let refreshed = async {
+            let base = match expired {
+                true => Some(IndexBase::generate().await?),
+                false => None,
+            };
+            let mut refreshed = Vec::with_capacity(scopes.len());
+            for scope in scopes {
+                let entries = generate_local_entries(&scope).await?;
+                refreshed.push((scope, entries));
+            }
+            AlumRes::Ok((base, refreshed))
+        }
+        .await;
+        let (base, refreshed) = match refreshed {
+            Ok(refreshed) => refreshed,
+            Err(e) => {
+                // The changed slices were taken out of the cache, don't keep serving an
+                // index we know is out of date
+                invalidate_index_cache();
+                return Err(e);
+            }
+        };
+
+        let mut cache = INDEX_CACHE.lock().unwrap();
+        // The cache may have been invalidated entirely while we were regenerating
+        if cache.base.is_some() {
+            if let Some(base) = base {
+                tracing::debug!("Regenerated the expired base of the tinfoil index");
+                base.apply(&mut cache);
+            }
+            for (scope, entries) in &refreshed {
+                cache.splice(scope, entries.clone());
+            }
+            tracing::debug!("Updated {} slices of the tinfoil index", refreshed.len());
+            metrics::record_index_cache(metrics::IndexCacheLookup::Partial);
+            return Ok(cache.served_for(recipient, filter));
+        }
     }
 
-    // If we got here, cache was missed or expired, regenerate the index
+    // If we got here, nothing usable was cached, regenerate the index
     tracing::debug!("Generating new tinfoil index data");
-    let games = generate_tinfoil_index_data().await?;
+    metrics::record_index_cache(metrics::IndexCacheLookup::Miss);
+    let base = IndexBase::generate().await?;
+    let local_files = generate_local_entries(&IndexScope::All).await?;
 
     // Update the cache with new data
-    {
-        let mut cache = INDEX_CACHE.lock().unwrap();
-        cache.data = Some(games.clone()); // Clone data for the cache
-        cache.last_updated = Some(Instant::now());
-        tracing::info!("Updated tinfoil index cache");
-    } // Lock is dropped here
+    let mut cache = INDEX_CACHE.lock().unwrap();
+    base.apply(&mut cache);
+    cache.local_files = local_files;
+    tracing::info!("Updated tinfoil index cache");
+
+    Ok(cache.served_for(recipient, filter))
+}
+
+/// Regenerate the whole cached index now, instead of on the next request
+pub async fn regenerate_index_cache() -> AlumRes<()> {
+    invalidate_index_cache();
+    cached_tinfoil_index(&MotdRecipient::default(), None).await?;
+    Ok(())
+}
+
+/// Generate the whole Tinfoil index as Tinfoil sees it, bypassing and leaving alone the cache
+pub(crate) async fn generate_tinfoil_index() -> AlumRes<Index> {
+    let index = IndexCache {
+        base: Some(generate_index_base().await?),
+        motd: current_motd().await,
+        local_files: generate_local_entries(&IndexScope::All).await?,
+        ..Default::default()
+    };
+    let recipient = MotdRecipient {
+        tinfoil_client: true,
+        ..Default::default()
+    };
+    Ok(index.assemble_for(&recipient, None).unwrap_or_default())
+}
 
-    Ok(Json(games)) // Return the newly generated data
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexFormat {
+    Json,
+    Encrypted,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TinfoilIndexQuery {
+    /// Force a response format. By default Tinfoil clients get the encrypted index
+    /// if a Tinfoil public key is configured, and everything else gets plain JSON.
+    pub format: Option<IndexFormat>,
+}
+
+#[axum::debug_handler]
+pub async fn tinfoil_index(
+    headers: HeaderMap,
+    user: Option<Extension<User>>,
+    Query(query): Query<TinfoilIndexQuery>,
+) -> AlumRes<Response> {
+    let filter = user_index_filter(user).await?;
+    let served = cached_tinfoil_index(&motd_recipient(&headers), filter.as_ref()).await?;
+    serve_index(&headers, &query, served)
+}
+
+/// Get the index of a directory the files from the rom dir are grouped into
+pub async fn tinfoil_directory(
+    headers: HeaderMap,
+    user: Option<Extension<User>>,
+    Path(name): Path<String>,
+    Query(query): Query<TinfoilIndexQuery>,
+) -> AlumRes<Response> {
+    let filter = user_index_filter(user).await?;
+    // Brings the cached file entries up to date
+    cached_tinfoil_index(&motd_recipient(&headers), None).await?;
+    let directory = INDEX_CACHE
+        .lock()
+        .unwrap()
+        .assemble_directory(&name, filter.as_ref());
+    match directory {
+        Some(index) => serve_index(&headers, &query, ServedIndex::new(index)),
+        None => Ok((
+            StatusCode::NOT_FOUND,
+            Json(TinfoilResponse::Failure(format!(
+                "No directory named {name}"
+            ))),
+        )
+            .into_response()),
+    }
+}
+
+/// Get the index filter of the user making a request, if it has one
+async fn user_index_filter(user: Option<Extension<User>>) -> AlumRes<Option<IndexFilter>> {
+    match user {
+        Some(Extension(user)) => Ok(IndexFilter::for_user(&user.username).await?),
+        None => Ok(None),
+    }
+}
+
+/// Languages of an `Accept-Language` header, most preferred first
+fn accept_languages(headers: &HeaderMap) -> Vec<String> {
+    let Some(header) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Vec::new();
+    };
+    let mut languages: Vec<(f32, &str)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let tag = params.next()?.trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.trim().parse().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((quality, tag))
+        })
+        .collect();
+    // Stable, so languages of the same quality keep their order
+    languages.sort_by(|a, b| b.0.total_cmp(&a.0));
+    languages
+        .into_iter()
+        .map(|(_, tag)| tag.to_string())
+        .collect()
+}
+
+fn motd_recipient(headers: &HeaderMap) -> MotdRecipient {
+    MotdRecipient {
+        tinfoil_client: is_tinfoil_client(headers),
+        languages: accept_languages(headers),
+    }
+}
+
+fn is_tinfoil_client(headers: &HeaderMap) -> bool {
+    TINFOIL_HEADERS
+        .iter()
+        .all(|&header| headers.contains_key(header))
+}
+
+/// Respond with an index, encrypted for Tinfoil clients if a key is configured
+fn serve_index(
+    headers: &HeaderMap,
+    query: &TinfoilIndexQuery,
+    served: ServedIndex,
+) -> AlumRes<Response> {
+    let encrypt = match query.format {
+        Some(format) => format == IndexFormat::Encrypted,
+        None => is_tinfoil_client(headers),
+    };
+    let key = match configured_public_key().map_err(|e| color_eyre::eyre::eyre!(e))? {
+        Some(key) if encrypt => Some(key),
+        // Tinfoil reads plain JSON just fine, the encryption is opt-in
+        None if encrypt && query.format.is_some() => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(TinfoilResponse::Failure(
+                    "Encrypted index requested, but no Tinfoil public key is configured"
+                        .to_string(),
+                )),
+            )
+                .into_response());
+        }
+        _ => None,
+    };
+
+    // The encrypted index is a different representation of the same contents
+    let etag = match key {
+        Some(_) => format!("\"{}-encrypted\"", served.hash),
+        None => format!("\"{}\"", served.hash),
+    };
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, INDEX_CACHE_CONTROL.to_string()),
+    ];
+    if is_not_modified(headers, &etag, None) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    match key {
+        Some(key) => {
+            let encrypted = encrypt_index(&served.index, key).map_err(color_eyre::Report::from)?;
+            Ok((
+                cache_headers,
+                [(header::CONTENT_TYPE, "application/octet-stream")],
+                encrypted,
+            )
+                .into_response())
+        }
+        None => Ok((cache_headers, Json(served.index)).into_response()),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeleteGameQuery {
+    /// Move the file into the trash folder of the rom dir instead of deleting it, see
+    /// [`crate::import::trash`]
+    #[serde(default)]
+    pub trash: bool,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct DeletedGame {
+    pub path: String,
+    /// Where the file is now, if it was moved to the trash
+    pub trashed_to: Option<String>,
+}
+
+/// Delete a game file and its metadata
+pub async fn delete_game(
+    Path(download_id_param): Path<String>,
+    Query(query): Query<DeleteGameQuery>,
+    user: Option<Extension<User>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !is_safe_download_id(&download_id_param) {
+        tracing::warn!(
+            "Path traversal attempt detected in download ID: {}",
+            download_id_param
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let metadata = NspMetadata::get_from_download_id(&download_id_param)
+        .await
+        .map_err(|err| {
+            tracing::error!(
+                "Failed to retrieve metadata for download ID {}: {}",
+                download_id_param,
+                err
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or_else(|| {
+            tracing::warn!("No metadata found for download ID: {}", download_id_param);
+            StatusCode::NOT_FOUND
+        })?;
+
+    let path = std::path::Path::new(&metadata.path);
+    let removal = if query.trash {
+        let rom_dir = crate::config::config().backend_config.rom_dir_of(path);
+        crate::import::trash::move_to_trash(&rom_dir, path)
+            .await
+            .map(Some)
+    } else {
+        tokio::fs::remove_file(path).await.map(|()| None)
+    };
+    let trashed_to = match removal {
+        Ok(trashed_to) => trashed_to,
+        // Already gone, only the metadata is left to clean up
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::error!("Failed to delete {}: {}", metadata.path, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Also drops the file from the tinfoil index
+    if let Err(e) = metadata.delete().await {
+        tracing::error!("Failed to delete metadata for {}: {}", metadata.path, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    tracing::info!(
+        path = metadata.path,
+        trashed_to = ?trashed_to,
+        user = user.map(|Extension(user)| user.username),
+        "Deleted game"
+    );
+
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: None,
+        data: Some(DeletedGame {
+            path: metadata.path,
+            trashed_to: trashed_to.map(|path| path.display().to_string()),
+        }),
+    }))
 }
 
 // Function to manually invalidate the cache if needed
 pub fn invalidate_index_cache() {
     let mut cache = INDEX_CACHE.lock().unwrap();
-    cache.data = None;
-    cache.last_updated = None;
+    *cache = IndexCache::default();
     tracing::info!("Tinfoil index cache invalidated");
 }
 
+/// Drop the cached index and regenerate all of it in the background
+pub async fn refresh_tinfoil_index() -> Json<ApiResponse<()>> {
+    invalidate_index_cache();
+    tokio::spawn(async {
+        if let Err(e) = regenerate_index_cache().await {
+            tracing::error!("Failed to regenerate the tinfoil index: {}", e);
+        }
+    });
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: Some("Tinfoil index is being regenerated".to_string()),
+        data: None,
+    })
+}
+
+/// Mark a slice of the rom dir as changed, so only its entries are regenerated
+/// the next time the index is requested
+pub fn invalidate_index_scope(scope: IndexScope) {
+    let mut cache = INDEX_CACHE.lock().unwrap();
+    // Nothing cached yet, the next request regenerates everything anyway
+    if cache.base.is_none() {
+        return;
+    }
+    if !cache.dirty.contains(&scope) {
+        tracing::debug!(?scope, "Tinfoil index slice invalidated");
+        cache.dirty.push(scope);
+    }
+}
+
+/// Builds a strong ETag for a file from its size and modification time.
+///
+/// This avoids hashing multi-gigabyte game files while still changing whenever
+/// the file on disk is replaced.
+fn file_etag(metadata: &std::fs::Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime)
+}
+
+/// Formats a timestamp as an HTTP date for `Last-Modified`
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether a client's cached copy of a file is still current, following the precedence of
+/// RFC 9110: `If-Modified-Since` is only looked at when there's no `If-None-Match`.
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false;
+        };
+        // Weak comparison, a W/ prefix doesn't make a tag stale
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    match (since, modified) {
+        // HTTP dates only have second precision
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+/// Range header we can't serve, answered with 416
+#[derive(Debug, PartialEq, Eq)]
+struct UnsatisfiableRange;
+
+/// Parse a `Range` header into the inclusive byte span to serve out of a file of `len` bytes.
+///
+/// Only single byte ranges are supported, multipart ranges are answered with the whole file
+/// (`Ok(None)`) which the spec allows.
+fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, UnsatisfiableRange> {
+    let spec = header
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(UnsatisfiableRange)?;
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.trim().split_once('-').ok_or(UnsatisfiableRange)?;
+    // Nothing in an empty file can be asked for
+    let last = len.checked_sub(1).ok_or(UnsatisfiableRange)?;
+    let parse = |value: &str| value.trim().parse::<u64>().map_err(|_| UnsatisfiableRange);
+
+    let (start, end) = match (start.trim().is_empty(), end.trim().is_empty()) {
+        // bytes=-500, the last 500 bytes
+        (true, false) => {
+            let suffix = parse(end)?;
+            if suffix == 0 {
+                return Err(UnsatisfiableRange);
+            }
+            (len.saturating_sub(suffix), last)
+        }
+        // bytes=500-, everything from byte 500 on
+        (false, true) => (parse(start)?, last),
+        (false, false) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if end < start {
+                return Err(UnsatisfiableRange);
+            }
+            (start, end.min(last))
+        }
+        (true, true) => return Err(UnsatisfiableRange),
+    };
+
+    if start >= len {
+        return Err(UnsatisfiableRange);
+    }
+    Ok(Some((start, end)))
+}
+
+/// Reject file downloads from other sites when the referrer is enforced
+pub(crate) async fn check_referer(headers: &HeaderMap) -> Result<(), StatusCode> {
+    let index_config = match TinfoilIndexConfig::get().await {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            tracing::error!("Failed to get the index config: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let header_value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let referer = header_value(header::REFERER);
+    if index_config.allows_referer(referer, header_value(header::HOST)) {
+        Ok(())
+    } else {
+        tracing::warn!("Rejected download with referer {:?}", referer);
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Check a download ID for path traversal attempts
+fn is_safe_download_id(download_id: &str) -> bool {
+    !(download_id.contains("..") || download_id.contains('/') || download_id.contains('\\'))
+}
+
 pub async fn download_file(
     Path(download_id_param): Path<String>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    user: Option<Extension<User>>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
+    check_referer(&headers).await?;
+
     // Block any path traversal attempts
-    if download_id_param.contains("..")
-        || download_id_param.contains('/')
-        || download_id_param.contains('\\')
-    {
+    if !is_safe_download_id(&download_id_param) {
         tracing::warn!(
             "Path traversal attempt detected in download ID: {}",
             download_id_param
@@ -162,11 +1096,24 @@ pub async fn download_file(
         }
     };
 
+    // Files hidden from the user's index don't exist as far as they're concerned
+    let user_ref = user.as_ref().map(|Extension(user)| user);
+    let visible = visible_files(user_ref, vec![metadata_entry])
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to apply the index filter: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let Some(metadata_entry) = visible.into_iter().next() else {
+        tracing::debug!("{} is hidden by the index filter", download_id_param);
+        return Err(StatusCode::NOT_FOUND);
+    };
+
     let file_path = &metadata_entry.path;
     tracing::debug!("Found file path: {}", file_path);
 
     // Open the file with better error handling
-    let file = match tokio::fs::File::open(file_path).await {
+    let mut file = match tokio::fs::File::open(file_path).await {
         Ok(file) => file,
         Err(e) => {
             tracing::error!("Failed to open file at {}: {}", file_path, e);
@@ -174,6 +1121,14 @@ pub async fn download_file(
         }
     };
 
+    let file_metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            tracing::error!("Failed to read file metadata for {}: {}", file_path, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
     // Get the raw filename and extension for formatting with better error handling
     let path = std::path::Path::new(file_path);
 
@@ -194,7 +1149,13 @@ pub async fn download_file(
         });
 
     // Create a nicely formatted filename for the download
-    let formatted_filename = format_game_name(&metadata_entry, &raw_filename, extension);
+    let filename_template = FilenameConfig::configured_template().await;
+    let formatted_filename = format_game_name(
+        &metadata_entry,
+        &raw_filename,
+        extension,
+        filename_template.as_ref(),
+    );
 
     // Sanitize the filename to ensure it's safe for Content-Disposition
     // Replace any characters that might cause issues in headers
@@ -202,16 +1163,98 @@ pub async fn download_file(
 
     tracing::info!("Serving download with filename: {}", safe_filename);
 
-    let stream = ReaderStream::new(file);
+    let file_len = file_metadata.len();
+    let etag = file_etag(&file_metadata);
+    let modified = file_metadata.modified().ok().map(DateTime::<Utc>::from);
+    let last_modified = modified.map(http_date);
+
+    let cache_control = game_file_cache_control(crate::config::config().backend_config.public);
+
+    if is_not_modified(&headers, &etag, modified) {
+        tracing::debug!("Client copy of {} is current", file_path);
+        let mut builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::ETAG, &etag);
+        if let Some(last_modified) = &last_modified {
+            builder = builder.header(header::LAST_MODIFIED, last_modified);
+        }
+        return builder.body(axum::body::Body::empty()).map_err(|e| {
+            tracing::error!("Failed to build response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        });
+    }
+
+    // A resumed download only gets the rest of the file if it still has the same version of it
+    let if_range_matches = headers
+        .get(header::IF_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .is_none_or(|value| value == etag || Some(value) == last_modified.as_deref());
+    let range = match headers.get(header::RANGE).filter(|_| if_range_matches) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| UnsatisfiableRange)
+            .and_then(|value| parse_range(value, file_len)),
+        None => Ok(None),
+    };
+    let range = match range {
+        Ok(range) => range,
+        Err(UnsatisfiableRange) => {
+            tracing::debug!("Unsatisfiable range requested for {}", file_path);
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{file_len}"))
+                .header(header::ACCEPT_RANGES, "bytes")
+                .body(axum::body::Body::empty())
+                .map_err(|e| {
+                    tracing::error!("Failed to build response: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                });
+        }
+    };
+
+    let (start, end) = range.unwrap_or((0, file_len.saturating_sub(1)));
+    let content_length = if file_len == 0 { 0 } else { end - start + 1 };
+    if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+        tracing::error!("Failed to seek in {}: {}", file_path, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if popular::counts_as_download(&headers, start) {
+        popular::spawn_record_download(metadata_entry.title_id.clone());
+    }
+
+    let stream = bandwidth::CountingStream::new(
+        ReaderStream::new(file.take(content_length)),
+        download_id_param,
+        content_length,
+        connect_info.map(|Extension(ConnectInfo(addr))| addr.ip().to_string()),
+        user.map(|Extension(user)| user.username),
+    );
     let body = axum::body::Body::from_stream(stream);
 
+    let mut builder = Response::builder();
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
+    if range.is_some() {
+        let content_range = format!("bytes {start}-{end}/{file_len}");
+        builder = builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, content_range);
+    }
+
     // Build the response with proper error handling
-    match Response::builder()
+    match builder
         .header(header::CONTENT_TYPE, "application/octet-stream")
         .header(
             header::CONTENT_DISPOSITION,
             format!("attachment; filename=\"{safe_filename}\""),
         )
+        .header(header::CONTENT_LENGTH, content_length)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag)
         .body(body)
     {
         Ok(response) => {
@@ -233,10 +1276,41 @@ pub fn api_router() -> Router {
     // Basic routes that all authenticated users can access (viewer level)
     let api_routes = Router::new()
         .nest("/downloads", downloader::downloader_api())
+        .nest("/import", import::import_api())
+        .nest("/imports", imports::imports_api())
+        .nest("/extra_indexes", extra_indexes::extra_indexes_api())
+        .nest("/backfill_names", backfill::backfill_api())
+        .nest("/repair", repair::repair_api())
+        .nest("/quarantine", quarantine::quarantine_api())
+        .nest("/rescan", rescan::rescan_api())
+        .nest("/popular", popular::popular_api())
+        .nest("/icons", icons::icons_api())
+        .nest("/bandwidth", bandwidth::bandwidth_api())
+        .nest("/themes", themes::themes_api())
         .nest("/config", config::config_router())
         .merge(metadata::metadata_api()) // Use merge to maintain original paths
+        .merge(validate::validate_api())
         .route("/tinfoil", get(tinfoil_index))
-        .route("/get_game/{download_id}", get(download_file));
+        .route("/tinfoil/directory/{name}", get(tinfoil_directory))
+        .route(
+            "/tinfoil/refresh",
+            post(refresh_tinfoil_index.layer(axum::middleware::from_fn(
+                crate::backend::user::auth_require_editor,
+            ))),
+        )
+        .route("/version", get(version::get_version))
+        .route("/health", get(health::get_health))
+        .route("/stats", get(stats::get_stats))
+        .route(
+            "/get_game/{download_id}",
+            get(download_file).delete(delete_game.layer(axum::middleware::from_fn(
+                crate::backend::user::auth_require_editor,
+            ))),
+        )
+        .route(
+            "/get_title_bundle/{title_id}",
+            get(bundle::get_title_bundle),
+        );
 
     // Combine the routes
     Router::new()
@@ -247,3 +1321,565 @@ pub fn api_router() -> Router {
             crate::backend::user::auth_optional_viewer,
         ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::kv_config::MotdTarget;
+
+    fn local(title_id: &str, url: &str) -> LocalIndexEntry {
+        LocalIndexEntry {
+            title_id: title_id.to_string(),
+            entry: TinfoilFileEntry {
+                url: url.to_string(),
+                size: 1,
+            },
+            title_meta: None,
+            directory: None,
+            facets: None,
+        }
+    }
+
+    fn urls(index: &Index) -> Vec<&str> {
+        index.files.iter().map(|file| file.url.as_str()).collect()
+    }
+
+    #[test]
+    fn test_index_scope_matches() {
+        let dir = IndexScope::Path("/roms/Game".to_string());
+        assert!(dir.matches("/roms/Game/base.nsp", "0100000000010000"));
+        assert!(!dir.matches("/roms/Game 2/base.nsp", "0100000000010000"));
+
+        let prefix = IndexScope::TitlePrefix("0100000000010".to_string());
+        assert!(prefix.matches("/roms/a.nsp", "0100000000010800"));
+        assert!(!prefix.matches("/roms/a.nsp", "0100000000020000"));
+    }
+
+    #[test]
+    fn test_index_cache_splice() {
+        let mut cache = IndexCache {
+            base: Some(Index {
+                files: vec![local("", "https://extra/file.nsp").entry],
+                ..Default::default()
+            }),
+            local_files: BTreeMap::from([
+                (
+                    "/roms/a/base.nsp".to_string(),
+                    local("0100000000010000", "a"),
+                ),
+                (
+                    "/roms/a/update.nsp".to_string(),
+                    local("0100000000010800", "a-update"),
+                ),
+                ("/roms/b.nsp".to_string(), local("0100000000020000", "b")),
+            ]),
+            ..Default::default()
+        };
+
+        // The update was removed and the base game re-scanned
+        cache.splice(
+            &IndexScope::Path("/roms/a".to_string()),
+            BTreeMap::from([(
+                "/roms/a/base.nsp".to_string(),
+                local("0100000000010000", "a-v2"),
+            )]),
+        );
+        assert_eq!(
+            urls(&cache.assemble(None).unwrap()),
+            vec!["a-v2", "b", "https://extra/file.nsp"]
+        );
+
+        cache.splice(
+            &IndexScope::TitlePrefix("0100000000020".to_string()),
+            BTreeMap::new(),
+        );
+        assert_eq!(
+            urls(&cache.assemble(None).unwrap()),
+            vec!["a-v2", "https://extra/file.nsp"]
+        );
+    }
+
+    #[test]
+    fn test_served_index_hash() {
+        let mut cache = IndexCache {
+            base: Some(Index::default()),
+            local_files: BTreeMap::from([("/roms/a.nsp".to_string(), local("", "a"))]),
+            ..Default::default()
+        };
+        let served = cache.served_for(&MotdRecipient::default(), None);
+        assert_eq!(served.hash.len(), 64);
+        assert_eq!(
+            cache.served_for(&MotdRecipient::default(), None).hash,
+            served.hash
+        );
+
+        // Regenerating the same entries keeps the hash, changing them doesn't
+        cache.splice(
+            &IndexScope::All,
+            BTreeMap::from([("/roms/a.nsp".to_string(), local("", "a"))]),
+        );
+        assert_eq!(
+            cache.served_for(&MotdRecipient::default(), None).hash,
+            served.hash
+        );
+        cache.splice(
+            &IndexScope::All,
+            BTreeMap::from([("/roms/a.nsp".to_string(), local("", "a-v2"))]),
+        );
+        assert_ne!(
+            cache.served_for(&MotdRecipient::default(), None).hash,
+            served.hash
+        );
+    }
+
+    #[test]
+    fn test_index_directory() {
+        let file = |title_id: &str, name: Option<&str>| NspMetadata {
+            path: format!("/roms/{title_id}.nsp"),
+            title_id: title_id.to_string(),
+            version: "v0".to_string(),
+            title_name: name.map(str::to_string),
+            download_id: title_id.to_string(),
+            unidentified: false,
+            required_system_version: None,
+        };
+        let base_games = HashMap::from([(
+            "010000000001".to_string(),
+            serde_json::from_value::<Title>(serde_json::json!({
+                "titleId": "0100000000010000",
+                "name": "Game",
+                "publisher": "Publisher",
+            }))
+            .unwrap(),
+        )]);
+        let dlc = file("0100000000011001", Some("game DLC"));
+        let other = file("0100000000020000", Some("2 Other"));
+
+        assert_eq!(
+            index_directory(IndexGrouping::Flat, &dlc, &base_games),
+            None
+        );
+        assert_eq!(
+            index_directory(IndexGrouping::FirstLetter, &dlc, &base_games).as_deref(),
+            Some("G")
+        );
+        assert_eq!(
+            index_directory(IndexGrouping::FirstLetter, &other, &base_games).as_deref(),
+            Some("#")
+        );
+        assert_eq!(
+            index_directory(IndexGrouping::Publisher, &dlc, &base_games).as_deref(),
+            Some("Publisher")
+        );
+        // Without a TitleDB entry there's no publisher to go by
+        assert_eq!(
+            index_directory(IndexGrouping::Publisher, &other, &base_games),
+            None
+        );
+        assert_eq!(
+            index_directory(IndexGrouping::BaseGame, &dlc, &base_games).as_deref(),
+            Some("Game")
+        );
+        assert_eq!(
+            index_directory(IndexGrouping::BaseGame, &other, &base_games).as_deref(),
+            Some("0100000000020000")
+        );
+    }
+
+    #[test]
+    fn test_index_cache_directories() {
+        let grouped = |title_id: &str, url: &str, directory: &str| LocalIndexEntry {
+            directory: Some(directory.to_string()),
+            ..local(title_id, url)
+        };
+        let cache = IndexCache {
+            base: Some(Index::default()),
+            local_files: BTreeMap::from([
+                (
+                    "/roms/a.nsp".to_string(),
+                    grouped("0100000000010000", "a", "A & B"),
+                ),
+                (
+                    "/roms/b.nsp".to_string(),
+                    grouped("0100000000020000", "b", "A & B"),
+                ),
+                (
+                    "/roms/c.nsp".to_string(),
+                    grouped("0100000000030000", "c", "C"),
+                ),
+                ("/roms/d.nsp".to_string(), local("0100000000040000", "d")),
+            ]),
+            ..Default::default()
+        };
+
+        let index = cache.assemble(None).unwrap();
+        assert_eq!(urls(&index), vec!["d"]);
+        assert_eq!(
+            index.directories,
+            vec![
+                "/api/tinfoil/directory/A%20%26%20B",
+                "/api/tinfoil/directory/C"
+            ]
+        );
+        assert_eq!(
+            urls(&cache.assemble_directory("A & B", None).unwrap()),
+            vec!["a", "b"]
+        );
+        assert!(cache.assemble_directory("D", None).is_none());
+    }
+
+    #[test]
+    fn test_index_cache_filter() {
+        let rated =
+            |title_id: &str, url: &str, rating: u32, directory: Option<&str>| LocalIndexEntry {
+                directory: directory.map(str::to_string),
+                facets: Some(TitleFacets {
+                    rating: Some(rating),
+                    ..Default::default()
+                }),
+                ..local(title_id, url)
+            };
+        let cache = IndexCache {
+            base: Some(Index {
+                files: vec![local("", "https://extra/file.nsp").entry],
+                ..Default::default()
+            }),
+            local_files: BTreeMap::from([
+                (
+                    "/roms/a.nsp".to_string(),
+                    rated("0100000000010000", "a", 3, None),
+                ),
+                (
+                    "/roms/b.nsp".to_string(),
+                    rated("0100000000020000", "b", 18, None),
+                ),
+                (
+                    "/roms/c.nsp".to_string(),
+                    rated("0100000000030000", "c", 18, Some("C")),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let kids = IndexFilter {
+            max_rating: Some(7),
+            ..Default::default()
+        };
+
+        let index = cache.assemble(Some(&kids)).unwrap();
+        assert_eq!(urls(&index), vec!["a"]);
+        assert!(index.directories.is_empty());
+        assert!(cache.assemble_directory("C", Some(&kids)).is_none());
+
+        let index = cache.assemble(None).unwrap();
+        assert_eq!(urls(&index), vec!["a", "b", "https://extra/file.nsp"]);
+        assert_eq!(index.directories, vec!["/api/tinfoil/directory/C"]);
+    }
+
+    #[test]
+    fn test_index_cache_expiry() {
+        let mut cache = IndexCache {
+            base: Some(Index::default()),
+            local_files: BTreeMap::from([(
+                "/roms/a.nsp".to_string(),
+                local("0100000000010000", "a"),
+            )]),
+            dirty: vec![IndexScope::Path("/roms/b".to_string())],
+            last_updated: Some(Instant::now()),
+            lifetime: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        assert_eq!(
+            cache.take_stale(),
+            (false, vec![IndexScope::Path("/roms/b".to_string())])
+        );
+
+        // Expired, so the entry of the unchanged file is regenerated too
+        cache.lifetime = Duration::ZERO;
+        cache.dirty = vec![IndexScope::Path("/roms/b".to_string())];
+        let (expired, scopes) = cache.take_stale();
+        assert!(expired);
+        assert_eq!(scopes, vec![IndexScope::All]);
+        assert!(cache.dirty.is_empty());
+
+        // TitleDB now knows the title, its rating and publisher apply
+        let known = LocalIndexEntry {
+            directory: Some("Nintendo".to_string()),
+            facets: Some(TitleFacets {
+                rating: Some(18),
+                ..Default::default()
+            }),
+            ..local("0100000000010000", "a")
+        };
+        cache.splice(
+            &scopes[0],
+            BTreeMap::from([("/roms/a.nsp".to_string(), known)]),
+        );
+        let kids = IndexFilter {
+            max_rating: Some(7),
+            ..Default::default()
+        };
+        assert!(urls(&cache.assemble(Some(&kids)).unwrap()).is_empty());
+        let index = cache.assemble(None).unwrap();
+        assert!(urls(&index).is_empty());
+        assert_eq!(index.directories, vec!["/api/tinfoil/directory/Nintendo"]);
+    }
+
+    #[test]
+    fn test_index_dedup() {
+        let game = |url: &str| local("0100000000010000", url);
+        let cache = IndexCache {
+            base: Some(Index::default()),
+            local_files: BTreeMap::from([
+                (
+                    "/roms/b/game.nsp".to_string(),
+                    game("/api/get_game/0100000000010000_v0.nsp#Game (copy).nsp"),
+                ),
+                (
+                    "/roms/a/game.nsp".to_string(),
+                    game("/api/get_game/0100000000010000_v0.nsp#Game.nsp"),
+                ),
+                (
+                    "/roms/a/game.xci".to_string(),
+                    game("/api/get_game/0100000000010000_v0.xci#Game.xci"),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        // Same title, version and format is only listed once, other formats stay
+        assert_eq!(
+            urls(&cache.assemble(None).unwrap()),
+            vec![
+                "/api/get_game/0100000000010000_v0.nsp#Game.nsp",
+                "/api/get_game/0100000000010000_v0.xci#Game.xci",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_motd_target() {
+        let cache = |target, warning: Option<&str>| IndexCache {
+            base: Some(Index {
+                success: warning.map(str::to_string),
+                ..Default::default()
+            }),
+            motd: Some(Motd {
+                message: Some("Welcome".to_string()),
+                enabled: true,
+                target,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let success = |cache: IndexCache, tinfoil_client| {
+            let recipient = MotdRecipient {
+                tinfoil_client,
+                ..Default::default()
+            };
+            cache.assemble_for(&recipient, None).unwrap().success
+        };
+
+        assert_eq!(
+            success(cache(MotdTarget::All, None), false).as_deref(),
+            Some("Welcome")
+        );
+        assert_eq!(success(cache(MotdTarget::Tinfoil, None), false), None);
+        assert_eq!(
+            success(cache(MotdTarget::Tinfoil, Some("Low space")), true).as_deref(),
+            Some("Welcome\n\nLow space")
+        );
+        // Warnings are shown even when the MOTD isn't
+        assert_eq!(
+            success(cache(MotdTarget::None, Some("Low space")), true).as_deref(),
+            Some("Low space")
+        );
+    }
+
+    #[test]
+    fn test_served_index_motd() {
+        let mut cache = IndexCache {
+            base: Some(Index::default()),
+            motd: Some(Motd {
+                message: Some("Welcome".to_string()),
+                enabled: true,
+                messages: BTreeMap::from([("de".to_string(), "Willkommen".to_string())]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let german = MotdRecipient {
+            languages: vec!["de-DE".to_string()],
+            ..Default::default()
+        };
+
+        let served = cache.served_for(&MotdRecipient::default(), None);
+        assert_eq!(served.index.success.as_deref(), Some("Welcome"));
+        let served_german = cache.served_for(&german, None);
+        assert_eq!(served_german.index.success.as_deref(), Some("Willkommen"));
+        // Clients with different messages can't share an ETag
+        assert_ne!(served.hash, served_german.hash);
+        assert_eq!(
+            cache.served_for(&MotdRecipient::default(), None).hash,
+            served.hash
+        );
+    }
+
+    #[test]
+    fn test_accept_languages() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_LANGUAGE, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(
+            accept_languages(&headers("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5")),
+            vec!["fr-CH", "fr", "en", "de"]
+        );
+        assert_eq!(
+            accept_languages(&headers("en;q=0.5, ja, xx;q=0")),
+            vec!["ja", "en"]
+        );
+        assert!(accept_languages(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_index_title_firmware() {
+        let metadata =
+            |path: &str, title_id: &str, version: &str, firmware: Option<u32>| NspMetadata {
+                path: path.to_string(),
+                title_id: title_id.to_string(),
+                version: version.to_string(),
+                title_name: Some("Game".to_string()),
+                download_id: format!("{title_id}_v{version}.nsp"),
+                unidentified: false,
+                required_system_version: firmware,
+            };
+        let entry = |metadata: NspMetadata| LocalIndexEntry {
+            title_meta: title_meta_from_metadata(&metadata, None),
+            ..local(&metadata.title_id, &metadata.download_id)
+        };
+
+        let cache = IndexCache {
+            base: Some(Index::default()),
+            local_files: BTreeMap::from([
+                (
+                    "/roms/a/base.nsp".to_string(),
+                    entry(metadata(
+                        "/roms/a/base.nsp",
+                        "0100000000010000",
+                        "0",
+                        Some(0x0c10_0000),
+                    )),
+                ),
+                (
+                    "/roms/a/base-v2.nsp".to_string(),
+                    entry(metadata(
+                        "/roms/a/base-v2.nsp",
+                        "0100000000010000",
+                        "65536",
+                        Some(1275133952),
+                    )),
+                ),
+                (
+                    "/roms/b.nsp".to_string(),
+                    entry(metadata("/roms/b.nsp", "0100000000020000", "0", None)),
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let index = cache.assemble(None).unwrap();
+        // Titles without a known requirement aren't listed
+        assert_eq!(index.titledb.len(), 1);
+        let meta = &index.titledb["0100000000010000"];
+        assert_eq!(meta.name, "Game");
+        assert_eq!(meta.version, 65536);
+        assert_eq!(meta.required_firmware.as_deref(), Some("19.0.1"));
+
+        let json = serde_json::to_value(&index).unwrap();
+        assert_eq!(
+            json["titledb"]["0100000000010000"]["requiredSystemVersion"],
+            1275133952
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // Ranges running past the end are clamped to the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        // Multipart ranges get the whole file
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=10-5", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=-", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=abc-", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("items=0-1", 1000), Err(UnsatisfiableRange));
+        assert_eq!(parse_range("bytes=0-", 0), Err(UnsatisfiableRange));
+    }
+
+    #[test]
+    fn test_game_file_cache_control() {
+        assert!(game_file_cache_control(true).starts_with("public,"));
+        // Responses to authenticated requests stay out of shared caches
+        assert!(game_file_cache_control(false).starts_with("private,"));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let etag = "\"10-20\"";
+        let modified = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert!(!is_not_modified(&headers(&[]), etag, Some(modified)));
+        assert!(is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"1-2\", W/\"10-20\"")]),
+            etag,
+            Some(modified)
+        ));
+        assert!(is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "*")]),
+            etag,
+            None
+        ));
+        assert!(!is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"1-2\"")]),
+            etag,
+            Some(modified)
+        ));
+
+        assert_eq!(http_date(modified), "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert!(is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]),
+            etag,
+            Some(modified)
+        ));
+        assert!(!is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")]),
+            etag,
+            Some(modified)
+        ));
+        // A stale ETag wins over a matching date
+        assert!(!is_not_modified(
+            &headers(&[
+                (header::IF_NONE_MATCH, "\"1-2\""),
+                (header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ]),
+            etag,
+            Some(modified)
+        ));
+    }
+}
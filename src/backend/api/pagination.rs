@@ -0,0 +1,126 @@
+//! Paging of list endpoints
+//!
+//! List and search endpoints take `limit` and `offset` query parameters, or `page_size` and
+//! a 1-based `page` instead. Without a limit, `ALU_DEFAULT_PAGE_SIZE` items are returned,
+//! and larger limits are clamped to `ALU_MAX_PAGE_SIZE`, so no request can get an
+//! unbounded result set. Lists keep their usual JSON shape, with the number of items across
+//! all pages in the `X-Total-Count` header so clients can fetch the rest.
+
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Default and maximum page size. Endpoints with their own, smaller limits, like
+/// autocomplete, use a constant policy rather than the configured one
+#[derive(Debug, Clone, Copy)]
+pub struct PagePolicy {
+    pub default: usize,
+    pub max: usize,
+}
+
+impl PagePolicy {
+    /// Policy configured with `ALU_DEFAULT_PAGE_SIZE` and `ALU_MAX_PAGE_SIZE`
+    pub fn from_config() -> Self {
+        let config = crate::config::config().backend_config;
+        Self {
+            default: config.default_page_size,
+            max: config.max_page_size,
+        }
+    }
+
+    /// Apply the default to a requested limit and clamp it to the maximum
+    pub fn clamp(&self, requested: Option<usize>) -> usize {
+        let max = self.max.max(1);
+        requested.unwrap_or(self.default).clamp(1, max)
+    }
+}
+
+/// Paging parameters of a list endpoint
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+pub struct Pagination {
+    #[serde(alias = "page_size")]
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// 1-based page number, used when no offset is given
+    pub page: Option<usize>,
+}
+
+impl Pagination {
+    pub fn new(limit: Option<usize>, offset: Option<usize>) -> Self {
+        Self {
+            limit,
+            offset,
+            page: None,
+        }
+    }
+
+    pub fn with_page(self, page: Option<usize>) -> Self {
+        Self { page, ..self }
+    }
+
+    /// Number of items to return, within the configured maximum
+    pub fn limit(&self) -> usize {
+        PagePolicy::from_config().clamp(self.limit)
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+            .or_else(|| {
+                self.page
+                    .map(|page| page.saturating_sub(1).saturating_mul(self.limit()))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Take the requested page out of a full list, along with the length of the list
+    pub fn paginate<I, C>(&self, items: I) -> (C, usize)
+    where
+        I: IntoIterator,
+        I::IntoIter: ExactSizeIterator,
+        C: FromIterator<I::Item>,
+    {
+        let items = items.into_iter();
+        let total = items.len();
+        let page = items.skip(self.offset()).take(self.limit()).collect();
+        (page, total)
+    }
+}
+
+/// Respond with a page as JSON and the number of items across all pages
+pub fn page_response(page: impl Serialize, total: usize) -> Response {
+    ([(TOTAL_COUNT_HEADER, total.to_string())], Json(page)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_page_size() {
+        let policy = PagePolicy {
+            default: 100,
+            max: 500,
+        };
+        assert_eq!(policy.clamp(None), 100);
+        assert_eq!(policy.clamp(Some(20)), 20);
+        assert_eq!(policy.clamp(Some(10_000)), 500);
+        assert_eq!(policy.clamp(Some(0)), 1);
+
+        // A misconfigured maximum still allows a single result
+        let policy = PagePolicy {
+            default: 100,
+            max: 0,
+        };
+        assert_eq!(policy.clamp(None), 1);
+        // And the default never goes past the maximum
+        let policy = PagePolicy {
+            default: 1000,
+            max: 500,
+        };
+        assert_eq!(policy.clamp(None), 500);
+    }
+}
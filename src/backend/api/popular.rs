@@ -0,0 +1,178 @@
+//! Per-title download counts
+//!
+//! Every served download bumps a counter in the `download_counts` table, bucketed by day so
+//! the most downloaded titles can be listed over any time window. Counting happens in the
+//! background, a slow or failing write never holds up the download itself.
+
+use axum::{Json, Router, extract::Query, handler::Handler, response::IntoResponse, routing::get};
+use chrono::{Duration, NaiveDate, Utc};
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    pagination::PagePolicy,
+    since::{invalid_since, parse_since},
+};
+use crate::{
+    db::{DB, with_retry},
+    titledb::Title,
+};
+
+const POPULAR_LIMITS: PagePolicy = PagePolicy {
+    default: 10,
+    max: 100,
+};
+/// Window used when no `since` is given
+const DEFAULT_POPULAR_DAYS: i64 = 30;
+
+/// Format of the `day` field, sorts the same as the dates it represents
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PopularTitle {
+    pub title_id: String,
+    pub name: Option<String>,
+    pub downloads: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PopularQuery {
+    pub limit: Option<usize>,
+    /// Only count downloads from this date on, either `YYYY-MM-DD` or RFC 3339
+    pub since: Option<String>,
+}
+
+/// Sent by the segmented downloader when it only checks a file can be downloaded in ranges,
+/// so asking for its first byte isn't counted on top of the download itself
+pub const RANGE_PROBE_HEADER: &str = "x-alumulemu-range-probe";
+
+/// Whether a request for a file counts as a download of it. Resumed downloads were already
+/// counted when they started
+pub fn counts_as_download(headers: &HeaderMap, start: u64) -> bool {
+    start == 0 && !headers.contains_key(RANGE_PROBE_HEADER)
+}
+
+/// Serializes counter increments. Concurrent `+= 1` upserts of the same record don't always
+/// conflict, so without this, simultaneous downloads of one title could be counted once.
+static RECORD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Count one download of a title for today
+pub async fn record_download(title_id: &str) -> surrealdb::Result<()> {
+    let _guard = RECORD_LOCK.lock().await;
+    let day = Utc::now().format(DAY_FORMAT).to_string();
+    with_retry("download_counts.record", || async {
+        DB.query(
+            "UPSERT type::thing('download_counts', [$title_id, $day])
+                SET title_id = $title_id, day = $day, downloads += 1",
+        )
+        .bind(("title_id", title_id.to_string()))
+        .bind(("day", day.clone()))
+        .await?
+        .check()
+        .map(|_| ())
+    })
+    .await
+}
+
+/// Record a download in the background, errors are only logged
+pub fn spawn_record_download(title_id: String) {
+    tokio::spawn(async move {
+        if let Err(e) = record_download(&title_id).await {
+            tracing::warn!("Failed to record download of {}: {}", title_id, e);
+        }
+    });
+}
+
+/// Get the most downloaded titles since the given day, most downloads first
+pub async fn most_downloaded(
+    since: NaiveDate,
+    limit: usize,
+) -> surrealdb::Result<Vec<PopularTitle>> {
+    let mut result = DB
+        .query(
+            // Sort outside of the grouping, ORDER BY doesn't apply to aggregated fields
+            "SELECT * FROM (
+                SELECT title_id, math::sum(downloads) AS downloads FROM download_counts
+                    WHERE day >= $since GROUP BY title_id
+            ) ORDER BY downloads DESC LIMIT $limit",
+        )
+        .bind(("since", since.format(DAY_FORMAT).to_string()))
+        .bind(("limit", limit))
+        .await?;
+
+    result.take(0)
+}
+
+/// List the most downloaded titles over a time window
+pub async fn get_popular(Query(query): Query<PopularQuery>) -> impl IntoResponse {
+    let since = match query.since.as_deref() {
+        Some(since) => match parse_since(since) {
+            Some(since) => since.date_naive(),
+            None => return invalid_since(since),
+        },
+        None => (Utc::now() - Duration::days(DEFAULT_POPULAR_DAYS)).date_naive(),
+    };
+    let limit = POPULAR_LIMITS.clamp(query.limit);
+
+    let mut titles = match most_downloaded(since, limit).await {
+        Ok(titles) => titles,
+        Err(e) => {
+            tracing::error!("Failed to get download counts: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let locale = crate::config::config().backend_config.get_locale_string();
+    for title in &mut titles {
+        match Title::get_from_title_id(&locale, &title.title_id).await {
+            Ok(found) => title.name = found.and_then(|found| found.name),
+            Err(e) => tracing::warn!("Failed to look up {}: {}", title.title_id, e),
+        }
+    }
+
+    Json(titles).into_response()
+}
+
+/// Delete every download count
+pub async fn clear_download_counts() -> surrealdb::Result<()> {
+    DB.query("DELETE download_counts").await?.check()?;
+    Ok(())
+}
+
+/// Clear all download counts
+pub async fn reset_download_counts() -> impl IntoResponse {
+    match clear_download_counts().await {
+        Ok(_) => {
+            tracing::info!("Download counts reset");
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            tracing::error!("Failed to reset download counts: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub fn popular_api() -> Router {
+    Router::new().route(
+        "/",
+        get(get_popular).delete(reset_download_counts.layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_as_download() {
+        let mut headers = HeaderMap::new();
+        assert!(counts_as_download(&headers, 0));
+        assert!(!counts_as_download(&headers, 1024));
+
+        headers.insert(RANGE_PROBE_HEADER, "1".parse().unwrap());
+        assert!(!counts_as_download(&headers, 0));
+    }
+}
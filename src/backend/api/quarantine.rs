@@ -0,0 +1,28 @@
+//! Files the scanner moved into the quarantine because they couldn't be read
+//!
+//! See [`crate::import::quarantine`] for when that happens. Files are listed with the note
+//! of why they were quarantined, so they can be inspected and fixed or deleted by hand.
+
+use axum::{Json, Router, response::IntoResponse, routing::get};
+use http::StatusCode;
+
+use crate::import::quarantine::list_quarantined;
+
+/// Handler for listing quarantined files
+pub async fn list_quarantined_handler() -> impl IntoResponse {
+    match list_quarantined().await {
+        Ok(files) => Json(files).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list quarantined files: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub fn quarantine_api() -> Router {
+    Router::new()
+        .route("/", get(list_quarantined_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_editor,
+        ))
+}
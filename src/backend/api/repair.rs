@@ -0,0 +1,133 @@
+//! Maintenance jobs that repair stored metadata
+//!
+//! Download IDs are stored with each file's metadata when it's scanned, so rows written by
+//! an older version keep whatever format was current back then. Repairing recomputes them
+//! from the title ID, version and extension, the same way [`file_download_id`] does for
+//! new files. Run it after upgrades that change the ID format.
+
+use std::path::Path;
+
+use axum::{Json, Router, response::IntoResponse, routing::post};
+use http::StatusCode;
+use serde::Serialize;
+
+use crate::{db::NspMetadata, util::file_download_id};
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct RepairReport {
+    /// Number of metadata rows that were checked
+    pub checked: usize,
+    /// Number of rows whose download ID was fixed
+    pub fixed: usize,
+    /// Number of rows that needed fixing but couldn't be saved
+    pub failed: usize,
+}
+
+/// Get the download ID a file should have, if its stored one is different
+fn repaired_download_id(metadata: &NspMetadata) -> Option<String> {
+    let extension = Path::new(&metadata.path)
+        .extension()
+        .map(|extension| extension.to_string_lossy())
+        .unwrap_or_default();
+    let expected = file_download_id(
+        &metadata.path,
+        &metadata.title_id,
+        &metadata.version,
+        &extension,
+    );
+    (metadata.download_id != expected).then_some(expected)
+}
+
+/// Recompute every stored download ID and fix the ones that differ
+pub async fn repair_download_ids() -> color_eyre::Result<RepairReport> {
+    let all_metadata = NspMetadata::get_all().await?;
+    let mut report = RepairReport {
+        checked: all_metadata.len(),
+        ..Default::default()
+    };
+
+    for mut metadata in all_metadata {
+        let Some(download_id) = repaired_download_id(&metadata) else {
+            continue;
+        };
+        tracing::info!(
+            path = metadata.path,
+            old = metadata.download_id,
+            new = download_id,
+            "Repairing download ID"
+        );
+        metadata.download_id = download_id;
+        match metadata.save().await {
+            Ok(_) => report.fixed += 1,
+            Err(e) => {
+                tracing::warn!("Failed to repair download ID of {}: {}", metadata.path, e);
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Handler for repairing download IDs
+pub async fn repair_download_ids_handler() -> impl IntoResponse {
+    match repair_download_ids().await {
+        Ok(report) => {
+            tracing::info!(
+                "Repaired {} of {} download IDs",
+                report.fixed,
+                report.checked
+            );
+            Json(report).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to repair download IDs: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub fn repair_api() -> Router {
+    Router::new()
+        .route("/download_ids", post(repair_download_ids_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(path: &str, version: &str, download_id: &str) -> NspMetadata {
+        NspMetadata {
+            path: path.to_string(),
+            title_id: "0100000000010800".to_string(),
+            version: version.to_string(),
+            title_name: None,
+            download_id: download_id.to_string(),
+            unidentified: false,
+            required_system_version: None,
+        }
+    }
+
+    #[test]
+    fn test_repaired_download_id() {
+        // Missing the `v` in front of the version, as older versions stored it
+        let wrong = metadata("/games/Update.nsp", "65536", "0100000000010800_65536.nsp");
+        assert_eq!(
+            repaired_download_id(&wrong).as_deref(),
+            Some("0100000000010800_v65536.nsp")
+        );
+
+        // The extension follows the file, even if it was renamed
+        let renamed = metadata("/games/Update.nsz", "v65536", "0100000000010800_v65536.nsp");
+        assert_eq!(
+            repaired_download_id(&renamed).as_deref(),
+            Some("0100000000010800_v65536.nsz")
+        );
+
+        let correct = metadata("/games/Update.nsp", "v65536", "0100000000010800_v65536.nsp");
+        assert_eq!(repaired_download_id(&correct), None);
+    }
+}
@@ -0,0 +1,241 @@
+//! Background metadata rescans
+//!
+//! Rescans of the rom dirs, or a directory in them, run as background jobs whose counters can
+//! be polled while they run. Only one rescan runs at a time, since two of them would race
+//! each other removing metadata of files they haven't seen.
+
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query},
+    response::IntoResponse,
+    routing::{get, post},
+};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::{
+    backend::admin::ApiResponse,
+    games_dirs,
+    router::{RescanCounts, RescanOptions, update_metadata_from_filesystem},
+};
+
+/// Finished jobs kept around for their progress to be looked up
+const MAX_FINISHED_JOBS: usize = 32;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "state", content = "error")]
+pub enum RescanJobStatus {
+    Running,
+    Completed,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RescanJob {
+    pub id: Ulid,
+    /// Directory that's scanned, `None` for all of the rom dirs
+    pub path: Option<String>,
+    /// Whether files that already have metadata are read again
+    pub rescan: bool,
+    pub status: RescanJobStatus,
+    #[serde(flatten)]
+    pub progress: RescanCounts,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of asking for a rescan
+#[derive(Debug, Clone, Copy)]
+pub enum RescanStart {
+    Started(Ulid),
+    /// Another rescan is still running, this is its ID
+    AlreadyRunning(Ulid),
+}
+
+static RESCAN_JOBS: LazyLock<Mutex<BTreeMap<Ulid, RescanJob>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn update_job(id: &Ulid, f: impl FnOnce(&mut RescanJob)) {
+    if let Some(job) = RESCAN_JOBS.lock().unwrap().get_mut(id) {
+        f(job);
+    }
+}
+
+/// Drop the oldest finished jobs past [`MAX_FINISHED_JOBS`]
+fn prune_jobs(jobs: &mut BTreeMap<Ulid, RescanJob>) {
+    let finished = jobs
+        .values()
+        .filter(|job| job.status != RescanJobStatus::Running)
+        .map(|job| job.id)
+        .collect::<Vec<_>>();
+    // ULIDs sort by creation time, so the first ones are the oldest
+    for id in finished
+        .iter()
+        .take(finished.len().saturating_sub(MAX_FINISHED_JOBS))
+    {
+        jobs.remove(id);
+    }
+}
+
+/// Start a rescan in the background, unless one is already running
+pub fn spawn_rescan(options: RescanOptions) -> RescanStart {
+    let id = {
+        let mut jobs = RESCAN_JOBS.lock().unwrap();
+        if let Some(running) = jobs
+            .values()
+            .find(|job| job.status == RescanJobStatus::Running)
+        {
+            tracing::info!("Rescan already in progress, ignoring new request");
+            return RescanStart::AlreadyRunning(running.id);
+        }
+
+        let id = Ulid::new();
+        jobs.insert(
+            id,
+            RescanJob {
+                id,
+                path: options.path.clone(),
+                rescan: options.rescan,
+                status: RescanJobStatus::Running,
+                progress: RescanCounts::default(),
+                started_at: Utc::now(),
+                finished_at: None,
+            },
+        );
+        prune_jobs(&mut jobs);
+        id
+    };
+
+    tracing::info!(job = %id, ?options, "Starting games directory rescan as background job");
+    tokio::spawn(async move {
+        let result = update_metadata_from_filesystem(&games_dirs(), options, |counts| {
+            update_job(&id, |job| job.progress = counts.clone())
+        })
+        .await;
+
+        let status = match result {
+            Ok(()) => {
+                tracing::info!(job = %id, "Background rescan job completed successfully");
+                RescanJobStatus::Completed
+            }
+            Err(e) => {
+                tracing::error!(job = %id, "Background rescan job failed: {}", e);
+                RescanJobStatus::Failed(e.to_string())
+            }
+        };
+        update_job(&id, |job| {
+            job.status = status;
+            job.finished_at = Some(Utc::now());
+        });
+    });
+
+    RescanStart::Started(id)
+}
+
+/// Start a rescan of the rom dirs or a directory in them
+pub async fn start_rescan(Query(options): Query<RescanOptions>) -> impl IntoResponse {
+    let Some(scan_dirs) = options.scan_dirs(&games_dirs()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<RescanJob> {
+                status: "error".to_string(),
+                message: Some("Path must be in the games directory".to_string()),
+                data: None,
+            }),
+        );
+    };
+    if !scan_dirs.iter().any(|dir| dir.is_dir()) {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: Some("Path is not a directory".to_string()),
+                data: None,
+            }),
+        );
+    }
+
+    let (status, message, id) = match spawn_rescan(options) {
+        RescanStart::Started(id) => (StatusCode::ACCEPTED, "Rescan started", id),
+        RescanStart::AlreadyRunning(id) => {
+            (StatusCode::CONFLICT, "A rescan is already running", id)
+        }
+    };
+    let job = RESCAN_JOBS.lock().unwrap().get(&id).cloned();
+    (
+        status,
+        Json(ApiResponse {
+            status: if status == StatusCode::ACCEPTED {
+                "success"
+            } else {
+                "error"
+            }
+            .to_string(),
+            message: Some(message.to_string()),
+            data: job,
+        }),
+    )
+}
+
+/// Get the progress of a rescan job
+pub async fn get_rescan_job(Path(id): Path<Ulid>) -> Result<Json<RescanJob>, StatusCode> {
+    RESCAN_JOBS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub fn rescan_api() -> Router {
+    Router::new()
+        .route("/", post(start_rescan))
+        .route("/{job_id}", get(get_rescan_job))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_editor,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(status: RescanJobStatus) -> RescanJob {
+        RescanJob {
+            id: Ulid::new(),
+            path: None,
+            rescan: false,
+            status,
+            progress: RescanCounts::default(),
+            started_at: Utc::now(),
+            finished_at: None,
+        }
+    }
+
+    #[test]
+    fn test_prune_jobs() {
+        let mut jobs = BTreeMap::new();
+        let running = job(RescanJobStatus::Running);
+        let running_id = running.id;
+        jobs.insert(running.id, running);
+        let mut finished = Vec::new();
+        for _ in 0..MAX_FINISHED_JOBS + 3 {
+            let job = job(RescanJobStatus::Completed);
+            finished.push(job.id);
+            jobs.insert(job.id, job);
+        }
+
+        prune_jobs(&mut jobs);
+        assert_eq!(jobs.len(), MAX_FINISHED_JOBS + 1);
+        assert!(jobs.contains_key(&running_id));
+        finished.sort();
+        assert!(!jobs.contains_key(&finished[0]));
+        assert!(jobs.contains_key(finished.last().unwrap()));
+    }
+}
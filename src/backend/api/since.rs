@@ -0,0 +1,57 @@
+//! The `since` parameter of endpoints that look at a time window
+//!
+//! Either a date as `YYYY-MM-DD`, which starts at midnight UTC, or an RFC 3339 timestamp.
+
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use http::StatusCode;
+
+use crate::index::TinfoilResponse;
+
+/// Parse the `since` parameter
+pub fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    let since = since.trim();
+    DateTime::parse_from_rfc3339(since)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+        .or_else(|| {
+            NaiveDate::parse_from_str(since, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|date| date.and_utc())
+        })
+}
+
+/// Response to a `since` that can't be parsed
+pub fn invalid_since(since: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(TinfoilResponse::Failure(format!(
+            "Invalid since '{since}', expected YYYY-MM-DD or an RFC 3339 timestamp"
+        ))),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_since() {
+        let midnight = "2024-03-09T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(parse_since("2024-03-09"), Some(midnight));
+        assert_eq!(parse_since("2024-03-09T02:00:00+02:00"), Some(midnight));
+        assert_eq!(parse_since("yesterday"), None);
+
+        let day = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+        let date_of = |since| parse_since(since).map(|since| since.date_naive());
+        assert_eq!(date_of("2024-03-09T12:30:00Z"), Some(day));
+        // Converted to UTC before taking the date
+        assert_eq!(date_of("2024-03-10T01:00:00+02:00"), Some(day));
+        assert_eq!(date_of("last week"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Library statistics
+//!
+//! Counts the files in the library by kind, how many of them have TitleDB metadata and how
+//! much space they take up. That means going over every file, so the result is cached for
+//! [`STATS_CACHE_LIFETIME`].
+
+use axum::Json;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{db::NspMetadata, router::AlumRes, title_kind::TitleKind, titledb::Metaview};
+
+const STATS_CACHE_LIFETIME: Duration = Duration::from_secs(60);
+/// Files whose size is looked up at once
+const SIZE_LOOKUP_CONCURRENCY: usize = 16;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LibraryStats {
+    /// Files of base games
+    pub base_games: usize,
+    pub updates: usize,
+    pub dlc: usize,
+    /// Files without a real title ID, such as homebrew, which aren't counted as any kind
+    pub unidentified: usize,
+    /// Identified files with TitleDB metadata
+    pub matched: usize,
+    /// Identified files TitleDB knows nothing about
+    pub unmatched: usize,
+    /// Combined size of the files in bytes
+    pub total_size: u64,
+    /// Files in the database that couldn't be found on disk, left out of `total_size`
+    pub missing: usize,
+    pub generated_at: DateTime<Utc>,
+}
+
+static STATS_CACHE: Mutex<Option<(Instant, LibraryStats)>> = Mutex::new(None);
+
+async fn compute_stats() -> color_eyre::Result<LibraryStats> {
+    let files = NspMetadata::get_all().await?;
+    let matched = Metaview::count_matched().await?;
+
+    let (mut base_games, mut updates, mut dlc, mut unidentified) = (0, 0, 0, 0);
+    for file in &files {
+        if file.unidentified {
+            unidentified += 1;
+            continue;
+        }
+        match TitleKind::from_title_id(&file.title_id) {
+            TitleKind::Base => base_games += 1,
+            TitleKind::Update => updates += 1,
+            TitleKind::Dlc => dlc += 1,
+        }
+    }
+
+    let paths: Vec<String> = files.iter().map(|file| file.path.clone()).collect();
+    let sizes: Vec<Option<u64>> = futures::stream::iter(paths)
+        .map(|path| async move {
+            tokio::fs::metadata(&path)
+                .await
+                .inspect_err(|e| tracing::debug!("Failed to get size of {}: {}", path, e))
+                .ok()
+                .map(|metadata| metadata.len())
+        })
+        .buffer_unordered(SIZE_LOOKUP_CONCURRENCY)
+        .collect()
+        .await;
+
+    let identified = files.len() - unidentified;
+    Ok(LibraryStats {
+        base_games,
+        updates,
+        dlc,
+        unidentified,
+        matched,
+        unmatched: identified.saturating_sub(matched),
+        total_size: sizes.iter().flatten().sum(),
+        missing: sizes.iter().filter(|size| size.is_none()).count(),
+        generated_at: Utc::now(),
+    })
+}
+
+/// Get the library statistics, at most [`STATS_CACHE_LIFETIME`] old
+pub async fn get_stats() -> AlumRes<Json<LibraryStats>> {
+    let cached = STATS_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(updated, _)| updated.elapsed() < STATS_CACHE_LIFETIME)
+        .map(|(_, stats)| stats.clone());
+    if let Some(stats) = cached {
+        return Ok(Json(stats));
+    }
+
+    let stats = compute_stats().await?;
+    *STATS_CACHE.lock().unwrap() = Some((Instant::now(), stats.clone()));
+    Ok(Json(stats))
+}
@@ -0,0 +1,70 @@
+//! Tinfoil theme black/whitelist management
+
+use axum::{Json, Router, response::IntoResponse, routing::get};
+use http::StatusCode;
+
+use crate::backend::{
+    admin::ApiResponse,
+    api::invalidate_index_cache,
+    kv_config::{KvOptExt, ThemeConfig},
+};
+
+/// Handler for getting the current theme restrictions
+pub async fn get_themes_handler() -> Result<impl IntoResponse, StatusCode> {
+    match ThemeConfig::get().await {
+        Ok(config) => Ok(Json(config.unwrap_or_default()).into_response()),
+        Err(e) => {
+            tracing::error!("Failed to get theme config: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handler for replacing the theme restrictions
+pub async fn set_themes_handler(Json(config): Json<ThemeConfig>) -> impl IntoResponse {
+    let config = match config.normalize() {
+        Ok(config) => config,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResponse::<ThemeConfig> {
+                    status: "error".to_string(),
+                    message: Some(e),
+                    data: None,
+                }),
+            );
+        }
+    };
+
+    if let Err(e) = config.set().await {
+        tracing::error!("Failed to save theme config: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: Some("Failed to save theme config".to_string()),
+                data: None,
+            }),
+        );
+    }
+
+    // The themes are part of the index, so don't serve a stale one
+    invalidate_index_cache();
+
+    (
+        StatusCode::OK,
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: None,
+            data: Some(config),
+        }),
+    )
+}
+
+pub fn themes_api() -> Router {
+    Router::new()
+        .route("/", get(get_themes_handler).put(set_themes_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
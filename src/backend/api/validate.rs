@@ -0,0 +1,239 @@
+//! Checking the Tinfoil index before pointing clients at it
+//!
+//! The index is generated the same way it's served, then every file entry is checked:
+//! files from the rom dir must exist and be readable, other URLs must be well-formed and
+//! no entry may be empty. The problems found are reported instead of the index.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use axum::{Json, Router, routing::get};
+use serde::Serialize;
+
+use super::generate_tinfoil_index;
+use crate::{db::NspMetadata, index::TinfoilFileEntry, router::AlumRes};
+
+/// Prefix of the URLs of files served from the rom dir
+const LOCAL_URL_PREFIX: &str = "/api/get_game/";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The entry has a size of 0
+    ZeroSize,
+    /// No file in the rom dir has the entry's download ID
+    UnknownDownloadId,
+    /// The file behind the entry is missing or can't be read
+    UnreadableFile,
+    /// The URL can't be parsed
+    InvalidUrl,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct IndexProblem {
+    pub url: String,
+    pub kind: ProblemKind,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct ValidationReport {
+    /// Number of file entries that were checked
+    pub checked: usize,
+    pub valid: bool,
+    pub problems: Vec<IndexProblem>,
+}
+
+/// What a file entry's URL points to
+#[derive(Debug, PartialEq, Eq)]
+enum EntryTarget<'a> {
+    /// A file of the rom dir, by its download ID
+    Local(&'a str),
+    /// Anything else Tinfoil can download from
+    Remote,
+}
+
+/// Work out what a URL points to, ignoring the file name Tinfoil saves it as
+fn parse_entry_url(url: &str) -> Result<EntryTarget<'_>, String> {
+    let location = url.split_once('#').map_or(url, |(location, _)| location);
+    if let Some(download_id) = location.strip_prefix(LOCAL_URL_PREFIX) {
+        if download_id.is_empty() {
+            return Err("missing download ID".to_string());
+        }
+        return Ok(EntryTarget::Local(download_id));
+    }
+    // Other paths of this server
+    if location.starts_with('/') {
+        return Ok(EntryTarget::Remote);
+    }
+
+    let parsed = url::Url::parse(location).map_err(|e| e.to_string())?;
+    if matches!(parsed.scheme(), "http" | "https") && parsed.host_str().is_none() {
+        return Err("missing host".to_string());
+    }
+    Ok(EntryTarget::Remote)
+}
+
+/// Check that a file exists and can be read
+fn check_readable(path: &Path) -> Result<(), String> {
+    let metadata = std::fs::metadata(path).map_err(|e| e.to_string())?;
+    if !metadata.is_file() {
+        return Err("not a file".to_string());
+    }
+    std::fs::File::open(path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Check the file entries of an index, with the paths of the rom dir's files keyed by
+/// download ID
+fn check_entries(
+    entries: &[TinfoilFileEntry],
+    local_paths: &HashMap<String, PathBuf>,
+) -> Vec<IndexProblem> {
+    let mut problems = Vec::new();
+    let mut report = |entry: &TinfoilFileEntry, kind, detail| {
+        problems.push(IndexProblem {
+            url: entry.url.clone(),
+            kind,
+            detail,
+        })
+    };
+
+    for entry in entries {
+        if entry.size == 0 {
+            report(entry, ProblemKind::ZeroSize, None);
+        }
+        match parse_entry_url(&entry.url) {
+            Ok(EntryTarget::Local(download_id)) => match local_paths.get(download_id) {
+                Some(path) => {
+                    if let Err(e) = check_readable(path) {
+                        let detail = format!("{}: {e}", path.display());
+                        report(entry, ProblemKind::UnreadableFile, Some(detail));
+                    }
+                }
+                None => report(entry, ProblemKind::UnknownDownloadId, None),
+            },
+            Ok(EntryTarget::Remote) => {}
+            Err(e) => report(entry, ProblemKind::InvalidUrl, Some(e)),
+        }
+    }
+
+    problems
+}
+
+/// Generate the Tinfoil index and check every file entry in it
+pub async fn validate_index() -> AlumRes<ValidationReport> {
+    let index = generate_tinfoil_index().await?;
+    let local_paths: HashMap<String, PathBuf> = NspMetadata::get_all()
+        .await
+        .map_err(color_eyre::Report::from)?
+        .into_iter()
+        .map(|metadata| (metadata.download_id, PathBuf::from(metadata.path)))
+        .collect();
+
+    let files = index.files;
+    let (checked, problems) =
+        tokio::task::spawn_blocking(move || (files.len(), check_entries(&files, &local_paths)))
+            .await
+            .map_err(color_eyre::Report::from)?;
+
+    Ok(ValidationReport {
+        checked,
+        valid: problems.is_empty(),
+        problems,
+    })
+}
+
+/// Handler for validating the Tinfoil index
+pub async fn validate_index_handler() -> AlumRes<Json<ValidationReport>> {
+    let report = validate_index().await?;
+    if report.valid {
+        tracing::info!("Tinfoil index is valid, checked {} files", report.checked);
+    } else {
+        tracing::warn!(
+            "Tinfoil index has {} problems in {} files",
+            report.problems.len(),
+            report.checked
+        );
+    }
+    Ok(Json(report))
+}
+
+pub fn validate_api() -> Router {
+    Router::new()
+        .route("/tinfoil/validate", get(validate_index_handler))
+        .layer(axum::middleware::from_fn(
+            crate::backend::user::auth_require_admin,
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry_url() {
+        assert_eq!(
+            parse_entry_url("/api/get_game/0100000000010000_v0.nsp#Game.nsp"),
+            Ok(EntryTarget::Local("0100000000010000_v0.nsp"))
+        );
+        assert_eq!(
+            parse_entry_url("https://example.com/Game.nsp#Game.nsp"),
+            Ok(EntryTarget::Remote)
+        );
+        assert_eq!(
+            parse_entry_url("sdmc:/switch/Game.nsp"),
+            Ok(EntryTarget::Remote)
+        );
+        assert!(parse_entry_url("/api/get_game/#Game.nsp").is_err());
+        assert!(parse_entry_url("https://").is_err());
+        assert!(parse_entry_url("Game.nsp").is_err());
+    }
+
+    #[test]
+    fn test_check_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let game = dir.path().join("game.nsp");
+        std::fs::write(&game, b"game").unwrap();
+        let local_paths = HashMap::from([
+            ("game.nsp".to_string(), game),
+            ("gone.nsp".to_string(), dir.path().join("gone.nsp")),
+        ]);
+        let entry = |url: &str, size| TinfoilFileEntry {
+            url: url.to_string(),
+            size,
+        };
+
+        let problems = check_entries(
+            &[
+                entry("/api/get_game/game.nsp#Game.nsp", 4),
+                entry("https://example.com/Other.nsp", 0),
+                entry("/api/get_game/gone.nsp#Gone.nsp", 4),
+                entry("/api/get_game/unknown.nsp#Unknown.nsp", 4),
+                entry("not a url", 4),
+            ],
+            &local_paths,
+        );
+        let kinds: Vec<(&str, ProblemKind)> = problems
+            .iter()
+            .map(|problem| (problem.url.as_str(), problem.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("https://example.com/Other.nsp", ProblemKind::ZeroSize),
+                (
+                    "/api/get_game/gone.nsp#Gone.nsp",
+                    ProblemKind::UnreadableFile
+                ),
+                (
+                    "/api/get_game/unknown.nsp#Unknown.nsp",
+                    ProblemKind::UnknownDownloadId
+                ),
+                ("not a url", ProblemKind::InvalidUrl),
+            ]
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Server version and build information
+
+use axum::Json;
+use serde::Serialize;
+
+/// Version and build information of the running server, populated at compile time
+#[derive(Serialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// Build time as an RFC 3339 timestamp
+    pub build_time: Option<String>,
+    pub features: Vec<&'static str>,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        let build_time = env!("ALUMULEMU_BUILD_TIME")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|time| time.to_rfc3339());
+
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("ALUMULEMU_GIT_SHA"),
+            build_time,
+            features: env!("ALUMULEMU_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+        }
+    }
+}
+
+pub async fn get_version() -> Json<VersionInfo> {
+    Json(VersionInfo::current())
+}
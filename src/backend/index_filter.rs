@@ -0,0 +1,204 @@
+//! Per-user filters of the Tinfoil index
+//!
+//! Admins can limit which titles a user sees in the index, like a kids account only seeing
+//! games rated for their age. Filters are stored on the user and applied to the cached
+//! index when it's served, users without one get the whole index.
+
+use axum::{Json, extract::Path};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use super::user::User;
+use crate::{
+    db::DB,
+    titledb::{Title, title_group_base_id},
+};
+
+/// Which titles a user sees in the index. Lists left empty don't filter anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexFilter {
+    /// Only these title IDs. The ID of a base game also covers its updates and DLC
+    #[serde(default)]
+    pub allow_title_ids: Vec<String>,
+    /// Never these title IDs. The ID of a base game also covers its updates and DLC
+    #[serde(default)]
+    pub deny_title_ids: Vec<String>,
+    /// Only titles in at least one of these TitleDB categories
+    #[serde(default)]
+    pub allow_categories: Vec<String>,
+    #[serde(default)]
+    pub deny_categories: Vec<String>,
+    /// Hide titles with any of these TitleDB rating content descriptors, like `Violence`
+    #[serde(default)]
+    pub deny_rating_content: Vec<String>,
+    /// Highest TitleDB age rating shown, titles without a rating are hidden
+    #[serde(default)]
+    pub max_rating: Option<u32>,
+}
+
+/// What a filter looks at of a title, from the TitleDB entry of its base game
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TitleFacets {
+    pub categories: Vec<String>,
+    pub rating: Option<u32>,
+    pub rating_content: Vec<String>,
+}
+
+impl From<&Title> for TitleFacets {
+    fn from(title: &Title) -> Self {
+        Self {
+            categories: title.category.clone().unwrap_or_default(),
+            rating: title.rating,
+            rating_content: title.rating_content.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Whether any of `values` is in `list`, ignoring case
+fn any_in(list: &[String], values: &[String]) -> bool {
+    list.iter()
+        .any(|item| values.iter().any(|value| value.eq_ignore_ascii_case(item)))
+}
+
+impl IndexFilter {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Whether a title is shown, `facets` being `None` if TitleDB doesn't know the title
+    pub fn allows(&self, title_id: &str, facets: Option<&TitleFacets>) -> bool {
+        let ids: Vec<String> = std::iter::once(title_id.to_uppercase())
+            .chain(title_group_base_id(title_id))
+            .collect();
+        if !self.allow_title_ids.is_empty() && !any_in(&self.allow_title_ids, &ids) {
+            return false;
+        }
+        if any_in(&self.deny_title_ids, &ids) {
+            return false;
+        }
+
+        let categories = facets.map(|f| f.categories.as_slice()).unwrap_or_default();
+        if !self.allow_categories.is_empty() && !any_in(&self.allow_categories, categories) {
+            return false;
+        }
+        if any_in(&self.deny_categories, categories) {
+            return false;
+        }
+        let rating_content = facets
+            .map(|f| f.rating_content.as_slice())
+            .unwrap_or_default();
+        if any_in(&self.deny_rating_content, rating_content) {
+            return false;
+        }
+        match self.max_rating {
+            Some(max) => facets
+                .and_then(|f| f.rating)
+                .is_some_and(|rating| rating <= max),
+            None => true,
+        }
+    }
+
+    /// Get the filter of a user, `None` if it has none
+    pub async fn for_user(username: &str) -> color_eyre::Result<Option<Self>> {
+        let mut res = DB
+            .query("SELECT VALUE index_filter FROM user WHERE username = $username")
+            .bind(("username", username.to_string()))
+            .await?;
+        let filters: Vec<Option<Self>> = res.take(0)?;
+        Ok(filters
+            .into_iter()
+            .flatten()
+            .next()
+            .filter(|filter| !filter.is_empty()))
+    }
+}
+
+/// Get the index filter of a user
+pub async fn get_index_filter(
+    Path(username): Path<String>,
+) -> Result<Json<IndexFilter>, StatusCode> {
+    User::get_user(&username)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let filter = IndexFilter::for_user(&username).await.map_err(|e| {
+        tracing::error!("Failed to get the index filter of {}: {}", username, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(filter.unwrap_or_default()))
+}
+
+/// Replace the index filter of a user, an empty one removing it
+pub async fn set_index_filter(
+    Path(username): Path<String>,
+    Json(filter): Json<IndexFilter>,
+) -> Result<Json<IndexFilter>, StatusCode> {
+    User::get_user(&username)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let stored = Some(filter.clone()).filter(|filter| !filter.is_empty());
+    DB.query("UPDATE user SET index_filter = $filter WHERE username = $username")
+        .bind(("username", username.clone()))
+        .bind(("filter", stored))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to set the index filter of {}: {}", username, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::info!("Updated the index filter of {}", username);
+    Ok(Json(filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_index_filter_allows() {
+        let facets = TitleFacets {
+            categories: strings(&["Action", "Adventure"]),
+            rating: Some(12),
+            rating_content: strings(&["Fantasy Violence"]),
+        };
+        let dlc = "0100000000011001";
+
+        assert!(IndexFilter::default().allows(dlc, None));
+
+        let by_id = IndexFilter {
+            allow_title_ids: strings(&["0100000000010000"]),
+            ..Default::default()
+        };
+        assert!(by_id.allows(dlc, None));
+        assert!(!by_id.allows("0100000000020000", None));
+        let deny_id = IndexFilter {
+            deny_title_ids: strings(&["0100000000011001"]),
+            ..Default::default()
+        };
+        assert!(!deny_id.allows(dlc, None));
+        assert!(deny_id.allows("0100000000010000", None));
+
+        let by_category = IndexFilter {
+            allow_categories: strings(&["adventure"]),
+            deny_rating_content: strings(&["Blood"]),
+            ..Default::default()
+        };
+        assert!(by_category.allows(dlc, Some(&facets)));
+        assert!(!by_category.allows(dlc, None));
+
+        let kids = IndexFilter {
+            max_rating: Some(7),
+            ..Default::default()
+        };
+        assert!(!kids.allows(dlc, Some(&facets)));
+        assert!(!kids.allows(dlc, None));
+        let teens = IndexFilter {
+            max_rating: Some(12),
+            deny_rating_content: strings(&["fantasy violence"]),
+            ..Default::default()
+        };
+        assert!(!teens.allows(dlc, Some(&facets)));
+    }
+}
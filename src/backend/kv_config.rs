@@ -7,10 +7,16 @@
 
 // The returning value should return a serde json value
 
-use crate::{db::DB, index::SourceList};
+use crate::{
+    db::DB,
+    index::SourceList,
+    util::{FilenameTemplate, FilenameTemplateError},
+};
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
+use std::collections::BTreeMap;
 const TABLE_NAME: &str = "settings";
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 
@@ -185,12 +191,84 @@ pub struct Motd {
     #[serde(default)] // Add this attribute
     pub message: Option<String>,
     pub enabled: bool,
+    /// Which clients get the message
+    #[serde(default)]
+    pub target: MotdTarget,
+    /// Messages by language, like `en` or `pt-BR`, for clients asking for one. Everyone
+    /// else gets `message`
+    #[serde(default)]
+    pub messages: BTreeMap<String, String>,
+    /// The message isn't shown before this time
+    #[serde(default)]
+    pub starts_at: Option<DateTime<Utc>>,
+    /// The message isn't shown from this time on
+    #[serde(default)]
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+/// Who an index is served to, which decides the MOTD they get
+#[derive(Debug, Clone, Default)]
+pub struct MotdRecipient {
+    pub tinfoil_client: bool,
+    /// Languages the client accepts, most preferred first
+    pub languages: Vec<String>,
+}
+
+/// Language tag in lowercase with `-` separators, so `pt_BR` matches `pt-BR`
+fn normalize_language(tag: &str) -> String {
+    tag.trim().replace('_', "-").to_lowercase()
 }
 
 impl KvOptExt for Motd {
     const KEY_NAME: &'static str = "motd";
 }
 
+impl Motd {
+    /// The message to show a client at a time, if it's enabled for that kind of client and
+    /// the time is in the message's window
+    pub fn message_for(&self, recipient: &MotdRecipient, now: DateTime<Utc>) -> Option<&str> {
+        let targeted = match self.target {
+            MotdTarget::All => true,
+            MotdTarget::Tinfoil => recipient.tinfoil_client,
+            MotdTarget::None => false,
+        };
+        let scheduled = self.starts_at.is_none_or(|start| start <= now)
+            && self.ends_at.is_none_or(|end| now < end);
+        if !(self.enabled && targeted && scheduled) {
+            return None;
+        }
+        self.localized(&recipient.languages)
+            .or(self.message.as_deref())
+    }
+
+    /// The message in the first of `languages` there's one for. A language with a region,
+    /// like `en-GB`, falls back to the message of the language alone
+    fn localized(&self, languages: &[String]) -> Option<&str> {
+        let message = |language: &str| {
+            self.messages
+                .iter()
+                .find(|(tag, _)| normalize_language(tag) == language)
+                .map(|(_, message)| message.as_str())
+        };
+        languages.iter().find_map(|language| {
+            let language = normalize_language(language);
+            let primary = language.split('-').next().unwrap_or_default();
+            message(&language).or_else(|| message(primary))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MotdTarget {
+    /// Tinfoil and everything else reading the index, like browsers
+    #[default]
+    All,
+    /// Only clients sending the Tinfoil headers
+    Tinfoil,
+    None,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ExtraSourcesConfig {
     pub sources: Vec<SourceList>,
@@ -204,6 +282,33 @@ impl KvOptExt for ExtraSourcesConfig {
 pub struct ExtraBackendConfig {
     pub import_titledb_on_start: bool,
     pub import_indexes_on_start: bool,
+    /// Hours between full rescans of the rom dir, catching files the filesystem watcher
+    /// missed. 0 disables periodic rescans
+    #[serde(default = "default_rescan_interval_hours")]
+    pub rescan_interval_hours: u64,
+    /// Most downloads running at once, the others wait in the queue. 0 for no limit
+    #[serde(default = "default_max_concurrent_downloads")]
+    pub max_concurrent_downloads: usize,
+    /// Connections each download is split into when the server supports range requests.
+    /// 1 downloads over a single connection
+    #[serde(default = "default_download_segments")]
+    pub download_segments: usize,
+    /// Scans of a file whose CNMT can't be read before it's moved into the quarantine
+    /// folder. 0 leaves such files where they are
+    #[serde(default)]
+    pub quarantine_after_failures: u32,
+}
+
+fn default_rescan_interval_hours() -> u64 {
+    24
+}
+
+fn default_max_concurrent_downloads() -> usize {
+    3
+}
+
+fn default_download_segments() -> usize {
+    1
 }
 
 impl Default for ExtraBackendConfig {
@@ -211,6 +316,10 @@ impl Default for ExtraBackendConfig {
         Self {
             import_titledb_on_start: true,
             import_indexes_on_start: true,
+            rescan_interval_hours: default_rescan_interval_hours(),
+            max_concurrent_downloads: default_max_concurrent_downloads(),
+            download_segments: default_download_segments(),
+            quarantine_after_failures: 0,
         }
     }
 }
@@ -218,3 +327,418 @@ impl Default for ExtraBackendConfig {
 impl KvOptExt for ExtraBackendConfig {
     const KEY_NAME: &'static str = "extra_backend_config";
 }
+
+/// Settings that control what is advertised to Tinfoil clients in the index
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct TinfoilIndexConfig {
+    /// Minimum Tinfoil client version required to use this shop, sent as the index `version`
+    #[serde(default)]
+    pub min_client_version: Option<String>,
+    /// Leave titles flagged as demos in TitleDB out of the index
+    #[serde(default)]
+    pub exclude_demos: bool,
+    /// Referrer sent in the index, which Tinfoil sends back as the `Referer` of every file
+    /// download
+    #[serde(default)]
+    pub referrer: Option<String>,
+    /// Reject file downloads whose `Referer` is neither the configured referrer nor a page
+    /// of this server, so other sites can't link to the files
+    #[serde(default)]
+    pub enforce_referrer: bool,
+    /// Seconds before the parts of the index that aren't files from the rom dir are
+    /// regenerated, 300 if unset
+    #[serde(default)]
+    pub cache_lifetime_seconds: Option<u64>,
+    /// How files from the rom dir are grouped into directories
+    #[serde(default)]
+    pub grouping: IndexGrouping,
+}
+
+/// Directories the files from the rom dir are grouped into in the index, which Tinfoil
+/// shows as folders
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexGrouping {
+    /// All files in one list
+    #[default]
+    Flat,
+    /// By the first letter of the title name, `#` for anything else
+    FirstLetter,
+    /// By the publisher TitleDB lists for the base game
+    Publisher,
+    /// Base games with their updates and DLC
+    BaseGame,
+}
+
+impl TinfoilIndexConfig {
+    /// How long the generated index is used before being regenerated
+    pub fn cache_lifetime(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_lifetime_seconds.unwrap_or(300))
+    }
+
+    /// The configured referrer, if it isn't blank
+    pub fn referrer(&self) -> Option<&str> {
+        self.referrer
+            .as_deref()
+            .map(str::trim)
+            .filter(|referrer| !referrer.is_empty())
+    }
+
+    /// Check if a file download with the given `Referer` and `Host` headers is allowed.
+    ///
+    /// Without enforcement or a configured referrer everything is allowed. Otherwise the
+    /// `Referer` has to be the configured one, or a page served from the requested host,
+    /// which is what browsers send for downloads started from the web interface.
+    pub fn allows_referer(&self, referer: Option<&str>, host: Option<&str>) -> bool {
+        let Some(referrer) = self.referrer().filter(|_| self.enforce_referrer) else {
+            return true;
+        };
+        let Some(referer) = referer.map(str::trim) else {
+            return false;
+        };
+        if referer == referrer {
+            return true;
+        }
+
+        let referer_host = url::Url::parse(referer)
+            .ok()
+            .filter(|url| url.has_host())
+            .map(|url| url[url::Position::BeforeHost..url::Position::BeforePath].to_string());
+        matches!(
+            (referer_host, host),
+            (Some(referer_host), Some(host)) if referer_host.eq_ignore_ascii_case(host.trim())
+        )
+    }
+}
+
+impl KvOptExt for TinfoilIndexConfig {
+    const KEY_NAME: &'static str = "tinfoil_index_config";
+}
+
+/// Tinfoil theme restrictions sent to clients in the index
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct ThemeConfig {
+    /// Theme IDs that clients are not allowed to use
+    #[serde(default)]
+    pub blacklist: Vec<String>,
+    /// If not empty, only these theme IDs are allowed
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    /// Message shown by Tinfoil when the current theme is not allowed
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ThemeConfig {
+    const MAX_THEME_ID_LEN: usize = 128;
+
+    /// Trim and deduplicate the lists, rejecting malformed or conflicting entries
+    pub fn normalize(mut self) -> std::result::Result<Self, String> {
+        fn normalize_list(list: Vec<String>) -> std::result::Result<Vec<String>, String> {
+            let mut normalized: Vec<String> = Vec::with_capacity(list.len());
+            for entry in list {
+                let entry = entry.trim().to_string();
+                if entry.is_empty() || entry.len() > ThemeConfig::MAX_THEME_ID_LEN {
+                    return Err(format!(
+                        "Theme IDs must be between 1 and {} characters long",
+                        ThemeConfig::MAX_THEME_ID_LEN
+                    ));
+                }
+                if !entry
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err(format!("Invalid theme ID: {entry}"));
+                }
+                if !normalized.contains(&entry) {
+                    normalized.push(entry);
+                }
+            }
+            Ok(normalized)
+        }
+
+        self.blacklist = normalize_list(self.blacklist)?;
+        self.whitelist = normalize_list(self.whitelist)?;
+
+        if let Some(conflict) = self
+            .blacklist
+            .iter()
+            .find(|entry| self.whitelist.contains(entry))
+        {
+            return Err(format!(
+                "Theme {conflict} can't be both blacklisted and whitelisted"
+            ));
+        }
+
+        self.error = self
+            .error
+            .map(|error| error.trim().to_string())
+            .filter(|error| !error.is_empty());
+
+        Ok(self)
+    }
+}
+
+impl KvOptExt for ThemeConfig {
+    const KEY_NAME: &'static str = "tinfoil_themes";
+}
+
+/// Names files are served with, in downloads and the Tinfoil index
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FilenameConfig {
+    /// See [`FilenameTemplate`], the default format is used when blank
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+impl FilenameConfig {
+    /// Parse the template, `None` if there's none
+    pub fn template(&self) -> std::result::Result<Option<FilenameTemplate>, FilenameTemplateError> {
+        self.template
+            .as_deref()
+            .map(str::trim)
+            .filter(|template| !template.is_empty())
+            .map(str::parse)
+            .transpose()
+    }
+
+    /// The configured template, if there's a valid one
+    pub async fn configured_template() -> Option<FilenameTemplate> {
+        let config = Self::get()
+            .await
+            .inspect_err(|e| tracing::error!("Failed to get filename config: {}", e))
+            .ok()
+            .flatten()?;
+        // Templates are checked when they're saved, so this only happens if the database was
+        // edited directly
+        config
+            .template()
+            .inspect_err(|e| tracing::warn!("Ignoring invalid filename template: {}", e))
+            .ok()
+            .flatten()
+    }
+}
+
+impl KvOptExt for FilenameConfig {
+    const KEY_NAME: &'static str = "filename_template";
+}
+
+/// Where to POST notifications about finished downloads, and which ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// No notifications are sent without a URL
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_true")]
+    pub on_completed: bool,
+    #[serde(default = "default_true")]
+    pub on_failed: bool,
+    #[serde(default)]
+    pub on_cancelled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            on_completed: true,
+            on_failed: true,
+            on_cancelled: false,
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// The configured URL, if it's an HTTP(S) URL
+    pub fn url(&self) -> std::result::Result<Option<url::Url>, String> {
+        let Some(url) = self
+            .url
+            .as_deref()
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+        else {
+            return Ok(None);
+        };
+        let url = url::Url::parse(url).map_err(|e| format!("Invalid webhook URL: {e}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("Unsupported webhook URL scheme: {}", url.scheme()));
+        }
+        Ok(Some(url))
+    }
+}
+
+impl KvOptExt for WebhookConfig {
+    const KEY_NAME: &'static str = "webhook";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipient(tinfoil_client: bool) -> MotdRecipient {
+        MotdRecipient {
+            tinfoil_client,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_motd_target() {
+        let motd = |target| Motd {
+            message: Some("hello".to_string()),
+            enabled: true,
+            target,
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let message = |motd: &Motd, tinfoil_client| {
+            motd.message_for(&recipient(tinfoil_client), now)
+                .map(str::to_string)
+        };
+        assert_eq!(
+            message(&motd(MotdTarget::All), false).as_deref(),
+            Some("hello")
+        );
+        assert_eq!(
+            message(&motd(MotdTarget::Tinfoil), true).as_deref(),
+            Some("hello")
+        );
+        assert_eq!(message(&motd(MotdTarget::Tinfoil), false), None);
+        assert_eq!(message(&motd(MotdTarget::None), true), None);
+
+        let disabled = Motd {
+            enabled: false,
+            ..motd(MotdTarget::All)
+        };
+        assert_eq!(message(&disabled, true), None);
+
+        // Configs saved before targets existed keep showing the message everywhere
+        let saved: Motd = serde_json::from_str(r#"{"message":"hi","enabled":true}"#).unwrap();
+        assert_eq!(saved.target, MotdTarget::All);
+    }
+
+    #[test]
+    fn test_motd_schedule_and_languages() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let motd = Motd {
+            message: Some("hello".to_string()),
+            enabled: true,
+            messages: BTreeMap::from([
+                ("fr".to_string(), "bonjour".to_string()),
+                ("pt_BR".to_string(), "olá".to_string()),
+            ]),
+            starts_at: Some(now - hour),
+            ends_at: Some(now + hour),
+            ..Default::default()
+        };
+        let speaking = |languages: &[&str]| MotdRecipient {
+            tinfoil_client: true,
+            languages: languages.iter().map(|l| l.to_string()).collect(),
+        };
+
+        assert_eq!(motd.message_for(&speaking(&[]), now), Some("hello"));
+        assert_eq!(
+            motd.message_for(&speaking(&["fr-CA"]), now),
+            Some("bonjour")
+        );
+        assert_eq!(
+            motd.message_for(&speaking(&["pt-BR", "fr"]), now),
+            Some("olá")
+        );
+        assert_eq!(motd.message_for(&speaking(&["pt-PT"]), now), Some("hello"));
+        assert_eq!(
+            motd.message_for(&speaking(&["de", "fr"]), now),
+            Some("bonjour")
+        );
+
+        assert_eq!(motd.message_for(&speaking(&[]), now - hour * 2), None);
+        assert_eq!(motd.message_for(&speaking(&[]), now + hour), None);
+    }
+
+    #[test]
+    fn test_theme_config_normalize() {
+        let config = ThemeConfig {
+            blacklist: vec![" abc123 ".to_string(), "abc123".to_string()],
+            whitelist: vec!["def-456".to_string()],
+            error: Some("  ".to_string()),
+        }
+        .normalize()
+        .unwrap();
+
+        assert_eq!(config.blacklist, vec!["abc123"]);
+        assert_eq!(config.whitelist, vec!["def-456"]);
+        assert_eq!(config.error, None);
+    }
+
+    #[test]
+    fn test_theme_config_rejects_invalid() {
+        let invalid = ThemeConfig {
+            blacklist: vec!["not a theme".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.normalize().is_err());
+
+        let conflicting = ThemeConfig {
+            blacklist: vec!["abc".to_string()],
+            whitelist: vec!["abc".to_string()],
+            error: None,
+        };
+        assert!(conflicting.normalize().is_err());
+    }
+
+    #[test]
+    fn test_webhook_config_url() {
+        let webhook = |url: &str| WebhookConfig {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(WebhookConfig::default().url(), Ok(None));
+        assert_eq!(webhook("  ").url(), Ok(None));
+        assert_eq!(
+            webhook(" https://ntfy.sh/alumulemu ")
+                .url()
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "https://ntfy.sh/alumulemu"
+        );
+        assert!(webhook("ftp://example.com/").url().is_err());
+        assert!(webhook("not a url").url().is_err());
+
+        let config: WebhookConfig = serde_json::from_str(r#"{"url": "http://x"}"#).unwrap();
+        assert!(config.on_completed && config.on_failed && !config.on_cancelled);
+    }
+
+    #[test]
+    fn test_allows_referer() {
+        let config = TinfoilIndexConfig {
+            referrer: Some("https://shop.example.com/".to_string()),
+            enforce_referrer: true,
+            ..Default::default()
+        };
+        let host = Some("shop.example.com:3000");
+        assert!(config.allows_referer(Some("https://shop.example.com/"), host));
+        // Downloads started from the web interface
+        assert!(config.allows_referer(Some("http://shop.example.com:3000/games"), host));
+        assert!(!config.allows_referer(Some("https://elsewhere.example.com/"), host));
+        assert!(!config.allows_referer(Some("not a url"), host));
+        assert!(!config.allows_referer(None, host));
+
+        // Nothing is enforced unless asked to, or without a referrer to check against
+        let not_enforced = TinfoilIndexConfig {
+            enforce_referrer: false,
+            ..config.clone()
+        };
+        assert!(not_enforced.allows_referer(None, host));
+        let no_referrer = TinfoilIndexConfig {
+            referrer: Some(" ".to_string()),
+            ..config
+        };
+        assert!(no_referrer.allows_referer(None, host));
+    }
+}
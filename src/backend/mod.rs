@@ -1,5 +1,7 @@
 pub mod admin;
 pub mod api;
+pub mod index_filter;
 pub mod router;
+pub mod session;
 pub mod user;
 pub mod kv_config;
@@ -14,6 +14,11 @@ pub fn create_router() -> Router {
         .nest("/api", api_router())
         // Admin routes
         .nest("/admin", admin_router())
+        // Prometheus metrics
+        .route(
+            "/metrics",
+            axum::routing::get(crate::backend::api::metrics::get_metrics),
+        )
         // Favicon route - placed before other routes for priority
         .route(
             "/favicon.ico",
@@ -53,6 +58,9 @@ pub fn create_router() -> Router {
         })
         // Use our new HRBAC middleware instead of the old basic_auth_if_public
         .layer(axum::middleware::from_fn(super::user::auth_optional_viewer))
+        // Logging in and out checks credentials itself, so it's added after the auth layer
+        .route("/api/login", axum::routing::post(super::session::login))
+        .route("/api/logout", axum::routing::post(super::session::logout))
 }
 
 pub fn static_router() -> Router {
@@ -0,0 +1,249 @@
+//! Session tokens
+//!
+//! `POST /api/login` checks a user's password once and hands out a random token, which is
+//! then sent as `Authorization: Bearer <token>` instead of Basic credentials. Sessions are
+//! stored in the `session` table under the SHA-256 of their token, so the database never
+//! holds usable tokens, and kept in memory along with the scopes the user had when logging
+//! in, so checking a token doesn't need the database.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use axum::Json;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, StatusCode, header};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::user::User;
+use crate::db::DB;
+
+const TABLE: &str = "session";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    /// Scopes of the user when the session was created
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Session {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+
+    /// The user the session acts as
+    pub fn user(&self) -> User {
+        User {
+            username: self.username.clone(),
+            password: String::new(),
+            scopes: Some(self.scopes.clone()),
+        }
+    }
+}
+
+/// Sessions known to be valid, by token hash
+static SESSIONS: LazyLock<Mutex<HashMap<String, Session>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Get the token of an `Authorization: Bearer` header
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    authorization
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Start a session for a user, returning its token
+pub async fn create(user: &User) -> color_eyre::Result<(String, Session)> {
+    let lifetime = crate::config::config()
+        .backend_config
+        .session_lifetime_hours;
+    let now = Utc::now();
+    let session = Session {
+        username: user.username.clone(),
+        scopes: user.scopes.clone().unwrap_or_default(),
+        created_at: now,
+        expires_at: i64::try_from(lifetime)
+            .ok()
+            .and_then(chrono::TimeDelta::try_hours)
+            .and_then(|lifetime| now.checked_add_signed(lifetime))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC),
+    };
+
+    let bytes: [u8; 32] = rand::random();
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let hash = token_hash(&token);
+
+    let _: Option<Session> = DB
+        .upsert((TABLE, hash.as_str()))
+        .content(session.clone())
+        .await?;
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| !session.is_expired(now));
+    sessions.insert(hash, session.clone());
+    Ok((token, session))
+}
+
+/// Look up the session of a token, `None` if it's unknown, expired or logged out
+pub async fn lookup(token: &str) -> Option<Session> {
+    let hash = token_hash(token);
+    let now = Utc::now();
+
+    let cached = SESSIONS.lock().unwrap().get(&hash).cloned();
+    let session = match cached {
+        Some(session) => session,
+        // Sessions from before a restart are only in the database
+        None => {
+            let stored: Option<Session> = match DB.select((TABLE, hash.as_str())).await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    tracing::error!("Failed to look up session: {}", e);
+                    return None;
+                }
+            };
+            let session = stored?;
+            SESSIONS
+                .lock()
+                .unwrap()
+                .insert(hash.clone(), session.clone());
+            session
+        }
+    };
+
+    if session.is_expired(now) {
+        remove(&hash).await;
+        return None;
+    }
+    Some(session)
+}
+
+async fn remove(hash: &str) -> bool {
+    let cached = SESSIONS.lock().unwrap().remove(hash).is_some();
+    match DB.delete::<Option<Session>>((TABLE, hash)).await {
+        Ok(stored) => cached || stored.is_some(),
+        Err(e) => {
+            tracing::error!("Failed to delete session: {}", e);
+            cached
+        }
+    }
+}
+
+/// End every session of a user, such as when it's deleted
+pub async fn revoke_user(username: &str) -> color_eyre::Result<()> {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .retain(|_, session| session.username != username);
+    DB.query("DELETE session WHERE username = $username")
+        .bind(("username", username.to_string()))
+        .await?;
+    Ok(())
+}
+
+/// Give every session of a user new scopes, after the user's were changed
+pub async fn set_user_scopes(username: &str, scopes: &[String]) -> color_eyre::Result<()> {
+    for session in SESSIONS
+        .lock()
+        .unwrap()
+        .values_mut()
+        .filter(|session| session.username == username)
+    {
+        session.scopes = scopes.to_vec();
+    }
+    DB.query("UPDATE session SET scopes = $scopes WHERE username = $username")
+        .bind(("username", username.to_string()))
+        .bind(("scopes", scopes.to_vec()))
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
+    pub token: String,
+    pub username: String,
+    pub scopes: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Check a user's password and start a session
+pub async fn login(Json(request): Json<LoginRequest>) -> Result<Json<LoginResponse>, StatusCode> {
+    let user = User::login_user(&request.username, &request.password)
+        .await
+        .map_err(|e| {
+            tracing::warn!("Login failed for user {}: {}", request.username, e);
+            StatusCode::UNAUTHORIZED
+        })?;
+
+    let (token, session) = create(&user).await.map_err(|e| {
+        tracing::error!("Failed to create session for {}: {}", user.username, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!("User {} logged in", session.username);
+
+    Ok(Json(LoginResponse {
+        token,
+        username: session.username,
+        scopes: session.scopes,
+        expires_at: session.expires_at,
+    }))
+}
+
+/// End the session of the bearer token the request is made with
+pub async fn logout(headers: HeaderMap) -> StatusCode {
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(bearer_token)
+    else {
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    if remove(&token_hash(token)).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("Bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let now = Utc::now();
+        let session = Session {
+            username: "user".to_string(),
+            scopes: vec!["viewer".to_string()],
+            created_at: now - chrono::Duration::hours(2),
+            expires_at: now - chrono::Duration::hours(1),
+        };
+        assert!(session.is_expired(now));
+        assert!(!session.is_expired(now - chrono::Duration::hours(2)));
+        assert!(session.user().can_view());
+        assert!(!session.user().can_edit());
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    Json, Router,
+    Extension, Json, Router,
     body::Body,
     extract::{Path as HttpPath, Request},
     middleware::Next,
@@ -12,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::str::FromStr;
 
+use super::admin::ApiResponse;
 use crate::{db::DB, index::TinfoilResponse};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -130,14 +131,19 @@ impl User {
     }
 
     pub async fn get_user(username: &str) -> color_eyre::Result<Self> {
+        Self::find_user(username)
+            .await?
+            .ok_or_else(|| color_eyre::eyre::eyre!("User not found"))
+    }
+
+    /// Look up a user, `None` if there's no user of that name
+    pub async fn find_user(username: &str) -> color_eyre::Result<Option<Self>> {
         let mut res = DB
             .query("SELECT * FROM user WHERE username = $username")
             .bind(("username", username.to_string()))
             .await?;
 
-        let user: Option<User> = res.take(0)?;
-
-        user.ok_or_else(|| color_eyre::eyre::eyre!("User not found"))
+        Ok(res.take(0)?)
     }
 
     pub async fn delete(&self) -> color_eyre::Result<()> {
@@ -186,7 +192,7 @@ pub async fn create_user(
         }
     };
 
-    let is_first_user = users.is_empty();
+    let is_first_user = users.is_empty() && crate::config::config().backend_config.first_user_admin;
 
     // Create the user
     match User::create(username, password).await {
@@ -232,6 +238,34 @@ pub async fn create_user(
     }
 }
 
+/// Create the admin user from `ALU_ADMIN_USERNAME` and `ALU_ADMIN_PASSWORD` if it doesn't
+/// exist yet. An existing user of that name is left as it is.
+pub async fn bootstrap_admin() -> color_eyre::Result<()> {
+    let config = crate::config::config().backend_config;
+    let Some((username, password)) = config
+        .admin_credentials()
+        .map_err(color_eyre::Report::msg)?
+    else {
+        if !config.first_user_admin {
+            tracing::info!(
+                "ALU_FIRST_USER_ADMIN is disabled, users only get the scopes they're created with"
+            );
+        }
+        return Ok(());
+    };
+
+    if User::find_user(&username).await?.is_some() {
+        tracing::debug!("Admin user '{}' already exists", username);
+        return Ok(());
+    }
+
+    create_user(&username, &password, Some(vec!["admin".to_string()]))
+        .await
+        .map_err(|e| color_eyre::eyre::eyre!("Failed to create admin user: {}", e))?;
+    tracing::info!("Created admin user '{}'", username);
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     username: String,
@@ -284,6 +318,152 @@ pub async fn delete_user(HttpPath(username): HttpPath<String>) -> Result<StatusC
     user.delete()
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = super::session::revoke_user(&username).await {
+        tracing::error!("Failed to end the sessions of {}: {}", username, e);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct UpdateScopesRequest {
+    scopes: Vec<String>,
+}
+
+/// Whether giving `username` the `scopes` leaves no admin behind
+fn removes_last_admin(users: &[User], username: &str, scopes: &[UserScope]) -> bool {
+    !scopes.contains(&UserScope::Admin)
+        && !users
+            .iter()
+            .any(|user| user.username != username && user.can_admin())
+}
+
+type ErrorResponse = (StatusCode, Json<ApiResponse<()>>);
+
+fn error(status: StatusCode, message: impl Into<String>) -> ErrorResponse {
+    (
+        status,
+        Json(ApiResponse {
+            status: "error".to_string(),
+            message: Some(message.into()),
+            data: None,
+        }),
+    )
+}
+
+/// Replace the scopes of a user
+pub async fn update_user_scopes(
+    HttpPath(username): HttpPath<String>,
+    Json(payload): Json<UpdateScopesRequest>,
+) -> Result<Json<UserInfo>, ErrorResponse> {
+    let mut scopes = Vec::new();
+    for scope in &payload.scopes {
+        let scope = UserScope::from_str(scope).map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let users: Vec<User> = DB.select("user").await.map_err(|e| {
+        tracing::error!("Failed to fetch users: {}", e);
+        error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch users")
+    })?;
+    if !users.iter().any(|user| user.username == username) {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("User {username} not found"),
+        ));
+    }
+    if removes_last_admin(&users, &username, &scopes) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            "At least one user has to stay an admin",
+        ));
+    }
+
+    let scopes = scopes
+        .iter()
+        .map(|scope| scope.as_str().to_string())
+        .collect::<Vec<_>>();
+    DB.query("UPDATE user SET scopes = $scopes WHERE username = $username")
+        .bind(("username", username.clone()))
+        .bind(("scopes", scopes.clone()))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update scopes of {}: {}", username, e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update scopes")
+        })?;
+    if let Err(e) = super::session::set_user_scopes(&username, &scopes).await {
+        tracing::error!("Failed to update the sessions of {}: {}", username, e);
+    }
+    tracing::info!("Updated scopes of {} to {:?}", username, scopes);
+
+    Ok(Json(UserInfo { username, scopes }))
+}
+
+/// Shortest password a user can change theirs to
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+/// Check that a new password is worth changing to
+fn check_new_password(old_password: &str, new_password: &str) -> Result<(), String> {
+    if new_password.trim().is_empty() {
+        return Err("New password can't be empty".to_string());
+    }
+    if new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "New password has to be at least {MIN_PASSWORD_LENGTH} characters long"
+        ));
+    }
+    if new_password == old_password {
+        return Err("New password has to differ from the old one".to_string());
+    }
+    Ok(())
+}
+
+/// Change the password of the logged in user
+pub async fn change_password(
+    Extension(user): Extension<User>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, ErrorResponse> {
+    check_new_password(&payload.old_password, &payload.new_password)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+
+    let failed = |e: surrealdb::Error| {
+        tracing::error!("Failed to change the password of {}: {}", user.username, e);
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to change password",
+        )
+    };
+    // Checking the old password in the same query keeps a wrong one from changing anything
+    let updated: Option<User> = DB
+        .query(
+            "UPDATE user SET password = crypto::argon2::generate($new_password)
+        WHERE username = $username AND crypto::argon2::compare(password, $old_password)",
+        )
+        .bind(("username", user.username.clone()))
+        .bind(("old_password", payload.old_password))
+        .bind(("new_password", payload.new_password))
+        .await
+        .map_err(failed)?
+        .take(0)
+        .map_err(failed)?;
+    if updated.is_none() {
+        tracing::warn!("Wrong old password given by {}", user.username);
+        return Err(error(StatusCode::FORBIDDEN, "Old password is incorrect"));
+    }
+
+    // Whoever had the old password may have logged in with it
+    if let Err(e) = super::session::revoke_user(&user.username).await {
+        tracing::error!("Failed to end the sessions of {}: {}", user.username, e);
+    }
+    tracing::info!("User {} changed their password", user.username);
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -443,6 +623,20 @@ pub async fn auth_require_admin(req: Request<Body>, next: Next) -> Result<Respon
 async fn authenticate_user(
     req: Request<Body>,
 ) -> Result<(User, Request<Body>), Result<Response, StatusCode>> {
+    // Session tokens already carry the user's scopes, they don't need the database
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|val| val.to_str().ok())
+        .and_then(super::session::bearer_token)
+        .map(str::to_owned);
+    if let Some(token) = token {
+        return match super::session::lookup(&token).await {
+            Some(session) => Ok((session.user(), req)),
+            None => Err(unauthorized_response()),
+        };
+    }
+
     let users: Vec<User> = match DB.select("user").await {
         Ok(users) => users,
         Err(e) => {
@@ -477,6 +671,11 @@ async fn authenticate_user(
         .and_then(|val| val.to_str().ok())
     {
         Some(header) => header,
+        // Without credentials in public mode, act as the anonymous user. If it lacks the
+        // required scope, the 401 still prompts for a login.
+        None if crate::config::config().backend_config.public => {
+            return Ok((anonymous_user(), req));
+        }
         None => return Err(unauthorized_response()),
     };
 
@@ -513,26 +712,33 @@ async fn authenticate_user(
     }
 }
 
+/// The user unauthenticated requests act as in public mode, with the configured
+/// `ALU_ANONYMOUS_SCOPES`
+pub fn anonymous_user() -> User {
+    let scopes = crate::config::config().backend_config.anonymous_scopes();
+    User {
+        username: "anonymous".to_string(),
+        password: "".to_string(),
+        scopes: Some(scopes.iter().map(|s| s.as_str().to_string()).collect()),
+    }
+}
+
 /// Middleware for optional authentication that provides viewer access for public systems
 /// This is a replacement for basic_auth_if_public that integrates with the HRBAC system
+///
+/// Requests that send credentials are authenticated in public mode too, so they act as
+/// their user, with its index filter, and wrong credentials are rejected.
 pub async fn auth_optional_viewer(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
     let config = crate::config::config();
     let is_public = config.backend_config.public;
 
-    if !is_public {
+    if !is_public || req.headers().contains_key(http::header::AUTHORIZATION) {
         auth_require_viewer(req, next).await
     } else {
-        // For public systems, we don't require authentication but we do add a default
-        // viewer user to the request context
-        let default_user = User {
-            username: "anonymous".to_string(),
-            password: "".to_string(),
-            scopes: Some(vec!["viewer".to_string()]),
-        };
-
-        // Add the default user to the request context
+        // For public systems, we don't require authentication but we do add the
+        // anonymous user to the request context
         let mut public_req = req;
-        public_req.extensions_mut().insert(default_user);
+        public_req.extensions_mut().insert(anonymous_user());
 
         Ok(next.run(public_req).await)
     }
@@ -542,8 +748,52 @@ pub fn user_router() -> Router {
     Router::new()
         .route("/", get(list_users))
         .route("/", post(create_user_handler))
-        .route("/{username}", delete(delete_user))
+        .route("/{username}", delete(delete_user).patch(update_user_scopes))
+        .route(
+            "/{username}/index_filter",
+            get(super::index_filter::get_index_filter).put(super::index_filter::set_index_filter),
+        )
         .fallback(|| async { Json(TinfoilResponse::Failure("Not Found".to_string())) })
         .layer(axum::middleware::from_fn(auth_require_admin))
+        // Any user can change their own password
+        .merge(
+            Router::new()
+                .route("/me/password", post(change_password))
+                .layer(axum::middleware::from_fn(auth_require_viewer)),
+        )
     // Authentication is handled at the API router level now
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, scopes: &[&str]) -> User {
+        User {
+            username: username.to_string(),
+            password: String::new(),
+            scopes: Some(scopes.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_removes_last_admin() {
+        let users = [user("root", &["admin"]), user("bob", &["viewer"])];
+
+        assert!(removes_last_admin(&users, "root", &[UserScope::Editor]));
+        assert!(!removes_last_admin(&users, "root", &[UserScope::Admin]));
+        assert!(!removes_last_admin(&users, "bob", &[UserScope::Editor]));
+
+        let users = [user("root", &["admin"]), user("alice", &["admin"])];
+        assert!(!removes_last_admin(&users, "root", &[]));
+    }
+
+    #[test]
+    fn test_check_new_password() {
+        assert!(check_new_password("old password", "").is_err());
+        assert!(check_new_password("old password", "        ").is_err());
+        assert!(check_new_password("old password", "short").is_err());
+        assert!(check_new_password("old password", "old password").is_err());
+        assert!(check_new_password("old password", "new password").is_ok());
+    }
+}
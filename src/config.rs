@@ -1,9 +1,14 @@
 //! Config module for alumulemu
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use bytesize::ByteSize;
 use clap::{Parser, ValueEnum};
 
+use crate::backend::user::UserScope;
+use crate::locale::{Language, Locale, LocaleList, Region};
+use crate::title_kind::TitleSuffix;
+
 #[derive(ValueEnum, Debug, Clone, Default)]
 #[clap(rename_all = "lowercase")]
 pub enum DatabaseAuthMethod {
@@ -39,23 +44,28 @@ pub struct DatabaseConfig {
 pub struct BackendConfig {
     /// Primary region for metadata to be pulled from
     #[clap(env = "ALU_PRIMARY_REGION", default_value = "US")]
-    pub primary_region: String,
+    pub primary_region: Region,
 
     /// Primary language for metadata to be pulled from
     #[clap(env = "ALU_PRIMARY_LANGUAGE", default_value = "en")]
-    pub primary_lang: String,
+    pub primary_lang: Language,
 
     /// Directory to store games
     #[clap(env = "ALU_ROM_DIR", default_value = "games/")]
     pub rom_dir: String,
 
-    /// Secondary locales for metadata fallback
+    /// More directories with games, such as ones on other drives, served along with `rom_dir`
     #[clap(
-        env = "ALU_SECONDARY_LOCALES",
+        long,
+        env = "ALU_EXTRA_ROM_DIRS",
         value_delimiter = ',',
         default_value = ""
     )]
-    pub secondary_locales: Vec<String>,
+    pub extra_rom_dirs: Vec<String>,
+
+    /// Secondary locales for metadata fallback, such as `JP_ja,US_es`
+    #[clap(env = "ALU_SECONDARY_LOCALES", default_value = "")]
+    pub secondary_locales: LocaleList,
 
     #[clap(long, env = "ALU_PROD_KEYS", default_value_t = get_default_prod_keys_path())]
     pub prod_keys: String,
@@ -66,10 +76,63 @@ pub struct BackendConfig {
     #[clap(long, env = "ALU_PUBLIC", default_value = "false")]
     pub public: bool,
 
+    /// Scopes of the anonymous user in public mode, such as `viewer,editor`
+    #[clap(
+        long,
+        env = "ALU_ANONYMOUS_SCOPES",
+        value_delimiter = ',',
+        default_value = "viewer"
+    )]
+    pub anonymous_scopes: Vec<UserScope>,
+
+    /// Allow `admin` in the anonymous scopes, giving anyone who can reach the server full control
+    #[clap(
+        long,
+        env = "ALU_DANGEROUSLY_ALLOW_ANONYMOUS_ADMIN",
+        default_value = "false"
+    )]
+    pub dangerously_allow_anonymous_admin: bool,
+
+    /// Make the first user that's created an admin, whatever scopes it was created with
+    #[clap(long, env = "ALU_FIRST_USER_ADMIN", default_value = "true")]
+    pub first_user_admin: bool,
+
+    /// Admin user created on startup if it doesn't exist yet, for scripted deployments
+    #[clap(long, env = "ALU_ADMIN_USERNAME")]
+    pub admin_username: Option<String>,
+
+    /// Password of the admin user created on startup
+    #[clap(long, env = "ALU_ADMIN_PASSWORD", hide_env_values = true)]
+    pub admin_password: Option<String>,
+
     /// Cache directory for importers and other temporary files, they should be cleaned up after use
     #[clap(long, env = "ALU_CACHE_DIR", default_value = "/tmp/alumulemu")]
     pub cache_dir: String,
 
+    /// Store the downloaded TitleDB gzip-compressed, existing plain caches are replaced on
+    /// their next download
+    #[clap(long, env = "ALU_TITLEDB_COMPRESS", default_value = "true")]
+    pub titledb_compress: bool,
+
+    /// Title ID suffixes of base games, such as `000`. Title IDs that are neither base
+    /// games nor updates are DLC
+    #[clap(
+        long,
+        env = "ALU_BASE_TITLE_SUFFIXES",
+        value_delimiter = ',',
+        default_value = "000"
+    )]
+    pub base_title_suffixes: Vec<TitleSuffix>,
+
+    /// Title ID suffixes of updates, such as `800`
+    #[clap(
+        long,
+        env = "ALU_UPDATE_TITLE_SUFFIXES",
+        value_delimiter = ',',
+        default_value = "800"
+    )]
+    pub update_title_suffixes: Vec<TitleSuffix>,
+
     /// Extra Tinfoil indexes to merge into the database
     #[clap(
         long,
@@ -78,6 +141,120 @@ pub struct BackendConfig {
         default_value = ""
     )]
     pub extra_indexes: Vec<String>,
+
+    /// Path to Tinfoil's RSA public key (PEM). When set, Tinfoil clients are served the
+    /// encrypted index instead of plain JSON
+    #[clap(long, env = "ALU_TINFOIL_PUBLIC_KEY")]
+    pub tinfoil_public_key: Option<String>,
+
+    /// Key used to encrypt secrets stored in the database, such as extra index headers
+    #[clap(long, env = "ALU_SECRET_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+
+    /// Hosts importers may download from, such as `example.com,*.cdn.example.com`.
+    /// Empty allows any public host
+    #[clap(
+        long,
+        env = "ALU_DOWNLOAD_ALLOWED_HOSTS",
+        value_delimiter = ',',
+        default_value = ""
+    )]
+    pub download_allowed_hosts: Vec<String>,
+
+    /// Hosts importers may never download from
+    #[clap(
+        long,
+        env = "ALU_DOWNLOAD_DENIED_HOSTS",
+        value_delimiter = ',',
+        default_value = ""
+    )]
+    pub download_denied_hosts: Vec<String>,
+
+    /// Allow importers to download from loopback, link-local and private network addresses
+    #[clap(long, env = "ALU_DOWNLOAD_ALLOW_PRIVATE", default_value = "false")]
+    pub download_allow_private: bool,
+
+    /// Hours a session token from `/api/login` stays valid
+    #[clap(long, env = "ALU_SESSION_LIFETIME_HOURS", default_value = "168")]
+    pub session_lifetime_hours: u64,
+
+    /// Milliseconds the watcher waits before forgetting a removed file, so files replaced
+    /// through delete-then-create keep their entry. 0 removes them right away
+    #[clap(long, env = "ALU_WATCHER_REMOVE_GRACE_MS", default_value = "2000")]
+    pub watcher_remove_grace_ms: u64,
+
+    /// Milliseconds a file has to go without changes before the watcher scans it, so files
+    /// still being copied aren't read half-written. 0 scans them right away
+    #[clap(long, env = "ALU_WATCHER_QUIET_MS", default_value = "5000")]
+    pub watcher_quiet_ms: u64,
+
+    /// Stage imported files in a hidden folder of the rom dir and only move them into place
+    /// once the whole import is there, so an interrupted import never leaves partial results
+    #[clap(long, env = "ALU_IMPORT_STAGING", default_value = "true")]
+    pub import_staging: bool,
+
+    /// Decompress imported NSZ and XCZ files into NSPs and XCIs before moving them into the
+    /// rom dir, for clients that can't read compressed files
+    #[clap(long, env = "ALU_DECOMPRESS_NSZ", default_value = "false")]
+    pub decompress_nsz: bool,
+
+    /// Name titles that TitleDB doesn't know, like homebrew, from the NACP inside the file
+    /// instead of its filename. This reads more of each file, disable it on slow storage
+    #[clap(long, env = "ALU_NACP_NAMES", default_value = "true")]
+    pub nacp_names: bool,
+
+    /// Most CNMTs read at once while scanning and importing files. Reads are heavy on the
+    /// disk, the default suits spinning disks and can be raised for SSDs
+    #[clap(long, env = "ALU_MAX_CNMT_READS", default_value = "2")]
+    pub max_cnmt_reads: usize,
+
+    /// Minimum milliseconds between saves of a download's progress to the database.
+    /// Clients following a download still get every update
+    #[clap(long, env = "ALU_PROGRESS_SAVE_INTERVAL_MS", default_value = "1000")]
+    pub progress_save_interval_ms: u64,
+
+    /// Also save a download's progress whenever it advanced by this many percent
+    #[clap(long, env = "ALU_PROGRESS_SAVE_PERCENT", default_value = "5")]
+    pub progress_save_percent: f32,
+
+    /// JSON Lines file every served download is logged to. Disabled by default, the file
+    /// isn't rotated
+    #[clap(long, env = "ALU_ACCESS_LOG", default_value = "")]
+    pub access_log: String,
+
+    /// Items a list or search endpoint returns when the request doesn't set a `limit`
+    #[clap(long, env = "ALU_DEFAULT_PAGE_SIZE", default_value = "100")]
+    pub default_page_size: usize,
+
+    /// Most items a list or search endpoint returns at once, larger limits are clamped.
+    /// Streamed searches aren't capped
+    #[clap(long, env = "ALU_MAX_PAGE_SIZE", default_value = "500")]
+    pub max_page_size: usize,
+
+    /// Proxy for outbound requests, such as `http://proxy:8080` or `socks5://proxy:1080`.
+    /// When unset, the usual `HTTP_PROXY`/`HTTPS_PROXY` variables are used
+    #[clap(long, env = "ALU_HTTP_PROXY")]
+    pub http_proxy: Option<String>,
+
+    /// User agent of outbound requests, importers may still send their own
+    #[clap(long, env = "ALU_HTTP_USER_AGENT", default_value = concat!("alumulemu/", env!("CARGO_PKG_VERSION")))]
+    pub http_user_agent: String,
+
+    /// Seconds to wait for an outbound connection to be established
+    #[clap(long, env = "ALU_HTTP_CONNECT_TIMEOUT", default_value = "30")]
+    pub http_connect_timeout_secs: u64,
+
+    /// Seconds an idle pooled connection is kept open for reuse
+    #[clap(long, env = "ALU_HTTP_POOL_IDLE_TIMEOUT", default_value = "90")]
+    pub http_pool_idle_timeout_secs: u64,
+
+    /// Interval of TCP and HTTP/2 keep-alive probes on outbound connections, 0 disables them
+    #[clap(long, env = "ALU_HTTP_KEEPALIVE", default_value = "60")]
+    pub http_keepalive_secs: u64,
+
+    /// Use HTTP/2 for outbound requests when the server supports it
+    #[clap(long, env = "ALU_HTTP2", default_value = "true")]
+    pub http2: bool,
 }
 
 /// Safely determine the default path for prod.keys
@@ -97,17 +274,32 @@ fn get_default_title_keys_path() -> String {
 }
 
 impl BackendConfig {
+    pub fn primary_locale(&self) -> Locale {
+        Locale::new(self.primary_region, self.primary_lang)
+    }
+
     pub fn get_locale_string(&self) -> String {
-        format!("{}_{}", self.primary_region, self.primary_lang)
+        self.primary_locale().to_string()
     }
 
-    /// Get valid secondary locales (filters out empty strings)
-    pub fn get_valid_secondary_locales(&self) -> Vec<String> {
-        self.secondary_locales
-            .iter()
-            .filter(|s| !s.is_empty())
-            .cloned()
-            .collect()
+    /// Every directory with games, `rom_dir` first
+    pub fn rom_dirs(&self) -> Vec<String> {
+        let mut dirs = vec![self.rom_dir.clone()];
+        for dir in self.extra_rom_dirs.iter().map(|dir| dir.trim()) {
+            if !dir.is_empty() && !dirs.iter().any(|known| Path::new(known) == Path::new(dir)) {
+                dirs.push(dir.to_string());
+            }
+        }
+        dirs
+    }
+
+    /// Get the games directory a path is in, `rom_dir` for paths outside of all of them
+    pub fn rom_dir_of(&self, path: &Path) -> PathBuf {
+        self.rom_dirs()
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|dir| path.starts_with(dir))
+            .unwrap_or_else(|| PathBuf::from(&self.rom_dir))
     }
 
     /// Get valid extra indexes (filters out empty strings)
@@ -119,6 +311,41 @@ impl BackendConfig {
             .collect()
     }
 
+    /// Check that the anonymous user is only made an admin on purpose
+    pub fn check_anonymous_scopes(&self) -> Result<(), String> {
+        if self.anonymous_scopes.contains(&UserScope::Admin)
+            && !self.dangerously_allow_anonymous_admin
+        {
+            return Err(
+                "ALU_ANONYMOUS_SCOPES grants admin, which also requires ALU_DANGEROUSLY_ALLOW_ANONYMOUS_ADMIN=true"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Username and password of the admin user to bootstrap, if both are set. Setting only
+    /// one of them is an error, rather than starting without the expected admin
+    pub fn admin_credentials(&self) -> Result<Option<(String, String)>, String> {
+        let set = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+        match (set(&self.admin_username), set(&self.admin_password)) {
+            (Some(username), Some(password)) => Ok(Some((username, password))),
+            (None, None) => Ok(None),
+            _ => {
+                Err("ALU_ADMIN_USERNAME and ALU_ADMIN_PASSWORD have to be set together".to_string())
+            }
+        }
+    }
+
+    /// Scopes of the anonymous user, without `admin` unless it's explicitly allowed
+    pub fn anonymous_scopes(&self) -> Vec<UserScope> {
+        self.anonymous_scopes
+            .iter()
+            .filter(|scope| **scope != UserScope::Admin || self.dangerously_allow_anonymous_admin)
+            .cloned()
+            .collect()
+    }
+
     pub fn temp_dir(&self) -> PathBuf {
         self.cache_dir.clone().into()
     }
@@ -129,6 +356,11 @@ pub struct Config {
     #[clap(env = "ALU_HOST", default_value = "0.0.0.0:3000")]
     pub host: String,
 
+    /// Maximum size of request bodies buffered by the API, such as JSON import requests.
+    /// Larger requests are rejected with 413 Payload Too Large
+    #[clap(long, env = "ALU_MAX_BODY_SIZE", default_value = "1MiB")]
+    pub max_body_size: ByteSize,
+
     #[clap(flatten)]
     pub db_config: DatabaseConfig,
 
@@ -1,10 +1,29 @@
 //! Database instance module
 use serde::{Deserialize, Serialize};
 
-use std::sync::LazyLock;
+use std::{fmt::Display, future::Future, sync::LazyLock, time::Duration};
 
 use surrealdb::{Surreal, engine::any::Any};
 
+use crate::router::IndexScope;
+use crate::title_kind::TitleKind;
+
+/// Maximum number of attempts for a write that keeps hitting transaction conflicts
+const DB_RETRY_MAX_ATTEMPTS: u32 = 5;
+/// Base delay before retrying a conflicting write, doubled on every attempt
+const DB_RETRY_BASE_DELAY_MS: u64 = 50;
+
+/// Title ID stored for files no title ID could be found for, such as homebrew
+pub const UNIDENTIFIED_TITLE_ID: &str = "00000000AAAA0000";
+
+/// Check if a title ID is a placeholder rather than a real title's ID
+pub fn is_placeholder_title_id(title_id: &str) -> bool {
+    title_id.eq_ignore_ascii_case(UNIDENTIFIED_TITLE_ID)
+        || title_id.len() != 16
+        || !title_id.chars().all(|c| c.is_ascii_hexdigit())
+        || title_id.chars().all(|c| c == '0')
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NspMetadata {
     pub path: String,
@@ -12,12 +31,55 @@ pub struct NspMetadata {
     pub version: String,
     pub title_name: Option<String>,
     pub download_id: String,
+    /// The file has no real title ID, so it's left out of title grouping and the metaview
+    #[serde(default)]
+    pub unidentified: bool,
+    /// Minimum system version the file needs, from its CNMT
+    #[serde(default)]
+    pub required_system_version: Option<u32>,
 }
 
 impl NspMetadata {
     pub async fn get_all() -> surrealdb::Result<Vec<Self>> {
         DB.select("nsp_metadata").await
     }
+    /// Get the metadata of files without a real title ID
+    #[tracing::instrument(level = "debug")]
+    pub async fn get_unidentified() -> surrealdb::Result<Vec<Self>> {
+        let mut query = DB
+            .query("SELECT * FROM nsp_metadata WHERE unidentified = true ORDER BY id")
+            .await?;
+        query.take(0)
+    }
+
+    /// Get the metadata of every file in a slice of the rom dir
+    #[tracing::instrument(level = "debug")]
+    pub async fn get_in_scope(scope: &IndexScope) -> surrealdb::Result<Vec<Self>> {
+        let query = match scope {
+            IndexScope::All => return Self::get_all().await,
+            IndexScope::Path(path) => DB
+                .query(
+                    "SELECT * FROM nsp_metadata
+                        WHERE path = $path OR string::starts_with(path, $dir)",
+                )
+                .bind(("path", path.clone()))
+                .bind(("dir", format!("{}/", path.trim_end_matches('/')))),
+            IndexScope::TitlePrefix(prefix) => DB
+                .query(
+                    "SELECT * FROM nsp_metadata
+                        WHERE string::starts_with(string::uppercase(title_id), $prefix)",
+                )
+                .bind(("prefix", prefix.to_uppercase())),
+        };
+
+        let metadata: Vec<Self> = query.await?.take(0)?;
+        // The query is a cheap superset, the scope has the final say
+        Ok(metadata
+            .into_iter()
+            .filter(|m| scope.matches(&m.path, &m.title_id))
+            .collect())
+    }
+
     #[tracing::instrument(level = "debug")]
     pub async fn get_by_path(path: &str) -> surrealdb::Result<Option<Self>> {
         DB.select(("nsp_metadata", path)).await
@@ -36,15 +98,15 @@ impl NspMetadata {
 
     #[tracing::instrument(level = "debug")]
     pub async fn save(&self) -> surrealdb::Result<Option<NspMetadata>> {
-        let created: Option<NspMetadata> = DB
-            .upsert(("nsp_metadata", &self.path))
-            .content(self.clone())
-            .await?;
-
-        // Invalidate the tinfoil index cache when metadata changes
-        crate::backend::api::invalidate_index_cache();
-        tracing::debug!("Invalidated tinfoil index cache after metadata update");
+        let created: Option<NspMetadata> = with_retry("nsp_metadata.save", || async {
+            DB.upsert(("nsp_metadata", &self.path))
+                .content(self.clone())
+                .await
+        })
+        .await?;
 
+        // Only this file's entry in the tinfoil index needs rebuilding
+        crate::backend::api::invalidate_index_scope(IndexScope::Path(self.path.clone()));
 
         Ok(created)
     }
@@ -52,11 +114,13 @@ impl NspMetadata {
     #[tracing::instrument(level = "debug")]
     pub async fn delete(&self) -> surrealdb::Result<()> {
         tracing::debug!("Deleting metadata for {}", self.path);
-        let _: Option<NspMetadata> = DB.delete(("nsp_metadata", &self.path)).await?;
+        let _: Option<NspMetadata> = with_retry("nsp_metadata.delete", || async {
+            DB.delete(("nsp_metadata", &self.path)).await
+        })
+        .await?;
 
-        // Invalidate the tinfoil index cache when metadata is deleted
-        crate::backend::api::invalidate_index_cache();
-        tracing::debug!("Invalidated tinfoil index cache after metadata deletion");
+        // Drop this file's entry from the tinfoil index
+        crate::backend::api::invalidate_index_scope(IndexScope::Path(self.path.clone()));
 
         Ok(())
     }
@@ -87,6 +151,58 @@ impl NspMetadata {
 }
 
 pub static DB: LazyLock<Surreal<Any>> = LazyLock::new(Surreal::init);
+
+/// Check if an error is an optimistic transaction conflict that SurrealDB says can be retried
+pub fn is_retryable_error(err: &impl Display) -> bool {
+    err.to_string().contains("This transaction can be retried")
+}
+
+/// Run a database operation, retrying it with exponential backoff while it fails with a
+/// retryable transaction conflict.
+///
+/// Concurrent writes to the same records (parallel scans, TitleDB imports, download progress)
+/// regularly conflict under optimistic transactions. Other errors are returned immediately,
+/// and so is the last conflict once `DB_RETRY_MAX_ATTEMPTS` is reached.
+pub async fn with_retry<T, E, F, Fut>(op_name: &str, mut op: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < DB_RETRY_MAX_ATTEMPTS && is_retryable_error(&e) => {
+                // Jitter keeps conflicting writers from retrying in lockstep
+                let backoff = DB_RETRY_BASE_DELAY_MS << (attempt - 1);
+                let jitter = rand::random_range(0..DB_RETRY_BASE_DELAY_MS);
+                let delay = Duration::from_millis(backoff + jitter);
+                tracing::debug!(
+                    "Transaction conflict in {} (attempt {}/{}), retrying in {:?}: {}",
+                    op_name,
+                    attempt,
+                    DB_RETRY_MAX_ATTEMPTS,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                if is_retryable_error(&e) {
+                    tracing::warn!(
+                        "Transaction conflict in {} persisted after {} attempts: {}",
+                        op_name,
+                        attempt,
+                        e
+                    );
+                }
+                return Err(e);
+            }
+            Ok(value) => return Ok(value),
+        }
+    }
+}
 #[tracing::instrument]
 pub async fn init_database() -> surrealdb::Result<()> {
     let config = crate::config::config();
@@ -153,7 +269,11 @@ pub async fn create_precomputed_metaview(locale: &str) -> surrealdb::Result<()>
     // Primary locale
     let start = std::time::Instant::now();
     tracing::info!("Creating metaview schema for locale {}", locale);
-    let metaview_schema_main = base_schema.replace("%LOCALE%", locale);
+    let base_title_id = TitleKind::sql_base_title_id("$parent.title_id");
+    let metaview_schema_main = base_schema
+        .replace("%LOCALE%", locale)
+        .replace("%BASE_TITLE_ID%", &base_title_id);
+    drop_outdated_metaview(locale, &base_title_id).await?;
     match DB.query(metaview_schema_main).await {
         Ok(response) => {
             tracing::debug!("Query response: {:?}", response);
@@ -168,3 +288,124 @@ pub async fn create_precomputed_metaview(locale: &str) -> surrealdb::Result<()>
 
     Ok(())
 }
+
+/// Drop a metaview defined before unidentified files were left out of it, or with other
+/// title ID suffixes than the ones configured now.
+///
+/// The schema only defines the view if it doesn't exist yet, so older databases would
+/// otherwise keep joining placeholder title IDs, or updates by their old suffix, forever.
+async fn drop_outdated_metaview(locale: &str, base_title_id: &str) -> surrealdb::Result<()> {
+    let table = format!("metaview_{locale}");
+    let tables: Option<std::collections::HashMap<String, String>> =
+        DB.query("INFO FOR DB").await?.take((0, "tables"))?;
+    let outdated = tables
+        .and_then(|tables| tables.get(&table).cloned())
+        .is_some_and(|definition| {
+            !definition.contains("unidentified") || !definition.contains(base_title_id)
+        });
+
+    if outdated {
+        tracing::info!("Redefining {} with the current schema", table);
+        DB.query(format!("REMOVE TABLE IF EXISTS {table}")).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const CONFLICT: &str = "Failed to commit transaction due to a read or write conflict. This transaction can be retried";
+
+    #[test]
+    fn test_is_placeholder_title_id() {
+        assert!(is_placeholder_title_id(UNIDENTIFIED_TITLE_ID));
+        assert!(is_placeholder_title_id("0000000000000000"));
+        assert!(is_placeholder_title_id("homebrew"));
+        assert!(is_placeholder_title_id("01000000000100ZZ"));
+        assert!(!is_placeholder_title_id("0100000000010000"));
+        assert!(!is_placeholder_title_id("0100abcdef010800"));
+    }
+
+    #[test]
+    fn test_is_retryable_error() {
+        assert!(is_retryable_error(&CONFLICT));
+        assert!(!is_retryable_error(&"Specify a namespace to use"));
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_recovers_from_conflicts() {
+        let calls = AtomicU32::new(0);
+        let result: Result<u32, String> = with_retry("test", || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(CONFLICT.to_string()),
+                n => Ok(n),
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_is_bounded() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), String> = with_retry("test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(CONFLICT.to_string())
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), DB_RETRY_MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_skips_other_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), String> = with_retry("test", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err("Specify a namespace to use".to_string())
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_parallel_metadata_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        DB.connect(format!("surrealkv://{}", dir.path().display()))
+            .await
+            .unwrap();
+        DB.use_ns("test").use_db("test").await.unwrap();
+
+        // Hammer a handful of records from many tasks at once to force conflicts
+        let handles: Vec<_> = (0..64)
+            .map(|i| {
+                tokio::spawn(async move {
+                    NspMetadata {
+                        path: format!("/roms/game_{}.nsp", i % 4),
+                        title_id: "0100000000010000".to_string(),
+                        version: format!("v{i}"),
+                        title_name: None,
+                        download_id: format!("download_{}", i % 4),
+                        unidentified: false,
+                        required_system_version: None,
+                    }
+                    .save()
+                    .await
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        assert_eq!(NspMetadata::get_all().await.unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,74 @@
+//! Shared outbound HTTP clients
+//!
+//! Every outbound request goes through one of two process-wide clients, so connections
+//! are pooled and kept alive across downloads instead of being set up again for every
+//! request. Proxy, user agent, timeouts and HTTP/2 are configured once here, and
+//! importers add their own headers per request.
+//!
+//! - [`client`] is for trusted hosts, such as TitleDB and configured extra indexes
+//! - [`guarded_client`] is for user-provided URLs. It connects through the
+//!   [`PolicyResolver`] and doesn't follow redirects itself, so every hop can be checked
+//!   against the host policy
+
+use std::{sync::LazyLock, time::Duration};
+
+use reqwest::{Client, ClientBuilder};
+
+use crate::import::host_policy::PolicyResolver;
+
+static CLIENT: LazyLock<Client> = LazyLock::new(|| build(builder()));
+
+static GUARDED_CLIENT: LazyLock<Client> = LazyLock::new(|| {
+    build(
+        builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(PolicyResolver::shared()),
+    )
+});
+
+/// Client for trusted hosts
+pub fn client() -> Client {
+    CLIENT.clone()
+}
+
+/// Client for user-provided URLs, which resolves hosts through the host policy and
+/// never follows redirects on its own
+pub fn guarded_client() -> Client {
+    GUARDED_CLIENT.clone()
+}
+
+/// Start a client builder with the configured connection settings
+fn builder() -> ClientBuilder {
+    let config = crate::config::config().backend_config;
+    let keepalive = Some(Duration::from_secs(config.http_keepalive_secs)).filter(|d| !d.is_zero());
+
+    let mut builder = Client::builder()
+        .user_agent(config.http_user_agent)
+        .connect_timeout(Duration::from_secs(config.http_connect_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(config.http_pool_idle_timeout_secs))
+        .tcp_keepalive(keepalive);
+
+    builder = if config.http2 {
+        builder
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(keepalive)
+            .http2_keep_alive_while_idle(true)
+    } else {
+        builder.http1_only()
+    };
+
+    if let Some(proxy) = config.http_proxy.filter(|proxy| !proxy.is_empty()) {
+        match reqwest::Proxy::all(&proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => tracing::error!("Invalid ALU_HTTP_PROXY '{}', ignoring it: {}", proxy, e),
+        }
+    }
+    builder
+}
+
+fn build(builder: ClientBuilder) -> Client {
+    builder.build().unwrap_or_else(|e| {
+        tracing::error!("Failed to build HTTP client, using defaults: {}", e);
+        Client::new()
+    })
+}
@@ -0,0 +1,224 @@
+//! Import from another alumulemu instance
+//!
+//! Fetches files straight from a remote's `/api/get_game/{download_id}` endpoint, so two
+//! instances can sync specific titles without going through a Tinfoil index.
+//! Title IDs are resolved into download IDs using the remote's
+//! `/api/title_meta/{title_id}/download_ids` endpoint.
+//!
+//! Remotes on the local network have to be put on the download allow list (or private
+//! downloads enabled), see [`super::host_policy`].
+
+use std::collections::HashMap;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use tracing::{info, warn};
+
+use crate::import::{ImportError, ImportSource, Importer, Result, downloader::Downloader};
+
+/// Request type for the alumulemu importer
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct AlumulemuImportRequest {
+    /// Base URL of the remote instance, e.g. `http://192.168.1.10:3000`
+    pub base_url: String,
+    /// Download IDs to import, as listed by the remote (`0100000000010000_v0.nsp`)
+    #[serde(default)]
+    pub download_ids: Vec<String>,
+    /// Title IDs to import, every file the remote has for each title gets imported
+    #[serde(default)]
+    pub title_ids: Vec<String>,
+    /// Credentials for remotes that require login
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl AlumulemuImportRequest {
+    fn base_url(&self) -> &str {
+        self.base_url.trim().trim_end_matches('/')
+    }
+
+    /// Headers sent with every request to the remote
+    fn headers(&self) -> Option<HashMap<String, String>> {
+        let username = self.username.as_deref()?;
+        let credentials = format!("{}:{}", username, self.password.as_deref().unwrap_or(""));
+        Some(HashMap::from([(
+            "Authorization".to_string(),
+            format!("Basic {}", BASE64.encode(credentials)),
+        )]))
+    }
+}
+
+/// Check that a download ID can't escape the `get_game` path on the remote
+fn is_valid_download_id(download_id: &str) -> bool {
+    !download_id.is_empty()
+        && !download_id.contains("..")
+        && !download_id.contains(['/', '\\', '?', '#'])
+}
+
+/// Build the download URL of a file on the remote
+fn download_url(base_url: &str, download_id: &str) -> String {
+    format!("{base_url}/api/get_game/{download_id}")
+}
+
+#[derive(Clone, Debug)]
+pub struct AlumulemuImporter;
+
+impl AlumulemuImporter {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Look up every download ID the remote has for a title
+    async fn get_download_ids(
+        &self,
+        request: &AlumulemuImportRequest,
+        title_id: &str,
+    ) -> Result<Vec<String>> {
+        let url = format!(
+            "{}/api/title_meta/{}/download_ids",
+            request.base_url(),
+            title_id.trim()
+        );
+        // Every redirect hop is checked against the host policy
+        let response = Downloader::new()
+            .get_with_redirects(&url, request.headers().as_ref())
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        Ok(response.error_for_status()?.json().await?)
+    }
+}
+
+impl Importer for AlumulemuImporter {
+    type ImportRequest = AlumulemuImportRequest;
+
+    async fn import(&self, request: Self::ImportRequest) -> Result<ImportSource> {
+        let mut download_ids = request.download_ids.clone();
+        for title_id in &request.title_ids {
+            let ids = self.get_download_ids(&request, title_id).await?;
+            if ids.is_empty() {
+                warn!(
+                    title_id,
+                    remote = request.base_url(),
+                    "Remote has no files for title"
+                );
+            }
+            download_ids.extend(ids);
+        }
+
+        if let Some(invalid) = download_ids.iter().find(|id| !is_valid_download_id(id)) {
+            return Err(ImportError::Other(color_eyre::eyre::eyre!(
+                "Invalid download ID: {}",
+                invalid
+            )));
+        }
+
+        download_ids.sort();
+        download_ids.dedup();
+        if download_ids.is_empty() {
+            return Err(ImportError::GameNotFound);
+        }
+
+        info!(
+            remote = request.base_url(),
+            files = download_ids.len(),
+            "Importing from remote alumulemu"
+        );
+
+        // Each file is a child of the import job, so the per-title outcome shows up there
+        Ok(ImportSource::RemoteHttpAutoList {
+            urls: download_ids
+                .iter()
+                .map(|id| download_url(request.base_url(), id))
+                .collect(),
+            headers: request.headers(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "alumulemu_importer"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Alumulemu Importer"
+    }
+
+    fn description(&self) -> &'static str {
+        "Imports games from another alumulemu instance"
+    }
+
+    fn request_schema(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["base_url"],
+            "properties": {
+                "base_url": {
+                    "type": "string",
+                    "description": "Base URL of the remote instance, e.g. http://192.168.1.10:3000",
+                    "format": "uri"
+                },
+                "download_ids": {
+                    "type": "array",
+                    "description": "Download IDs to import, as listed by the remote",
+                    "items": { "type": "string" }
+                },
+                "title_ids": {
+                    "type": "array",
+                    "description": "Title IDs to import, with every file the remote has for them",
+                    "items": { "type": "string" }
+                },
+                "username": { "type": "string" },
+                "password": { "type": "string", "format": "password" }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(username: Option<&str>) -> AlumulemuImportRequest {
+        AlumulemuImportRequest {
+            base_url: "http://remote:3000/ ".to_string(),
+            download_ids: vec![
+                "0100000000010000_v0.nsp".to_string(),
+                "0100000000010000_v0.nsp".to_string(),
+            ],
+            title_ids: Vec::new(),
+            username: username.map(str::to_string),
+            password: Some("hunter2".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alumulemu_import_urls() {
+        let source = AlumulemuImporter::new()
+            .import(request(Some("admin")))
+            .await
+            .unwrap();
+
+        match source {
+            ImportSource::RemoteHttpAutoList { urls, headers } => {
+                assert_eq!(
+                    urls,
+                    vec!["http://remote:3000/api/get_game/0100000000010000_v0.nsp"]
+                );
+                assert_eq!(
+                    headers.unwrap()["Authorization"],
+                    "Basic YWRtaW46aHVudGVyMg=="
+                );
+            }
+            _ => panic!("Expected RemoteHttpAutoList import source"),
+        }
+    }
+
+    #[test]
+    fn test_alumulemu_request_validation() {
+        assert!(request(None).headers().is_none());
+        assert!(is_valid_download_id("0100000000010000_v0.nsp"));
+        assert!(!is_valid_download_id("../config"));
+        assert!(!is_valid_download_id("a/b.nsp"));
+        assert!(!is_valid_download_id(""));
+    }
+}
@@ -0,0 +1,639 @@
+//! Archive extraction
+//!
+//! Archives are recognized by their magic bytes, falling back to their file name, since
+//! downloads often get a generic name from the server. Zip files go through async_zip,
+//! tarballs and single compressed files are streamed through async-compression's decoders,
+//! so even multi-GB dumps are never buffered in memory. Formats we can't read, such as rar
+//! and 7z, are still recognized as archives so importing them fails with a clear error
+//! instead of treating them as game files.
+
+use std::path::{Component, Path, PathBuf};
+
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+};
+use tracing::{debug, info, warn};
+
+use super::{ImportError, Result, extract_zip_to_directory};
+use crate::storage::{SpaceWatch, ensure_space};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Archive formats we recognize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarBz2,
+    TarXz,
+    /// A single gzip compressed file
+    Gz,
+    /// A single bzip2 compressed file
+    Bz2,
+    /// A single xz compressed file
+    Xz,
+    /// Recognized, but can't be extracted
+    Rar,
+    /// Recognized, but can't be extracted
+    SevenZip,
+}
+
+impl ArchiveFormat {
+    /// Detect the format from the file name
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        let format = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Self::TarGz
+        } else if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") || name.ends_with(".tbz") {
+            Self::TarBz2
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Self::TarXz
+        } else if name.ends_with(".tar") {
+            Self::Tar
+        } else if name.ends_with(".zip") {
+            Self::Zip
+        } else if name.ends_with(".gz") {
+            Self::Gz
+        } else if name.ends_with(".bz2") {
+            Self::Bz2
+        } else if name.ends_with(".xz") {
+            Self::Xz
+        } else if name.ends_with(".rar") {
+            Self::Rar
+        } else if name.ends_with(".7z") {
+            Self::SevenZip
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+
+    /// Detect the format from the first bytes of a file.
+    ///
+    /// Compressed files are reported as single files, whether they hold a tarball is only
+    /// known after decompressing them.
+    pub fn from_magic(header: &[u8]) -> Option<Self> {
+        let format = if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Self::Zip
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Self::Gz
+        } else if header.starts_with(b"BZh") {
+            Self::Bz2
+        } else if header.starts_with(b"\xfd7zXZ\x00") {
+            Self::Xz
+        } else if header.starts_with(b"7z\xbc\xaf\x27\x1c") {
+            Self::SevenZip
+        } else if header.starts_with(b"Rar!\x1a\x07") {
+            Self::Rar
+        } else if is_tar_header(header) {
+            Self::Tar
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+
+    /// The tarball variant of a compressed file format
+    fn as_tar(self) -> Self {
+        match self {
+            Self::Gz => Self::TarGz,
+            Self::Bz2 => Self::TarBz2,
+            Self::Xz => Self::TarXz,
+            format => format,
+        }
+    }
+}
+
+/// Whether the block starts with a ustar or GNU tar header
+fn is_tar_header(header: &[u8]) -> bool {
+    header.get(257..262) == Some(b"ustar")
+}
+
+/// Detect the archive format of a file, `None` if it doesn't look like an archive.
+///
+/// The first block of the file is checked for known magic bytes, the file name is only
+/// used when they don't match anything. Compressed files without a tarball name have
+/// their first block decompressed to tell tarballs apart.
+pub async fn detect(path: &Path) -> Option<ArchiveFormat> {
+    let by_name = ArchiveFormat::from_path(path);
+    let by_magic = match read_header(File::open(path).await, path).await {
+        Some(header) => ArchiveFormat::from_magic(&header),
+        None => None,
+    };
+
+    let format = match by_magic {
+        Some(format @ (ArchiveFormat::Gz | ArchiveFormat::Bz2 | ArchiveFormat::Xz)) => {
+            if by_name == Some(format.as_tar()) {
+                format.as_tar()
+            } else {
+                match open_decoded(path, format).await {
+                    Ok(reader) => match read_header(Ok(reader), path).await {
+                        Some(header) if is_tar_header(&header) => format.as_tar(),
+                        _ => format,
+                    },
+                    Err(_) => format,
+                }
+            }
+        }
+        Some(format) => format,
+        None => by_name?,
+    };
+
+    if by_name != Some(format) {
+        debug!(path = ?path, ?format, ?by_name, "Archive format differs from the file name");
+    }
+    Some(format)
+}
+
+/// Read the first block of a stream, `None` if it can't be read
+async fn read_header<R: AsyncRead + Unpin>(
+    reader: std::io::Result<R>,
+    path: &Path,
+) -> Option<Vec<u8>> {
+    let mut header = Vec::with_capacity(BLOCK_SIZE as usize);
+    let result = match reader {
+        Ok(reader) => reader.take(BLOCK_SIZE).read_to_end(&mut header).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => Some(header),
+        Err(e) => {
+            debug!(path = ?path, error = %e, "Failed to read file header");
+            None
+        }
+    }
+}
+
+/// Open a tarball or compressed file, decompressing it on the fly
+async fn open_decoded(
+    path: &Path,
+    format: ArchiveFormat,
+) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let file = BufReader::new(File::open(path).await?);
+    let reader: Box<dyn AsyncRead + Unpin + Send> = match format {
+        ArchiveFormat::TarGz | ArchiveFormat::Gz => {
+            let mut decoder = GzipDecoder::new(file);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        ArchiveFormat::TarBz2 | ArchiveFormat::Bz2 => Box::new(BzDecoder::new(file)),
+        ArchiveFormat::TarXz | ArchiveFormat::Xz => Box::new(XzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    Ok(reader)
+}
+
+/// Extract an archive into a directory, detecting its format if it isn't known yet.
+/// Returns a list of paths to the extracted files.
+pub async fn extract_to_directory(
+    path: &Path,
+    format: Option<ArchiveFormat>,
+    destination: &Path,
+) -> Result<Vec<PathBuf>> {
+    let format = match format {
+        Some(format) => format,
+        None => detect(path)
+            .await
+            .ok_or_else(|| ImportError::UnsupportedArchive(path.to_path_buf()))?,
+    };
+    info!(archive = ?path, destination = ?destination, ?format, "Extracting archive");
+
+    match format {
+        ArchiveFormat::Zip => extract_zip_to_directory(path, destination).await,
+        ArchiveFormat::Tar
+        | ArchiveFormat::TarGz
+        | ArchiveFormat::TarBz2
+        | ArchiveFormat::TarXz => extract_tar(open_decoded(path, format).await?, destination).await,
+        ArchiveFormat::Gz | ArchiveFormat::Bz2 | ArchiveFormat::Xz => {
+            decompress_file(open_decoded(path, format).await?, path, destination).await
+        }
+        ArchiveFormat::Rar | ArchiveFormat::SevenZip => {
+            Err(ImportError::UnsupportedArchive(path.to_path_buf()))
+        }
+    }
+}
+
+/// Decompress a single compressed file, named after the archive without its extension
+async fn decompress_file<R: AsyncRead + Unpin>(
+    mut reader: R,
+    archive_path: &Path,
+    destination: &Path,
+) -> Result<Vec<PathBuf>> {
+    let name = archive_path
+        .file_stem()
+        .ok_or_else(|| ImportError::UnsupportedArchive(archive_path.to_path_buf()))?;
+    tokio::fs::create_dir_all(destination).await?;
+    let path = destination.join(name);
+
+    // The decompressed size isn't known up front, so check for space while writing
+    let mut output_file = File::create(&path).await?;
+    let mut space = SpaceWatch::new(&path);
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut bytes = 0u64;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        if let Err(e) = space.wrote(read as u64).await {
+            drop(output_file);
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e.into());
+        }
+        output_file.write_all(&buffer[..read]).await?;
+        bytes += read as u64;
+    }
+    output_file.flush().await?;
+    debug!(bytes, path = ?path, "File decompressed successfully");
+
+    Ok(vec![path])
+}
+
+/// Extract a tar stream, reading it front to back.
+///
+/// Supports ustar and GNU headers, GNU long names and the path and size records of pax
+/// headers. Links and other special entries are skipped.
+async fn extract_tar<R: AsyncRead + Unpin>(
+    mut reader: R,
+    destination: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut extracted_files = Vec::new();
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    // Overrides for the next entry, set by GNU long name and pax headers
+    let mut next_name: Option<String> = None;
+    let mut next_size: Option<u64> = None;
+
+    loop {
+        if !read_block(&mut reader, &mut header).await? {
+            break;
+        }
+        // The archive ends with zeroed blocks
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !checksum_matches(&header) {
+            return Err(invalid_tar("header checksum mismatch, not a tar archive?").into());
+        }
+
+        let size = match next_size.take() {
+            Some(size) => size,
+            None => parse_number(&header[124..136])?,
+        };
+        let entry_type = header[156];
+
+        match entry_type {
+            // GNU long name of the next entry
+            b'L' => {
+                let data = read_data(&mut reader, size).await?;
+                next_name = Some(String::from_utf8_lossy(trim_nul(&data)).into_owned());
+                continue;
+            }
+            // pax extended header of the next entry
+            b'x' => {
+                let data = read_data(&mut reader, size).await?;
+                for (key, value) in parse_pax(&data) {
+                    match key {
+                        "path" => next_name = Some(value.to_string()),
+                        "size" => {
+                            next_size = Some(
+                                value
+                                    .parse()
+                                    .map_err(|_| invalid_tar("invalid size in pax header"))?,
+                            )
+                        }
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = next_name.take().unwrap_or_else(|| header_name(&header));
+        let Some(relative) = sanitize_path(&name) else {
+            warn!(path = name, "Skipping tar entry with an unsafe path");
+            skip(&mut reader, padded(size)).await?;
+            continue;
+        };
+        let path = destination.join(&relative);
+
+        match entry_type {
+            b'5' => {
+                tokio::fs::create_dir_all(&path).await?;
+            }
+            // Regular files, old tar regular files and contiguous files
+            b'0' | 0 | b'7' => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                ensure_space(&path, size).await?;
+                let mut output_file = File::create(&path).await?;
+                let copied =
+                    tokio::io::copy(&mut (&mut reader).take(size), &mut output_file).await?;
+                if copied != size {
+                    return Err(invalid_tar("archive ends in the middle of a file").into());
+                }
+                skip(&mut reader, padded(size) - size).await?;
+
+                info!(path = name, bytes = size, "Extracted file");
+                extracted_files.push(path);
+            }
+            _ => {
+                debug!(path = name, entry_type, "Skipping unsupported tar entry");
+                skip(&mut reader, padded(size)).await?;
+            }
+        }
+    }
+
+    Ok(extracted_files)
+}
+
+fn invalid_tar(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Size of an entry's data including the padding to the next block
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+/// Read a whole block, `false` if the stream ended before it
+async fn read_block<R: AsyncRead + Unpin>(reader: &mut R, block: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        let read = reader.read(&mut block[filled..]).await?;
+        if read == 0 {
+            if filled == 0 {
+                return Ok(false);
+            }
+            return Err(invalid_tar("archive ends in the middle of a header").into());
+        }
+        filled += read;
+    }
+    Ok(true)
+}
+
+/// Read the data of a metadata entry, such as a long name
+async fn read_data<R: AsyncRead + Unpin>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    // Metadata entries are small, anything bigger is a broken archive
+    if size > 1024 * 1024 {
+        return Err(invalid_tar("metadata entry is too large").into());
+    }
+    let mut data = vec![0u8; size as usize];
+    reader.read_exact(&mut data).await?;
+    skip(reader, padded(size) - size).await?;
+    Ok(data)
+}
+
+async fn skip<R: AsyncRead + Unpin>(reader: &mut R, len: u64) -> Result<()> {
+    let skipped = tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+    if skipped != len {
+        return Err(invalid_tar("archive ends in the middle of an entry").into());
+    }
+    Ok(())
+}
+
+fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// Checksum of the header, summed with the checksum field itself as spaces
+fn checksum_matches(header: &[u8; BLOCK_SIZE as usize]) -> bool {
+    let Ok(expected) = parse_number(&header[148..156]) else {
+        return false;
+    };
+    let actual: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+    expected == actual
+}
+
+/// Parse a numeric header field, octal text or GNU base-256 for sizes of 8 GiB and up
+fn parse_number(field: &[u8]) -> std::io::Result<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let value = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |acc, &b| {
+                (acc << 8) | u64::from(b)
+            });
+        return Ok(value);
+    }
+
+    let text = std::str::from_utf8(trim_nul(field))
+        .map_err(|_| invalid_tar("invalid number in header"))?
+        .trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid_tar("invalid number in header"))
+}
+
+/// Path of the entry, joining the ustar prefix and name fields
+fn header_name(header: &[u8; BLOCK_SIZE as usize]) -> String {
+    let name = String::from_utf8_lossy(trim_nul(&header[..100]));
+    let prefix = if &header[257..262] == b"ustar" {
+        String::from_utf8_lossy(trim_nul(&header[345..500])).into_owned()
+    } else {
+        String::new()
+    };
+    if prefix.is_empty() {
+        name.into_owned()
+    } else {
+        format!("{prefix}/{name}")
+    }
+}
+
+/// Parse the `<length> <key>=<value>\n` records of a pax header
+fn parse_pax(data: &[u8]) -> Vec<(&str, &str)> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter_map(|record| {
+            let (_, pair) = record.split_once(' ')?;
+            pair.split_once('=')
+        })
+        .collect()
+}
+
+/// Make an entry's path relative to the destination, `None` if it would escape it
+fn sanitize_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a ustar header for a tar written by the tests
+    fn tar_header(name: &str, size: u64, entry_type: u8) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        header[156] = entry_type;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+            .sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        header
+    }
+
+    fn tar_entry(tar: &mut Vec<u8>, name: &str, data: &[u8], entry_type: u8) {
+        tar.extend_from_slice(&tar_header(name, data.len() as u64, entry_type));
+        tar.extend_from_slice(data);
+        tar.resize(padded(tar.len() as u64) as usize, 0);
+    }
+
+    fn test_tar() -> Vec<u8> {
+        let long_name = format!("{}/game.nsp", "a".repeat(120));
+        let mut tar = Vec::new();
+        tar_entry(&mut tar, "dir/", b"", b'5');
+        tar_entry(&mut tar, "dir/update.nsp", b"update", b'0');
+        tar_entry(&mut tar, "././@LongLink", long_name.as_bytes(), b'L');
+        tar_entry(&mut tar, "truncated", b"game", b'0');
+        tar_entry(&mut tar, "../escape.nsp", b"nope", b'0');
+        tar.extend_from_slice(&[0u8; 1024]);
+        tar
+    }
+
+    #[test]
+    fn test_archive_format() {
+        let format = |name: &str| ArchiveFormat::from_path(Path::new(name));
+        assert_eq!(format("Game.ZIP"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("game.tar"), Some(ArchiveFormat::Tar));
+        assert_eq!(format("game.tar.gz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("game.tgz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("game.tar.xz"), Some(ArchiveFormat::TarXz));
+        assert_eq!(format("game.nsp.gz"), Some(ArchiveFormat::Gz));
+        assert_eq!(format("game.nsp"), None);
+        assert_eq!(format("game.rar"), Some(ArchiveFormat::Rar));
+    }
+
+    #[test]
+    fn test_archive_magic() {
+        let magic = ArchiveFormat::from_magic;
+        assert_eq!(magic(b"PK\x03\x04\x14\x00"), Some(ArchiveFormat::Zip));
+        assert_eq!(magic(&[0x1f, 0x8b, 0x08]), Some(ArchiveFormat::Gz));
+        assert_eq!(
+            magic(b"7z\xbc\xaf\x27\x1c\x00\x04"),
+            Some(ArchiveFormat::SevenZip)
+        );
+        assert_eq!(magic(&test_tar()), Some(ArchiveFormat::Tar));
+        assert_eq!(magic(b"PFS0\x04\x00\x00\x00"), None);
+        assert_eq!(magic(b""), None);
+    }
+
+    #[tokio::test]
+    async fn test_detect() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A zip with a generic name is detected from its contents
+        let path = dir.path().join("download.bin");
+        std::fs::write(&path, b"PK\x03\x04rest of the zip").unwrap();
+        assert_eq!(detect(&path).await, Some(ArchiveFormat::Zip));
+
+        // A gzipped tarball without a tarball name is told apart after decompressing
+        let path = dir.path().join("download.gz");
+        write_gzip(&path, &test_tar());
+        assert_eq!(detect(&path).await, Some(ArchiveFormat::TarGz));
+
+        let path = dir.path().join("game.nsp.gz");
+        write_gzip(&path, b"PFS0");
+        assert_eq!(detect(&path).await, Some(ArchiveFormat::Gz));
+
+        // Unrecognized contents fall back to the file name
+        let path = dir.path().join("game.nsp");
+        std::fs::write(&path, b"PFS0").unwrap();
+        assert_eq!(detect(&path).await, None);
+        let path = dir.path().join("game.rar");
+        std::fs::write(&path, b"not really").unwrap();
+        assert_eq!(detect(&path).await, Some(ArchiveFormat::Rar));
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number(b"00000001750\0").unwrap(), 1000);
+        assert_eq!(parse_number(b"\0\0\0\0").unwrap(), 0);
+        // 16 GiB in GNU base-256
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[7] = 0x04;
+        assert_eq!(parse_number(&field).unwrap(), 16 * 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(sanitize_path("./a/b.nsp"), Some(PathBuf::from("a/b.nsp")));
+        assert_eq!(sanitize_path("../b.nsp"), None);
+        assert_eq!(sanitize_path("/etc/passwd"), None);
+        assert_eq!(sanitize_path("./"), None);
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = extract_tar(test_tar().as_slice(), dir.path())
+            .await
+            .unwrap();
+
+        let long_path = dir.path().join("a".repeat(120)).join("game.nsp");
+        assert_eq!(
+            files,
+            vec![dir.path().join("dir/update.nsp"), long_path.clone()]
+        );
+        assert_eq!(std::fs::read(&long_path).unwrap(), b"game");
+        assert!(!dir.path().join("truncated").exists());
+        assert!(!dir.path().parent().unwrap().join("escape.nsp").exists());
+    }
+
+    fn write_gzip(path: &Path, data: &[u8]) {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(path).unwrap(),
+            flate2::Compression::fast(),
+        );
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_extract_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("games.tar.gz");
+        write_gzip(&archive, &test_tar());
+
+        let out = dir.path().join("out");
+        let files = extract_to_directory(&archive, None, &out).await.unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            std::fs::read(out.join("dir/update.nsp")).unwrap(),
+            b"update"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_not_a_tar() {
+        let result = extract_tar(&[1u8; 1024][..], Path::new("/nonexistent")).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Removal of superseded updates
+//!
+//! Importing update v2 of a game that already has v1 on disk leaves both, and Tinfoil lists
+//! two updates. With the clean policy enabled (per import, or through
+//! [`super::job::ImportJobConfig`]), older versions of every update that was imported are
+//! deleted afterwards. Base games and DLC are never touched, and nothing is deleted unless
+//! the new update's CNMT could be read from its final location.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    db::NspMetadata, nsp::read_cnmt_limited, title_kind::TitleKind, titledb::Metaview,
+    util::parse_download_id,
+};
+
+/// Check if a title ID is an update
+fn is_update_title_id(title_id: &str) -> bool {
+    title_id.len() == 16 && TitleKind::is_update(title_id)
+}
+
+/// Pick the download IDs of older versions of an update
+fn superseded_download_ids(update_id: &str, version: u32, download_ids: &[String]) -> Vec<String> {
+    download_ids
+        .iter()
+        .filter(|download_id| {
+            parse_download_id(download_id).is_some_and(|(title_id, old_version)| {
+                title_id.eq_ignore_ascii_case(update_id) && old_version < version
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Delete older versions of the updates among the imported files.
+///
+/// Returns the paths that were deleted.
+pub async fn remove_superseded_updates(imported: &[PathBuf]) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+    for path in imported {
+        let Some(path_str) = path.to_str() else {
+            continue;
+        };
+        // Reading the CNMT back from the published file also confirms it's usable
+        let cnmt = match read_cnmt_limited(path_str).await {
+            Ok(cnmt) => cnmt,
+            Err(e) => {
+                tracing::warn!(path = ?path, "Not cleaning older updates, failed to read CNMT: {}", e);
+                continue;
+            }
+        };
+        let title_id = cnmt.get_title_id_string();
+        if !is_update_title_id(&title_id) {
+            continue;
+        }
+
+        let download_ids = match Metaview::get_download_ids(&title_id).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                tracing::warn!(title_id, "Failed to list files of title: {}", e);
+                continue;
+            }
+        };
+
+        for download_id in
+            superseded_download_ids(&title_id, cnmt.header.title_version, &download_ids)
+        {
+            match remove_download(&download_id, path).await {
+                Ok(Some(old)) => removed.push(old),
+                Ok(None) => {}
+                Err(e) => tracing::warn!(download_id, "Failed to remove superseded update: {}", e),
+            }
+        }
+    }
+    removed
+}
+
+/// Delete the file of a download ID and its metadata, unless it's the file that was imported
+async fn remove_download(
+    download_id: &str,
+    imported: &Path,
+) -> color_eyre::Result<Option<PathBuf>> {
+    let Some(metadata) = NspMetadata::get_from_download_id(download_id).await? else {
+        return Ok(None);
+    };
+    let old = PathBuf::from(&metadata.path);
+    if old == imported {
+        return Ok(None);
+    }
+
+    match tokio::fs::remove_file(&old).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    metadata.delete().await?;
+    tracing::info!(path = ?old, download_id, "Removed superseded update");
+    Ok(Some(old))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superseded_download_ids() {
+        let ids: Vec<String> = [
+            "0100000000010000_v0.nsp",
+            "0100000000010800_v65536.nsp",
+            "0100000000010800_v131072.xci",
+            "0100000000010800_v196608.nsp",
+            "0100000000011001_v0.nsp",
+        ]
+        .map(str::to_string)
+        .to_vec();
+
+        assert!(is_update_title_id("0100000000010800"));
+        assert!(!is_update_title_id("0100000000010000"));
+        assert!(!is_update_title_id("0100000000011001"));
+
+        // Compared as numbers, as strings 65536 would sort after 131072
+        assert_eq!(
+            superseded_download_ids("0100000000010800", 131072, &ids),
+            vec!["0100000000010800_v65536.nsp"]
+        );
+        assert_eq!(
+            superseded_download_ids("0100000000010800", 196608, &ids),
+            vec![
+                "0100000000010800_v65536.nsp",
+                "0100000000010800_v131072.xci"
+            ]
+        );
+        assert!(superseded_download_ids("0100000000010800", 65536, &ids).is_empty());
+    }
+}
@@ -71,7 +71,7 @@ pub struct DbiDirectory {
 
 impl DbiImporter {
     pub fn new(base_url: String, device: NxDevice) -> Self {
-        let client = reqwest::Client::new();
+        let client = crate::http_client::client();
 
         Self {
             client,
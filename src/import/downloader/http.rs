@@ -5,23 +5,34 @@
 
 use futures_util::StreamExt;
 use reqwest::{
-    Client, Response, Url,
-    header::{self, HeaderMap, HeaderValue},
+    Client, Response, StatusCode, Url,
+    header::{self, HeaderValue},
 };
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
     io,
     path::{Path, PathBuf},
 };
-use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
 use tokio_util::sync::CancellationToken;
-use tracing::{Level, debug, error, info, instrument, span, trace};
+use tracing::{Level, debug, error, info, instrument, span, trace, warn};
 
-use super::models::{DownloadStatus, PartialDownloadError, Progress, parse_content_disposition};
+use super::models::{
+    ChecksumMismatchError, DownloadStatus, PartialDownloadError, Progress,
+    parse_content_disposition, sha256_matches,
+};
+use crate::import::host_policy::check_url;
 
 pub struct Downloader {
-    client: Client,
+    pub(super) client: Client,
     max_redirects: usize,
+    expected_sha256: Option<String>,
+    pub(super) segments: usize,
 }
 
 impl Default for Downloader {
@@ -32,20 +43,12 @@ impl Default for Downloader {
 
 impl Downloader {
     pub fn new() -> Self {
-        // Setup headers exactly like curl
-        let mut headers = HeaderMap::new();
-        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
-
-        // Create a client that doesn't follow redirects automatically
-        let client = Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .default_headers(headers)
-            .build()
-            .unwrap();
-
+        // Doesn't follow redirects automatically, they're checked against the host policy here
         Self {
-            client,
+            client: crate::http_client::guarded_client(),
             max_redirects: 10,
+            expected_sha256: None,
+            segments: 1,
         }
     }
 
@@ -54,6 +57,20 @@ impl Downloader {
         self
     }
 
+    /// Hash cancellable downloads as they're written and fail them if the file doesn't
+    /// have this SHA-256
+    pub fn with_expected_sha256(mut self, expected_sha256: Option<String>) -> Self {
+        self.expected_sha256 = expected_sha256;
+        self
+    }
+
+    /// Split cancellable downloads into this many ranged requests running at once, if the
+    /// server supports range requests. 1 downloads over a single connection
+    pub fn with_segments(mut self, segments: usize) -> Self {
+        self.segments = segments.max(1);
+        self
+    }
+
     pub async fn download_file<P: AsRef<Path>>(
         &self,
         url: &str,
@@ -74,10 +91,13 @@ impl Downloader {
         let mut redirect_count = 0;
 
         loop {
+            // Every hop has to pass the host policy, not just the URL we started with
+            check_url(&current_url).await?;
+
             // Build request with custom headers if provided
             let mut request_builder = self.client.get(&current_url);
             if let Some(custom_headers) = headers {
-                for (key, value) in custom_headers {
+                for (key, value) in &headers_for_hop(custom_headers, url, &current_url) {
                     match HeaderValue::from_str(value) {
                         Ok(header_val) => {
                             request_builder = request_builder.header(key, header_val);
@@ -203,6 +223,7 @@ impl Downloader {
                 downloaded: 0,
                 status: DownloadStatus::Downloading,
                 file_path: Some(final_path.clone()),
+                sha256: None,
             })
             .await;
 
@@ -222,6 +243,7 @@ impl Downloader {
                             downloaded,
                             status: DownloadStatus::Downloading,
                             file_path: Some(final_path.clone()),
+                            sha256: None,
                         })
                         .await;
                 }
@@ -236,6 +258,7 @@ impl Downloader {
                 downloaded,
                 status: DownloadStatus::Completed,
                 file_path: Some(final_path.clone()),
+                sha256: None,
             })
             .await;
 
@@ -251,13 +274,35 @@ impl Downloader {
         progress_tx: mpsc::Sender<Progress>,
         cancel_token: CancellationToken,
         headers: Option<&HashMap<String, String>>,
+        resume_from: u64,
     ) -> io::Result<PathBuf> {
         trace!("Starting download with progress tracking");
 
         const MAX_RETRIES: usize = 3;
         let mut retry_count = 0;
         let mut last_error: Option<io::Error> = None;
-        let mut downloaded_so_far: u64 = 0;
+        let mut downloaded_so_far: u64 = resume_from;
+
+        // Resumed and verified downloads are written in order, over a single connection
+        if self.segments > 1 && resume_from == 0 && self.expected_sha256.is_none() {
+            match self
+                .download_segmented(
+                    url,
+                    output_path.as_ref(),
+                    &progress_tx,
+                    &cancel_token,
+                    headers,
+                )
+                .await
+            {
+                Ok(Some(path)) => return Ok(path),
+                Ok(None) => debug!("Downloading over a single connection"),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => {
+                    warn!(error = %e, "Segmented download failed, retrying over a single connection");
+                }
+            }
+        }
 
         // Keep trying until we succeed or exceed max retries
         while retry_count <= MAX_RETRIES {
@@ -375,6 +420,8 @@ impl Downloader {
         resume_from: u64,
         headers: Option<&HashMap<String, String>>,
     ) -> io::Result<PathBuf> {
+        check_url(url).await?;
+
         // Build the request with range header if resuming and custom headers
         let mut request_builder = self.client.get(url);
         if resume_from > 0 {
@@ -463,6 +510,17 @@ impl Downloader {
             ));
         }
 
+        // A server that ignores the range sends the whole file again
+        let resume_from = if resume_from > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            info!(
+                resume_from,
+                "Server can't resume the download, starting over"
+            );
+            0
+        } else {
+            resume_from
+        };
+
         // Check if already canceled
         if cancel_token.is_cancelled() {
             info!("Download cancelled before starting");
@@ -472,63 +530,7 @@ impl Downloader {
             ));
         }
 
-        // Check if output_path is a directory
-        let final_path = if output_path.is_dir() {
-            // Try to extract filename from Content-Disposition header
-            let filename = if let Some(content_disposition) =
-                response.headers().get(header::CONTENT_DISPOSITION)
-            {
-                trace!(content_disposition = ?content_disposition, "Content-Disposition header found");
-
-                let content_disposition_str = content_disposition.to_str().map_err(|e| {
-                    error!(error = %e, "Failed to convert Content-Disposition to string");
-                    io::Error::new(io::ErrorKind::InvalidData, e)
-                })?;
-
-                // Parse Content-Disposition for filename
-                let parsed_filename = parse_content_disposition(content_disposition_str);
-                if let Some(ref name) = parsed_filename {
-                    debug!(filename = %name, "Extracted filename from Content-Disposition");
-                }
-                parsed_filename
-            } else {
-                trace!("No Content-Disposition header found");
-                None
-            };
-
-            // If we couldn't get filename from Content-Disposition, try to get it from the URL
-            let filename = filename
-                .or_else(|| {
-                    trace!("Attempting to extract filename from URL");
-                    let binding = Url::parse(url).ok()?;
-                    let url_path = binding.path();
-                    let path = Path::new(url_path);
-                    let filename = path.file_name()?.to_str().map(|s| s.to_string());
-
-                    if let Some(ref name) = filename {
-                        debug!(filename = %name, "Extracted filename from URL path");
-                    }
-
-                    filename
-                })
-                .unwrap_or_else(|| {
-                    // If all else fails, use a generic filename with timestamp
-                    let now = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    let generic_name = format!("download_{}.bin", now);
-                    debug!(filename = %generic_name, "Using generic filename");
-                    generic_name
-                });
-
-            let final_path = output_path.join(&filename);
-            debug!(path = ?final_path, "Final download path");
-            final_path
-        } else {
-            debug!(path = ?output_path, "Using specified file path");
-            output_path.to_path_buf()
-        };
+        let final_path = resolve_final_path(&response, url, output_path)?;
 
         // Get content length if available
         let total_size = match (
@@ -574,6 +576,15 @@ impl Downloader {
             info!(path = ?final_path, "Starting download of unknown size");
         }
 
+        // Fail before writing anything if the file can't fit, files of unknown size are
+        // checked while they're written
+        if let Some(size) = total_size {
+            crate::storage::ensure_space(&final_path, size.saturating_sub(resume_from)).await?;
+        }
+        let mut space = total_size
+            .is_none()
+            .then(|| crate::storage::SpaceWatch::new(&final_path));
+
         // Create or open output file
         let file = if resume_from > 0 && final_path.exists() {
             let mut options = tokio::fs::OpenOptions::new();
@@ -587,6 +598,13 @@ impl Downloader {
         let mut file = file;
         let mut downloaded = resume_from;
 
+        // Only hash downloads that are verified, the part already on disk is hashed first
+        let expected_sha256 = self.expected_sha256.as_deref();
+        let mut hasher = match expected_sha256 {
+            Some(_) => Some(hash_prefix(&final_path, resume_from).await?),
+            None => None,
+        };
+
         // Stream the response to file
         let mut stream = response.bytes_stream();
 
@@ -598,6 +616,7 @@ impl Downloader {
                 downloaded,
                 status: DownloadStatus::Downloading,
                 file_path: Some(final_path.clone()),
+                sha256: None,
             })
             .await;
 
@@ -624,7 +643,18 @@ impl Downloader {
             match chunk {
                 Ok(chunk) => {
                     let chunk_size = chunk.len() as u64;
+                    if let Some(space) = space.as_mut() {
+                        if let Err(e) = space.wrote(chunk_size).await {
+                            error!(error = %e, "Ran out of disk space, stopping download");
+                            let _ = file.shutdown().await;
+                            let _ = tokio::fs::remove_file(&final_path).await;
+                            return Err(e.into());
+                        }
+                    }
                     file.write_all(&chunk).await?;
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&chunk);
+                    }
 
                     // Update download progress
                     downloaded += chunk_size;
@@ -643,6 +673,7 @@ impl Downloader {
                             downloaded,
                             status: DownloadStatus::Downloading,
                             file_path: Some(final_path.clone()),
+                            sha256: None,
                         })
                         .await;
                 }
@@ -667,6 +698,22 @@ impl Downloader {
 
         info!(bytes = downloaded, "Download completed");
 
+        let sha256 = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+        if let (Some(expected), Some(actual)) = (expected_sha256, &sha256) {
+            if !sha256_matches(expected, actual) {
+                error!(expected, actual, "Download doesn't match its checksum");
+                let _ = tokio::fs::remove_file(&final_path).await;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    ChecksumMismatchError {
+                        expected: expected.to_string(),
+                        actual: actual.clone(),
+                    },
+                ));
+            }
+            debug!(sha256 = actual, "Download matches its checksum");
+        }
+
         // Send final progress update
         let _ = progress_tx
             .send(Progress {
@@ -674,9 +721,182 @@ impl Downloader {
                 downloaded,
                 status: DownloadStatus::Completed,
                 file_path: Some(final_path.clone()),
+                sha256,
             })
             .await;
 
         Ok(final_path)
     }
 }
+
+/// Headers that carry credentials, only sent to the origin they were given for
+const CREDENTIAL_HEADERS: [header::HeaderName; 3] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+];
+
+/// Custom headers to send to `url`, reached by following redirects from `origin`. Like
+/// reqwest's own redirect policy, credentials are dropped once the scheme, host or port
+/// changed, so a redirect to another host never gets them.
+pub(super) fn headers_for_hop(
+    headers: &HashMap<String, String>,
+    origin: &str,
+    url: &str,
+) -> HashMap<String, String> {
+    let same_origin = match (Url::parse(origin), Url::parse(url)) {
+        (Ok(origin), Ok(url)) => origin.origin() == url.origin(),
+        _ => false,
+    };
+    headers
+        .iter()
+        .filter(|(key, _)| {
+            same_origin
+                || !CREDENTIAL_HEADERS
+                    .iter()
+                    .any(|name| key.eq_ignore_ascii_case(name.as_str()))
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Where a response is saved: a file in `output_path` named after the response or its URL
+/// if it's a directory, `output_path` itself otherwise
+pub(super) fn resolve_final_path(
+    response: &Response,
+    url: &str,
+    output_path: &Path,
+) -> io::Result<PathBuf> {
+    // Check if output_path is a directory
+    if output_path.is_dir() {
+        // Try to extract filename from Content-Disposition header
+        let filename = if let Some(content_disposition) =
+            response.headers().get(header::CONTENT_DISPOSITION)
+        {
+            trace!(content_disposition = ?content_disposition, "Content-Disposition header found");
+
+            let content_disposition_str = content_disposition.to_str().map_err(|e| {
+                error!(error = %e, "Failed to convert Content-Disposition to string");
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            })?;
+
+            // Parse Content-Disposition for filename
+            let parsed_filename = parse_content_disposition(content_disposition_str);
+            if let Some(ref name) = parsed_filename {
+                debug!(filename = %name, "Extracted filename from Content-Disposition");
+            }
+            parsed_filename
+        } else {
+            trace!("No Content-Disposition header found");
+            None
+        };
+
+        // If we couldn't get filename from Content-Disposition, try to get it from the URL
+        let filename = filename
+            .or_else(|| {
+                trace!("Attempting to extract filename from URL");
+                let binding = Url::parse(url).ok()?;
+                let url_path = binding.path();
+                let path = Path::new(url_path);
+                let filename = path.file_name()?.to_str().map(|s| s.to_string());
+
+                if let Some(ref name) = filename {
+                    debug!(filename = %name, "Extracted filename from URL path");
+                }
+
+                filename
+            })
+            .unwrap_or_else(|| {
+                // If all else fails, use a generic filename with timestamp
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let generic_name = format!("download_{}.bin", now);
+                debug!(filename = %generic_name, "Using generic filename");
+                generic_name
+            });
+
+        let final_path = output_path.join(&filename);
+        debug!(path = ?final_path, "Final download path");
+        Ok(final_path)
+    } else {
+        debug!(path = ?output_path, "Using specified file path");
+        Ok(output_path.to_path_buf())
+    }
+}
+
+/// Hash the first `len` bytes of a partially downloaded file, so a resumed download can
+/// keep hashing where it left off
+async fn hash_prefix(path: &Path, len: u64) -> io::Result<Sha256> {
+    let mut hasher = Sha256::new();
+    if len == 0 {
+        return Ok(hasher);
+    }
+
+    let mut file = File::open(path).await?.take(len);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_for_hop() {
+        let headers = HashMap::from([
+            (
+                "Authorization".to_string(),
+                "Basic dXNlcjpwYXNz".to_string(),
+            ),
+            ("cookie".to_string(), "session=1".to_string()),
+            ("Accept".to_string(), "application/json".to_string()),
+        ]);
+        let origin = "https://games.example.com/api/tinfoil";
+
+        let same = headers_for_hop(&headers, origin, "https://games.example.com/api/get_game/1");
+        assert_eq!(same, headers);
+
+        // Another host, scheme or port doesn't get the credentials
+        for url in [
+            "https://cdn.example.net/game.nsp",
+            "http://games.example.com/api/get_game/1",
+            "https://games.example.com:8443/api/get_game/1",
+        ] {
+            let hop = headers_for_hop(&headers, origin, url);
+            assert_eq!(hop.len(), 1, "{url}");
+            assert_eq!(hop["Accept"], "application/json");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partial.nsp");
+        tokio::fs::write(&path, b"hello world").await.unwrap();
+
+        // Resuming after "hello" and hashing the rest gives the digest of the whole file
+        let mut hasher = hash_prefix(&path, 5).await.unwrap();
+        hasher.update(b" world");
+        let resumed = format!("{:x}", hasher.finalize());
+        let whole = format!("{:x}", Sha256::digest(b"hello world"));
+        assert_eq!(resumed, whole);
+
+        assert!(sha256_matches(
+            &format!(" {} ", whole.to_uppercase()),
+            &whole
+        ));
+        assert!(!sha256_matches(
+            &format!("{:x}", Sha256::digest(b"hello")),
+            &whole
+        ));
+    }
+}
@@ -6,11 +6,16 @@
 mod http;
 mod models;
 mod queue;
+mod segmented;
+mod webhook;
 
 // Re-export the public API
 pub use http::Downloader;
 pub use models::{DownloadQueueItem, DownloadStatus, ImportSource, Progress};
-pub use queue::{DOWNLOAD_QUEUE, DownloadHandle, DownloadQueue};
+pub use queue::{
+    DOWNLOAD_QUEUE, DownloadHandle, DownloadQueue, RetryError, SetPriorityError, restore_downloads,
+    retry_download, retry_failed_downloads, run_scheduler,
+};
 
 // Re-export utility functions
 pub use models::parse_content_disposition;
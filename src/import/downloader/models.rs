@@ -47,7 +47,7 @@ impl fmt::Display for DownloadStatus {
 }
 
 /// Represents the progress of a download
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Progress {
     /// Total size of the download in bytes (if known)
     pub total_size: Option<u64>,
@@ -57,6 +57,9 @@ pub struct Progress {
     pub status: DownloadStatus,
     /// Final path of the downloaded file (once known)
     pub file_path: Option<PathBuf>,
+    /// SHA-256 of the whole file, only computed for downloads with an expected checksum
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 impl Progress {
@@ -99,8 +102,21 @@ pub struct DownloadQueueItem {
     pub output_path: PathBuf,
     pub progress: Progress,
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
-    #[serde(skip_serializing_if = "Option::is_none")] // Don't save headers to DB
+    /// Saved with the download, so it can be restored or retried with the same headers
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<HashMap<String, String>>,
+    /// Import job that queued this download
+    #[serde(default)]
+    pub import_job_id: Option<ulid::Ulid>,
+    /// Title the import job was started for, if it named one
+    #[serde(default)]
+    pub title_id: Option<String>,
+    /// Downloads with a higher priority are started first
+    #[serde(default)]
+    pub priority: i32,
+    /// SHA-256 the downloaded file must have, as given by the importer
+    #[serde(default)]
+    pub expected_sha256: Option<String>,
 }
 
 impl DownloadQueueItem {
@@ -127,9 +143,26 @@ impl DownloadQueueItem {
             progress: Progress::default(),
             created_at: None,
             headers, // Add headers here
+            import_job_id: None,
+            title_id: None,
+            priority: 0,
+            expected_sha256: None,
         }
     }
 
+    /// Tag the download with the import job it belongs to, and the job's title
+    pub fn with_import_job(mut self, job_id: ulid::Ulid, title_id: Option<String>) -> Self {
+        self.import_job_id = Some(job_id);
+        self.title_id = title_id;
+        self
+    }
+
+    /// Verify the downloaded file against a SHA-256, failing the download if it doesn't match
+    pub fn with_expected_sha256(mut self, expected_sha256: Option<String>) -> Self {
+        self.expected_sha256 = expected_sha256;
+        self
+    }
+
     pub async fn save(&self) -> color_eyre::Result<()> {
         if let Some(id) = &self.id {
             // Extract just the ID part without the table prefix
@@ -138,10 +171,13 @@ impl DownloadQueueItem {
             let id_raw = id.id.to_string();
             tracing::trace!("Saving download progress with id: {}", id_raw);
 
-            let _: Option<Self> = crate::db::DB
-                .upsert(("download_queue", id_raw))
-                .content(self.clone())
-                .await?;
+            let _: Option<Self> = crate::db::with_retry("download_queue.save", || async {
+                crate::db::DB
+                    .upsert(("download_queue", id_raw.as_str()))
+                    .content(self.clone())
+                    .await
+            })
+            .await?;
             Ok(())
         } else {
             Err(color_eyre::eyre::eyre!("Cannot save item without ID"))
@@ -231,3 +267,27 @@ impl std::error::Error for PartialDownloadError {
         Some(&self.source)
     }
 }
+
+/// A download whose SHA-256 didn't match the one it was expected to have
+#[derive(Debug)]
+pub struct ChecksumMismatchError {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SHA-256 mismatch: expected {}, got {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatchError {}
+
+/// Compare an expected SHA-256 hex digest to a computed one, ignoring case and surrounding whitespace
+pub fn sha256_matches(expected: &str, actual: &str) -> bool {
+    expected.trim().eq_ignore_ascii_case(actual.trim())
+}
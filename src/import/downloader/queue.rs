@@ -4,11 +4,13 @@
 //! multiple concurrent downloads and their progress.
 
 use std::{
-    collections::BTreeMap,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{
-    sync::{mpsc, watch},
+    sync::{Notify, mpsc, oneshot, watch},
     task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
@@ -16,15 +18,84 @@ use tracing::{Level, debug, error, info, span, trace, warn};
 use ulid::Ulid;
 
 use super::http::Downloader;
-use super::models::{DownloadQueueItem, DownloadStatus, Progress};
+use super::models::{ChecksumMismatchError, DownloadQueueItem, DownloadStatus, Progress};
+use crate::backend::kv_config::{ExtraBackendConfig, KvOptExt};
 use crate::db::DB;
 
 /// Global download queue instance
 pub static DOWNLOAD_QUEUE: LazyLock<Mutex<DownloadQueue>> =
     LazyLock::new(|| Mutex::new(DownloadQueue::new()));
 
+/// Wakes the scheduler when a download finished, freeing its slot
+static SLOT_FREED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// How often the scheduler picks up a changed limit while no download finishes
+const SCHEDULER_POLL: Duration = Duration::from_secs(30);
+
+/// Start queued downloads of the global queue as slots free up.
+///
+/// The limit is read from [`ExtraBackendConfig`] before every promotion, so changing it
+/// applies without a restart.
+pub async fn run_scheduler() {
+    loop {
+        let limit = configured_limit().await;
+
+        {
+            let mut queue = DOWNLOAD_QUEUE.lock().unwrap();
+            if let Some(limit) = limit {
+                queue.set_max_concurrent(limit);
+            }
+            queue.dispatch();
+        }
+
+        let _ = tokio::time::timeout(SCHEDULER_POLL, SLOT_FREED.notified()).await;
+    }
+}
+
+/// The configured download limit, or `None` if it couldn't be read
+async fn configured_limit() -> Option<Option<usize>> {
+    match ExtraBackendConfig::get().await {
+        Ok(config) => {
+            let limit = config.unwrap_or_default().max_concurrent_downloads;
+            Some((limit > 0).then_some(limit))
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to get the download limit, keeping the current one");
+            None
+        }
+    }
+}
+
+/// Put the downloads that were queued, running or paused when the server stopped back
+/// into the global queue.
+///
+/// Downloads that had started continue their partial file, paused ones stay paused.
+/// Finished, failed and cancelled downloads are left in the database as they are.
+/// Returns the URL and handle of every restored download.
+pub async fn restore_downloads() -> color_eyre::Result<Vec<(String, DownloadHandle)>> {
+    let items: Vec<DownloadQueueItem> = DB.select("download_queue").await?;
+    // Apply the limit first, so the restored downloads don't all start at once
+    let limit = configured_limit().await;
+
+    let mut queue = DOWNLOAD_QUEUE.lock().unwrap();
+    if let Some(limit) = limit {
+        queue.set_max_concurrent(limit);
+    }
+    let handles: Vec<(String, DownloadHandle)> = items
+        .into_iter()
+        .filter_map(|item| {
+            let url = item.url.clone();
+            queue.restore(item).map(|handle| (url, handle))
+        })
+        .collect();
+    if !handles.is_empty() {
+        info!(count = handles.len(), "Restored unfinished downloads");
+    }
+    Ok(handles)
+}
+
 // Download handle returned to caller for tracking progress and cancellation
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DownloadHandle {
     pub id: Ulid,
     pub progress_rx: watch::Receiver<Progress>,
@@ -68,11 +139,6 @@ impl DownloadHandle {
     /// or Err with the error message if it failed.
     pub async fn wait_until_done(&mut self) -> Result<std::path::PathBuf, String> {
         loop {
-            // Wait for next progress update
-            if let Err(e) = self.wait_for_progress_change().await {
-                return Err(format!("Failed to monitor download progress: {}", e));
-            }
-
             let progress = self.progress();
 
             if progress.is_complete() {
@@ -97,14 +163,293 @@ impl DownloadHandle {
                     _ => Err("Download entered unexpected state".to_string()),
                 };
             }
+
+            // Wait for next progress update
+            if let Err(e) = self.wait_for_progress_change().await {
+                return Err(format!("Failed to monitor download progress: {}", e));
+            }
+        }
+    }
+}
+
+/// Decides which progress updates of a download are saved to the database.
+///
+/// Downloads report progress for every chunk, saving each one would mean hundreds of
+/// writes per second. Updates are saved at most once per interval, or when the progress
+/// advanced by a step. Status and path changes, including the terminal status, are always
+/// saved.
+#[derive(Debug)]
+pub struct ProgressThrottle {
+    interval: Duration,
+    percent_step: f32,
+    last_saved: Option<(Instant, Progress)>,
+}
+
+impl ProgressThrottle {
+    pub fn new(interval: Duration, percent_step: f32) -> Self {
+        Self {
+            interval,
+            percent_step,
+            last_saved: None,
+        }
+    }
+
+    /// Throttle configured with `ALU_PROGRESS_SAVE_INTERVAL_MS` and `ALU_PROGRESS_SAVE_PERCENT`
+    pub fn from_config() -> Self {
+        let config = crate::config::config().backend_config;
+        Self::new(
+            Duration::from_millis(config.progress_save_interval_ms),
+            config.progress_save_percent,
+        )
+    }
+
+    /// Check if an update should be saved, marking it as saved if so
+    pub fn should_save(&mut self, progress: &Progress, now: Instant) -> bool {
+        let save = match &self.last_saved {
+            None => true,
+            Some((saved_at, saved)) => {
+                progress.is_complete()
+                    || progress.status != saved.status
+                    || progress.file_path != saved.file_path
+                    || now.duration_since(*saved_at) >= self.interval
+                    || match (progress.percentage(), saved.percentage()) {
+                        (Some(current), Some(saved)) => current - saved >= self.percent_step,
+                        _ => false,
+                    }
+            }
+        };
+        if save {
+            self.last_saved = Some((now, progress.clone()));
         }
+        save
     }
 }
 
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum SetPriorityError {
+    #[error("Download not found")]
+    NotFound,
+    #[error("Download already started, only queued downloads can be reprioritized")]
+    Started,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RetryError {
+    #[error("Download not found")]
+    NotFound,
+    #[error("Download didn't fail, only failed downloads can be retried")]
+    NotFailed,
+    #[error("Failed to load download: {0}")]
+    Database(String),
+}
+
+/// Queue a failed download saved in the database again, as a new download.
+///
+/// The new download gets the URL, headers, destination and checksum the failed one was
+/// queued with, and the failed one is removed so it isn't retried twice. Returns the URL
+/// and handle of the new download.
+pub async fn retry_download(id: &Ulid) -> Result<(String, DownloadHandle), RetryError> {
+    let item: Option<DownloadQueueItem> = DB
+        .select(("download_queue", id.to_string()))
+        .await
+        .map_err(|e| RetryError::Database(e.to_string()))?;
+    let item = item.ok_or(RetryError::NotFound)?;
+    if !matches!(item.progress.status, DownloadStatus::Failed(_)) {
+        return Err(RetryError::NotFailed);
+    }
+
+    let url = item.url.clone();
+    let handle = DOWNLOAD_QUEUE.lock().unwrap().retry(id, item);
+    let _: Option<DownloadQueueItem> = DB
+        .delete(("download_queue", id.to_string()))
+        .await
+        .inspect_err(|e| warn!(id = %id, error = %e, "Failed to remove retried download"))
+        .ok()
+        .flatten();
+    Ok((url, handle))
+}
+
+/// Queue every failed download saved in the database again, see [`retry_download`]
+pub async fn retry_failed_downloads() -> color_eyre::Result<Vec<(String, DownloadHandle)>> {
+    let items: Vec<DownloadQueueItem> = DB.select("download_queue").await?;
+    let failed: Vec<Ulid> = items
+        .iter()
+        .filter(|item| matches!(item.progress.status, DownloadStatus::Failed(_)))
+        .filter_map(|item| match &item.id.as_ref()?.id {
+            surrealdb::sql::Id::String(id) => id.parse().ok(),
+            _ => None,
+        })
+        .collect();
+
+    let mut handles = Vec::with_capacity(failed.len());
+    for id in failed {
+        match retry_download(&id).await {
+            Ok(handle) => handles.push(handle),
+            // Retried by someone else in the meantime
+            Err(RetryError::NotFound | RetryError::NotFailed) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    info!(count = handles.len(), "Retrying failed downloads");
+    Ok(handles)
+}
+
+/// Order in which waiting downloads are started: highest priority first, then the order
+/// they were added in
+fn dispatch_order(waiting: impl IntoIterator<Item = (Ulid, i32)>) -> Vec<Ulid> {
+    let mut order: Vec<(Ulid, i32)> = waiting.into_iter().collect();
+    order.sort_by_key(|&(id, priority)| (Reverse(priority), id));
+    order.into_iter().map(|(id, _)| id).collect()
+}
+
+/// What the queue keeps of a download to restart its task after it's paused
+#[derive(Debug)]
+struct DownloadRun {
+    token: CancellationToken,
+    /// Feeds the download's progress task, which stops once every sender is gone
+    progress_tx: mpsc::Sender<Progress>,
+    /// Tells the progress task the priority the download started with, the first time
+    started_tx: Option<oneshot::Sender<i32>>,
+}
+
+/// Spawn the task running a download once the queue starts it.
+///
+/// Downloads that already have a file, because they were paused, continue it from where it
+/// ends instead of starting over.
+fn spawn_download_task(
+    id: Ulid,
+    item: &DownloadQueueItem,
+    run: &DownloadRun,
+    progress: watch::Receiver<Progress>,
+) -> JoinHandle<()> {
+    let url = item.url.clone();
+    let output_path = item.output_path.clone();
+    let headers = item.headers.clone();
+    let expected_sha256 = item.expected_sha256.clone();
+    let token = run.token.clone();
+    let internal_tx = run.progress_tx.clone();
+    // The final status goes through the same channel, so it can't be overtaken by
+    // progress updates that are still queued
+    let final_tx = internal_tx.clone();
+
+    tokio::spawn(async move {
+        let download_span = span!(Level::DEBUG, "download_task", id = %id, url = %url);
+        let _guard = download_span.enter();
+
+        // Cancelled through its handle while it was queued
+        if token.is_cancelled() {
+            let cancelled = Progress {
+                status: DownloadStatus::Cancelled,
+                ..progress.borrow().clone()
+            };
+            let _ = final_tx.send(cancelled).await;
+            return;
+        }
+
+        // Don't start filling the disk while the free-space guard is tripped,
+        // but still allow the download to be cancelled while it waits
+        if crate::storage::is_low_on_space() {
+            warn!("Low on disk space, waiting for space before starting download");
+            tokio::select! {
+                _ = crate::storage::wait_for_space() => {}
+                _ = token.cancelled() => {
+                    let cancelled = Progress {
+                        status: DownloadStatus::Cancelled,
+                        ..progress.borrow().clone()
+                    };
+                    let _ = final_tx.send(cancelled).await;
+                    return;
+                }
+            }
+        }
+
+        // Continue the file of a paused download, its size is what really made it to disk
+        let previous_file = progress.borrow().file_path.clone();
+        let (output_path, resume_from) = match previous_file {
+            Some(path) => match tokio::fs::metadata(&path).await {
+                Ok(metadata) => (path, metadata.len()),
+                Err(_) => (path, 0),
+            },
+            None => (output_path, 0),
+        };
+
+        info!(resume_from, "Starting download task");
+        let segments = match ExtraBackendConfig::get().await {
+            Ok(config) => config.unwrap_or_default().download_segments,
+            Err(e) => {
+                warn!(error = %e, "Failed to get the download segment count, using one connection");
+                1
+            }
+        };
+        let downloader = Downloader::new()
+            .with_expected_sha256(expected_sha256)
+            .with_segments(segments);
+        let result = downloader
+            .download_file_with_progress_cancellable(
+                &url,
+                &output_path,
+                internal_tx,
+                token.clone(),
+                headers.as_ref(),
+                resume_from,
+            )
+            .await;
+
+        // Update progress with final status
+        let final_progress = match &result {
+            Ok(path) => {
+                info!(path = ?path, "Download completed successfully");
+                Progress {
+                    status: DownloadStatus::Completed,
+                    file_path: Some(path.clone()),
+                    ..progress.borrow().clone()
+                }
+            }
+            Err(e) => {
+                error!(error = %e, "Download failed");
+                // Keep the checksum the file really had, so the mismatch can be reported
+                let sha256 = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<ChecksumMismatchError>())
+                    .map(|e| e.actual.clone());
+                Progress {
+                    status: DownloadStatus::Failed(e.to_string()),
+                    sha256,
+                    ..progress.borrow().clone()
+                }
+            }
+        };
+
+        // Send final update
+        let _ = final_tx.send(final_progress).await;
+    })
+}
+
+/// Save a status the download's own tasks can't report, such as after aborting them
+fn save_in_background(mut item: DownloadQueueItem, progress: Progress) {
+    if let Some(path) = &progress.file_path {
+        item.output_path = path.clone();
+    }
+    item.progress = progress;
+    tokio::spawn(async move {
+        if let Err(e) = item.save().await {
+            warn!(error = %e, "Failed to save download status to database");
+        }
+    });
+}
+
 #[derive(Debug, Default)]
 pub struct DownloadQueue {
-    downloads: BTreeMap<Ulid, (DownloadQueueItem, JoinHandle<()>)>,
+    /// Downloads and their task, which is only spawned once the queue starts them
+    downloads: BTreeMap<Ulid, (DownloadQueueItem, Option<JoinHandle<()>>)>,
     progress_watchers: BTreeMap<Ulid, watch::Sender<Progress>>,
+    runs: BTreeMap<Ulid, DownloadRun>,
+    /// Downloads waiting for their turn
+    waiting: BTreeSet<Ulid>,
+    /// Most downloads running at once, `None` for no limit
+    max_concurrent: Option<usize>,
+    /// ID of the last download added, IDs of later ones sort after it
+    last_id: Ulid,
 }
 
 impl DownloadQueue {
@@ -113,8 +458,13 @@ impl DownloadQueue {
     }
 
     pub fn add(&mut self, mut item: DownloadQueueItem) -> DownloadHandle {
-        // Create a new ulid first
-        let id_ulid = Ulid::new();
+        // Create a new ulid first. Ulids of the same millisecond are random, so bump it if
+        // needed to keep them in the order downloads were added
+        let id_ulid = match Ulid::new() {
+            id if id > self.last_id => id,
+            id => self.last_id.increment().unwrap_or(id),
+        };
+        self.last_id = id_ulid;
 
         // Log the ID we're creating
         info!("Creating new download with ULID: {}", id_ulid);
@@ -131,66 +481,72 @@ impl DownloadQueue {
         info!(id = %id_ulid, url = %item.url, "Adding download to queue");
         debug!(id = %id_ulid, path = ?item.output_path, "Download destination");
 
+        self.insert(id_ulid, item, Progress::default())
+    }
+
+    /// Add a download saved by an earlier run of the server back to the queue, keeping its ID.
+    ///
+    /// Only downloads that weren't finished are restored. One that was running is queued
+    /// again and continues its file once it's started, like a resumed download.
+    pub fn restore(&mut self, item: DownloadQueueItem) -> Option<DownloadHandle> {
+        // The ID's display form may be escaped, the raw string is the ULID
+        let surrealdb::sql::Id::String(id) = &item.id.as_ref()?.id else {
+            return None;
+        };
+        let id_ulid = id.parse::<Ulid>().ok()?;
+        if self.downloads.contains_key(&id_ulid) {
+            return None;
+        }
+
+        let status = match item.progress.status {
+            DownloadStatus::Queued | DownloadStatus::Downloading => DownloadStatus::Queued,
+            DownloadStatus::Paused => DownloadStatus::Paused,
+            _ => return None,
+        };
+        let progress = Progress {
+            status,
+            ..item.progress.clone()
+        };
+
+        info!(id = %id_ulid, url = %item.url, downloaded = progress.downloaded, "Restoring download");
+        self.last_id = self.last_id.max(id_ulid);
+        Some(self.insert(id_ulid, item, progress))
+    }
+
+    /// Track a download and its progress, and queue it unless it's paused
+    fn insert(
+        &mut self,
+        id_ulid: Ulid,
+        mut item: DownloadQueueItem,
+        progress: Progress,
+    ) -> DownloadHandle {
+        let paused = progress.status == DownloadStatus::Paused;
+        item.progress = progress.clone();
+
         // Create a watch channel for progress updates
-        let (progress_tx, progress_rx) = watch::channel(Progress::default());
+        let (progress_tx, progress_rx) = watch::channel(progress);
 
         let cancellation_token = CancellationToken::new();
 
-        // Clone what we need to move into the task
-        let url = item.url.clone();
-        let output_path = item.output_path.clone();
-        let headers = item.headers.clone();
-        let token_clone = cancellation_token.clone();
         // Save the progress transmitter for later use
         self.progress_watchers.insert(id_ulid, progress_tx.clone());
 
-        // Create a channel for the download task to send progress updates
+        // The priority may still change while the download waits for its turn
+        let (started_tx, started_rx) = oneshot::channel();
+
+        // Create a channel for the download task to send progress updates. The queue keeps
+        // a sender, so the progress task outlives the download task when it's paused
         let (internal_tx, mut internal_rx) = mpsc::channel(10);
+        let run = DownloadRun {
+            token: cancellation_token.clone(),
+            progress_tx: internal_tx,
+            started_tx: Some(started_tx),
+        };
 
         // Clone for database updates
         let item_clone = item.clone();
         let progress_tx_clone = progress_tx.clone();
-        let id_for_task = id_ulid; // Clone the ID for use in the download task
-
-        // Start the download task
-        let handle = tokio::spawn(async move {
-            let download_span = span!(Level::DEBUG, "download_task", id = %id_for_task, url = %url);
-            let _guard = download_span.enter();
-
-            info!("Starting download task");
-            let downloader = Downloader::new();
-            let result = downloader
-                .download_file_with_progress_cancellable(
-                    &url,
-                    &output_path,
-                    internal_tx,
-                    token_clone.clone(),
-                    headers.as_ref(),
-                )
-                .await;
-
-            // Update progress with final status
-            let final_progress = match &result {
-                Ok(path) => {
-                    info!(path = ?path, "Download completed successfully");
-                    Progress {
-                        status: DownloadStatus::Completed,
-                        file_path: Some(path.clone()),
-                        ..progress_tx.borrow().clone()
-                    }
-                }
-                Err(e) => {
-                    error!(error = %e, "Download failed");
-                    Progress {
-                        status: DownloadStatus::Failed(e.to_string()),
-                        ..progress_tx.borrow().clone()
-                    }
-                }
-            };
-
-            // Send final update
-            let _ = progress_tx.send(final_progress);
-        });
+        let token_for_progress = cancellation_token.clone();
 
         // Start a task to forward progress updates from the internal channel to both
         // the watch channel (for the handle) and the database
@@ -201,8 +557,40 @@ impl DownloadQueue {
 
             let mut db_item = item_clone;
 
+            // Save right away, so downloads still waiting for their turn survive a restart
+            if let Err(e) = db_item.save().await {
+                warn!(error = %e, "Failed to save download to database");
+            }
+
+            // The priority may have changed while the download was waiting
+            tokio::select! {
+                priority = started_rx => {
+                    // Not sent if the download was paused or removed before it started
+                    if let Ok(priority) = priority {
+                        db_item.priority = priority;
+                    }
+                }
+                // Pass on the cancellation from the download task
+                _ = token_for_progress.cancelled() => {}
+            }
+
+            let mut throttle = ProgressThrottle::from_config();
+
             // Forward progress updates from the downloader to the watch channel and database
             while let Some(progress) = internal_rx.recv().await {
+                // Updates still queued when the download was cancelled or paused don't undo it
+                let current_status = progress_tx_clone.borrow().status.clone();
+                let stopped = matches!(
+                    current_status,
+                    DownloadStatus::Completed
+                        | DownloadStatus::Failed(_)
+                        | DownloadStatus::Cancelled
+                        | DownloadStatus::Paused
+                );
+                if stopped && !progress.is_complete() {
+                    continue;
+                }
+
                 // Only log detailed progress at trace level
                 if let Some(total) = progress.total_size {
                     let percentage = (progress.downloaded as f32 / total as f32) * 100.0;
@@ -259,41 +647,82 @@ impl DownloadQueue {
 
                 // Update the database with progress information
                 db_item.progress = progress.clone();
-                if let Err(e) = db_item.save().await {
-                    warn!(error = %e, "Failed to save download progress to database");
+                if throttle.should_save(&progress, Instant::now()) {
+                    if let Err(e) = db_item.save().await {
+                        warn!(error = %e, "Failed to save download progress to database");
+                    }
                 }
 
                 // Update the watch channel for clients
                 let _ = progress_tx_clone.send(progress.clone());
+
+                if progress.is_complete() {
+                    SLOT_FREED.notify_one();
+                    // Delivered on its own, so a slow webhook doesn't hold up the queue
+                    tokio::spawn(super::webhook::notify_download(
+                        id_clone,
+                        db_item.url.clone(),
+                        db_item.title_id.clone(),
+                        progress.clone(),
+                    ));
+                }
             }
 
             trace!("Progress channel closed");
         });
 
         // Store the download information
-        self.downloads.insert(id_ulid, (item, handle));
+        self.downloads.insert(id_ulid, (item, None));
+        self.runs.insert(id_ulid, run);
+        if !paused {
+            self.waiting.insert(id_ulid);
+        }
 
         info!(id = %id_ulid, "Download added to queue");
+        self.dispatch();
 
         // Return the handle to the caller
         DownloadHandle::new(id_ulid, progress_rx, cancellation_token)
     }
 
     pub fn cancel(&mut self, id: &Ulid) -> bool {
-        if let Some((_, handle)) = self.downloads.get(id) {
+        if let Some((item, handle)) = self.downloads.get(id) {
             info!("Cancelling download: id={}", id);
-            handle.abort();
+            // Downloads that are still queued never had a task
+            if let Some(handle) = handle {
+                handle.abort();
+            }
 
             // Update progress with cancelled status
             if let Some(progress_tx) = self.progress_watchers.get(id) {
                 let mut current = progress_tx.borrow().clone();
+
+                // Paused downloads have no task left to remove their partial file
+                if current.status == DownloadStatus::Paused {
+                    if let Some(path) = current.file_path.clone() {
+                        tokio::spawn(async move {
+                            if let Err(e) = tokio::fs::remove_file(&path).await {
+                                warn!(error = %e, path = ?path, "Failed to remove partial download");
+                            }
+                        });
+                    }
+                }
+
                 current.status = DownloadStatus::Cancelled;
-                let _ = progress_tx.send(current);
+                let _ = progress_tx.send(current.clone());
+
+                // The aborted task can't report it, so save the terminal status here
+                save_in_background(item.clone(), current);
             }
 
             self.downloads.remove(id);
             self.progress_watchers.remove(id);
+            self.runs.remove(id);
+            let was_running = !self.waiting.remove(id);
             info!("Download cancelled and removed from queue: id={}", id);
+            if was_running {
+                self.dispatch();
+            }
             true
         } else {
             warn!("Attempted to cancel non-existent download: id={}", id);
@@ -301,6 +730,130 @@ impl DownloadQueue {
         }
     }
 
+    /// Pause a queued or running download, keeping what was downloaded so far. Returns
+    /// `false` if there's no such download or it can't be paused
+    pub fn pause(&mut self, id: &Ulid) -> bool {
+        let (Some((item, handle)), Some(progress_tx)) =
+            (self.downloads.get(id), self.progress_watchers.get(id))
+        else {
+            warn!("Attempted to pause non-existent download: id={}", id);
+            return false;
+        };
+        let mut current = progress_tx.borrow().clone();
+        if !matches!(
+            current.status,
+            DownloadStatus::Queued | DownloadStatus::Downloading
+        ) {
+            debug!(id = %id, status = %current.status, "Download can't be paused");
+            return false;
+        }
+
+        info!(id = %id, downloaded = current.downloaded, "Pausing download");
+        // The partial file is only removed when the download is cancelled, not aborted
+        if let Some(handle) = handle {
+            handle.abort();
+        }
+        self.waiting.remove(id);
+
+        current.status = DownloadStatus::Paused;
+        let _ = progress_tx.send(current.clone());
+        save_in_background(item.clone(), current);
+
+        self.dispatch();
+        true
+    }
+
+    /// Resume a paused download from where its file ends. It waits for its turn like a new
+    /// download. Returns `false` if there's no such download or it isn't paused
+    pub fn resume(&mut self, id: &Ulid) -> bool {
+        let Some(progress_tx) = self.progress_watchers.get(id) else {
+            warn!("Attempted to resume non-existent download: id={}", id);
+            return false;
+        };
+        let mut current = progress_tx.borrow().clone();
+        if current.status != DownloadStatus::Paused {
+            debug!(id = %id, status = %current.status, "Download isn't paused");
+            return false;
+        }
+
+        info!(id = %id, downloaded = current.downloaded, "Resuming download");
+        current.status = DownloadStatus::Queued;
+        let _ = progress_tx.send(current);
+        self.waiting.insert(*id);
+
+        self.dispatch();
+        true
+    }
+
+    /// Cancel every unfinished download queued by an import job, returning how many
+    /// were cancelled
+    pub fn cancel_import_job(&mut self, job_id: &Ulid) -> usize {
+        let ids: Vec<Ulid> = self
+            .list_downloads()
+            .into_iter()
+            .filter(|(_, item, progress)| {
+                item.import_job_id.as_ref() == Some(job_id) && !progress.is_complete()
+            })
+            .map(|(id, _, _)| id)
+            .collect();
+
+        ids.iter().filter(|id| self.cancel(id)).count()
+    }
+
+    /// Add a failed download to the queue again as a new download, forgetting the failed one
+    pub fn retry(&mut self, failed_id: &Ulid, failed: DownloadQueueItem) -> DownloadHandle {
+        info!(id = %failed_id, url = %failed.url, "Retrying failed download");
+        self.downloads.remove(failed_id);
+        self.progress_watchers.remove(failed_id);
+        self.runs.remove(failed_id);
+        self.waiting.remove(failed_id);
+
+        let mut item = DownloadQueueItem::new(failed.url, failed.output_path, failed.headers)
+            .with_expected_sha256(failed.expected_sha256);
+        // The import job it belonged to is over, but the title is still known
+        item.title_id = failed.title_id;
+        item.priority = failed.priority;
+        self.add(item)
+    }
+
+    /// Cancel every unfinished download, returning how many were cancelled
+    pub fn cancel_all(&mut self) -> usize {
+        let mut ids: Vec<(bool, Ulid)> = self
+            .list_downloads()
+            .into_iter()
+            .filter(|(_, _, progress)| !progress.is_complete())
+            .map(|(id, _, _)| (!self.waiting.contains(&id), id))
+            .collect();
+        // Waiting downloads go first, so the slots freed by running ones don't start them
+        ids.sort();
+
+        let cancelled = ids.iter().filter(|(_, id)| self.cancel(id)).count();
+        info!("Cancelled {} downloads", cancelled);
+        cancelled
+    }
+
+    /// Watch the progress of every download queued by an import job
+    pub fn subscribe_import_job(&self, job_id: &Ulid) -> Vec<watch::Receiver<Progress>> {
+        self.downloads
+            .iter()
+            .filter(|(_, (item, _))| item.import_job_id.as_ref() == Some(job_id))
+            .filter_map(|(id, _)| self.progress_watchers.get(id).map(|tx| tx.subscribe()))
+            .collect()
+    }
+
+    /// Watch the progress of a download
+    pub fn subscribe(&self, id: &Ulid) -> Option<watch::Receiver<Progress>> {
+        self.progress_watchers.get(id).map(|tx| tx.subscribe())
+    }
+
+    /// Watch the progress of every download in the queue
+    pub fn subscribe_all(&self) -> Vec<(Ulid, watch::Receiver<Progress>)> {
+        self.progress_watchers
+            .iter()
+            .map(|(id, tx)| (*id, tx.subscribe()))
+            .collect()
+    }
+
     pub fn get_item(&self, id: &Ulid) -> Option<&DownloadQueueItem> {
         self.downloads.get(id).map(|(item, _)| item)
     }
@@ -309,24 +862,114 @@ impl DownloadQueue {
         self.progress_watchers.get(id).map(|tx| tx.borrow().clone())
     }
 
+    /// List the downloads in the order they're started in
     pub fn list_downloads(&self) -> Vec<(Ulid, &DownloadQueueItem, Progress)> {
-        self.downloads
-            .iter()
-            .filter_map(|(id, (item, _))| {
-                self.progress_watchers
-                    .get(id)
-                    .map(|tx| (*id, item, tx.borrow().clone()))
-            })
+        dispatch_order(
+            self.downloads
+                .iter()
+                .map(|(id, (item, _))| (*id, item.priority)),
+        )
+        .into_iter()
+        .filter_map(|id| {
+            let (item, _) = self.downloads.get(&id)?;
+            let progress = self.progress_watchers.get(&id)?.borrow().clone();
+            Some((id, item, progress))
+        })
+        .collect()
+    }
+
+    /// Positions of the downloads waiting for their turn, the next one to start being 1
+    pub fn queue_positions(&self) -> HashMap<Ulid, usize> {
+        self.waiting_order()
+            .into_iter()
+            .enumerate()
+            .map(|(index, id)| (id, index + 1))
             .collect()
     }
 
+    /// Change the priority of a download that hasn't started yet
+    pub fn set_priority(&mut self, id: &Ulid, priority: i32) -> Result<(), SetPriorityError> {
+        let (item, _) = self
+            .downloads
+            .get_mut(id)
+            .ok_or(SetPriorityError::NotFound)?;
+        if !self.waiting.contains(id) {
+            return Err(SetPriorityError::Started);
+        }
+        info!(id = %id, priority, "Changing download priority");
+        item.priority = priority;
+        self.dispatch();
+        Ok(())
+    }
+
+    fn waiting_order(&self) -> Vec<Ulid> {
+        dispatch_order(self.waiting.iter().filter_map(|id| {
+            let (item, _) = self.downloads.get(id)?;
+            Some((*id, item.priority))
+        }))
+    }
+
+    /// Most downloads running at once, `None` for no limit
+    pub fn max_concurrent(&self) -> Option<usize> {
+        self.max_concurrent
+    }
+
+    /// Change the most downloads running at once. Running downloads keep running if it's
+    /// lowered, the new limit applies from the next download started
+    pub fn set_max_concurrent(&mut self, max_concurrent: Option<usize>) {
+        if self.max_concurrent != max_concurrent {
+            info!(?max_concurrent, "Changing the concurrent download limit");
+            self.max_concurrent = max_concurrent;
+        }
+    }
+
+    /// Start waiting downloads in order, as long as there are free slots
+    fn dispatch(&mut self) {
+        let running = self
+            .downloads
+            .keys()
+            .filter(|id| !self.waiting.contains(id))
+            .filter(|id| {
+                self.progress_watchers.get(id).is_some_and(|tx| {
+                    let progress = tx.borrow();
+                    !progress.is_complete() && progress.status != DownloadStatus::Paused
+                })
+            })
+            .count();
+        let free = self
+            .max_concurrent
+            .map_or(usize::MAX, |max| max.saturating_sub(running));
+
+        for id in self.waiting_order().into_iter().take(free) {
+            self.waiting.remove(&id);
+            let (Some((item, handle)), Some(run), Some(progress_tx)) = (
+                self.downloads.get_mut(&id),
+                self.runs.get_mut(&id),
+                self.progress_watchers.get(&id),
+            ) else {
+                continue;
+            };
+            debug!(id = %id, priority = item.priority, "Starting queued download");
+            if let Some(started_tx) = run.started_tx.take() {
+                let _ = started_tx.send(item.priority);
+            }
+            *handle = Some(spawn_download_task(id, item, run, progress_tx.subscribe()));
+        }
+    }
+
     // Cleans up completed downloads
     pub fn cleanup(&mut self) -> usize {
-        // First, clean up finished tasks with the previous behavior
+        // First, clean up finished tasks with the previous behavior. Paused downloads have
+        // no task running but aren't done
         let completed_ids: Vec<Ulid> = self
             .downloads
             .iter()
-            .filter(|(_, (_, handle))| handle.is_finished())
+            .filter(|(_, (_, handle))| handle.as_ref().is_some_and(|h| h.is_finished()))
+            .filter(|(id, _)| {
+                self.progress_watchers
+                    .get(id)
+                    .is_none_or(|tx| tx.borrow().status != DownloadStatus::Paused)
+            })
             .map(|(id, _)| *id)
             .collect();
 
@@ -366,6 +1009,8 @@ impl DownloadQueue {
             info!("Removing download from queue: id={}", id);
             self.downloads.remove(&id);
             self.progress_watchers.remove(&id);
+            self.runs.remove(&id);
+            self.waiting.remove(&id);
         }
 
         count
@@ -438,3 +1083,231 @@ impl DownloadQueue {
         id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(downloaded: u64, status: DownloadStatus) -> Progress {
+        Progress {
+            total_size: Some(1000),
+            downloaded,
+            status,
+            file_path: None,
+            sha256: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let mut queue = DownloadQueue::new();
+        queue.set_max_concurrent(Some(0));
+        let first = queue.add(DownloadQueueItem::new("http://example.com/a", "/tmp", None));
+        let second = queue.add(DownloadQueueItem::new("http://example.com/b", "/tmp", None));
+        let dropped = queue.add(DownloadQueueItem::new("http://example.com/c", "/tmp", None));
+
+        // Nothing is spawned for downloads waiting for a slot
+        assert!(queue.downloads.values().all(|(_, handle)| handle.is_none()));
+        assert!(queue.cancel(&dropped.id));
+        assert_eq!(queue.queue_positions().len(), 2);
+
+        // Started tasks stop right away instead of downloading
+        first.cancel();
+        second.cancel();
+        queue.set_max_concurrent(Some(1));
+        queue.dispatch();
+        assert!(queue.downloads[&first.id].1.is_some());
+        assert!(queue.downloads[&second.id].1.is_none());
+        assert_eq!(queue.queue_positions().get(&second.id), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        let mut queue = DownloadQueue::new();
+        queue.set_max_concurrent(Some(0));
+        let running = queue.add(DownloadQueueItem::new("http://example.com/a", "/tmp", None));
+        let waiting = queue.add(DownloadQueueItem::new("http://example.com/b", "/tmp", None));
+        let paused = queue.add(DownloadQueueItem::new("http://example.com/c", "/tmp", None));
+        assert!(queue.pause(&paused.id));
+        // Stops right away instead of downloading
+        running.cancel();
+        queue.set_max_concurrent(Some(1));
+        queue.dispatch();
+
+        assert_eq!(queue.cancel_all(), 3);
+        assert!(queue.list_downloads().is_empty());
+        assert!(queue.queue_positions().is_empty());
+        for handle in [&running, &waiting, &paused] {
+            assert_eq!(handle.progress().status, DownloadStatus::Cancelled);
+        }
+        assert_eq!(queue.cancel_all(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let mut queue = DownloadQueue::new();
+        queue.set_max_concurrent(Some(0));
+        let failed_id = Ulid::from_parts(1, 0);
+        let mut failed = DownloadQueueItem::new(
+            "http://example.com/a",
+            "/tmp/a.nsp",
+            Some(HashMap::from([("Cookie".to_string(), "a=b".to_string())])),
+        )
+        .with_expected_sha256(Some("abc".to_string()));
+        failed.title_id = Some("0100000000010000".to_string());
+        failed.progress = progress(500, DownloadStatus::Failed("timed out".to_string()));
+        let (progress_tx, _) = watch::channel(failed.progress.clone());
+        queue.progress_watchers.insert(failed_id, progress_tx);
+        queue.downloads.insert(failed_id, (failed.clone(), None));
+
+        let handle = queue.retry(&failed_id, failed.clone());
+        assert_ne!(handle.id, failed_id);
+        assert!(queue.get_item(&failed_id).is_none());
+        assert_eq!(handle.progress().status, DownloadStatus::Queued);
+        assert_eq!(handle.progress().downloaded, 0);
+        let item = queue.get_item(&handle.id).unwrap();
+        assert_eq!(item.url, failed.url);
+        assert_eq!(item.output_path, failed.output_path);
+        assert_eq!(item.headers, failed.headers);
+        assert_eq!(item.expected_sha256, failed.expected_sha256);
+        assert_eq!(item.title_id, failed.title_id);
+        assert_eq!(item.import_job_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let mut queue = DownloadQueue::new();
+        queue.set_max_concurrent(Some(0));
+
+        let saved = |n: u64, status: DownloadStatus| {
+            let id = Ulid::from_parts(n, 0);
+            let mut item = DownloadQueueItem::new(format!("http://example.com/{n}"), "/tmp", None);
+            item.id = Some(surrealdb::sql::Thing::from((
+                "download_queue",
+                surrealdb::sql::Id::from(id.to_string()),
+            )));
+            item.progress = Progress {
+                file_path: Some(format!("/tmp/{n}.nsp").into()),
+                ..progress(n * 100, status)
+            };
+            (id, item)
+        };
+
+        let (running, item) = saved(1, DownloadStatus::Downloading);
+        let handle = queue.restore(item.clone()).unwrap();
+        assert_eq!(handle.id, running);
+        // Continues the file it had started
+        let restored = handle.progress();
+        assert_eq!(restored.status, DownloadStatus::Queued);
+        assert_eq!(restored.file_path, item.progress.file_path);
+        // Already in the queue
+        assert!(queue.restore(item).is_none());
+
+        let (paused, item) = saved(2, DownloadStatus::Paused);
+        assert_eq!(
+            queue.restore(item).unwrap().progress().status,
+            DownloadStatus::Paused
+        );
+
+        for status in [
+            DownloadStatus::Completed,
+            DownloadStatus::Cancelled,
+            DownloadStatus::Failed("connection reset".to_string()),
+        ] {
+            let (_, item) = saved(3, status);
+            assert!(queue.restore(item).is_none());
+        }
+
+        // Paused downloads don't wait for a slot
+        assert_eq!(queue.queue_positions().len(), 1);
+        assert!(queue.queue_positions().contains_key(&running));
+        assert!(queue.get_item(&paused).is_some());
+
+        // New downloads sort after the restored ones
+        let new = queue.add(DownloadQueueItem::new(
+            "http://example.com/new",
+            "/tmp",
+            None,
+        ));
+        assert!(new.id > paused);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_keeps_paused() {
+        let mut queue = DownloadQueue::new();
+        let paused = Ulid::from_parts(1, 0);
+        let completed = Ulid::from_parts(2, 0);
+        for (id, status) in [
+            (paused, DownloadStatus::Paused),
+            (completed, DownloadStatus::Completed),
+        ] {
+            // Paused downloads have no task left running either
+            let handle = tokio::spawn(async {});
+            while !handle.is_finished() {
+                tokio::task::yield_now().await;
+            }
+            queue
+                .downloads
+                .insert(id, (DownloadQueueItem::default(), Some(handle)));
+            let (progress_tx, _) = watch::channel(progress(10, status));
+            queue.progress_watchers.insert(id, progress_tx);
+        }
+
+        // Finished downloads can't be paused
+        assert!(!queue.pause(&completed));
+        assert!(!queue.resume(&completed));
+
+        assert_eq!(queue.cleanup(), 1);
+        assert!(queue.get_item(&paused).is_some());
+        assert!(queue.get_item(&completed).is_none());
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let first = Ulid::from_parts(1, 0);
+        let second = Ulid::from_parts(2, 0);
+        let urgent = Ulid::from_parts(3, 0);
+        let background = Ulid::from_parts(4, 0);
+
+        assert_eq!(
+            dispatch_order([(background, -1), (second, 0), (urgent, 10), (first, 0)]),
+            vec![urgent, first, second, background]
+        );
+    }
+
+    #[test]
+    fn test_progress_throttle() {
+        let mut throttle = ProgressThrottle::new(Duration::from_secs(1), 10.0);
+        let start = Instant::now();
+
+        assert!(throttle.should_save(&progress(0, DownloadStatus::Downloading), start));
+        assert!(!throttle.should_save(&progress(50, DownloadStatus::Downloading), start));
+        // Enough progress since the last save
+        assert!(throttle.should_save(&progress(100, DownloadStatus::Downloading), start));
+        assert!(!throttle.should_save(&progress(101, DownloadStatus::Downloading), start));
+        // Enough time since the last save
+        let later = start + Duration::from_secs(1);
+        assert!(throttle.should_save(&progress(102, DownloadStatus::Downloading), later));
+    }
+
+    #[test]
+    fn test_progress_throttle_keeps_terminal_status() {
+        let terminal = [
+            DownloadStatus::Completed,
+            DownloadStatus::Failed("connection reset".to_string()),
+            DownloadStatus::Cancelled,
+        ];
+        for status in terminal {
+            let mut throttle = ProgressThrottle::new(Duration::from_secs(3600), 100.0);
+            let now = Instant::now();
+            assert!(throttle.should_save(&progress(0, DownloadStatus::Downloading), now));
+            assert!(!throttle.should_save(&progress(1, DownloadStatus::Downloading), now));
+            assert!(
+                throttle.should_save(&progress(1, status.clone()), now),
+                "{status:?}"
+            );
+            // Repeated terminal updates are saved too
+            assert!(throttle.should_save(&progress(1, status), now));
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Multi-connection downloads
+//!
+//! Splits a download into byte ranges that are fetched concurrently over separate
+//! connections, for servers that support range requests.
+
+use futures_util::{StreamExt, future::try_join_all};
+use reqwest::{
+    StatusCode,
+    header::{self, HeaderValue},
+};
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace};
+
+use super::http::{Downloader, headers_for_hop, resolve_final_path};
+use super::models::{DownloadStatus, Progress};
+use crate::backend::api::popular::RANGE_PROBE_HEADER;
+
+/// Segments smaller than this aren't worth their own connection
+const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Bytes of a segmented download, shared by its segments
+struct SegmentProgress {
+    total: u64,
+    downloaded: AtomicU64,
+    /// Bytes written from the start of the file without a gap
+    prefix: Arc<AtomicU64>,
+}
+
+/// Cuts a segmented download that didn't finish back to the part written from the start
+/// of the file, so it can be resumed from the file's size like a single-stream download.
+///
+/// This happens on drop, so it also covers downloads whose task was aborted.
+struct PartialFile {
+    path: PathBuf,
+    prefix: Arc<AtomicU64>,
+    finished: bool,
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let prefix = self.prefix.load(Ordering::SeqCst);
+        debug!(path = ?self.path, bytes = prefix, "Truncating unfinished segmented download");
+        if let Ok(file) = std::fs::OpenOptions::new().write(true).open(&self.path) {
+            let _ = file.set_len(prefix);
+        }
+    }
+}
+
+impl Downloader {
+    /// Download a file over several connections at once.
+    ///
+    /// Returns `Ok(None)` without downloading anything if the server doesn't support range
+    /// requests, doesn't tell the file's size, or the file is too small to be split.
+    pub(super) async fn download_segmented(
+        &self,
+        url: &str,
+        output_path: &Path,
+        progress_tx: &mpsc::Sender<Progress>,
+        cancel_token: &CancellationToken,
+        headers: Option<&HashMap<String, String>>,
+    ) -> io::Result<Option<PathBuf>> {
+        // Ask for the first byte only, a server with range support answers with the size
+        let mut probe_headers = headers.cloned().unwrap_or_default();
+        probe_headers.insert(header::RANGE.to_string(), "bytes=0-0".to_string());
+        // Keeps alumulemu remotes from counting the probe as a download of its own
+        probe_headers.insert(RANGE_PROBE_HEADER.to_string(), "1".to_string());
+        let probe = self.get_with_redirects(url, Some(&probe_headers)).await?;
+        if probe.status() != StatusCode::PARTIAL_CONTENT {
+            debug!(status = %probe.status(), "Server doesn't support range requests");
+            return Ok(None);
+        }
+
+        let Some(total) = probe
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(content_range_total)
+        else {
+            debug!("Server didn't send the size of the file");
+            return Ok(None);
+        };
+
+        let ranges = split_ranges(total, self.segments, MIN_SEGMENT_SIZE);
+        if ranges.len() < 2 {
+            return Ok(None);
+        }
+
+        // Segments go straight to where the redirects led
+        let final_url = probe.url().to_string();
+        let final_path = resolve_final_path(&probe, url, output_path)?;
+        drop(probe);
+        let headers = headers.map(|headers| headers_for_hop(headers, url, &final_url));
+
+        info!(
+            bytes = total,
+            segments = ranges.len(),
+            path = ?final_path,
+            "Starting segmented download"
+        );
+        crate::storage::ensure_space(&final_path, total).await?;
+        File::create(&final_path).await?.set_len(total).await?;
+
+        let progress = SegmentProgress {
+            total,
+            downloaded: AtomicU64::new(0),
+            prefix: Arc::new(AtomicU64::new(0)),
+        };
+        let mut partial = PartialFile {
+            path: final_path.clone(),
+            prefix: progress.prefix.clone(),
+            finished: false,
+        };
+        send_progress(
+            progress_tx,
+            &progress,
+            DownloadStatus::Downloading,
+            &final_path,
+        )
+        .await;
+
+        let segments = ranges.iter().map(|&(start, end)| {
+            self.download_segment(
+                &final_url,
+                &final_path,
+                (start, end),
+                headers.as_ref(),
+                progress_tx,
+                &progress,
+            )
+        });
+
+        // Dropping the segments aborts the ones still running
+        let result = tokio::select! {
+            result = try_join_all(segments) => result.map(|_| ()),
+            _ = cancel_token.cancelled() => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Download cancelled",
+            )),
+        };
+
+        match result {
+            Ok(()) => {
+                partial.finished = true;
+                info!(bytes = total, "Segmented download completed");
+                send_progress(
+                    progress_tx,
+                    &progress,
+                    DownloadStatus::Completed,
+                    &final_path,
+                )
+                .await;
+                Ok(Some(final_path))
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    info!("Download cancelled during progress");
+                    partial.finished = true;
+                    let _ = tokio::fs::remove_file(&final_path).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Download the inclusive byte range `start..=end` of a file into the same range of `path`
+    async fn download_segment(
+        &self,
+        url: &str,
+        path: &Path,
+        (start, end): (u64, u64),
+        headers: Option<&HashMap<String, String>>,
+        progress_tx: &mpsc::Sender<Progress>,
+        progress: &SegmentProgress,
+    ) -> io::Result<()> {
+        let mut request_builder = self
+            .client
+            .get(url)
+            .header(header::RANGE, format!("bytes={}-{}", start, end));
+        if let Some(custom_headers) = headers {
+            for (key, value) in custom_headers {
+                match HeaderValue::from_str(value) {
+                    Ok(header_val) => {
+                        request_builder = request_builder.header(key, header_val);
+                    }
+                    Err(e) => {
+                        error!(header_key = %key, error = %e, "Invalid header value, skipping");
+                    }
+                }
+            }
+        }
+
+        let response = request_builder.send().await.map_err(io::Error::other)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(io::Error::other(format!(
+                "Server answered the range request with {}",
+                response.status()
+            )));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let length = end - start + 1;
+        let mut written: u64 = 0;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(io::Error::other)?;
+            // Never write into the next segment
+            let chunk = &chunk[..chunk.len().min((length - written) as usize)];
+            let previous = written;
+            file.write_all(chunk).await?;
+            written += chunk.len() as u64;
+
+            // A write only starts once the previous one is done, so everything up to this
+            // chunk is on disk
+            if start == 0 {
+                progress.prefix.store(previous, Ordering::SeqCst);
+            }
+            progress
+                .downloaded
+                .fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            trace!(start, bytes = written, "Received segment chunk");
+            send_progress(progress_tx, progress, DownloadStatus::Downloading, path).await;
+
+            if written == length {
+                break;
+            }
+        }
+
+        file.flush().await?;
+        if start == 0 {
+            progress.prefix.store(written, Ordering::SeqCst);
+        }
+        if written < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Segment at {} ended after {} of {} bytes",
+                    start, written, length
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn send_progress(
+    progress_tx: &mpsc::Sender<Progress>,
+    progress: &SegmentProgress,
+    status: DownloadStatus,
+    path: &Path,
+) {
+    let _ = progress_tx
+        .send(Progress {
+            total_size: Some(progress.total),
+            downloaded: progress.downloaded.load(Ordering::SeqCst),
+            status,
+            file_path: Some(path.to_path_buf()),
+            sha256: None,
+        })
+        .await;
+}
+
+/// The total size in a `Content-Range` header, such as `bytes 0-0/1234`
+fn content_range_total(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Split `total` bytes into at most `segments` inclusive ranges of at least `min_size` bytes
+fn split_ranges(total: u64, segments: usize, min_size: u64) -> Vec<(u64, u64)> {
+    if total == 0 {
+        return Vec::new();
+    }
+    let count = (total / min_size.max(1)).clamp(1, segments.max(1) as u64);
+    let size = total.div_ceil(count);
+    (0..count)
+        .map(|i| i * size)
+        .filter(|&start| start < total)
+        .map(|start| (start, (start + size).min(total) - 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_ranges() {
+        assert_eq!(
+            split_ranges(100, 4, 10),
+            vec![(0, 24), (25, 49), (50, 74), (75, 99)]
+        );
+        // Uneven sizes leave the last segment shorter
+        assert_eq!(split_ranges(10, 3, 1), vec![(0, 3), (4, 7), (8, 9)]);
+        // Small files aren't split into segments below the minimum size
+        assert_eq!(split_ranges(25, 8, 10), vec![(0, 12), (13, 24)]);
+        assert_eq!(split_ranges(5, 8, 10), vec![(0, 4)]);
+        assert!(split_ranges(0, 4, 10).is_empty());
+    }
+
+    #[test]
+    fn test_content_range_total() {
+        assert_eq!(content_range_total("bytes 0-0/1234"), Some(1234));
+        assert_eq!(content_range_total("bytes 0-0/*"), None);
+        assert_eq!(content_range_total("bytes 0-0"), None);
+    }
+}
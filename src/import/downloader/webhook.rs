@@ -0,0 +1,239 @@
+//! Webhook notifications about finished downloads
+//!
+//! When a webhook URL is set in [`WebhookConfig`], a JSON payload describing the result is
+//! POSTed to it after a background download completed, failed or was cancelled. Delivery
+//! never affects the download, failures are retried a few times and then only logged. The
+//! webhook host is checked against the download host policy, and redirects aren't followed.
+
+use std::{path::PathBuf, time::Duration};
+
+use reqwest::StatusCode;
+use serde::Serialize;
+use tracing::{debug, error, warn};
+use ulid::Ulid;
+
+use super::models::{DownloadStatus, Progress};
+use crate::{
+    backend::kv_config::{KvOptExt, WebhookConfig},
+    titledb::{GameFileDataNaive, Title},
+};
+
+/// Attempts made to deliver a notification before giving up
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubled for every retry after it
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl WebhookEvent {
+    fn is_enabled(self, config: &WebhookConfig) -> bool {
+        match self {
+            Self::Completed => config.on_completed,
+            Self::Failed => config.on_failed,
+            Self::Cancelled => config.on_cancelled,
+        }
+    }
+}
+
+/// What's sent to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    /// Summary of the result, which chat services like Discord show as the message
+    pub content: String,
+    pub status: WebhookEvent,
+    pub id: String,
+    pub url: String,
+    /// Where the download was saved, which is a temporary location if it's imported
+    /// afterwards, such as extracted or renamed into the rom dir
+    pub download_path: Option<PathBuf>,
+    pub title_id: Option<String>,
+    pub title_name: Option<String>,
+    pub error: Option<String>,
+}
+
+impl WebhookPayload {
+    fn new(id: Ulid, url: String, status: WebhookEvent) -> Self {
+        Self {
+            content: String::new(),
+            status,
+            id: id.to_string(),
+            url,
+            download_path: None,
+            title_id: None,
+            title_name: None,
+            error: None,
+        }
+    }
+
+    /// Fill in the summary from the other fields
+    fn summarize(mut self) -> Self {
+        let file_name = self
+            .download_path
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        let subject = self
+            .title_name
+            .clone()
+            .or(file_name)
+            .unwrap_or_else(|| self.url.clone());
+        self.content = match (self.status, &self.error) {
+            (WebhookEvent::Completed, _) => format!("Imported {subject}"),
+            (WebhookEvent::Cancelled, _) => format!("Cancelled download of {subject}"),
+            (WebhookEvent::Failed, Some(error)) => {
+                format!("Failed to download {subject}: {error}")
+            }
+            (WebhookEvent::Failed, None) => format!("Failed to download {subject}"),
+        };
+        self
+    }
+}
+
+/// Whether a failed delivery is worth retrying
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Name of the title of a download, from the title the import job was started for or the
+/// title ID in the file name
+async fn title_of(
+    title_id: Option<String>,
+    path: Option<&PathBuf>,
+) -> (Option<String>, Option<String>) {
+    let title_id = title_id.or_else(|| {
+        let file_name = path?.file_name()?.to_str()?;
+        GameFileDataNaive::parse_from_filename(file_name).title_id
+    });
+    let Some(title_id) = title_id else {
+        return (None, None);
+    };
+    let locale = crate::config::config().backend_config.get_locale_string();
+    let name = Title::get_from_title_id(&locale, &title_id)
+        .await
+        .inspect_err(|e| debug!("Failed to look up {} for the webhook: {}", title_id, e))
+        .ok()
+        .flatten()
+        .and_then(|title| title.name);
+    (Some(title_id), name)
+}
+
+/// Notify the configured webhook about a finished download, if it wants to know about it
+pub async fn notify_download(id: Ulid, url: String, title_id: Option<String>, progress: Progress) {
+    let (status, error) = match progress.status {
+        DownloadStatus::Completed => (WebhookEvent::Completed, None),
+        DownloadStatus::Failed(e) => (WebhookEvent::Failed, Some(e)),
+        DownloadStatus::Cancelled => (WebhookEvent::Cancelled, None),
+        _ => return,
+    };
+    let config = match WebhookConfig::get().await {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to get webhook config: {}", e);
+            return;
+        }
+    };
+    if !status.is_enabled(&config) {
+        return;
+    }
+    let webhook_url = match config.url() {
+        Ok(Some(webhook_url)) => webhook_url,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Not sending webhook: {}", e);
+            return;
+        }
+    };
+
+    let mut payload = WebhookPayload::new(id, url, status);
+    payload.error = error;
+    payload.download_path = progress
+        .file_path
+        .filter(|_| status == WebhookEvent::Completed);
+    (payload.title_id, payload.title_name) =
+        title_of(title_id, payload.download_path.as_ref()).await;
+    send(webhook_url, &payload.summarize()).await;
+}
+
+/// POST a payload, retrying transient failures with a growing backoff. The URL is set by a
+/// user, so it has to pass the host policy like any download does.
+async fn send(webhook_url: url::Url, payload: &WebhookPayload) {
+    if let Err(e) = crate::import::host_policy::check_url(webhook_url.as_str()).await {
+        error!("Not sending webhook for download {}: {}", payload.id, e);
+        return;
+    }
+    let client = crate::http_client::guarded_client();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let (retry, reason) = match client.post(webhook_url.clone()).json(payload).send().await {
+            Ok(res) if res.status().is_success() => {
+                debug!("Sent webhook for download {}", payload.id);
+                return;
+            }
+            Ok(res) => (is_transient(res.status()), res.status().to_string()),
+            Err(e) => (true, e.to_string()),
+        };
+        if !retry || attempt == MAX_ATTEMPTS {
+            error!(
+                "Failed to send webhook for download {} after {} attempts: {}",
+                payload.id, attempt, reason
+            );
+            return;
+        }
+        warn!(
+            "Webhook for download {} failed, retrying: {}",
+            payload.id, reason
+        );
+        tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhook_payload_summary() {
+        let id = Ulid::from_parts(1, 0);
+        let mut payload = WebhookPayload::new(
+            id,
+            "http://example.com/game".to_string(),
+            WebhookEvent::Completed,
+        );
+        payload.download_path = Some("/roms/Game [0100000000010000][v0].nsp".into());
+        assert_eq!(
+            payload.clone().summarize().content,
+            "Imported Game [0100000000010000][v0].nsp"
+        );
+        payload.title_name = Some("Game".to_string());
+        assert_eq!(payload.clone().summarize().content, "Imported Game");
+
+        let mut failed = WebhookPayload::new(
+            id,
+            "http://example.com/game".to_string(),
+            WebhookEvent::Failed,
+        );
+        failed.error = Some("connection reset".to_string());
+        let json = serde_json::to_value(failed.summarize()).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(
+            json["content"],
+            "Failed to download http://example.com/game: connection reset"
+        );
+    }
+
+    #[test]
+    fn test_webhook_is_transient() {
+        assert!(is_transient(StatusCode::BAD_GATEWAY));
+        assert!(is_transient(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_transient(StatusCode::NOT_FOUND));
+        assert!(!is_transient(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,310 @@
+//! Outbound host policy for importers
+//!
+//! Importers download from user-provided URLs, so without a policy anyone allowed to start
+//! an import could make the server request internal addresses on their behalf (SSRF).
+//! Every URL is checked before it's requested, including each redirect hop:
+//!
+//! - Hosts on the deny list are always rejected
+//! - If an allow list is set, only hosts on it are permitted
+//! - Hosts resolving to loopback, link-local or private addresses are rejected, unless the
+//!   host is explicitly on the allow list or private downloads are enabled
+//!
+//! The check resolves the host itself, and [`PolicyResolver`] applies the same address
+//! filter when the HTTP client connects, so a host can't pass the check and then resolve
+//! to an internal address for the actual request.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use reqwest::{
+    Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+};
+use url::Host;
+
+#[derive(thiserror::Error, Debug)]
+pub enum HostPolicyError {
+    #[error("invalid URL '{0}'")]
+    InvalidUrl(String),
+    #[error("unsupported URL scheme '{0}', only http and https are allowed")]
+    UnsupportedScheme(String),
+    #[error("host '{0}' is not on the download allow list")]
+    NotAllowed(String),
+    #[error("host '{0}' is on the download deny list")]
+    Denied(String),
+    #[error("host '{host}' resolves to the non-public address {addr}")]
+    PrivateAddress { host: String, addr: IpAddr },
+    #[error("failed to resolve host '{host}': {source}")]
+    Resolve {
+        host: String,
+        source: std::io::Error,
+    },
+}
+
+impl From<HostPolicyError> for std::io::Error {
+    fn from(err: HostPolicyError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::PermissionDenied, err)
+    }
+}
+
+/// Check if a host matches a list entry, either exactly or through a `*.example.com` wildcard
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Check if an address is publicly routable
+pub fn is_public_address(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => is_public_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(v6),
+        },
+    }
+}
+
+fn is_public_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+    let shared = a == 100 && (64..128).contains(&b); // 100.64.0.0/10, carrier-grade NAT
+    !(addr.is_loopback()
+        || addr.is_private()
+        || addr.is_link_local()
+        || addr.is_unspecified()
+        || addr.is_broadcast()
+        || addr.is_multicast()
+        || addr.is_documentation()
+        || shared
+        || a == 0)
+}
+
+fn is_public_v6(addr: Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+    let unique_local = first & 0xfe00 == 0xfc00; // fc00::/7
+    let link_local = first & 0xffc0 == 0xfe80; // fe80::/10
+    !(addr.is_loopback()
+        || addr.is_unspecified()
+        || addr.is_multicast()
+        || unique_local
+        || link_local)
+}
+
+/// Hosts importers are allowed to download from
+#[derive(Debug, Clone, Default)]
+pub struct HostPolicy {
+    /// If not empty, only these hosts are allowed
+    pub allowed: Vec<String>,
+    /// These hosts are always rejected
+    pub denied: Vec<String>,
+    /// Allow hosts that resolve to loopback, link-local or private addresses
+    pub allow_private: bool,
+}
+
+impl HostPolicy {
+    /// Get the policy from the current configuration
+    pub fn from_config() -> Self {
+        let config = crate::config::config().backend_config;
+        let clean = |hosts: Vec<String>| {
+            hosts
+                .into_iter()
+                .filter(|host| !host.trim().is_empty())
+                .collect()
+        };
+        Self {
+            allowed: clean(config.download_allowed_hosts),
+            denied: clean(config.download_denied_hosts),
+            allow_private: config.download_allow_private,
+        }
+    }
+
+    fn is_explicitly_allowed(&self, host: &str) -> bool {
+        self.allowed
+            .iter()
+            .any(|pattern| host_matches(pattern, host))
+    }
+
+    /// Check the host of a URL against the allow and deny lists.
+    ///
+    /// Returns the host and whether addresses it resolves to still need to be checked.
+    fn check_host(&self, url: &Url) -> Result<(String, bool), HostPolicyError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(HostPolicyError::UnsupportedScheme(url.scheme().to_string()));
+        }
+
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.trim_end_matches('.').to_lowercase(),
+            Some(Host::Ipv4(addr)) => addr.to_string(),
+            Some(Host::Ipv6(addr)) => addr.to_string(),
+            None => return Err(HostPolicyError::InvalidUrl(url.to_string())),
+        };
+
+        if self
+            .denied
+            .iter()
+            .any(|pattern| host_matches(pattern, &host))
+        {
+            return Err(HostPolicyError::Denied(host));
+        }
+
+        let explicitly_allowed = self.is_explicitly_allowed(&host);
+        if !self.allowed.is_empty() && !explicitly_allowed {
+            return Err(HostPolicyError::NotAllowed(host));
+        }
+
+        let check_addresses = !self.allow_private && !explicitly_allowed;
+        Ok((host, check_addresses))
+    }
+
+    /// Check an address the host resolved to
+    fn check_address(&self, host: &str, addr: IpAddr) -> Result<(), HostPolicyError> {
+        if self.allow_private || is_public_address(addr) || self.is_explicitly_allowed(host) {
+            Ok(())
+        } else {
+            Err(HostPolicyError::PrivateAddress {
+                host: host.to_string(),
+                addr,
+            })
+        }
+    }
+
+    /// Check if a URL may be downloaded from, resolving its host if needed
+    pub async fn check_url(&self, url: &str) -> Result<(), HostPolicyError> {
+        let parsed = Url::parse(url).map_err(|_| HostPolicyError::InvalidUrl(url.to_string()))?;
+        let (host, check_addresses) = self.check_host(&parsed)?;
+        if !check_addresses {
+            return Ok(());
+        }
+
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return self.check_address(&host, addr);
+        }
+
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addrs = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|source| HostPolicyError::Resolve {
+                host: host.clone(),
+                source,
+            })?;
+        for addr in addrs {
+            self.check_address(&host, addr.ip())?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a URL against the configured host policy
+pub async fn check_url(url: &str) -> Result<(), HostPolicyError> {
+    HostPolicy::from_config().check_url(url).await
+}
+
+/// DNS resolver for download clients that drops addresses the host policy doesn't allow
+#[derive(Debug, Default)]
+pub struct PolicyResolver;
+
+impl PolicyResolver {
+    pub fn shared() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let policy = HostPolicy::from_config();
+            let host = name.as_str().trim_end_matches('.').to_lowercase();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| policy.check_address(&host, addr.ip()).is_ok())
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("host '{host}' has no addresses allowed for downloads").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches(" Example.COM ", "example.com"));
+        assert!(!host_matches("example.com", "cdn.example.com"));
+        assert!(host_matches("*.example.com", "cdn.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn test_is_public_address() {
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.10",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_address(addr.parse().unwrap()), "{addr}");
+        }
+        for addr in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public_address(addr.parse().unwrap()), "{addr}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_url() {
+        let policy = HostPolicy::default();
+        assert!(policy.check_url("http://1.1.1.1/game.nsp").await.is_ok());
+        assert!(matches!(
+            policy.check_url("http://127.0.0.1:3000/").await,
+            Err(HostPolicyError::PrivateAddress { .. })
+        ));
+        assert!(matches!(
+            policy.check_url("http://[::1]/").await,
+            Err(HostPolicyError::PrivateAddress { .. })
+        ));
+        assert!(matches!(
+            policy.check_url("file:///etc/passwd").await,
+            Err(HostPolicyError::UnsupportedScheme(_))
+        ));
+
+        let policy = HostPolicy {
+            allowed: vec!["192.168.1.10".to_string(), "*.example.com".to_string()],
+            denied: vec!["bad.example.com".to_string()],
+            allow_private: false,
+        };
+        assert!(policy.check_url("http://192.168.1.10:3000/").await.is_ok());
+        assert!(matches!(
+            policy.check_url("http://1.1.1.1/").await,
+            Err(HostPolicyError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            policy.check_url("https://bad.example.com/").await,
+            Err(HostPolicyError::Denied(_))
+        ));
+
+        let policy = HostPolicy {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(policy.check_url("http://10.0.0.2/").await.is_ok());
+    }
+}
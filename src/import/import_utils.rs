@@ -2,8 +2,12 @@ use axum::Json;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use tracing::{error, info};
+use ulid::Ulid;
 
 use crate::backend::admin::{ApiResponse, trigger_rescan};
+use crate::backend::kv_config::KvOptExt;
+use crate::import::ImportSource;
+use crate::import::job::{ImportJob, ImportJobConfig, ImportJobStatus};
 use crate::import::registry;
 use crate::router::RescanOptions;
 
@@ -25,10 +29,14 @@ pub enum ImportError {
 
 impl IntoResponse for ImportError {
     fn into_response(self) -> Response {
+        let status = match self {
+            ImportError::ImporterNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        };
         let error_msg = self.to_string();
         error!("Import error: {}", error_msg);
         (
-            StatusCode::BAD_REQUEST,
+            status,
             Json(ApiResponse::<()> {
                 status: "error".to_string(),
                 message: Some(error_msg),
@@ -43,43 +51,34 @@ impl IntoResponse for ImportError {
 pub type ImportResult = std::result::Result<Response, ImportError>;
 
 
+/// Options any import request may set, next to the importer's own fields
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct ImportOptions {
+    /// Delete older versions of imported updates, defaults to the import job config
+    pub clean_superseded_updates: Option<bool>,
+    /// Title the import is for, the job's downloads are tagged with it
+    pub title_id: Option<String>,
+}
+
 /// Helper function to import with JSON
 pub async fn import_with_json(importer_id: &str, json: &str) -> ImportResult {
     info!(importer = importer_id, "Starting import request with JSON");
 
+    let options: ImportOptions = serde_json::from_str(json).unwrap_or_default();
+
     // Use the registry to find the import source - this validates the request
     // but doesn't start the download yet
     match registry::import_with_json(importer_id, json).await {
         Ok(import_source) => {
             // Store the importer_id for the response
             let response_importer_id = importer_id.to_string();
-
-            // Start a background task to process the import
-            let importer_id = importer_id.to_string();
-            tokio::spawn(async move {
-                info!(
-                    importer = importer_id,
-                    "Starting import process in background"
-                );
-
-                match import_source.import().await {
-                    Ok(_) => {
-                        info!(importer = importer_id, "Import completed successfully");
-
-                        // Trigger a rescan after successful import
-                        info!("Triggering rescan after import");
-                        let _ = trigger_rescan(RescanOptions::default()).await;
-                    }
-                    Err(e) => {
-                        error!(importer = importer_id, error = %e, "Import failed");
-                    }
-                }
-            });
+            let job_id = spawn_import_job(importer_id, import_source, options);
 
             // Define a response type for import start
             #[derive(serde::Serialize)]
             struct ImportStartResponse {
                 importer: String,
+                job_id: ulid::Ulid,
             }
 
             // Return success immediately - the source was found and download queued
@@ -88,6 +87,7 @@ pub async fn import_with_json(importer_id: &str, json: &str) -> ImportResult {
                 message: Some("Import started".to_string()),
                 data: Some(ImportStartResponse {
                     importer: response_importer_id,
+                    job_id,
                 }),
             })
             .into_response())
@@ -95,7 +95,102 @@ pub async fn import_with_json(importer_id: &str, json: &str) -> ImportResult {
         Err(e) => {
             // Return error immediately - the source wasn't found, no download started
             error!(importer = importer_id, error = %e, "Import error (pre-download)");
-            Err(ImportError::ImportFailed(e))
+            Err(match e {
+                crate::import::ImportError::ImporterNotFound(id) => {
+                    ImportError::ImporterNotFound(id)
+                }
+                crate::import::ImportError::InvalidRequest(e) => {
+                    ImportError::InvalidRequest(e.to_string())
+                }
+                e => ImportError::ImportFailed(e),
+            })
         }
     }
 }
+
+/// Create an import job for the source and import it in the background
+pub fn spawn_import_job(
+    importer_id: &str,
+    import_source: ImportSource,
+    options: ImportOptions,
+) -> Ulid {
+    let job_id = ImportJob::create(importer_id, options.title_id);
+    run_import_job(
+        job_id,
+        importer_id,
+        import_source,
+        options.clean_superseded_updates,
+    );
+    job_id
+}
+
+/// Import the source in the background as part of an existing import job.
+///
+/// Older versions of imported updates are removed if `clean_superseded_updates` is set,
+/// or if it's unset and the import job config says so.
+pub fn run_import_job(
+    job_id: Ulid,
+    importer_id: &str,
+    import_source: ImportSource,
+    clean_superseded_updates: Option<bool>,
+) {
+    let importer_id = importer_id.to_string();
+
+    tokio::spawn(async move {
+        info!(
+            importer = importer_id,
+            job = %job_id,
+            "Starting import process in background"
+        );
+        ImportJob::set_status(&job_id, ImportJobStatus::Running);
+
+        match import_source.import(Some(job_id)).await {
+            Ok(imported) => {
+                let clean_superseded_updates = match clean_superseded_updates {
+                    Some(clean) => clean,
+                    None => {
+                        let config = ImportJobConfig::get().await.ok().flatten();
+                        config.unwrap_or_default().clean_superseded_updates
+                    }
+                };
+                if clean_superseded_updates {
+                    let removed = crate::import::clean::remove_superseded_updates(&imported).await;
+                    if !removed.is_empty() {
+                        info!(job = %job_id, removed = ?removed, "Removed superseded updates");
+                    }
+                }
+
+                // Children that failed even after retries were skipped, the rest got imported
+                let failed = ImportJob::get(&job_id)
+                    .map(|job| job.failed_children().len())
+                    .unwrap_or_default();
+
+                if failed > 0 {
+                    error!(
+                        importer = importer_id,
+                        job = %job_id,
+                        failed = failed,
+                        "Import partially failed"
+                    );
+                    ImportJob::set_status(
+                        &job_id,
+                        ImportJobStatus::Failed(format!(
+                            "{failed} download(s) failed after all retries"
+                        )),
+                    );
+                } else {
+                    info!(importer = importer_id, job = %job_id, "Import completed successfully");
+                    ImportJob::set_status(&job_id, ImportJobStatus::Completed);
+                }
+
+                // Trigger a rescan after successful import
+                info!("Triggering rescan after import");
+                let _ = trigger_rescan(RescanOptions::default()).await;
+            }
+            Err(e) => {
+                error!(importer = importer_id, job = %job_id, error = %e, "Import failed");
+                ImportJob::set_status(&job_id, ImportJobStatus::Failed(e.to_string()));
+            }
+        }
+    });
+}
@@ -0,0 +1,562 @@
+//! Import job tracking
+//!
+//! An import job covers a single import request (for example an UltraNX `AllSplit` import)
+//! and every child download it spawns. While the download layer retries transient network
+//! errors on its own, the job layer re-attempts whole child downloads that failed after
+//! all the other children have settled, and records the final outcome.
+//!
+//! Running jobs live in memory, and every change is also written to the `import_job` table
+//! in the background, so the history of past imports survives restarts. Changes are also
+//! announced to subscribers, which follow a job's progress together with its downloads.
+
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use ulid::Ulid;
+
+use super::downloader::{DOWNLOAD_QUEUE, DownloadStatus, Progress};
+use crate::{
+    backend::kv_config::KvOptExt,
+    db::{DB, with_retry},
+};
+
+/// Global registry of import jobs
+static IMPORT_JOBS: LazyLock<Mutex<BTreeMap<Ulid, ImportJob>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Snapshots of changed jobs, saved one after another so an older snapshot never
+/// overwrites a newer one. `None` outside of a runtime, where nothing is persisted.
+static PERSIST_TX: LazyLock<Option<mpsc::UnboundedSender<ImportJob>>> = LazyLock::new(|| {
+    let handle = tokio::runtime::Handle::try_current().ok()?;
+    let (tx, mut rx) = mpsc::unbounded_channel::<ImportJob>();
+    handle.spawn(async move {
+        while let Some(job) = rx.recv().await {
+            if let Err(e) = job.save().await {
+                tracing::warn!(job = %job.id, "Failed to save import job: {}", e);
+            }
+        }
+    });
+    Some(tx)
+});
+
+/// IDs of jobs that just changed, for following their progress
+static JOB_UPDATES: LazyLock<broadcast::Sender<Ulid>> = LazyLock::new(|| broadcast::channel(256).0);
+
+const TABLE: &str = "import_job";
+
+/// Job-level retry and cleanup policy for imports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportJobConfig {
+    /// How many times failed child downloads are re-attempted before the job is marked failed
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Seconds to wait before re-attempting failed child downloads
+    #[serde(default = "default_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    /// Delete older versions of an update once a newer one was imported, unless the import
+    /// request says otherwise
+    #[serde(default)]
+    pub clean_superseded_updates: bool,
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_delay_secs() -> u64 {
+    60
+}
+
+impl Default for ImportJobConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            retry_delay_secs: default_retry_delay_secs(),
+            clean_superseded_updates: false,
+        }
+    }
+}
+
+impl KvOptExt for ImportJobConfig {
+    const KEY_NAME: &'static str = "import_job_config";
+}
+
+/// Status of an import job
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ImportJobStatus {
+    /// The job has been created but has not started processing yet
+    #[default]
+    Pending,
+    /// Child downloads are in progress, or files are being moved into place
+    Running,
+    /// Some child downloads failed and the job is waiting to re-attempt them
+    Retrying,
+    /// Every child download succeeded and the files were imported
+    Completed,
+    /// The job failed, possibly after importing some of its children
+    Failed(String),
+    /// The job was cancelled along with its unfinished downloads
+    Cancelled,
+}
+
+impl ImportJobStatus {
+    /// Names of the statuses, as accepted by the history filter
+    pub const NAMES: [&'static str; 6] = [
+        "pending",
+        "running",
+        "retrying",
+        "completed",
+        "failed",
+        "cancelled",
+    ];
+
+    /// Name of the status without its details
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Retrying => "retrying",
+            Self::Completed => "completed",
+            Self::Failed(_) => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    /// Check if the job is over, successfully or not
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Completed | Self::Failed(_) | Self::Cancelled)
+    }
+}
+
+/// Archives of an import job being extracted
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct ExtractionProgress {
+    /// Number of archives that are or will be extracted
+    pub archives: usize,
+    /// Number of archives that were extracted
+    pub extracted: usize,
+}
+
+/// A single download belonging to an import job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportJobChild {
+    pub url: String,
+    pub status: DownloadStatus,
+    /// Number of times this child has been attempted
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportJob {
+    pub id: Ulid,
+    /// ID of the importer that created this job
+    pub importer: String,
+    /// Title the import was requested for, if the request named one
+    #[serde(default)]
+    pub title_id: Option<String>,
+    pub status: ImportJobStatus,
+    /// Number of job-level retry rounds performed so far
+    pub retries: u32,
+    pub children: Vec<ImportJobChild>,
+    #[serde(default)]
+    pub extraction: ExtractionProgress,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ImportJob {
+    /// Create a new pending job for the given importer and register it
+    pub fn create(importer: &str, title_id: Option<String>) -> Ulid {
+        let now = Utc::now();
+        let job = Self {
+            id: Ulid::new(),
+            importer: importer.to_string(),
+            title_id,
+            status: ImportJobStatus::Pending,
+            retries: 0,
+            children: Vec::new(),
+            extraction: ExtractionProgress::default(),
+            created_at: now,
+            updated_at: now,
+        };
+        let id = job.id;
+        persist(job.clone());
+        IMPORT_JOBS.lock().unwrap().insert(id, job);
+        id
+    }
+
+    pub fn get(id: &Ulid) -> Option<Self> {
+        IMPORT_JOBS.lock().unwrap().get(id).cloned()
+    }
+
+    /// Apply a change to a registered job, bumping its `updated_at` timestamp
+    pub fn update(id: &Ulid, f: impl FnOnce(&mut Self)) {
+        if let Some(job) = IMPORT_JOBS.lock().unwrap().get_mut(id) {
+            f(job);
+            job.updated_at = Utc::now();
+            persist(job.clone());
+            let _ = JOB_UPDATES.send(*id);
+        } else {
+            tracing::warn!(job = %id, "Attempted to update non-existent import job");
+        }
+    }
+
+    /// Set the status of a job, unless it was cancelled, which is final
+    pub fn set_status(id: &Ulid, status: ImportJobStatus) {
+        Self::update(id, |job| {
+            if job.status != ImportJobStatus::Cancelled {
+                job.status = status;
+            }
+        });
+    }
+
+    pub fn is_cancelled(id: &Ulid) -> bool {
+        Self::get(id).is_some_and(|job| job.status == ImportJobStatus::Cancelled)
+    }
+
+    /// Cancel a job and every download it queued that hasn't finished yet.
+    ///
+    /// Returns the number of downloads that were cancelled, or `None` if the job isn't
+    /// running anymore.
+    pub fn cancel(id: &Ulid) -> Option<usize> {
+        {
+            let mut jobs = IMPORT_JOBS.lock().unwrap();
+            let job = jobs.get_mut(id).filter(|job| !job.status.is_finished())?;
+            job.status = ImportJobStatus::Cancelled;
+            job.updated_at = Utc::now();
+            persist(job.clone());
+            let _ = JOB_UPDATES.send(*id);
+        }
+
+        let cancelled = DOWNLOAD_QUEUE.lock().unwrap().cancel_import_job(id);
+        tracing::info!(job = %id, downloads = cancelled, "Import job cancelled");
+        Some(cancelled)
+    }
+
+    /// Record the outcome of a single attempt of a child download
+    pub fn record_child(id: &Ulid, url: &str, status: DownloadStatus) {
+        Self::update(id, |job| {
+            match job.children.iter_mut().find(|child| child.url == url) {
+                Some(child) => {
+                    child.status = status;
+                    child.attempts += 1;
+                }
+                None => job.children.push(ImportJobChild {
+                    url: url.to_string(),
+                    status,
+                    attempts: 1,
+                }),
+            }
+        });
+    }
+
+    /// Record that archives of a job are about to be extracted
+    pub fn start_extraction(id: &Ulid, archives: usize) {
+        Self::update(id, |job| job.extraction.archives += archives);
+    }
+
+    /// Record that one of a job's archives was extracted
+    pub fn finish_extraction(id: &Ulid) {
+        Self::update(id, |job| job.extraction.extracted += 1);
+    }
+
+    /// Subscribe to the IDs of jobs as they change
+    pub fn subscribe() -> broadcast::Receiver<Ulid> {
+        JOB_UPDATES.subscribe()
+    }
+
+    /// Aggregate progress of the job, over its queued downloads and extraction
+    pub fn progress(&self) -> ImportJobProgress {
+        let downloads: Vec<Progress> = DOWNLOAD_QUEUE
+            .lock()
+            .unwrap()
+            .list_downloads()
+            .into_iter()
+            .filter(|(_, item, _)| item.import_job_id.as_ref() == Some(&self.id))
+            .map(|(_, _, progress)| progress)
+            .collect();
+        ImportJobProgress::new(self, &downloads)
+    }
+
+    /// Children that ended up failing, even after retries
+    pub fn failed_children(&self) -> Vec<&ImportJobChild> {
+        self.children
+            .iter()
+            .filter(|child| matches!(child.status, DownloadStatus::Failed(_)))
+            .collect()
+    }
+}
+
+/// Progress of an import job as a whole, such as a base game with its update and DLCs
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ImportJobProgress {
+    pub id: Ulid,
+    pub status: ImportJobStatus,
+    /// Number of downloads the job queued that are still known to the download queue
+    pub downloads: usize,
+    pub downloads_completed: usize,
+    pub downloads_failed: usize,
+    pub downloaded_bytes: u64,
+    /// Total size of the downloads, if all of them are known
+    pub total_bytes: Option<u64>,
+    pub extraction: ExtractionProgress,
+    /// Overall progress in percent, if it can be estimated
+    pub percentage: Option<f32>,
+}
+
+impl ImportJobProgress {
+    fn new(job: &ImportJob, downloads: &[Progress]) -> Self {
+        let total_bytes = downloads
+            .iter()
+            .map(|progress| progress.total_size)
+            .sum::<Option<u64>>();
+        let downloaded_bytes = downloads.iter().map(|progress| progress.downloaded).sum();
+        let count = |f: fn(&DownloadStatus) -> bool| {
+            downloads
+                .iter()
+                .filter(|progress| f(&progress.status))
+                .count()
+        };
+
+        // Downloading and extracting each make up a share of the progress bar, if the
+        // job has anything to do for them
+        let mut shares = Vec::new();
+        if !downloads.is_empty() {
+            shares.push(
+                total_bytes
+                    .filter(|total| *total > 0)
+                    .map(|total| downloaded_bytes as f32 / total as f32),
+            );
+        }
+        if job.extraction.archives > 0 {
+            shares.push(Some(
+                job.extraction.extracted as f32 / job.extraction.archives as f32,
+            ));
+        }
+        let percentage = if job.status == ImportJobStatus::Completed {
+            Some(100.0)
+        } else if shares.is_empty() {
+            None
+        } else {
+            shares
+                .iter()
+                .copied()
+                .sum::<Option<f32>>()
+                .map(|sum| (sum / shares.len() as f32 * 100.0).min(100.0))
+        };
+
+        Self {
+            id: job.id,
+            status: job.status.clone(),
+            downloads: downloads.len(),
+            downloads_completed: count(|status| *status == DownloadStatus::Completed),
+            downloads_failed: count(|status| matches!(status, DownloadStatus::Failed(_))),
+            downloaded_bytes,
+            total_bytes,
+            extraction: job.extraction.clone(),
+            percentage,
+        }
+    }
+}
+
+/// Queue a snapshot of a job to be saved
+fn persist(job: ImportJob) {
+    if let Some(tx) = PERSIST_TX.as_ref() {
+        let _ = tx.send(job);
+    }
+}
+
+/// How an import job is stored in the database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImportJobRecord {
+    /// Status name, for filtering without matching on the failure message
+    state: String,
+    job: ImportJob,
+}
+
+/// Filter for the import history
+#[derive(Debug, Clone, Default)]
+pub struct ImportHistoryFilter {
+    /// One of [`ImportJobStatus::NAMES`]
+    pub status: Option<String>,
+    pub importer: Option<String>,
+    /// Only jobs created from this time on
+    pub since: Option<DateTime<Utc>>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+/// A page of the import history
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportHistoryPage {
+    /// Number of jobs matching the filter, across all pages
+    pub total: usize,
+    pub limit: usize,
+    pub offset: usize,
+    pub jobs: Vec<ImportJob>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountRow {
+    count: usize,
+}
+
+impl ImportJob {
+    /// Save the job to the database
+    async fn save(&self) -> surrealdb::Result<()> {
+        let record = ImportJobRecord {
+            state: self.status.name().to_string(),
+            job: self.clone(),
+        };
+        let id = self.id.to_string();
+        let _: Option<ImportJobRecord> = with_retry("import_job.save", || async {
+            DB.upsert((TABLE, id.as_str()))
+                .content(record.clone())
+                .await
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Get a job, from memory if it's still known or from the history otherwise
+    pub async fn load(id: &Ulid) -> surrealdb::Result<Option<Self>> {
+        if let Some(job) = Self::get(id) {
+            return Ok(Some(job));
+        }
+        let record: Option<ImportJobRecord> = DB.select((TABLE, id.to_string())).await?;
+        Ok(record.map(|record| record.job))
+    }
+
+    /// Search past and current jobs, newest first
+    pub async fn history(filter: &ImportHistoryFilter) -> surrealdb::Result<ImportHistoryPage> {
+        let mut conditions = Vec::new();
+        if filter.status.is_some() {
+            conditions.push("state = $status");
+        }
+        if filter.importer.is_some() {
+            conditions.push("job.importer = $importer");
+        }
+        if filter.since.is_some() {
+            conditions.push("<datetime> job.created_at >= <datetime> $since");
+        }
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+
+        // Records are keyed by the job's ULID, so they sort by creation time
+        let query = format!(
+            "SELECT count() FROM {TABLE}{where_clause} GROUP ALL;
+            SELECT * FROM {TABLE}{where_clause} ORDER BY id DESC LIMIT $limit START $offset;"
+        );
+        let mut response = DB
+            .query(query)
+            .bind(("status", filter.status.clone()))
+            .bind(("importer", filter.importer.clone()))
+            .bind(("since", filter.since))
+            .bind(("limit", filter.limit))
+            .bind(("offset", filter.offset))
+            .await?;
+        let count: Option<CountRow> = response.take(0)?;
+        let records: Vec<ImportJobRecord> = response.take(1)?;
+
+        Ok(ImportHistoryPage {
+            total: count.map(|row| row.count).unwrap_or_default(),
+            limit: filter.limit,
+            offset: filter.offset,
+            jobs: records.into_iter().map(|record| record.job).collect(),
+        })
+    }
+
+    /// Mark jobs that were still running when the server stopped as failed
+    pub async fn fail_interrupted() -> surrealdb::Result<usize> {
+        let mut response = DB
+            .query(format!(
+                "SELECT VALUE job FROM {TABLE} WHERE state NOT IN ['completed', 'failed', 'cancelled']"
+            ))
+            .await?;
+        let jobs: Vec<ImportJob> = response.take(0)?;
+        for mut job in jobs.iter().cloned() {
+            job.status = ImportJobStatus::Failed("Interrupted by a server restart".to_string());
+            job.updated_at = Utc::now();
+            job.save().await?;
+        }
+        Ok(jobs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(status: ImportJobStatus, extraction: ExtractionProgress) -> ImportJob {
+        ImportJob {
+            id: Ulid::new(),
+            importer: "test".to_string(),
+            title_id: None,
+            status,
+            retries: 0,
+            children: Vec::new(),
+            extraction,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn download(downloaded: u64, total_size: Option<u64>, status: DownloadStatus) -> Progress {
+        Progress {
+            total_size,
+            downloaded,
+            status,
+            file_path: None,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_import_job_progress() {
+        let downloads = [
+            download(100, Some(100), DownloadStatus::Completed),
+            download(50, Some(300), DownloadStatus::Downloading),
+            download(0, Some(0), DownloadStatus::Failed("404".to_string())),
+        ];
+        let running = job(ImportJobStatus::Running, ExtractionProgress::default());
+        let progress = ImportJobProgress::new(&running, &downloads);
+        assert_eq!(progress.downloads, 3);
+        assert_eq!(progress.downloads_completed, 1);
+        assert_eq!(progress.downloads_failed, 1);
+        assert_eq!(progress.downloaded_bytes, 150);
+        assert_eq!(progress.total_bytes, Some(400));
+        assert_eq!(progress.percentage, Some(37.5));
+
+        // Downloading and extracting count for half of the progress each
+        let extracting = job(
+            ImportJobStatus::Running,
+            ExtractionProgress {
+                archives: 2,
+                extracted: 1,
+            },
+        );
+        let downloads = [download(100, Some(100), DownloadStatus::Completed)];
+        let progress = ImportJobProgress::new(&extracting, &downloads);
+        assert_eq!(progress.percentage, Some(75.0));
+
+        // The size of a download isn't known yet
+        let downloads = [download(100, None, DownloadStatus::Downloading)];
+        let progress = ImportJobProgress::new(&running, &downloads);
+        assert_eq!(progress.total_bytes, None);
+        assert_eq!(progress.percentage, None);
+
+        let completed = job(ImportJobStatus::Completed, ExtractionProgress::default());
+        assert_eq!(
+            ImportJobProgress::new(&completed, &[]).percentage,
+            Some(100.0)
+        );
+    }
+}
@@ -125,22 +125,38 @@
 //!
 
 use async_zip::tokio::read::seek::ZipFileReader;
-use downloader::{DOWNLOAD_QUEUE, DownloadQueueItem};
+use downloader::{DOWNLOAD_QUEUE, DownloadHandle, DownloadQueueItem};
 use futures::future::join_all;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tokio::{fs::File, io::BufReader};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{debug, info};
-
-use crate::nsp::read_cnmt_merged;
+use tracing::{debug, info, warn};
+use ulid::Ulid;
+
+use crate::backend::kv_config::KvOptExt;
+use crate::nsp::read_cnmt_limited;
+use crate::title_kind::TitleKind;
+use downloader::DownloadStatus;
+use job::{ImportJob, ImportJobConfig, ImportJobStatus};
+pub mod alumulemu;
+pub mod archive;
+pub mod clean;
 pub mod dbi;
 pub mod downloader;
+pub mod host_policy;
 pub mod import_utils;
+pub mod job;
 pub mod not_ultranx;
+pub mod nsz;
+pub mod quarantine;
 pub mod registry;
+pub mod repository;
+pub mod split;
+pub mod staging;
 pub mod tests;
+pub mod trash;
 pub mod url;
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -178,9 +194,44 @@ pub enum ImportError {
     #[error("Game Not Found in importer source")]
     GameNotFound,
 
+    #[error("Importer not found: {0}")]
+    ImporterNotFound(String),
+
+    #[error("Invalid import request: {0}")]
+    InvalidRequest(#[from] serde_json::Error),
+
+    #[error("Download not allowed: {0}")]
+    HostNotAllowed(#[from] host_policy::HostPolicyError),
+
     #[error("Zip error: {0}")]
     ZipError(#[from] async_zip::error::ZipError),
 
+    #[error(transparent)]
+    InsufficientSpace(#[from] crate::storage::InsufficientSpaceError),
+
+    #[error("Unsupported archive format: {0:?}")]
+    UnsupportedArchive(PathBuf),
+
+    #[error("Checksum mismatch: expected SHA-256 {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error(
+        "Failed to move {failed:?} from {from:?} to {to:?}: {source}. {} files were moved, {} are still in the source",
+        moved.len(),
+        remaining.len()
+    )]
+    PartialMove {
+        from: PathBuf,
+        to: PathBuf,
+        /// The file that couldn't be moved
+        failed: PathBuf,
+        /// Destinations of the files that were moved before the failure
+        moved: Vec<PathBuf>,
+        /// Files that are still at the source, including the one that failed
+        remaining: Vec<PathBuf>,
+        source: std::io::Error,
+    },
+
     // Mutex errors
     #[error("Mutex lock error: {0}")]
     MutexError(String),
@@ -196,44 +247,149 @@ impl<T> From<std::sync::PoisonError<std::sync::MutexGuard<'_, T>>> for ImportErr
         ImportError::MutexError(err.to_string())
     }
 }
+
+/// Where a file is copied to before it's renamed to `dest`: a hidden file next to it, with
+/// an extension the scanner and watcher don't pick up
+fn partial_copy_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".{name}.alumulemu-part"))
+}
+
+/// Move a single file, falling back to copying it for cross-filesystem moves.
+///
+/// Copies are written next to `dest` under a hidden name and renamed into place once
+/// complete, so a partial file never shows up at `dest`. The copy's size is checked against
+/// the source before the source is deleted. If the source can't be deleted, the copy is
+/// removed again so the file is only in one place.
+async fn move_file(src: &Path, dest: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(src, dest).await.is_ok() {
+        return Ok(());
+    }
+
+    // Make sure parent directory exists
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let partial = partial_copy_path(dest);
+    let expected = tokio::fs::metadata(src).await?.len();
+    let copied = match tokio::fs::copy(src, &partial).await {
+        Ok(copied) => copied,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    };
+    let written = tokio::fs::metadata(&partial).await?.len();
+    if copied != expected || written != expected {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(std::io::Error::other(format!(
+            "copy is incomplete, expected {expected} bytes but wrote {written}"
+        )));
+    }
+    // Same directory, so this is atomic
+    if let Err(e) = tokio::fs::rename(&partial, dest).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    if let Err(e) = tokio::fs::remove_file(src).await {
+        let _ = tokio::fs::remove_file(dest).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
 /// Recursively move a path to another location, handling cross-filesystem moves.
 ///
 /// This is a more robust version of `tokio::fs::rename` that can handle cross-filesystem moves
-/// by manually copying files and directories.
+/// by manually copying files and directories. Copies are verified before their source is
+/// deleted, and the move stops at the first file that fails, returning
+/// [`ImportError::PartialMove`] with what was and wasn't moved. The source directory is only
+/// removed once everything in it was moved.
 pub async fn recursive_move(src: &Path, dest: &Path) -> Result<()> {
     debug!(from = ?src, to = ?dest, "Moving path");
 
-    if src.is_dir() {
-        // Try atomic rename (fast path)
-        if tokio::fs::rename(src, dest).await.is_ok() {
-            return Ok(());
-        }
+    if !src.is_dir() {
+        move_file(src, dest)
+            .await
+            .map_err(|source| ImportError::PartialMove {
+                from: src.to_path_buf(),
+                to: dest.to_path_buf(),
+                failed: src.to_path_buf(),
+                moved: Vec::new(),
+                remaining: vec![src.to_path_buf()],
+                source,
+            })?;
+        debug!(from = ?src, to = ?dest, "Path moved successfully");
+        return Ok(());
+    }
 
-        // Manual copy for cross-filesystem moves
-        tokio::fs::create_dir_all(dest).await?;
+    // Try atomic rename (fast path)
+    if tokio::fs::rename(src, dest).await.is_ok() {
+        return Ok(());
+    }
 
-        let walker = jwalk::WalkDir::new(src);
-        for entry in walker.into_iter().filter_map(|entry| entry.ok()) {
-            let path = entry.path();
-            let relative = path.strip_prefix(src).unwrap();
-            let dest_path = dest.join(relative);
+    // Manual copy for cross-filesystem moves. Walk everything up front, so an unreadable
+    // directory stops the move before anything is touched.
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for entry in jwalk::WalkDir::new(src) {
+        let entry = entry.map_err(|e| ImportError::PartialMove {
+            from: src.to_path_buf(),
+            to: dest.to_path_buf(),
+            failed: e
+                .path()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| src.to_path_buf()),
+            moved: Vec::new(),
+            remaining: Vec::new(),
+            source: e.into(),
+        })?;
+        if entry.file_type().is_dir() {
+            dirs.push(entry.path());
+        } else {
+            files.push(entry.path());
+        }
+    }
 
-            if path.is_dir() {
-                tokio::fs::create_dir_all(&dest_path).await?;
-            } else if tokio::fs::rename(&path, &dest_path).await.is_err() {
-                tokio::fs::copy(&path, &dest_path).await?;
-                tokio::fs::remove_file(&path).await?;
-            }
+    tokio::fs::create_dir_all(dest).await?;
+
+    let mut moved = Vec::with_capacity(files.len());
+    for (i, path) in files.iter().enumerate() {
+        let relative = path.strip_prefix(src).unwrap();
+        let dest_path = dest.join(relative);
+
+        if let Err(source) = move_file(path, &dest_path).await {
+            let remaining = files[i..].to_vec();
+            tracing::error!(
+                from = ?src,
+                to = ?dest,
+                failed = ?path,
+                moved = ?moved,
+                remaining = ?remaining,
+                "Move stopped partway: {}",
+                source
+            );
+            return Err(ImportError::PartialMove {
+                from: src.to_path_buf(),
+                to: dest.to_path_buf(),
+                failed: path.clone(),
+                moved,
+                remaining,
+                source,
+            });
         }
+        moved.push(dest_path);
+    }
 
-        tokio::fs::remove_dir_all(src).await?;
-    } else if tokio::fs::rename(src, dest).await.is_err() {
-        // Make sure parent directory exists
-        if let Some(parent) = dest.parent() {
-            tokio::fs::create_dir_all(parent).await?;
+    // Everything was moved, only (now empty) directories are left. Remove them deepest
+    // first, without `remove_dir_all`, so anything that showed up in the meantime survives.
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        if let Err(e) = tokio::fs::remove_dir(&dir).await {
+            tracing::warn!(dir = ?dir, "Failed to remove moved directory: {}", e);
         }
-        tokio::fs::copy(src, dest).await?;
-        tokio::fs::remove_file(src).await?;
     }
 
     debug!(from = ?src, to = ?dest, "Path moved successfully");
@@ -250,6 +406,58 @@ pub fn download_path() -> PathBuf {
     path
 }
 
+/// Restore the downloads an earlier run of the server didn't finish, and import each one
+/// once it's done.
+///
+/// The import jobs they belonged to were failed by the restart, so they're imported
+/// without one. Returns how many downloads were restored.
+pub async fn resume_downloads() -> color_eyre::Result<usize> {
+    let downloads = downloader::restore_downloads().await?;
+    let count = downloads.len();
+    for (url, download) in downloads {
+        import_queued_download(url, download, "restored");
+    }
+    Ok(count)
+}
+
+/// Queue a failed download again and import it once it's done, without an import job.
+/// Returns the ID of the new download
+pub async fn retry_download(id: &Ulid) -> std::result::Result<Ulid, downloader::RetryError> {
+    let (url, download) = downloader::retry_download(id).await?;
+    let new_id = download.id;
+    import_queued_download(url, download, "retried");
+    Ok(new_id)
+}
+
+/// Queue every failed download again, see [`retry_download`]. Returns the IDs of the new
+/// downloads
+pub async fn retry_failed_downloads() -> color_eyre::Result<Vec<Ulid>> {
+    let downloads = downloader::retry_failed_downloads().await?;
+    Ok(downloads
+        .into_iter()
+        .map(|(url, download)| {
+            let id = download.id;
+            import_queued_download(url, download, "retried");
+            id
+        })
+        .collect())
+}
+
+/// Import a download in the background once it's done
+fn import_queued_download(url: String, download: DownloadHandle, kind: &'static str) {
+    let source = ImportSource::QueuedHttp {
+        url,
+        download,
+        extract: None,
+    };
+    tokio::spawn(async move {
+        match source.import(None).await {
+            Ok(files) => info!(files = files.len(), "Imported {} download", kind),
+            Err(e) => warn!("Failed to import {} download: {}", kind, e),
+        }
+    });
+}
+
 pub enum ImportSource {
     /// A single local file to import
     Local(PathBuf),
@@ -263,6 +471,8 @@ pub enum ImportSource {
     RemoteHttp {
         url: String,
         headers: Option<HashMap<String, String>>,
+        /// SHA-256 the downloaded file is verified against, if the importer knows it
+        expected_sha256: Option<String>,
     },
     /// A remote archive file accessed via HTTP that will be extracted
     RemoteHttpArchive {
@@ -273,14 +483,28 @@ pub enum ImportSource {
     RemoteHttpAuto {
         url: String,
         headers: Option<HashMap<String, String>>,
+        /// SHA-256 the downloaded file is verified against, if the importer knows it
+        expected_sha256: Option<String>,
+    },
+    /// The files of a Tinfoil index that aren't in the library yet, see
+    /// [`repository::RepositoryImporter`]
+    Repository {
+        index_url: String,
+        /// Only import files of these titles, all of them if it's empty
+        title_ids: Vec<String>,
     },
-    /// (Not implemented) Import from a repository
-    Repository,
 
     RemoteHttpAutoList {
         urls: Vec<String>,
         headers: Option<HashMap<String, String>>,
     },
+    /// A remote file already added to the download queue, see [`ImportSource::enqueue_http`].
+    /// It's extracted if `extract` is set, or if it looks like an archive when unset
+    QueuedHttp {
+        url: String,
+        download: DownloadHandle,
+        extract: Option<bool>,
+    },
 }
 
 impl ImportSource {
@@ -292,14 +516,32 @@ impl ImportSource {
         Self::RemoteHttpAuto {
             url: url.into(),
             headers,
+            expected_sha256: None,
         }
     }
 
-    pub async fn process(&self) -> Result<(Vec<PathBuf>, Option<tempfile::TempDir>)> {
+    /// Fetch and unpack the source, returning the files to import.
+    ///
+    /// If `job_id` is set, the outcome of each download is recorded on that import job.
+    /// Split NSP folders (numbered `00`, `01`, ... parts) are joined back into single files.
+    pub async fn process(
+        &self,
+        job_id: Option<Ulid>,
+    ) -> Result<(Vec<PathBuf>, Option<tempfile::TempDir>)> {
+        let (files, temp_dir) = self.fetch(job_id).await?;
+        let files = split::reassemble_split_folders(files).await?;
+        Ok((files, temp_dir))
+    }
+
+    /// Fetch and unpack the source, without any post-processing of the resulting files
+    async fn fetch(
+        &self,
+        job_id: Option<Ulid>,
+    ) -> Result<(Vec<PathBuf>, Option<tempfile::TempDir>)> {
         match self {
             ImportSource::Local(path) => Ok((vec![path.to_path_buf()], None)),
             ImportSource::LocalArchive(path) => {
-                let result = self.extract_archive(path).await?;
+                let result = self.extract_archive(path, None, job_id).await?;
                 // Delete the archive after successful extraction
                 if tokio::fs::remove_file(path).await.is_err() {
                     debug!(archive = ?path, "Failed to remove archive file after extraction");
@@ -318,13 +560,23 @@ impl ImportSource {
                     .collect();
                 Ok((files, None))
             }
-            ImportSource::RemoteHttp { url, headers } => {
-                let path = Self::download_http(url, headers.clone()).await?;
+            ImportSource::RemoteHttp {
+                url,
+                headers,
+                expected_sha256,
+            } => {
+                let path = Self::download_http_for_job(
+                    url,
+                    headers.clone(),
+                    expected_sha256.clone(),
+                    job_id,
+                )
+                .await?;
                 Ok((vec![path], None))
             }
             ImportSource::RemoteHttpArchive { url, headers } => {
-                let path = Self::download_http(url, headers.clone()).await?;
-                let result = self.extract_archive(&path).await?;
+                let path = Self::download_http_for_job(url, headers.clone(), None, job_id).await?;
+                let result = self.extract_archive(&path, None, job_id).await?;
                 // Delete the downloaded archive after successful extraction
                 if tokio::fs::remove_file(&path).await.is_err() {
                     debug!(archive = ?path, "Failed to remove downloaded archive file after extraction");
@@ -333,15 +585,23 @@ impl ImportSource {
                 }
                 Ok(result)
             }
-            ImportSource::RemoteHttpAuto { url, headers } => {
-                let path = Self::download_http(url, headers.clone()).await?;
-
-                // Check if the downloaded file appears to be an archive based on extension
-                let is_archive = self.is_archive_file(&path);
-
-                if is_archive {
-                    info!(path = ?path, "Auto-detected archive file, extracting");
-                    let result = self.extract_archive(&path).await?;
+            ImportSource::RemoteHttpAuto {
+                url,
+                headers,
+                expected_sha256,
+            } => {
+                let path = Self::download_http_for_job(
+                    url,
+                    headers.clone(),
+                    expected_sha256.clone(),
+                    job_id,
+                )
+                .await?;
+
+                // Check if the downloaded file appears to be an archive based on its contents
+                if let Some(format) = archive::detect(&path).await {
+                    info!(path = ?path, ?format, "Auto-detected archive file, extracting");
+                    let result = self.extract_archive(&path, Some(format), job_id).await?;
                     // Delete the downloaded archive after successful extraction
                     if tokio::fs::remove_file(&path).await.is_err() {
                         debug!(archive = ?path, "Failed to remove downloaded archive file after successful extraction");
@@ -355,99 +615,130 @@ impl ImportSource {
                 }
             }
 
+            ImportSource::QueuedHttp {
+                url,
+                download,
+                extract,
+            } => {
+                let result = download.clone().wait_until_done().await;
+                if let Some(job_id) = job_id {
+                    let status = match &result {
+                        Ok(_) => DownloadStatus::Completed,
+                        Err(e) => DownloadStatus::Failed(e.clone()),
+                    };
+                    ImportJob::record_child(&job_id, url, status);
+                }
+                let path = result.map_err(|e| {
+                    ImportError::Other(color_eyre::eyre::eyre!("Download failed: {}", e))
+                })?;
+
+                let format = match extract {
+                    Some(true) => None,
+                    Some(false) => return Ok((vec![path], None)),
+                    None => match archive::detect(&path).await {
+                        Some(format) => Some(format),
+                        None => return Ok((vec![path], None)),
+                    },
+                };
+                let result = self.extract_archive(&path, format, job_id).await?;
+                if tokio::fs::remove_file(&path).await.is_err() {
+                    debug!(archive = ?path, "Failed to remove downloaded archive file after extraction");
+                } else {
+                    info!(archive = ?path, "Removed downloaded archive file after successful extraction");
+                }
+                Ok(result)
+            }
+
             ImportSource::RemoteHttpAutoList { urls, headers } => {
-                let download_futures = urls
-                    .iter()
-                    .map(|url| Self::download_http(url, headers.clone()));
-
-                let download_results = join_all(download_futures).await;
-
-                let mut downloaded_paths = Vec::new();
-                for result in download_results {
-                    match result {
-                        Ok(path) => downloaded_paths.push(path),
-                        Err(e) => {
-                            // Decide how to handle individual download errors.
-                            // Option 1: Return the first error encountered.
-                            // return Err(e);
-                            // Option 2: Log the error and continue with successful downloads.
-                            tracing::error!("Failed to download one of the URLs: {}", e);
-                            continue;
-                            // Option 3: Collect all errors.
-                            // errors.push(e); // Requires defining `errors` Vec earlier.
-                        }
-                    }
+                self.fetch_auto_list(urls, headers, job_id).await
+            }
+            ImportSource::Remote => unimplemented!(
+                "Generic Remote import source not implemented, this should be a generic remote import, but the details are not yet defined"
+            ),
+            ImportSource::Repository {
+                index_url,
+                title_ids,
+            } => {
+                let (urls, headers) =
+                    repository::repository_downloads(index_url, title_ids).await?;
+                if urls.is_empty() {
+                    info!(
+                        index_url,
+                        "The library already has every file of the repository"
+                    );
+                    return Ok((Vec::new(), None));
                 }
-                // If collecting errors (Option 3), check if errors occurred and return appropriately.
-                // if !errors.is_empty() { ... }
+                self.fetch_auto_list(&urls, &headers, job_id).await
+            }
+        }
+    }
 
-                let mut output_files = Vec::new();
-                let mut archive_paths = Vec::new();
-                let mut temp_dir = None; // We'll create this only if needed
+    /// Download a list of files, extracting the ones that look like archives
+    async fn fetch_auto_list(
+        &self,
+        urls: &[String],
+        headers: &Option<HashMap<String, String>>,
+        job_id: Option<Ulid>,
+    ) -> Result<(Vec<PathBuf>, Option<tempfile::TempDir>)> {
+        // Failed children are retried at the job level and recorded on the job,
+        // the successful ones are still imported so a single dead link
+        // doesn't throw away the rest of the batch.
+        let downloaded_paths = Self::download_all_with_retry(urls, headers, job_id).await;
+
+        let mut output_files = Vec::new();
+        let mut archive_paths = Vec::new();
+        let mut temp_dir = None; // We'll create this only if needed
+
+        // Separate archives from regular files
+        for path in downloaded_paths {
+            if let Some(format) = archive::detect(&path).await {
+                archive_paths.push((path, format));
+            } else {
+                output_files.push(path); // Add non-archives directly
+            }
+        }
 
-                // Separate archives from regular files
-                for path in downloaded_paths {
-                    if self.is_archive_file(&path) {
-                        archive_paths.push(path);
-                    } else {
-                        output_files.push(path); // Add non-archives directly
+        // Process archives concurrently if any exist
+        if !archive_paths.is_empty() {
+            let main_temp_dir = crate::util::tempdir()?; // Create one temp dir for all extractions
+            let temp_path = main_temp_dir.path().to_path_buf(); // Get path for the async block
+            if let Some(job_id) = job_id {
+                ImportJob::start_extraction(&job_id, archive_paths.len());
+            }
+
+            let extraction_futures = archive_paths.into_iter().map(|(path, format)| {
+                // Clone path and temp_path for the async block
+                let path_clone = path.clone();
+                let temp_path_clone = temp_path.clone();
+                async move {
+                    // Use the new helper function to extract to the shared temp dir
+                    let result = extract_archive_to(&path_clone, format, &temp_path_clone).await;
+                    if let (Ok(_), Some(job_id)) = (&result, job_id) {
+                        ImportJob::finish_extraction(&job_id);
                     }
+                    result
                 }
+            });
 
-                // Process archives concurrently if any exist
-                if !archive_paths.is_empty() {
-                    let main_temp_dir = crate::util::tempdir()?; // Create one temp dir for all extractions
-                    let temp_path = main_temp_dir.path().to_path_buf(); // Get path for the async block
-
-                    let extraction_futures = archive_paths.into_iter().map(|path| {
-                        // Clone path and temp_path for the async block
-                        let path_clone = path.clone();
-                        let temp_path_clone = temp_path.clone();
-                        async move {
-                            // Use the new helper function to extract to the shared temp dir
-                            extract_archive_to(&path_clone, &temp_path_clone).await
-                        }
-                    });
-
-                    let extraction_results = join_all(extraction_futures).await;
-
-                    for result in extraction_results {
-                        match result {
-                            Ok(files) => output_files.extend(files),
-                            Err(e) => {
-                                // Handle extraction errors similarly to download errors
-                                // Close the temp dir before returning the error
-                                let _ = main_temp_dir.close();
-                                return Err(e);
-                                // Or log and continue, or collect errors
-                                // tracing::error!("Failed to extract an archive: {}", e);
-                            }
-                        }
+            let extraction_results = join_all(extraction_futures).await;
+
+            for result in extraction_results {
+                match result {
+                    Ok(files) => output_files.extend(files),
+                    Err(e) => {
+                        // Handle extraction errors similarly to download errors
+                        // Close the temp dir before returning the error
+                        let _ = main_temp_dir.close();
+                        return Err(e);
+                        // Or log and continue, or collect errors
+                        // tracing::error!("Failed to extract an archive: {}", e);
                     }
-                    temp_dir = Some(main_temp_dir); // Assign the temp dir if extractions happened
                 }
-
-                Ok((output_files, temp_dir))
             }
-            ImportSource::Remote => unimplemented!(
-                "Generic Remote import source not implemented, this should be a generic remote import, but the details are not yet defined"
-            ),
-            ImportSource::Repository => unimplemented!(
-                "Repository import source not implemented, This should take in a Tinfoil index"
-            ),
+            temp_dir = Some(main_temp_dir); // Assign the temp dir if extractions happened
         }
-    }
 
-    /// Determine if a file is likely an archive based on its extension
-    fn is_archive_file(&self, path: &Path) -> bool {
-        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
-            matches!(
-                extension.to_lowercase().as_str(),
-                "zip" | "rar" | "7z" | "tar" | "gz" | "bz2" | "xz"
-            )
-        } else {
-            false
-        }
+        Ok((output_files, temp_dir))
     }
 
     pub fn new_local(path: impl Into<PathBuf>) -> Self {
@@ -472,6 +763,7 @@ impl ImportSource {
         Self::RemoteHttp {
             url: url.into(),
             headers,
+            expected_sha256: None,
         }
     }
 
@@ -486,23 +778,152 @@ impl ImportSource {
         }
     }
 
-    pub async fn download_http(
+    /// Download a single file, recording the outcome on the import job if there is one
+    async fn download_http_for_job(
         url: &str,
         headers: Option<HashMap<String, String>>,
+        expected_sha256: Option<String>,
+        job_id: Option<Ulid>,
     ) -> Result<PathBuf> {
+        let result = Self::download_http(url, headers, expected_sha256, job_id).await;
+        if let Some(job_id) = job_id {
+            let status = match &result {
+                Ok(_) => DownloadStatus::Completed,
+                Err(e) => DownloadStatus::Failed(e.to_string()),
+            };
+            ImportJob::record_child(&job_id, url, status);
+        }
+        result
+    }
+
+    /// Download a list of files concurrently, re-attempting the ones that failed
+    /// once all of them have settled, according to the [`ImportJobConfig`] retry policy.
+    ///
+    /// Returns the paths of all files that were downloaded successfully.
+    async fn download_all_with_retry(
+        urls: &[String],
+        headers: &Option<HashMap<String, String>>,
+        job_id: Option<Ulid>,
+    ) -> Vec<PathBuf> {
+        let config = ImportJobConfig::get()
+            .await
+            .unwrap_or_default()
+            .unwrap_or_default();
+
+        let mut pending = urls.to_vec();
+        let mut downloaded_paths = Vec::new();
+        let mut retries = 0;
+
+        loop {
+            let download_futures = pending
+                .iter()
+                .map(|url| Self::download_http_for_job(url, headers.clone(), None, job_id));
+            let download_results = join_all(download_futures).await;
+
+            let mut failed = Vec::new();
+            for (url, result) in pending.iter().zip(download_results) {
+                match result {
+                    Ok(path) => downloaded_paths.push(path),
+                    Err(e) => {
+                        tracing::error!(url = url, "Failed to download one of the URLs: {}", e);
+                        failed.push(url.clone());
+                    }
+                }
+            }
+
+            if failed.is_empty() {
+                break;
+            }
+
+            if job_id.is_some_and(|job_id| ImportJob::is_cancelled(&job_id)) {
+                tracing::info!(
+                    failed = failed.len(),
+                    "Import job was cancelled, not retrying"
+                );
+                break;
+            }
+
+            if retries >= config.max_retries {
+                tracing::error!(
+                    failed = failed.len(),
+                    retries = retries,
+                    "Giving up on failed downloads"
+                );
+                break;
+            }
+
+            retries += 1;
+            warn!(
+                failed = failed.len(),
+                attempt = retries,
+                max_retries = config.max_retries,
+                "Re-attempting failed downloads in {}s",
+                config.retry_delay_secs
+            );
+
+            if let Some(job_id) = job_id {
+                ImportJob::update(&job_id, |job| job.retries = retries);
+                ImportJob::set_status(&job_id, ImportJobStatus::Retrying);
+            }
+
+            tokio::time::sleep(std::time::Duration::from_secs(config.retry_delay_secs)).await;
+
+            if let Some(job_id) = job_id {
+                if ImportJob::is_cancelled(&job_id) {
+                    break;
+                }
+                ImportJob::set_status(&job_id, ImportJobStatus::Running);
+            }
+
+            pending = failed;
+        }
+
+        downloaded_paths
+    }
+
+    /// Check a URL against the host policy and add it to the download queue, tagged with
+    /// the import job it's for if there is one. The file is verified against
+    /// `expected_sha256` once downloaded, if it's set
+    pub async fn enqueue_http(
+        url: &str,
+        headers: Option<HashMap<String, String>>,
+        expected_sha256: Option<String>,
+        job_id: Option<Ulid>,
+    ) -> Result<DownloadHandle> {
+        // Fail before queueing, the downloader checks again for every redirect
+        host_policy::check_url(url).await?;
+
         let download_path = download_path();
-        let queue_item = DownloadQueueItem::new(url, download_path, headers);
+        let mut queue_item = DownloadQueueItem::new(url, download_path, headers)
+            .with_expected_sha256(expected_sha256);
+        if let Some(job_id) = job_id {
+            let title_id = ImportJob::get(&job_id).and_then(|job| job.title_id);
+            queue_item = queue_item.with_import_job(job_id, title_id);
+        }
 
         // Create a scope to ensure the lock is dropped after getting the handle
-        let mut handle = {
+        let handle = {
             let mut queue = DOWNLOAD_QUEUE.lock()?;
             queue.add(queue_item)
         };
 
         tracing::info!("Download added to queue with handle: {:?}", handle);
+        Ok(handle)
+    }
+
+    pub async fn download_http(
+        url: &str,
+        headers: Option<HashMap<String, String>>,
+        expected_sha256: Option<String>,
+        job_id: Option<Ulid>,
+    ) -> Result<PathBuf> {
+        let mut handle = Self::enqueue_http(url, headers, expected_sha256.clone(), job_id).await?;
 
         if let Ok(path) = handle.wait_until_done().await {
             Ok(path)
+        } else if let (Some(expected), Some(actual)) = (expected_sha256, handle.progress().sha256) {
+            // Failed downloads only keep their checksum when it didn't match
+            Err(ImportError::ChecksumMismatch { expected, actual })
         } else {
             Err(ImportError::Other(color_eyre::eyre::eyre!(
                 "Download failed"
@@ -510,29 +931,65 @@ impl ImportSource {
         }
     }
 
-    // Directly import to the roms directory
-    pub async fn import(&self) -> Result<()> {
+    // Directly import to the roms directory, returning where the files ended up
+    pub async fn import(&self, job_id: Option<Ulid>) -> Result<Vec<PathBuf>> {
         let config = crate::config::config();
-        let rom_dir = config.backend_config.rom_dir.clone();
-        let rom_dir = Path::new(&rom_dir);
 
-        let (output_files, temp_dir) = self.process().await?;
+        let (output_files, temp_dir) = self.process(job_id).await?;
+        if job_id.is_some_and(|job_id| ImportJob::is_cancelled(&job_id)) {
+            // Downloads that finished before the cancellation are dropped, local files aren't
+            let downloads = download_path();
+            for file in output_files
+                .iter()
+                .filter(|file| file.starts_with(&downloads))
+            {
+                let _ = tokio::fs::remove_file(file).await;
+            }
+            return Err(ImportError::Other(color_eyre::eyre::eyre!(
+                "Import was cancelled"
+            )));
+        }
+        let mut output_files = output_files;
+        if config.backend_config.decompress_nsz {
+            for file in output_files
+                .iter_mut()
+                .filter(|file| nsz::is_compressed(file))
+            {
+                match nsz::decompress(file).await {
+                    Ok(decompressed) => *file = decompressed,
+                    // The compressed file is still there, import it as it is
+                    Err(e) => tracing::warn!(file = ?file, "Failed to decompress file: {}", e),
+                }
+            }
+        }
+        let mut needed = 0;
+        for file in &output_files {
+            needed += tokio::fs::metadata(file)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+        }
+        let rom_dir = crate::storage::pick_rom_dir(needed).await;
+        let rom_dir = rom_dir.as_path();
+
+        let mut imported = Vec::with_capacity(output_files.len());
+        let mut staging = if config.backend_config.import_staging {
+            Some(staging::Staging::new(rom_dir).await?)
+        } else {
+            None
+        };
         // Process each output file
         for file in output_files {
             // 1. Try to read CNMT data to get the title ID
             let base_title_id = match file.to_str() {
-                Some(path_str) => match read_cnmt_merged(path_str) {
+                Some(path_str) => match read_cnmt_limited(path_str).await {
                     Ok(cnmt) => {
-                        let mut title_id = cnmt.get_title_id_string();
-                        let len = title_id.len();
-                        if len >= 3 {
-                            // Replace the last 3 characters with "000"
-                            title_id.replace_range(len - 3.., "000");
-                            Some(title_id)
-                        } else {
+                        let title_id = cnmt.get_title_id_string();
+                        let base_title_id = TitleKind::base_title_id(&title_id);
+                        if base_title_id.is_none() {
                             tracing::warn!(file = ?file, "Title ID too short to modify: {}", title_id);
-                            None
                         }
+                        base_title_id
                     }
                     Err(e) => {
                         tracing::warn!(file = ?file, "Failed to read CNMT data: {}", e);
@@ -551,9 +1008,6 @@ impl ImportSource {
                 None => rom_dir.to_path_buf(), // Place in root if ID couldn't be determined
             };
 
-            // Ensure the base target directory exists
-            tokio::fs::create_dir_all(&target_base_dir).await?;
-
             // 3. Determine the final destination path, preserving structure if from temp dir
             let dest = if let Some(temp_dir) = &temp_dir {
                 // Check if the file originated from the temporary directory
@@ -580,47 +1034,73 @@ impl ImportSource {
                 })?)
             };
 
-            // 4. Ensure the final destination's parent directory exists
+            // 4. Move the file, either into staging or straight into place
+            if let Some(job) = &mut staging {
+                if let Err(e) = job.stage(&file, &dest).await {
+                    tracing::error!(source = ?file, destination = ?dest, "Failed to stage file: {}", e);
+                    if let Some(job) = staging.take() {
+                        if let Err(e) = job.rollback().await {
+                            tracing::error!("Failed to roll back staged import: {}", e);
+                        }
+                    }
+                    return Err(e);
+                }
+                imported.push(dest);
+                continue;
+            }
+
             if let Some(parent) = dest.parent() {
                 tokio::fs::create_dir_all(parent).await?;
             }
-
-            // 5. Move the file
             if let Err(e) = recursive_move(&file, &dest).await {
                 tracing::error!(source = ?file, destination = ?dest, "Failed to move file: {}", e);
-                // Decide if we should return the error or just log and continue
-                // For now, let's return the error to stop the import on failure
+                // Stop the import on failure
                 return Err(e);
             } else {
                 info!(source = ?file, destination = ?dest, "Successfully moved file");
             }
+            imported.push(dest);
+        }
+
+        // 5. Everything is staged, publish it all at once
+        if let Some(staging) = staging {
+            staging.publish().await?;
         }
 
         if let Some(temp_dir) = temp_dir {
             let _ = temp_dir.close();
         }
-        Ok(())
+        Ok(imported)
     }
 
-    /// Extract an archive to a temporary directory
+    /// Extract an archive to a temporary directory, counting it on the import job if set.
+    /// The format is detected from the file if it isn't known yet.
     async fn extract_archive(
         &self,
         path: &Path,
+        format: Option<archive::ArchiveFormat>,
+        job_id: Option<Ulid>,
     ) -> Result<(Vec<PathBuf>, Option<tempfile::TempDir>)> {
         info!(archive_path = ?path, "Extracting archive to temporary directory");
+        if let Some(job_id) = job_id {
+            ImportJob::start_extraction(&job_id, 1);
+        }
 
         // Create temporary directory
         let temp_dir = crate::util::tempdir()?;
         let temp_path = temp_dir.path();
 
         // Extract the archive to the temporary directory
-        let extracted_files = extract_zip_to_directory(path, temp_path).await?;
+        let extracted_files = archive::extract_to_directory(path, format, temp_path).await?;
 
         info!(
             files_extracted = extracted_files.len(),
             temp_dir = ?temp_path,
             "Extraction complete"
         );
+        if let Some(job_id) = job_id {
+            ImportJob::finish_extraction(&job_id);
+        }
 
         Ok((extracted_files, Some(temp_dir)))
     }
@@ -628,10 +1108,15 @@ impl ImportSource {
 
 /// Helper function to extract an archive to a specific directory and delete the archive afterwards.
 /// Returns a list of paths to the extracted files.
-async fn extract_archive_to(archive_path: &Path, destination: &Path) -> Result<Vec<PathBuf>> {
+async fn extract_archive_to(
+    archive_path: &Path,
+    format: archive::ArchiveFormat,
+    destination: &Path,
+) -> Result<Vec<PathBuf>> {
     info!(archive = ?archive_path, destination = ?destination, "Extracting archive");
 
-    let extracted_files = extract_zip_to_directory(archive_path, destination).await?;
+    let extracted_files =
+        archive::extract_to_directory(archive_path, Some(format), destination).await?;
 
     // Delete the archive after successful extraction
     if tokio::fs::remove_file(archive_path).await.is_err() {
@@ -694,6 +1179,8 @@ pub async fn extract_zip_to_directory(zip_path: &Path, destination: &Path) -> Re
         }
 
         // Extract the file
+        let size = zip.file().entries()[index].uncompressed_size();
+        crate::storage::ensure_space(&path, size).await?;
         let entry_reader = zip.reader_with_entry(index).await?;
         let mut output_file = tokio::fs::File::create(&path).await?;
         let bytes_copied = tokio::io::copy(&mut entry_reader.compat(), &mut output_file).await?;
@@ -727,4 +1214,9 @@ pub trait Importer: Send + Sync + Clone + 'static {
 
     /// Return a description of this importer
     fn description(&self) -> &'static str;
+
+    /// JSON schema of the import request, for clients to build a form from
+    fn request_schema(&self) -> serde_json::Value {
+        serde_json::json!({})
+    }
 }
@@ -20,6 +20,8 @@ pub struct UltraNxImportRequest {
 #[derive(Clone)]
 pub struct NotUltranxImporter {
     client: reqwest::Client,
+    headers: HeaderMap,
+    config: UltraNxDownloadConfig,
 }
 
 #[derive(Debug)]
@@ -31,10 +33,35 @@ pub struct NotUltranxTitle {
     pub full_pkg_url: Option<String>,
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Default)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct UltraNxDownloadConfig {
     pub token: Option<String>,
     pub device: Option<NxDevice>,
+    /// How many times fetching the DLC list of a title is re-attempted before the import fails
+    #[serde(default = "default_dlc_fetch_retries")]
+    pub dlc_fetch_retries: u32,
+    /// Milliseconds to wait before the first re-attempt, doubled for every further one
+    #[serde(default = "default_dlc_fetch_retry_delay_ms")]
+    pub dlc_fetch_retry_delay_ms: u64,
+}
+
+fn default_dlc_fetch_retries() -> u32 {
+    3
+}
+
+fn default_dlc_fetch_retry_delay_ms() -> u64 {
+    1000
+}
+
+impl Default for UltraNxDownloadConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            device: None,
+            dlc_fetch_retries: default_dlc_fetch_retries(),
+            dlc_fetch_retry_delay_ms: default_dlc_fetch_retry_delay_ms(),
+        }
+    }
 }
 
 impl UltraNxDownloadConfig {
@@ -84,20 +111,22 @@ impl NotUltranxImporter {
             .unwrap_or_default()
             .unwrap_or_default();
 
-        let headers = config.headers();
-
-        let client = reqwest::Client::builder()
-            .default_headers(headers)
-            .build()
-            .unwrap_or_else(|_| reqwest::Client::new()); // Fallback to default client on build error
-
-        Self { client }
+        Self {
+            client: crate::http_client::client(),
+            headers: config.headers(),
+            config,
+        }
     }
 
     // find a div with the class "download-buttons, and find all the <a> tags within it
     pub async fn get_download_links(&self, title_id: &str) -> Result<Option<Vec<String>>> {
         let url = format!("{}/game/{}", WEB_URL, title_id);
-        let response = self.client.get(&url).send().await?;
+        let response = self
+            .client
+            .get(&url)
+            .headers(self.headers.clone())
+            .send()
+            .await?;
 
         if response.status() == 404 {
             return Ok(None);
@@ -114,25 +143,69 @@ impl NotUltranxImporter {
         Ok(Some(links))
     }
 
-    pub async fn get_dlc_links(&self, title_id: &str) -> Result<Option<Vec<String>>> {
+    /// Fetch the links of the DLC list on a title's page, once
+    async fn fetch_dlc_links(&self, title_id: &str) -> Result<Vec<String>> {
         let url = format!("{}/game/{}", WEB_URL, title_id);
-        let response = self.client.get(&url).send().await?;
-
-        if response.status() == 404 {
-            return Ok(None);
-        }
+        let response = self
+            .client
+            .get(&url)
+            .headers(self.headers.clone())
+            .send()
+            .await?
+            .error_for_status()?;
 
         let body = response.text().await?;
-        let document = Html::parse_document(&body);
-        // Using a selector inspired by the user input, targeting links within #dlcsList
-        // Assuming the user wants all links within #dlcsList, not just one specific link.
-        let selector = Selector::parse("#dlcsList a").unwrap();
-        let links: Vec<String> = document
-            .select(&selector)
-            .filter_map(|element| element.value().attr("href").map(|href| href.to_string()))
-            .collect();
+        Ok(parse_dlc_links(&body))
+    }
 
-        Ok(Some(links))
+    /// Get the links of every DLC of a title, retrying failed fetches with backoff.
+    ///
+    /// An empty list means the title has no DLC. The page offers a DLC pack whenever there
+    /// are some, so an empty list despite the pack is an error too, most likely from a change
+    /// of the page's markup, instead of silently importing the title without its DLC.
+    pub async fn get_dlc_links(&self, title: &NotUltranxTitle) -> Result<Vec<String>> {
+        let retries = self.config.dlc_fetch_retries;
+        let mut attempt = 0;
+        loop {
+            let error = match self.fetch_dlc_links(&title.title_id).await {
+                Ok(links) if !links.is_empty() => {
+                    tracing::info!(title_id = title.title_id, dlcs = links.len(), "Found DLCs");
+                    return Ok(links);
+                }
+                Ok(_) if title.dlcs_url.is_none() => {
+                    tracing::info!(title_id = title.title_id, "Title has no DLCs");
+                    return Ok(Vec::new());
+                }
+                Ok(_) => "the title has DLCs, but none were listed on its page".to_string(),
+                Err(e) => e.to_string(),
+            };
+
+            if attempt >= retries {
+                tracing::error!(
+                    title_id = title.title_id,
+                    attempts = attempt + 1,
+                    "Failed to fetch the DLC list: {}",
+                    error
+                );
+                return Err(ImportError::Other(color_eyre::eyre::eyre!(
+                    "Failed to fetch the DLC list of {}: {}",
+                    title.title_id,
+                    error
+                )));
+            }
+
+            let delay = self.config.dlc_fetch_retry_delay_ms << attempt.min(10);
+            attempt += 1;
+            tracing::warn!(
+                title_id = title.title_id,
+                attempt,
+                retries,
+                "Failed to fetch the DLC list, retrying in {}ms: {}",
+                delay,
+                error
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        }
     }
 
     pub async fn get_title(&self, title_id: &str) -> Result<Option<NotUltranxTitle>> {
@@ -161,6 +234,16 @@ impl NotUltranxImporter {
     }
 }
 
+/// Get the links within the DLC list of a title's page
+fn parse_dlc_links(body: &str) -> Vec<String> {
+    let document = Html::parse_document(body);
+    let selector = Selector::parse("#dlcsList a").unwrap();
+    document
+        .select(&selector)
+        .filter_map(|element| element.value().attr("href").map(|href| href.to_string()))
+        .collect()
+}
+
 #[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum NotUltranxDownloadType {
@@ -210,12 +293,14 @@ impl Importer for NotUltranxImporter {
             NotUltranxDownloadType::Base => Ok(ImportSource::RemoteHttp {
                 url: title.base_url,
                 headers: headers_option,
+                expected_sha256: None,
             }),
             NotUltranxDownloadType::Update => {
                 if let Some(url) = title.update_url {
                     Ok(ImportSource::RemoteHttp {
                         url,
                         headers: headers_option,
+                        expected_sha256: None,
                     })
                 } else {
                     Err(ImportError::Other(color_eyre::eyre::eyre!(
@@ -250,10 +335,9 @@ impl Importer for NotUltranxImporter {
                 }
             }
             NotUltranxDownloadType::AllSplit => {
+                let dlcs_url = self.get_dlc_links(&title).await?;
                 let basegame_url = title.base_url;
                 let update_url = title.update_url;
-                let dlcs = self.get_dlc_links(&request.title_id).await?;
-                let dlcs_url = dlcs.unwrap_or_default();
 
                 let mut all_urls = vec![basegame_url];
                 if let Some(update_url) = update_url {
@@ -282,4 +366,46 @@ impl Importer for NotUltranxImporter {
     fn description(&self) -> &'static str {
         "Imports games from the not.ultranx.ru game archive"
     }
+
+    fn request_schema(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["title_id"],
+            "properties": {
+                "title_id": {
+                    "type": "string",
+                    "description": "Title ID of the game",
+                    "pattern": "^[0-9A-Fa-f]{16}$"
+                },
+                "download_type": {
+                    "type": "string",
+                    "description": "What to download: the base game, its latest update, its DLCs, the full package, or all of them as separate files",
+                    "enum": ["base", "update", "dlcs", "fullpkg", "allsplit"],
+                    "default": "fullpkg"
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dlc_links() {
+        let body = r#"<html><body>
+            <div class="download-buttons"><a href="/dl/0100000000010000/dlcs">All DLCs</a></div>
+            <ul id="dlcsList">
+                <li><a href="/dl/0100000000011001">DLC 1</a></li>
+                <li><a href="/dl/0100000000011002">DLC 2</a></li>
+                <li><a>No link</a></li>
+            </ul>
+        </body></html>"#;
+        assert_eq!(
+            parse_dlc_links(body),
+            vec!["/dl/0100000000011001", "/dl/0100000000011002"]
+        );
+        assert!(parse_dlc_links("<html><body></body></html>").is_empty());
+    }
 }
@@ -0,0 +1,762 @@
+//! NSZ and XCZ decompression
+//!
+//! NSZ and XCZ files are NSPs and XCIs whose NCAs were replaced by NCZs. An NCZ keeps the
+//! NCA header as-is, followed by a table of the NCA's sections with their keys, and the
+//! decrypted rest of the NCA compressed with zstd, either as a single stream or as
+//! independently compressed blocks. Decompressing re-encrypts every section with the key
+//! stored in the NCZ, which gives back the original NCA.
+//!
+//! The containers around the NCAs are rebuilt as they're decompressed. Only the `.ncz`
+//! extensions in their string tables change, so the headers keep their size and are written
+//! last, once the new sizes and hashes are known. For XCZs only the secure partition holds
+//! NCZs, the other partitions are copied as they are.
+//!
+//! NCAs are named after their content ID, the start of their SHA-256, so every rebuilt NCA
+//! is checked against its name before the compressed file is deleted. Sizes and counts in
+//! the headers are checked against the file before anything is allocated for them, since
+//! the files are downloaded and can't be trusted.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use aes::Aes128;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use super::Result;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+/// The NCA header at the start of an NCZ, stored uncompressed
+const NCA_HEADER_SIZE: u64 = 0x4000;
+const SECTION_MAGIC: &[u8; 8] = b"NCZSECTN";
+const BLOCK_MAGIC: &[u8; 8] = b"NCZBLOCK";
+const SECTION_ENTRY_SIZE: usize = 0x40;
+/// Section crypto types that are encrypted with AES-CTR, plain CTR and BKTR
+const CRYPTO_CTR: [u64; 2] = [3, 4];
+const CHUNK_SIZE: usize = 0x10000;
+/// Files in game card partitions are aligned to the card's media unit
+const MEDIA_UNIT: u64 = 0x200;
+
+/// Whether the file is a compressed NSP or XCI, going by its extension
+pub fn is_compressed(path: &Path) -> bool {
+    decompressed_path(path).is_some()
+}
+
+/// Path of the decompressed file, `None` if the file isn't an NSZ or XCZ
+pub fn decompressed_path(path: &Path) -> Option<PathBuf> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "nsz" => Some(path.with_extension("nsp")),
+        "xcz" => Some(path.with_extension("xci")),
+        _ => None,
+    }
+}
+
+/// Decompress an NSZ or XCZ into an NSP or XCI next to it, deleting the compressed file
+/// once done.
+///
+/// The output is written to a temporary file first. If anything fails, it's removed again
+/// and the compressed file is left alone.
+pub async fn decompress(path: &Path) -> Result<PathBuf> {
+    let path = path.to_path_buf();
+    let output = tokio::task::spawn_blocking(move || decompress_blocking(&path))
+        .await
+        .map_err(io::Error::other)??;
+    Ok(output)
+}
+
+fn decompress_blocking(path: &Path) -> io::Result<PathBuf> {
+    let output = decompressed_path(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an NSZ or XCZ file"))?;
+    let partial = output.with_extension(format!(
+        "{}.part",
+        output
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+    ));
+    info!(source = ?path, destination = ?output, "Decompressing file");
+
+    let result = (|| {
+        let mut input = File::open(path)?;
+        let mut writer = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial)?;
+        if output.extension().is_some_and(|e| e == "xci") {
+            rebuild_xci(&mut input, &mut writer)?;
+        } else {
+            rebuild_partition(&mut input, 0, &mut writer, PartitionKind::Pfs0)?;
+        }
+        writer.sync_all()
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    std::fs::rename(&partial, &output)?;
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!(path = ?path, "Failed to remove compressed file after decompressing: {}", e);
+    }
+    info!(destination = ?output, "Decompression complete");
+    Ok(output)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PartitionKind {
+    /// The partition of NSPs
+    Pfs0,
+    /// The partitions of XCIs, with a hash of the start of each file
+    Hfs0,
+}
+
+impl PartitionKind {
+    fn magic(self) -> &'static [u8; 4] {
+        match self {
+            Self::Pfs0 => b"PFS0",
+            Self::Hfs0 => b"HFS0",
+        }
+    }
+
+    fn entry_size(self) -> usize {
+        match self {
+            Self::Pfs0 => 0x18,
+            Self::Hfs0 => 0x40,
+        }
+    }
+}
+
+/// A file of a partition, as listed in its header
+struct PartitionEntry {
+    /// Offset of the entry in the header
+    header_offset: usize,
+    /// Offset of the file's name in the header
+    name_offset: usize,
+    name: String,
+    data_offset: u64,
+    size: u64,
+}
+
+/// Read a partition's header and its entries, ordered by where their data is
+fn read_partition<R: Read + Seek>(
+    input: &mut R,
+    base: u64,
+    kind: PartitionKind,
+) -> io::Result<(Vec<u8>, Vec<PartitionEntry>)> {
+    let mut header = vec![0u8; 0x10];
+    input.seek(SeekFrom::Start(base))?;
+    input.read_exact(&mut header)?;
+    if &header[..4] != kind.magic() {
+        return Err(invalid_data("unexpected partition magic"));
+    }
+    let count = read_u32(&header, 4) as usize;
+    let string_table_size = read_u32(&header, 8) as usize;
+    let strings_start = 0x10 + count * kind.entry_size();
+    let file_size = input.seek(SeekFrom::End(0))?;
+    if (strings_start + string_table_size) as u64 > file_size.saturating_sub(base) {
+        return Err(invalid_data("partition header is larger than the file"));
+    }
+    input.seek(SeekFrom::Start(base + 0x10))?;
+    header.resize(strings_start + string_table_size, 0);
+    input.read_exact(&mut header[0x10..])?;
+
+    let mut entries = (0..count)
+        .map(|i| {
+            let header_offset = 0x10 + i * kind.entry_size();
+            let name_offset = strings_start + read_u32(&header, header_offset + 0x10) as usize;
+            let name = header
+                .get(name_offset..)
+                .ok_or_else(|| invalid_data("file name outside of the string table"))?;
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            Ok(PartitionEntry {
+                header_offset,
+                name_offset,
+                name: String::from_utf8_lossy(name).into_owned(),
+                data_offset: read_u64(&header, header_offset),
+                size: read_u64(&header, header_offset + 8),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.data_offset);
+    Ok((header, entries))
+}
+
+/// Rewrite a partition at the output's position, decompressing the NCZs in it.
+/// Returns the new header of the partition.
+fn rebuild_partition<R: Read + Seek, W: Read + Write + Seek>(
+    input: &mut R,
+    base: u64,
+    output: &mut W,
+    kind: PartitionKind,
+) -> io::Result<Vec<u8>> {
+    let (mut header, entries) = read_partition(input, base, kind)?;
+    let input_data_start = base + header.len() as u64;
+    let output_start = output.stream_position()?;
+    let output_data_start = output_start + header.len() as u64;
+    // The header is written again once the new offsets are known
+    output.write_all(&header)?;
+
+    for entry in entries {
+        let mut position = output.stream_position()?;
+        if kind == PartitionKind::Hfs0 && position % MEDIA_UNIT != 0 {
+            let padding = MEDIA_UNIT - position % MEDIA_UNIT;
+            output.write_all(&vec![0u8; padding as usize])?;
+            position += padding;
+        }
+
+        let source = input_data_start + entry.data_offset;
+        let size = if entry.name.to_lowercase().ends_with(".ncz") {
+            info!(file = entry.name, "Decompressing NCZ");
+            // Same length, so the string table keeps its size
+            let extension = entry.name_offset + entry.name.len() - 4;
+            header[extension..extension + 4].copy_from_slice(b".nca");
+            let (size, hash) = decompress_ncz(input, source, entry.size, output)?;
+            verify_content_id(&entry.name, &hash)?;
+            size
+        } else if kind == PartitionKind::Hfs0 && entry.name == "secure" {
+            rebuild_partition(input, source, output, PartitionKind::Hfs0)?;
+            output.stream_position()? - position
+        } else {
+            input.seek(SeekFrom::Start(source))?;
+            let copied = io::copy(&mut input.by_ref().take(entry.size), output)?;
+            if copied != entry.size {
+                return Err(invalid_data("partition ends in the middle of a file"));
+            }
+            copied
+        };
+
+        let at = entry.header_offset;
+        header[at..at + 8].copy_from_slice(&(position - output_data_start).to_le_bytes());
+        header[at + 8..at + 16].copy_from_slice(&size.to_le_bytes());
+        if kind == PartitionKind::Hfs0 {
+            let hashed_size = (read_u32(&header, at + 0x14) as u64).min(size);
+            let hash = hash_region(output, position, hashed_size)?;
+            header[at + 0x20..at + 0x40].copy_from_slice(&hash);
+        }
+    }
+
+    let end = output.stream_position()?;
+    output.seek(SeekFrom::Start(output_start))?;
+    output.write_all(&header)?;
+    output.seek(SeekFrom::Start(end))?;
+    Ok(header)
+}
+
+/// Content ID an NCA is named after, such as `0123456789abcdef0123456789abcdef.nca`
+fn content_id(name: &str) -> Option<[u8; 16]> {
+    let id = name.split('.').next()?;
+    if id.len() != 32 || !id.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&id[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Check a rebuilt NCA against the content ID in its name, the first half of its SHA-256
+fn verify_content_id(name: &str, hash: &[u8; 32]) -> io::Result<()> {
+    let id = content_id(name)
+        .ok_or_else(|| invalid_data(&format!("{name} isn't named after a content ID")))?;
+    if hash[..16] != id {
+        return Err(invalid_data(&format!(
+            "{name} doesn't match its content ID after decompressing"
+        )));
+    }
+    Ok(())
+}
+
+/// SHA-256 of a region of what was written so far
+fn hash_region<W: Read + Seek>(output: &mut W, offset: u64, len: u64) -> io::Result<[u8; 32]> {
+    let end = output.stream_position()?;
+    output.seek(SeekFrom::Start(offset))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut output.by_ref().take(len), &mut hasher)?;
+    output.seek(SeekFrom::Start(end))?;
+    Ok(hasher.finalize().into())
+}
+
+/// Rewrite an XCZ as an XCI, patching the card header for the new root partition
+fn rebuild_xci<R: Read + Seek, W: Read + Write + Seek>(
+    input: &mut R,
+    output: &mut W,
+) -> io::Result<()> {
+    // Full dumps have a key area in front of the card header
+    let mut magic = [0u8; 4];
+    let mut header_start = None;
+    for start in [0, 0x1000] {
+        input.seek(SeekFrom::Start(start + 0x100))?;
+        input.read_exact(&mut magic)?;
+        if &magic == b"HEAD" {
+            header_start = Some(start);
+            break;
+        }
+    }
+    let header_start = header_start.ok_or_else(|| invalid_data("no XCI header found"))?;
+
+    let mut card_header = [0u8; 0x200];
+    input.seek(SeekFrom::Start(header_start))?;
+    input.read_exact(&mut card_header)?;
+    let root_offset = header_start + read_u64(&card_header, 0x130);
+
+    // Everything in front of the root partition is kept as it is
+    input.seek(SeekFrom::Start(0))?;
+    output.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(&mut input.by_ref().take(root_offset), output)?;
+    if copied != root_offset {
+        return Err(invalid_data("XCI ends before its root partition"));
+    }
+    let root_header = rebuild_partition(input, root_offset, output, PartitionKind::Hfs0)?;
+
+    // The card's data ends on a whole media unit
+    let mut end = output.stream_position()?;
+    if end % MEDIA_UNIT != 0 {
+        let padding = MEDIA_UNIT - end % MEDIA_UNIT;
+        output.write_all(&vec![0u8; padding as usize])?;
+        end += padding;
+    }
+    let valid_data_end = ((end - header_start) / MEDIA_UNIT - 1) as u32;
+    card_header[0x118..0x11C].copy_from_slice(&valid_data_end.to_le_bytes());
+    card_header[0x138..0x140].copy_from_slice(&(root_header.len() as u64).to_le_bytes());
+    card_header[0x140..0x160].copy_from_slice(&Sha256::digest(&root_header));
+    output.seek(SeekFrom::Start(header_start))?;
+    output.write_all(&card_header)?;
+    output.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// A section of the NCA, as listed in the NCZ
+struct NczSection {
+    offset: u64,
+    size: u64,
+    crypto_type: u64,
+    key: [u8; 16],
+    counter: [u8; 16],
+}
+
+/// Decompress an NCZ at `offset` of the input, writing the NCA to the output's position.
+/// Returns the size of the NCA and its SHA-256.
+fn decompress_ncz<R: Read + Seek, W: Write>(
+    input: &mut R,
+    offset: u64,
+    size: u64,
+    output: &mut W,
+) -> io::Result<(u64, [u8; 32])> {
+    input.seek(SeekFrom::Start(offset))?;
+    let mut reader = input.by_ref().take(size);
+    let mut hasher = Sha256::new();
+
+    let mut nca_header = vec![0u8; NCA_HEADER_SIZE as usize];
+    reader.read_exact(&mut nca_header)?;
+    output.write_all(&nca_header)?;
+    hasher.update(&nca_header);
+
+    let mut table = [0u8; 0x10];
+    reader.read_exact(&mut table)?;
+    if &table[..8] != SECTION_MAGIC {
+        return Err(invalid_data("missing NCZ section table"));
+    }
+    let count = read_u64(&table, 8) as usize;
+    if count == 0 || count > 0x100 {
+        return Err(invalid_data("invalid NCZ section count"));
+    }
+    let mut entries = vec![0u8; count * SECTION_ENTRY_SIZE];
+    reader.read_exact(&mut entries)?;
+    let mut sections: Vec<NczSection> = entries
+        .chunks_exact(SECTION_ENTRY_SIZE)
+        .map(|entry| NczSection {
+            offset: read_u64(entry, 0),
+            size: read_u64(entry, 8),
+            crypto_type: read_u64(entry, 0x10),
+            key: entry[0x20..0x30].try_into().unwrap(),
+            counter: entry[0x30..0x40].try_into().unwrap(),
+        })
+        .collect();
+    // Data between the header and the first section is stored unencrypted
+    if sections[0].offset > NCA_HEADER_SIZE {
+        sections.insert(
+            0,
+            NczSection {
+                offset: NCA_HEADER_SIZE,
+                size: sections[0].offset - NCA_HEADER_SIZE,
+                crypto_type: 1,
+                key: [0; 16],
+                counter: [0; 16],
+            },
+        );
+    }
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    let remaining = reader.limit() + magic.len() as u64;
+    let body_start = offset + size - remaining;
+    let input = reader.into_inner();
+    input.seek(SeekFrom::Start(body_start))?;
+    let body = input.take(remaining);
+    let mut body: Box<dyn Read + '_> = if &magic == BLOCK_MAGIC {
+        Box::new(BlockReader::new(body, remaining)?)
+    } else {
+        Box::new(zstd::stream::read::Decoder::new(body)?)
+    };
+
+    let mut written = NCA_HEADER_SIZE;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    for section in sections {
+        let mut cipher = CRYPTO_CTR
+            .contains(&section.crypto_type)
+            .then(|| section_cipher(&section));
+        let mut remaining = section.size;
+        while remaining > 0 {
+            let len = remaining.min(CHUNK_SIZE as u64) as usize;
+            body.read_exact(&mut chunk[..len])
+                .map_err(|_| invalid_data("NCZ ends before its last section"))?;
+            if let Some(cipher) = &mut cipher {
+                cipher.apply_keystream(&mut chunk[..len]);
+            }
+            output.write_all(&chunk[..len])?;
+            hasher.update(&chunk[..len]);
+            remaining -= len as u64;
+        }
+        written += section.size;
+    }
+    Ok((written, hasher.finalize().into()))
+}
+
+/// AES-CTR cipher positioned at the start of a section.
+///
+/// The upper half of the counter is the section's nonce, the lower half the offset in the
+/// NCA in AES blocks.
+fn section_cipher(section: &NczSection) -> Aes128Ctr {
+    let mut iv = [0u8; 16];
+    iv[..8].copy_from_slice(&section.counter[..8]);
+    iv[8..].copy_from_slice(&(section.offset >> 4).to_be_bytes());
+    Aes128Ctr::new(&section.key.into(), &iv.into())
+}
+
+/// Reader over the blocks of a block compressed NCZ.
+///
+/// Blocks that didn't get smaller when compressed are stored as they are.
+struct BlockReader<R> {
+    inner: R,
+    block_size: u64,
+    decompressed_size: u64,
+    compressed_sizes: Vec<u32>,
+    /// Bytes of the input left for the blocks
+    available: u64,
+    next_block: usize,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> BlockReader<R> {
+    /// Read the block header from the input, which has `available` bytes left
+    fn new(mut inner: R, available: u64) -> io::Result<Self> {
+        let mut header = [0u8; 0x18];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != BLOCK_MAGIC {
+            return Err(invalid_data("missing NCZ block header"));
+        }
+        let exponent = header[0xB];
+        if !(14..=32).contains(&exponent) {
+            return Err(invalid_data("invalid NCZ block size"));
+        }
+        let block_size = 1u64 << exponent;
+        let count = read_u32(&header, 0xC) as usize;
+        let decompressed_size = read_u64(&header, 0x10);
+        let table_end = header.len() as u64 + count as u64 * 4;
+        if count as u64 > decompressed_size.div_ceil(block_size) || table_end > available {
+            return Err(invalid_data("invalid NCZ block count"));
+        }
+        let mut sizes = vec![0u8; count * 4];
+        inner.read_exact(&mut sizes)?;
+
+        Ok(Self {
+            inner,
+            block_size,
+            decompressed_size,
+            compressed_sizes: sizes
+                .chunks_exact(4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+                .collect(),
+            available: available - table_end,
+            next_block: 0,
+            block: Vec::new(),
+            position: 0,
+        })
+    }
+
+    fn read_block(&mut self) -> io::Result<()> {
+        let start = self.next_block as u64 * self.block_size;
+        let expected = self
+            .block_size
+            .min(self.decompressed_size.saturating_sub(start)) as usize;
+        let compressed_size = self.compressed_sizes[self.next_block] as usize;
+        // Blocks that don't get smaller are stored as they are, never larger
+        if compressed_size > expected || compressed_size as u64 > self.available {
+            return Err(invalid_data("invalid NCZ block size"));
+        }
+        self.available -= compressed_size as u64;
+
+        let mut compressed = vec![0u8; compressed_size];
+        self.inner.read_exact(&mut compressed)?;
+        self.block = if compressed_size < expected {
+            zstd::bulk::decompress(&compressed, expected)?
+        } else {
+            compressed.truncate(expected);
+            compressed
+        };
+        self.next_block += 1;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.block.len() {
+            if self.next_block == self.compressed_sizes.len() {
+                return Ok(0);
+            }
+            self.read_block()?;
+        }
+        let len = buf.len().min(self.block.len() - self.position);
+        buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: [u8; 16] = [0x11; 16];
+    const COUNTER: [u8; 16] = [0x22; 16];
+
+    /// A fake NCA: header, an unencrypted gap and one CTR encrypted section, with the NCZ
+    /// for it compressed either way
+    fn test_ncz(block: bool) -> (Vec<u8>, Vec<u8>) {
+        let header = vec![0xAA; NCA_HEADER_SIZE as usize];
+        let gap = vec![0xBB; 0x200];
+        let plain: Vec<u8> = (0..0x30000u32).map(|i| (i % 251) as u8).collect();
+        let section = NczSection {
+            offset: NCA_HEADER_SIZE + gap.len() as u64,
+            size: plain.len() as u64,
+            crypto_type: 3,
+            key: KEY,
+            counter: COUNTER,
+        };
+
+        let mut encrypted = plain.clone();
+        section_cipher(&section).apply_keystream(&mut encrypted);
+        let nca = [header.clone(), gap.clone(), encrypted].concat();
+
+        let mut ncz = header;
+        ncz.extend_from_slice(SECTION_MAGIC);
+        ncz.extend_from_slice(&1u64.to_le_bytes());
+        ncz.extend_from_slice(&section.offset.to_le_bytes());
+        ncz.extend_from_slice(&section.size.to_le_bytes());
+        ncz.extend_from_slice(&section.crypto_type.to_le_bytes());
+        ncz.extend_from_slice(&[0; 8]);
+        ncz.extend_from_slice(&KEY);
+        ncz.extend_from_slice(&COUNTER);
+
+        let body = [gap, plain].concat();
+        if block {
+            let exponent = 16u8;
+            let blocks: Vec<Vec<u8>> = body
+                .chunks(1 << exponent)
+                .enumerate()
+                // Store the first block as it is, like blocks that don't compress
+                .map(|(i, chunk)| match i {
+                    0 => chunk.to_vec(),
+                    _ => zstd::bulk::compress(chunk, 3).unwrap(),
+                })
+                .collect();
+            ncz.extend_from_slice(BLOCK_MAGIC);
+            ncz.extend_from_slice(&[2, 1, 0, exponent]);
+            ncz.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+            ncz.extend_from_slice(&(body.len() as u64).to_le_bytes());
+            for block in &blocks {
+                ncz.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            }
+            ncz.extend(blocks.concat());
+        } else {
+            ncz.extend(zstd::stream::encode_all(body.as_slice(), 3).unwrap());
+        }
+        (ncz, nca)
+    }
+
+    /// Name of an NCZ for this NCA, after its content ID
+    fn ncz_name(nca: &[u8]) -> String {
+        let id: String = Sha256::digest(nca)[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{id}.ncz")
+    }
+
+    fn test_partition(kind: PartitionKind, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut entries = Vec::new();
+        let mut data = Vec::new();
+        for (name, content) in files {
+            let mut entry = vec![0u8; kind.entry_size()];
+            entry[..8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            entry[8..16].copy_from_slice(&(content.len() as u64).to_le_bytes());
+            entry[0x10..0x14].copy_from_slice(&(strings.len() as u32).to_le_bytes());
+            if kind == PartitionKind::Hfs0 {
+                entry[0x14..0x18].copy_from_slice(&0x200u32.to_le_bytes());
+            }
+            entries.extend(entry);
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            data.extend_from_slice(content);
+        }
+        strings.resize(strings.len().next_multiple_of(0x20), 0);
+
+        let mut partition = kind.magic().to_vec();
+        partition.extend_from_slice(&(files.len() as u32).to_le_bytes());
+        partition.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        partition.extend_from_slice(&[0; 4]);
+        partition.extend(entries);
+        partition.extend(strings);
+        partition.extend(data);
+        partition
+    }
+
+    #[test]
+    fn test_decompressed_path() {
+        assert_eq!(
+            decompressed_path(Path::new("/games/Game [0100000000010000].NSZ")),
+            Some(PathBuf::from("/games/Game [0100000000010000].nsp"))
+        );
+        assert_eq!(
+            decompressed_path(Path::new("game.xcz")),
+            Some(PathBuf::from("game.xci"))
+        );
+        assert!(!is_compressed(Path::new("game.nsp")));
+    }
+
+    #[test]
+    fn test_decompress_ncz() {
+        for block in [false, true] {
+            let (ncz, nca) = test_ncz(block);
+            let mut input = Cursor::new([vec![0; 0x10], ncz.clone()].concat());
+            let mut output = Vec::new();
+            let (size, hash) =
+                decompress_ncz(&mut input, 0x10, ncz.len() as u64, &mut output).unwrap();
+            assert_eq!(size, nca.len() as u64);
+            assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(&nca)));
+            assert!(output == nca, "block compressed: {block}");
+        }
+    }
+
+    #[test]
+    fn test_rebuild_pfs0() {
+        let (ncz, nca) = test_ncz(false);
+        let name = ncz_name(&nca);
+        let nsz = test_partition(
+            PartitionKind::Pfs0,
+            &[(&name, &ncz), ("0123.tik", b"ticket")],
+        );
+
+        let mut output = Cursor::new(Vec::new());
+        rebuild_partition(&mut Cursor::new(nsz), 0, &mut output, PartitionKind::Pfs0).unwrap();
+        let nsp = output.into_inner();
+
+        let (header, entries) =
+            read_partition(&mut Cursor::new(&nsp), 0, PartitionKind::Pfs0).unwrap();
+        let file = |entry: &PartitionEntry| {
+            let start = header.len() + entry.data_offset as usize;
+            nsp[start..start + entry.size as usize].to_vec()
+        };
+        assert_eq!(entries[0].name, name.replace(".ncz", ".nca"));
+        assert!(file(&entries[0]) == nca);
+        assert_eq!(entries[1].name, "0123.tik");
+        assert_eq!(file(&entries[1]), b"ticket");
+    }
+
+    #[test]
+    fn test_rebuild_hfs0_hashes() {
+        let (ncz, nca) = test_ncz(true);
+        let secure = test_partition(PartitionKind::Hfs0, &[(&ncz_name(&nca), &ncz)]);
+
+        let mut output = Cursor::new(Vec::new());
+        let header = rebuild_partition(
+            &mut Cursor::new(secure),
+            0,
+            &mut output,
+            PartitionKind::Hfs0,
+        )
+        .unwrap();
+        let data = output.into_inner();
+
+        let entry = 0x10;
+        let start = header.len() + read_u64(&header, entry) as usize;
+        assert_eq!(start % MEDIA_UNIT as usize, 0);
+        assert!(data[start..] == nca[..]);
+        assert_eq!(
+            header[entry + 0x20..entry + 0x40],
+            Sha256::digest(&nca[..0x200])[..]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_rejects_mismatched_content_id() {
+        let (ncz, _) = test_ncz(false);
+        for name in ["00112233445566778899aabbccddeeff.ncz", "0123.ncz"] {
+            let nsz = test_partition(PartitionKind::Pfs0, &[(name, &ncz)]);
+            let mut output = Cursor::new(Vec::new());
+            let err = rebuild_partition(&mut Cursor::new(nsz), 0, &mut output, PartitionKind::Pfs0)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_untrusted_sizes() {
+        // More entries than the file could hold
+        let mut partition = test_partition(PartitionKind::Pfs0, &[("0123.tik", b"ticket")]);
+        partition[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_partition(&mut Cursor::new(partition), 0, PartitionKind::Pfs0).is_err());
+
+        // More blocks than the decompressed size has
+        let mut blocks = BLOCK_MAGIC.to_vec();
+        blocks.extend_from_slice(&[2, 1, 0, 16]);
+        blocks.extend_from_slice(&u32::MAX.to_le_bytes());
+        blocks.extend_from_slice(&0x10000u64.to_le_bytes());
+        let available = blocks.len() as u64;
+        assert!(BlockReader::new(Cursor::new(blocks), available).is_err());
+
+        // A block larger than it is decompressed
+        let mut blocks = BLOCK_MAGIC.to_vec();
+        blocks.extend_from_slice(&[2, 1, 0, 16]);
+        blocks.extend_from_slice(&1u32.to_le_bytes());
+        blocks.extend_from_slice(&0x100u64.to_le_bytes());
+        blocks.extend_from_slice(&u32::MAX.to_le_bytes());
+        let available = blocks.len() as u64;
+        let mut reader = BlockReader::new(Cursor::new(blocks), available).unwrap();
+        assert!(reader.read(&mut [0u8; 0x10]).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! Quarantine folder for files that can't be read
+//!
+//! When the CNMT of a file can't be read, the scanner falls back to its filename, which for
+//! corrupt or mislabeled files means an unidentified entry in the index and the same error
+//! on every scan. Format errors are counted per path in the database, while missing or
+//! outdated keys and failed reads aren't the file's fault and aren't counted. Once a file
+//! has failed `quarantine_after_failures` scans it's moved into a hidden folder of the rom dir
+//! (`.alumulemu-quarantine/`), next to a note of the error. A successful read resets the
+//! count. Like the trash, the scanner skips the folder and the watcher ignores it explicitly.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{move_file, trash::rom_relative_path};
+use crate::{
+    backend::kv_config::{ExtraBackendConfig, KvOptExt},
+    db::{DB, NspMetadata, with_retry},
+};
+
+/// Name of the quarantine folder in the rom dir
+pub const QUARANTINE_DIR: &str = ".alumulemu-quarantine";
+
+/// Appended to the name of a quarantined file for the name of its note
+const NOTE_SUFFIX: &str = ".error.json";
+
+/// Failed reads of a file that isn't quarantined yet, keyed by its path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanFailure {
+    pub path: String,
+    pub failures: u32,
+    pub error: String,
+    pub last_failed_at: DateTime<Utc>,
+}
+
+/// Note written next to a quarantined file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuarantineNote {
+    /// Where the file was before it was quarantined
+    pub original_path: String,
+    pub error: String,
+    pub failures: u32,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+/// A file in the quarantine
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedFile {
+    pub path: String,
+    pub size: u64,
+    /// Missing if the note was deleted or can't be read
+    pub note: Option<QuarantineNote>,
+}
+
+/// Check if a path is inside a quarantine folder
+pub fn is_quarantine_path(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == QUARANTINE_DIR)
+}
+
+/// Where a file goes in the quarantine
+fn quarantine_path(rom_dir: &Path, path: &Path) -> PathBuf {
+    rom_dir
+        .join(QUARANTINE_DIR)
+        .join(rom_relative_path(rom_dir, path))
+}
+
+/// Path of the note of a quarantined file
+fn note_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(NOTE_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Move a file into the quarantine, write its note and get where it ended up. If the
+/// quarantine already has a file of that name, the time of quarantine is appended to the
+/// new one.
+pub async fn quarantine_file(
+    rom_dir: &Path,
+    path: &Path,
+    note: &QuarantineNote,
+) -> std::io::Result<PathBuf> {
+    let mut dest = quarantine_path(rom_dir, path);
+    if tokio::fs::try_exists(&dest).await? {
+        let mut name = dest.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", note.quarantined_at.timestamp()));
+        dest.set_file_name(name);
+    }
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    move_file(path, &dest).await?;
+
+    let json = serde_json::to_vec_pretty(note).map_err(std::io::Error::other)?;
+    tokio::fs::write(note_path(&dest), json).await?;
+    Ok(dest)
+}
+
+/// Check if a file failed to be read before, so its cached metadata shouldn't be trusted
+pub async fn has_failed(path: &str) -> bool {
+    let failure: surrealdb::Result<Option<ScanFailure>> = DB.select(("scan_failure", path)).await;
+    failure
+        .inspect_err(|e| tracing::warn!("Failed to get scan failures of {}: {}", path, e))
+        .is_ok_and(|failure| failure.is_some())
+}
+
+/// Paths of all files that failed to be read and aren't quarantined yet
+pub async fn failing_paths() -> surrealdb::Result<Vec<String>> {
+    let failures: Vec<ScanFailure> = DB.select("scan_failure").await?;
+    Ok(failures.into_iter().map(|failure| failure.path).collect())
+}
+
+/// Forget the failed reads of a file after it was read successfully
+pub async fn clear_failures(path: &str) {
+    let result: surrealdb::Result<Option<ScanFailure>> =
+        with_retry("scan_failure.delete", || async {
+            DB.delete(("scan_failure", path)).await
+        })
+        .await;
+    if let Err(e) = result {
+        tracing::warn!("Failed to clear scan failures of {}: {}", path, e);
+    }
+}
+
+/// Count a file that isn't a valid NSP or XCI, and quarantine it once it failed too often.
+/// Returns where the file was moved to, if it was.
+pub async fn record_failure(path: &Path, error: &str) -> color_eyre::Result<Option<PathBuf>> {
+    let threshold = ExtraBackendConfig::get()
+        .await?
+        .unwrap_or_default()
+        .quarantine_after_failures;
+    if threshold == 0 {
+        return Ok(None);
+    }
+    // Without keys every file fails, which says nothing about this one
+    if !crate::nsp::keys_loaded() {
+        return Ok(None);
+    }
+
+    let path_str = path.to_string_lossy().to_string();
+    let previous: Option<ScanFailure> = DB.select(("scan_failure", path_str.as_str())).await?;
+    let failure = ScanFailure {
+        path: path_str.clone(),
+        failures: previous.map_or(0, |previous| previous.failures) + 1,
+        error: error.to_string(),
+        last_failed_at: Utc::now(),
+    };
+
+    if failure.failures < threshold {
+        tracing::info!(
+            "{} failed to be read {} of {} times before quarantine",
+            path.display(),
+            failure.failures,
+            threshold
+        );
+        let _: Option<ScanFailure> = with_retry("scan_failure.save", || async {
+            DB.upsert(("scan_failure", path_str.as_str()))
+                .content(failure.clone())
+                .await
+        })
+        .await?;
+        return Ok(None);
+    }
+
+    let note = QuarantineNote {
+        original_path: path_str.clone(),
+        error: failure.error,
+        failures: failure.failures,
+        quarantined_at: failure.last_failed_at,
+    };
+    let rom_dir = crate::config::config().backend_config.rom_dir_of(path);
+    let dest = quarantine_file(&rom_dir, path, &note).await?;
+    tracing::warn!(
+        path = path_str,
+        quarantined_to = %dest.display(),
+        failures = note.failures,
+        "Quarantined file that can't be read: {}",
+        note.error
+    );
+
+    // Also drops the file from the tinfoil index
+    if let Some(metadata) = NspMetadata::get_by_path(&path_str).await? {
+        metadata.delete().await?;
+    }
+    clear_failures(&path_str).await;
+    Ok(Some(dest))
+}
+
+/// Files in the quarantine folder of a rom dir, with their notes
+fn quarantined_files(rom_dir: &Path) -> Vec<QuarantinedFile> {
+    let dir = rom_dir.join(QUARANTINE_DIR);
+    if !dir.is_dir() {
+        return Vec::new();
+    }
+    jwalk::WalkDir::new(&dir)
+        .skip_hidden(false)
+        .sort(true)
+        .into_iter()
+        .filter_map(|entry| {
+            entry
+                .inspect_err(|e| tracing::warn!("Failed to read quarantine folder: {}", e))
+                .ok()
+        })
+        .filter(|entry| {
+            entry.file_type().is_file()
+                && !entry.file_name().to_string_lossy().ends_with(NOTE_SUFFIX)
+        })
+        .map(|entry| {
+            let path = entry.path();
+            let note = std::fs::read(note_path(&path))
+                .ok()
+                .and_then(|json| serde_json::from_slice(&json).ok());
+            QuarantinedFile {
+                path: path.display().to_string(),
+                size: entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+                note,
+            }
+        })
+        .collect()
+}
+
+/// List the quarantined files of every rom dir
+pub async fn list_quarantined() -> color_eyre::Result<Vec<QuarantinedFile>> {
+    let rom_dirs = crate::config::config().backend_config.rom_dirs();
+    let files = tokio::task::spawn_blocking(move || {
+        rom_dirs
+            .iter()
+            .flat_map(|rom_dir| quarantined_files(Path::new(rom_dir)))
+            .collect()
+    })
+    .await?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(path: &Path) -> QuarantineNote {
+        QuarantineNote {
+            original_path: path.display().to_string(),
+            error: "Invalid NCA magic".to_string(),
+            failures: 3,
+            quarantined_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_quarantine_path() {
+        let rom_dir = Path::new("/games");
+        assert_eq!(
+            quarantine_path(rom_dir, Path::new("/games/Some Game/game.nsp")),
+            Path::new("/games/.alumulemu-quarantine/Some Game/game.nsp")
+        );
+        assert_eq!(
+            note_path(Path::new("/games/.alumulemu-quarantine/game.nsp")),
+            Path::new("/games/.alumulemu-quarantine/game.nsp.error.json")
+        );
+
+        assert!(is_quarantine_path(Path::new(
+            "/games/.alumulemu-quarantine/game.nsp"
+        )));
+        assert!(!is_quarantine_path(Path::new("/games/game.nsp")));
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_file() {
+        let rom_dir = tempfile::tempdir().unwrap();
+        let game = rom_dir.path().join("Some Game").join("game.nsp");
+        std::fs::create_dir_all(game.parent().unwrap()).unwrap();
+        std::fs::write(&game, b"not an nsp").unwrap();
+
+        let dest = quarantine_file(rom_dir.path(), &game, &note(&game))
+            .await
+            .unwrap();
+        assert_eq!(
+            dest,
+            rom_dir
+                .path()
+                .join(QUARANTINE_DIR)
+                .join("Some Game")
+                .join("game.nsp")
+        );
+        assert!(!game.exists());
+
+        // The note is listed with the file, and not as a file of its own
+        let files = quarantined_files(rom_dir.path());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, dest.display().to_string());
+        assert_eq!(files[0].size, 10);
+        assert_eq!(files[0].note, Some(note(&game)));
+    }
+}
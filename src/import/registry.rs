@@ -7,7 +7,10 @@ use std::{
 use once_cell::sync::Lazy;
 use tracing::{debug, info};
 
-use crate::import::{Importer, Result, not_ultranx::NotUltranxImporter, url::UrlImporter};
+use crate::import::{
+    Importer, Result, alumulemu::AlumulemuImporter, not_ultranx::NotUltranxImporter,
+    repository::RepositoryImporter, url::UrlImporter,
+};
 
 /// A static global registry for importers
 static IMPORTER_REGISTRY: Lazy<Arc<RwLock<ImporterRegistry>>> =
@@ -36,6 +39,9 @@ pub trait DynImporter: Send + Sync {
     /// Return a description of this importer
     fn description(&self) -> &'static str;
 
+    /// Return the JSON schema of this importer's requests
+    fn request_schema(&self) -> serde_json::Value;
+
     /// Clone the importer into a Box
     fn clone_box(&self) -> Box<dyn DynImporter>;
 }
@@ -58,6 +64,10 @@ impl<T: Importer> DynImporter for T {
         self.description()
     }
 
+    fn request_schema(&self) -> serde_json::Value {
+        self.request_schema()
+    }
+
     fn clone_box(&self) -> Box<dyn DynImporter> {
         Box::new(self.clone())
     }
@@ -96,9 +106,15 @@ impl ImporterRegistry {
         self.importers.get(id)
     }
 
-    /// Get all registered importers
-    pub fn get_all(&self) -> Vec<&Box<dyn DynImporter>> {
-        self.importers.values().collect()
+    /// Get all registered importers with their IDs, sorted by ID
+    pub fn get_all(&self) -> Vec<(&str, &Box<dyn DynImporter>)> {
+        let mut importers: Vec<_> = self
+            .importers
+            .iter()
+            .map(|(id, importer)| (id.as_str(), importer))
+            .collect();
+        importers.sort_by_key(|(id, _)| *id);
+        importers
     }
 
     /// Check if an importer with the specified ID is registered
@@ -123,6 +139,12 @@ pub async fn init_registry() {
     // Register the UrlImporter
     register("url", UrlImporter::new());
 
+    // Register the AlumulemuImporter
+    register("alumulemu", AlumulemuImporter::new());
+
+    // Register the RepositoryImporter
+    register("repository", RepositoryImporter::new());
+
     // Add more importers here as they become available
 
     info!("Importer registry initialized");
@@ -139,13 +161,13 @@ pub fn get_importer(id: &str) -> Option<Box<dyn DynImporter>> {
     registry.get(id).map(|importer| importer.clone_box())
 }
 
-/// Get all registered importers from the global registry
-pub fn get_all_importers() -> Vec<Box<dyn DynImporter>> {
+/// Get all registered importers and their IDs from the global registry
+pub fn get_all_importers() -> Vec<(String, Box<dyn DynImporter>)> {
     let registry = IMPORTER_REGISTRY.read().unwrap();
     registry
         .get_all()
         .into_iter()
-        .map(|importer| importer.clone_box())
+        .map(|(id, importer)| (id.to_string(), importer.clone_box()))
         .collect()
 }
 
@@ -153,7 +175,7 @@ pub fn get_all_importers() -> Vec<Box<dyn DynImporter>> {
 /// This is a more effective approach that ensures locks are released before async operations
 pub async fn import_with_json(id: &str, json: &str) -> Result<crate::import::ImportSource> {
     // First, clone the importers while holding the lock, if they exist
-    let (ultranx_importer, url_importer) = {
+    let (ultranx_importer, url_importer, alumulemu_importer, repository_importer) = {
         // Create a scope to ensure the lock is released before any async operations
         let registry = IMPORTER_REGISTRY.read().unwrap();
 
@@ -174,35 +196,46 @@ pub async fn import_with_json(id: &str, json: &str) -> Result<crate::import::Imp
             _ => None,
         };
 
-        (ultranx, url)
+        let alumulemu = match id {
+            "alumulemu" => registry
+                .get(id)
+                .and_then(|imp| imp.as_any().downcast_ref::<AlumulemuImporter>())
+                .cloned(),
+            _ => None,
+        };
+
+        let repository = match id {
+            "repository" => registry
+                .get(id)
+                .and_then(|imp| imp.as_any().downcast_ref::<RepositoryImporter>())
+                .cloned(),
+            _ => None,
+        };
+
+        (ultranx, url, alumulemu, repository)
     }; // Lock is dropped here
 
     // Now process the import with the cloned importer (no locks held)
     if let Some(importer) = ultranx_importer {
         // We can now safely call async methods since we no longer hold the lock
-        let request = serde_json::from_str(json).map_err(|e| {
-            crate::import::ImportError::Other(color_eyre::eyre::eyre!(
-                "Failed to parse JSON request: {}",
-                e
-            ))
-        })?;
+        let request = serde_json::from_str(json)?;
 
         importer.import(request).await
     } else if let Some(importer) = url_importer {
         // We can now safely call async methods since we no longer hold the lock
-        let request = serde_json::from_str(json).map_err(|e| {
-            crate::import::ImportError::Other(color_eyre::eyre::eyre!(
-                "Failed to parse JSON request: {}",
-                e
-            ))
-        })?;
+        let request = serde_json::from_str(json)?;
+
+        importer.import(request).await
+    } else if let Some(importer) = alumulemu_importer {
+        let request = serde_json::from_str(json)?;
+
+        importer.import(request).await
+    } else if let Some(importer) = repository_importer {
+        let request = serde_json::from_str(json)?;
 
         importer.import(request).await
     } else {
-        Err(crate::import::ImportError::Other(color_eyre::eyre::eyre!(
-            "Importer not found or not supported: {}",
-            id
-        )))
+        Err(crate::import::ImportError::ImporterNotFound(id.to_string()))
     }
 }
 
@@ -222,6 +255,14 @@ mod tests {
             "UltraNX importer should be registered"
         );
         assert!(registry.has("url"), "URL importer should be registered");
+        assert!(
+            registry.has("alumulemu"),
+            "Alumulemu importer should be registered"
+        );
+        assert!(
+            registry.has("repository"),
+            "Repository importer should be registered"
+        );
     }
 
     #[tokio::test]
@@ -239,4 +280,21 @@ mod tests {
             "Import should either succeed or fail with a handled error"
         );
     }
+
+    #[tokio::test]
+    async fn test_import_errors() {
+        init_registry().await;
+
+        let result = import_with_json("nonexistent", "{}").await;
+        assert!(matches!(
+            result,
+            Err(crate::import::ImportError::ImporterNotFound(id)) if id == "nonexistent"
+        ));
+
+        let result = import_with_json("url", r#"{"link": "https://example.com"}"#).await;
+        assert!(matches!(
+            result,
+            Err(crate::import::ImportError::InvalidRequest(_))
+        ));
+    }
 }
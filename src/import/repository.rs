@@ -0,0 +1,427 @@
+//! Import from Tinfoil indexes
+//!
+//! Tinfoil indexes list their files by URL, relative URLs pointing to the server the index
+//! came from. Files are downloaded with the headers the index asks for, all of them as
+//! part of a single import job.
+//!
+//! The [`RepositoryImporter`] treats an index as a repository to mirror: only the files
+//! that aren't in the library yet are downloaded. Its index comes from a user-provided URL,
+//! so it's fetched under the same host policy as the files.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use url::Url;
+
+use super::{ImportError, ImportSource, Importer, downloader::Downloader};
+use crate::db::{NspMetadata, is_placeholder_title_id};
+use crate::index::Index;
+use crate::title_kind::TitleKind;
+use crate::titledb::GameFileDataNaive;
+
+/// Largest index a repository may serve or be imported from, which keeps a bad URL from
+/// filling the memory
+pub const MAX_INDEX_BYTES: usize = 64 * 1024 * 1024;
+
+/// A JSON import request for the files of a Tinfoil index
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RepositoryImportRequest {
+    /// URL of the Tinfoil index
+    pub index_url: String,
+    /// Only import files of these titles, or of games with these base title IDs.
+    /// Every file is imported if it's empty
+    #[serde(default)]
+    pub title_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryImporter;
+
+impl RepositoryImporter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Importer for RepositoryImporter {
+    type ImportRequest = RepositoryImportRequest;
+
+    async fn import(&self, request: Self::ImportRequest) -> super::Result<ImportSource> {
+        let index_url = request.index_url.trim();
+        Url::parse(index_url)
+            .map_err(|e| ImportError::Other(color_eyre::eyre::eyre!("Invalid index URL: {}", e)))?;
+
+        Ok(ImportSource::Repository {
+            index_url: index_url.to_string(),
+            title_ids: request.title_ids,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "repository_importer"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Repository Importer"
+    }
+
+    fn description(&self) -> &'static str {
+        "Imports the files of a Tinfoil index that aren't in the library yet"
+    }
+
+    fn request_schema(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["index_url"],
+            "properties": {
+                "index_url": {
+                    "type": "string",
+                    "description": "URL of the Tinfoil index",
+                    "format": "uri"
+                },
+                "title_ids": {
+                    "type": "array",
+                    "description": "Only import files of these titles or games, all of them if empty",
+                    "items": { "type": "string" }
+                }
+            }
+        })
+    }
+}
+
+/// Download an index from a user-provided URL. The URL and every redirect hop have to pass
+/// the host policy, and the body can't be larger than [`MAX_INDEX_BYTES`].
+async fn fetch_index(index_url: &str) -> super::Result<Index> {
+    let too_large = || {
+        ImportError::Other(color_eyre::eyre::eyre!(
+            "Index at {} is larger than {} bytes",
+            index_url,
+            MAX_INDEX_BYTES
+        ))
+    };
+
+    let mut response = Downloader::new()
+        .get_with_redirects(index_url, None)
+        .await?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_INDEX_BYTES as u64)
+    {
+        return Err(too_large());
+    }
+
+    let status = response.status();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_INDEX_BYTES {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Index::from_response_body(index_url, status, &body)
+        .map_err(|e| ImportError::Other(color_eyre::eyre::eyre!(e)))
+}
+
+/// Load a repository's index and get the URLs of the files to import from it, along with
+/// the headers to download them with
+pub async fn repository_downloads(
+    index_url: &str,
+    title_ids: &[String],
+) -> super::Result<(Vec<String>, Option<HashMap<String, String>>)> {
+    let index = fetch_index(index_url).await?;
+    let base_url = Url::parse(index_url).ok();
+
+    let library = NspMetadata::get_all()
+        .await
+        .map_err(|e| ImportError::Other(color_eyre::eyre::eyre!(e)))?;
+    let library = LibraryVersions::new(&library);
+
+    let files = resolve_index_files(&index, base_url.as_ref());
+    let listed = files.len();
+    let urls: Vec<String> = files
+        .into_iter()
+        .filter_map(|file| {
+            if let Some(error) = &file.error {
+                warn!(url = file.url, "Skipping index entry: {}", error);
+            }
+            file.download_url
+        })
+        .filter(|url| is_wanted(url, &library, title_ids))
+        .collect();
+
+    info!(
+        index_url,
+        listed,
+        queued = urls.len(),
+        "Resolved the files to import from a repository"
+    );
+    Ok((urls, index_headers(&index)))
+}
+
+/// Versions of every title in the library
+struct LibraryVersions(HashMap<String, HashSet<String>>);
+
+impl LibraryVersions {
+    fn new(library: &[NspMetadata]) -> Self {
+        let mut versions: HashMap<String, HashSet<String>> = HashMap::new();
+        for metadata in library {
+            versions
+                .entry(metadata.title_id.to_uppercase())
+                .or_default()
+                .insert(metadata.version.trim_start_matches('v').to_string());
+        }
+        Self(versions)
+    }
+
+    /// Whether the library has the title, in the given version if it's known
+    fn contains(&self, title_id: &str, version: Option<&str>) -> bool {
+        match (self.0.get(title_id), version) {
+            (None, _) => false,
+            (Some(versions), Some(version)) => versions.contains(version),
+            (Some(_), None) => true,
+        }
+    }
+}
+
+/// Name of the file an index entry is saved as, its `#` override if it has one
+fn entry_file_name(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let name = match url.fragment().filter(|fragment| !fragment.is_empty()) {
+        Some(fragment) => fragment.to_string(),
+        None => url.path_segments()?.next_back()?.to_string(),
+    };
+    Some(
+        urlencoding::decode(&name)
+            .map(|name| name.into_owned())
+            .unwrap_or(name),
+    )
+}
+
+/// Whether a file of an index should be imported: it passes the title filter and the
+/// library doesn't have its title and version yet. Files without a title ID in their name
+/// can't be told apart, so they're imported unless a filter is set
+fn is_wanted(url: &str, library: &LibraryVersions, title_ids: &[String]) -> bool {
+    let parsed = entry_file_name(url).map(|name| GameFileDataNaive::parse_from_filename(&name));
+    let title_id = parsed
+        .as_ref()
+        .and_then(|parsed| parsed.title_id.as_deref())
+        .filter(|title_id| !is_placeholder_title_id(title_id))
+        .map(str::to_uppercase);
+    let Some(title_id) = title_id else {
+        return title_ids.is_empty();
+    };
+
+    if !title_ids.is_empty() {
+        let base_title_id = TitleKind::base_title_id(&title_id);
+        let matches = title_ids.iter().any(|filter| {
+            filter.trim().eq_ignore_ascii_case(&title_id)
+                || base_title_id
+                    .as_deref()
+                    .is_some_and(|base| filter.trim().eq_ignore_ascii_case(base))
+        });
+        if !matches {
+            return false;
+        }
+    }
+
+    let version = parsed
+        .as_ref()
+        .and_then(|parsed| parsed.version.as_deref())
+        .map(|version| version.trim_start_matches('v'));
+    !library.contains(&title_id, version)
+}
+
+/// What became of a file entry of an index
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct IndexFileResult {
+    /// URL of the entry, as listed in the index
+    pub url: String,
+    /// URL the file is downloaded from, if it's being imported
+    pub download_url: Option<String>,
+    /// Why the file isn't imported
+    pub error: Option<String>,
+}
+
+/// Headers of an index, which lists them as `Name: value` lines
+pub fn index_headers(index: &Index) -> Option<HashMap<String, String>> {
+    let headers: HashMap<String, String> = index
+        .headers
+        .iter()
+        .filter_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect();
+    (!headers.is_empty()).then_some(headers)
+}
+
+/// Resolve the URL of a file entry, relative URLs against the URL of the index
+pub fn resolve_file_url(url: &str, base_url: Option<&Url>) -> Result<Url, String> {
+    let url = url.trim();
+    let resolved = match Url::parse(url) {
+        Ok(url) => url,
+        Err(url::ParseError::RelativeUrlWithoutBase) => match base_url {
+            Some(base_url) => base_url.join(url).map_err(|e| e.to_string())?,
+            None => return Err("relative URL, but no base URL was given".to_string()),
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // Tinfoil also knows about other locations, such as its SD card or Google Drive
+    if !matches!(resolved.scheme(), "http" | "https") {
+        return Err(format!("unsupported URL scheme '{}'", resolved.scheme()));
+    }
+    Ok(resolved)
+}
+
+/// Resolve the file entries of an index, each of them once
+pub fn resolve_index_files(index: &Index, base_url: Option<&Url>) -> Vec<IndexFileResult> {
+    let mut seen = std::collections::HashSet::new();
+    index
+        .files
+        .iter()
+        .map(|entry| match resolve_file_url(&entry.url, base_url) {
+            Ok(url) if !seen.insert(url.clone()) => IndexFileResult {
+                url: entry.url.clone(),
+                download_url: None,
+                error: Some("listed more than once".to_string()),
+            },
+            Ok(url) => IndexFileResult {
+                url: entry.url.clone(),
+                download_url: Some(url.into()),
+                error: None,
+            },
+            Err(error) => IndexFileResult {
+                url: entry.url.clone(),
+                download_url: None,
+                error: Some(error),
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_file_url() {
+        let base = Url::parse("https://shop.example.com/index/").unwrap();
+        assert_eq!(
+            resolve_file_url(
+                "/api/get_game/0100000000010000_v0.nsp#Game.nsp",
+                Some(&base)
+            )
+            .unwrap()
+            .as_str(),
+            "https://shop.example.com/api/get_game/0100000000010000_v0.nsp#Game.nsp"
+        );
+        assert_eq!(
+            resolve_file_url("files/Game.nsp", Some(&base))
+                .unwrap()
+                .as_str(),
+            "https://shop.example.com/index/files/Game.nsp"
+        );
+        assert_eq!(
+            resolve_file_url("https://cdn.example.com/Game.nsp", None)
+                .unwrap()
+                .as_str(),
+            "https://cdn.example.com/Game.nsp"
+        );
+        assert!(resolve_file_url("/Game.nsp", None).is_err());
+        assert!(resolve_file_url("sdmc:/switch/Game.nsp", Some(&base)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_index_files() {
+        let index: Index = serde_json::from_str(
+            r#"{
+                "files": [
+                    {"url": "/Game.nsp", "size": 1},
+                    {"url": "https://shop.example.com/Game.nsp", "size": 1},
+                    {"url": "gdrive:/Other.nsp", "size": 1}
+                ],
+                "headers": ["Authorization: Basic dXNlcjpwYXNz", "invalid"]
+            }"#,
+        )
+        .unwrap();
+        let base = Url::parse("https://shop.example.com/").unwrap();
+
+        let results = resolve_index_files(&index, Some(&base));
+        let queued: Vec<bool> = results
+            .iter()
+            .map(|result| result.download_url.is_some())
+            .collect();
+        assert_eq!(queued, vec![true, false, false]);
+        assert_eq!(results[1].error.as_deref(), Some("listed more than once"));
+
+        let headers = index_headers(&index).unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["Authorization"], "Basic dXNlcjpwYXNz");
+    }
+
+    #[test]
+    fn test_is_wanted() {
+        let metadata = |title_id: &str, version: &str| NspMetadata {
+            path: String::new(),
+            title_id: title_id.to_string(),
+            version: version.to_string(),
+            title_name: None,
+            download_id: String::new(),
+            unidentified: false,
+            required_system_version: None,
+        };
+        let library = LibraryVersions::new(&[
+            metadata("0100000000010000", "v0"),
+            metadata("0100000000010800", "65536"),
+        ]);
+        let url = |name: &str| format!("https://shop.example.com/api/get_game/file#{name}");
+
+        // Already in the library, in that version
+        assert!(!is_wanted(
+            &url("Game [0100000000010000][v0].nsp"),
+            &library,
+            &[]
+        ));
+        assert!(!is_wanted(
+            &url("Game [0100000000010800][v65536].nsp"),
+            &library,
+            &[]
+        ));
+        // Newer update of a title in the library
+        assert!(is_wanted(
+            &url("Game [0100000000010800][v131072].nsp"),
+            &library,
+            &[]
+        ));
+        // The name is taken from the path without a `#` override
+        assert!(is_wanted(
+            "https://shop.example.com/files/Other%20%5B0100000000020000%5D%5Bv0%5D.nsp",
+            &library,
+            &[]
+        ));
+        // Files without a title ID are only imported without a filter
+        assert!(is_wanted(&url("Homebrew.nsp"), &library, &[]));
+        assert!(!is_wanted(
+            &url("Homebrew.nsp"),
+            &library,
+            &["0100000000020000".to_string()]
+        ));
+
+        // The filter matches the title ID, or the base title ID of updates
+        let filter = ["0100000000010000".to_string()];
+        assert!(is_wanted(
+            &url("Game [0100000000010800][v131072].nsp"),
+            &library,
+            &filter
+        ));
+        assert!(!is_wanted(
+            &url("Other [0100000000020000][v0].nsp"),
+            &library,
+            &filter
+        ));
+    }
+}
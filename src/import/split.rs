@@ -0,0 +1,209 @@
+//! Split NSP/XCI folder reassembly
+//!
+//! Dumps meant for FAT32 storage split large NSPs into a folder (usually named after the
+//! game, e.g. `Game [0100...].nsp/`) containing numbered parts `00`, `01`, `02`, ...
+//! These folders are joined back into a single file before being moved into the rom dir,
+//! so the scanner sees one title instead of a bunch of unreadable fragments.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::Result;
+
+/// Extensions a reassembled file can keep, anything else gets `.nsp` appended
+const GAME_EXTENSIONS: [&str; 4] = ["nsp", "nsz", "xci", "xcz"];
+
+/// Order the parts of a split folder, given the names of every file in it.
+///
+/// Returns the part names in order if all files are two-digit numbered parts (`00`, `01`, ...)
+/// forming a contiguous run starting at `00`, or `None` if this is not a split folder.
+pub fn split_part_order<S: AsRef<str>>(names: &[S]) -> Option<Vec<String>> {
+    if names.is_empty() {
+        return None;
+    }
+
+    let mut parts = names
+        .iter()
+        .map(|name| {
+            let name = name.as_ref();
+            (name.len() == 2 && name.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| name.parse::<usize>().ok())
+                .flatten()
+                .map(|index| (index, name.to_string()))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    parts.sort();
+    let contiguous = parts.iter().enumerate().all(|(i, (index, _))| i == *index);
+
+    contiguous.then(|| parts.into_iter().map(|(_, name)| name).collect())
+}
+
+/// The file name a split folder is reassembled into
+fn joined_file_name(folder_name: &str) -> String {
+    let has_game_extension = Path::new(folder_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| GAME_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+
+    if has_game_extension {
+        folder_name.to_string()
+    } else {
+        format!("{folder_name}.nsp")
+    }
+}
+
+/// Concatenate the parts of a split folder into `output`
+async fn write_parts(folder: &Path, parts: &[String], output: &Path) -> std::io::Result<()> {
+    let mut output = tokio::fs::File::create(output).await?;
+    for part in parts {
+        let mut input = tokio::fs::File::open(folder.join(part)).await?;
+        tokio::io::copy(&mut input, &mut output).await?;
+    }
+    output.flush().await
+}
+
+/// Join the parts of a split folder into a single file next to it, removing the folder.
+async fn join_split_folder(folder: &Path, parts: &[String]) -> Result<PathBuf> {
+    let folder_name = folder
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "split".to_string());
+    let parent = folder.parent().unwrap_or(Path::new("."));
+    let dest = parent.join(joined_file_name(&folder_name));
+    // The destination may share the folder's name, so build it under a temporary name first
+    let partial = parent.join(format!("{folder_name}.joining"));
+
+    info!(folder = ?folder, parts = parts.len(), dest = ?dest, "Reassembling split folder");
+
+    if let Err(e) = write_parts(folder, parts, &partial).await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e.into());
+    }
+
+    tokio::fs::remove_dir_all(folder).await?;
+    tokio::fs::rename(&partial, &dest).await?;
+
+    Ok(dest)
+}
+
+/// Replace the parts of any split folders in `files` with the reassembled file.
+///
+/// A directory is only treated as a split folder if every entry in it is a numbered part,
+/// so stray files named `00` next to other content are left untouched.
+pub async fn reassemble_split_folders(files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut by_dir: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        by_dir.entry(dir).or_default().push(file);
+    }
+
+    let mut output = Vec::new();
+    for (dir, files) in by_dir {
+        let names: Vec<String> = files
+            .iter()
+            .filter_map(|file| file.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+
+        let parts = match split_part_order(&names) {
+            Some(parts) if dir_entry_count(&dir).await == Some(parts.len()) => parts,
+            _ => {
+                output.extend(files);
+                continue;
+            }
+        };
+
+        match join_split_folder(&dir, &parts).await {
+            Ok(joined) => output.push(joined),
+            Err(e) => {
+                warn!(folder = ?dir, "Failed to reassemble split folder: {}", e);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Count the entries of a directory, or `None` if it can't be read
+async fn dir_entry_count(dir: &Path) -> Option<usize> {
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let mut count = 0;
+    while entries.next_entry().await.ok()?.is_some() {
+        count += 1;
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_part_order() {
+        assert_eq!(
+            split_part_order(&["02", "00", "01"]),
+            Some(vec!["00".to_string(), "01".to_string(), "02".to_string()])
+        );
+        assert_eq!(split_part_order(&["00"]), Some(vec!["00".to_string()]));
+
+        // Gaps, a missing first part or anything else in the folder disqualify it
+        assert_eq!(split_part_order(&["00", "02"]), None);
+        assert_eq!(split_part_order(&["01", "02"]), None);
+        assert_eq!(split_part_order(&["00", "01", "game.nsp"]), None);
+        assert_eq!(split_part_order(&["000", "001"]), None);
+        assert_eq!(split_part_order::<&str>(&[]), None);
+    }
+
+    #[test]
+    fn test_joined_file_name() {
+        assert_eq!(joined_file_name("Game [0100].nsp"), "Game [0100].nsp");
+        assert_eq!(joined_file_name("Game [0100].XCI"), "Game [0100].XCI");
+        assert_eq!(joined_file_name("Game [0100]"), "Game [0100].nsp");
+    }
+
+    #[tokio::test]
+    async fn test_reassemble_split_folders() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("Game.nsp");
+        tokio::fs::create_dir(&folder).await.unwrap();
+        for (name, data) in [("00", "abc"), ("01", "def"), ("02", "g")] {
+            tokio::fs::write(folder.join(name), data).await.unwrap();
+        }
+        let other = root.path().join("Other.nsp");
+        tokio::fs::write(&other, "xyz").await.unwrap();
+
+        let files = vec![
+            folder.join("01"),
+            folder.join("00"),
+            folder.join("02"),
+            other.clone(),
+        ];
+        let mut result = reassemble_split_folders(files).await.unwrap();
+        result.sort();
+
+        assert_eq!(result, vec![folder.clone(), other]);
+        assert!(folder.is_file());
+        assert_eq!(tokio::fs::read_to_string(&folder).await.unwrap(), "abcdefg");
+    }
+
+    #[tokio::test]
+    async fn test_join_split_folder_cleans_up() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("Game.nsp");
+        tokio::fs::create_dir(&folder).await.unwrap();
+        tokio::fs::write(folder.join("00"), "abc").await.unwrap();
+
+        // The second part is missing, so opening it fails halfway through
+        let parts = vec!["00".to_string(), "01".to_string()];
+        assert!(join_split_folder(&folder, &parts).await.is_err());
+        assert!(!root.path().join("Game.nsp.joining").exists());
+        assert!(folder.join("00").is_file());
+    }
+}
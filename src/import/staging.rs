@@ -0,0 +1,272 @@
+//! Crash-consistent publishing of imported files
+//!
+//! Instead of moving files into the rom dir one by one, an import first moves everything
+//! into a hidden staging folder inside the rom dir (`.alumulemu-staging/<id>/`), so the
+//! final renames never cross a filesystem. Once every file is staged, the job's manifest is
+//! marked ready and the files are renamed into place.
+//!
+//! The manifest is written before anything is moved, so if the process dies, the startup
+//! [`recover`] pass knows what to do with a leftover job:
+//!
+//! - Ready jobs are published, finishing the renames that didn't happen yet
+//! - Anything else is rolled back, moving the staged files back to where they came from
+//!
+//! Either way an import ends up fully in the rom dir or not at all. The scanner skips
+//! hidden folders, and the watcher ignores the staging folder explicitly.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use ulid::Ulid;
+
+use super::{ImportError, Result, recursive_move};
+
+/// Name of the staging folder in the rom dir
+pub const STAGING_DIR: &str = ".alumulemu-staging";
+
+const MANIFEST_FILE: &str = "manifest.json";
+/// Staged files are kept under this folder of the job, mirroring their place in the rom dir
+const DATA_DIR: &str = "data";
+
+/// Get the staging folder of a rom dir
+pub fn staging_root(rom_dir: &Path) -> PathBuf {
+    rom_dir.join(STAGING_DIR)
+}
+
+/// Check if a path is inside a staging folder
+pub fn is_staging_path(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == STAGING_DIR)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StagedFile {
+    /// Where the file was imported from
+    source: PathBuf,
+    /// Where the file is while staged
+    staged: PathBuf,
+    /// Where the file gets published to
+    dest: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StagingManifest {
+    files: Vec<StagedFile>,
+    /// Set once every file is staged, from then on the job is published instead of rolled back
+    ready: bool,
+}
+
+/// Files of one import, staged in the rom dir until they're all ready to be published
+#[derive(Debug)]
+pub struct Staging {
+    rom_dir: PathBuf,
+    dir: PathBuf,
+    manifest: StagingManifest,
+}
+
+impl Staging {
+    /// Start a new staging job in the rom dir
+    pub async fn new(rom_dir: &Path) -> Result<Self> {
+        let dir = staging_root(rom_dir).join(Ulid::new().to_string());
+        tokio::fs::create_dir_all(dir.join(DATA_DIR)).await?;
+        let staging = Self {
+            rom_dir: rom_dir.to_path_buf(),
+            dir,
+            manifest: StagingManifest::default(),
+        };
+        staging.write_manifest().await?;
+        Ok(staging)
+    }
+
+    /// Load a job left behind by a previous run
+    async fn load(rom_dir: &Path, dir: PathBuf) -> Result<Self> {
+        let manifest = match tokio::fs::read(dir.join(MANIFEST_FILE)).await {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                ImportError::Other(color_eyre::eyre::eyre!(
+                    "Invalid staging manifest in {:?}: {}",
+                    dir,
+                    e
+                ))
+            })?,
+            // The job died before its manifest was written, so nothing was staged yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StagingManifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            rom_dir: rom_dir.to_path_buf(),
+            dir,
+            manifest,
+        })
+    }
+
+    /// Write the manifest, replacing the previous one in a single rename
+    async fn write_manifest(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.manifest)
+            .map_err(|e| ImportError::Other(color_eyre::eyre::eyre!(e)))?;
+        let tmp = self.dir.join(format!("{MANIFEST_FILE}.tmp"));
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, self.dir.join(MANIFEST_FILE)).await?;
+        Ok(())
+    }
+
+    /// Move a file into staging, to be published at `dest` later
+    pub async fn stage(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        let relative = match dest.strip_prefix(&self.rom_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => PathBuf::from(dest.file_name().ok_or_else(|| {
+                ImportError::Other(color_eyre::eyre::eyre!(
+                    "Failed to get filename for: {:?}",
+                    dest
+                ))
+            })?),
+        };
+        let staged = self.dir.join(DATA_DIR).join(relative);
+        if let Some(parent) = staged.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Record the file before moving it, so a crash mid-move can still be rolled back
+        self.manifest.files.push(StagedFile {
+            source: source.to_path_buf(),
+            staged: staged.clone(),
+            dest: dest.to_path_buf(),
+        });
+        self.write_manifest().await?;
+
+        recursive_move(source, &staged).await
+    }
+
+    /// Move every staged file into place and remove the job
+    pub async fn publish(mut self) -> Result<()> {
+        if !self.manifest.ready {
+            self.manifest.ready = true;
+            self.write_manifest().await?;
+        }
+
+        for file in &self.manifest.files {
+            // Already published by an earlier, interrupted attempt
+            if !tokio::fs::try_exists(&file.staged).await? {
+                continue;
+            }
+            if let Some(parent) = file.dest.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            recursive_move(&file.staged, &file.dest).await?;
+            tracing::info!(source = ?file.source, destination = ?file.dest, "Published imported file");
+        }
+
+        tokio::fs::remove_dir_all(&self.dir).await?;
+        Ok(())
+    }
+
+    /// Move every staged file back to its source and remove the job.
+    ///
+    /// If a file can't be moved back, the job is left in place so nothing is lost.
+    pub async fn rollback(self) -> Result<()> {
+        let mut failed = false;
+        for file in self.manifest.files.iter().rev() {
+            if !tokio::fs::try_exists(&file.staged).await? {
+                continue;
+            }
+            if let Some(parent) = file.source.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            if let Err(e) = recursive_move(&file.staged, &file.source).await {
+                tracing::error!(staged = ?file.staged, source = ?file.source, "Failed to roll back staged file: {}", e);
+                failed = true;
+            }
+        }
+
+        if failed {
+            return Err(ImportError::Other(color_eyre::eyre::eyre!(
+                "Some staged files couldn't be rolled back, they were left in {:?}",
+                self.dir
+            )));
+        }
+        tokio::fs::remove_dir_all(&self.dir).await?;
+        Ok(())
+    }
+}
+
+/// Finish or roll back imports that were interrupted while staging
+pub async fn recover(rom_dir: &Path) -> Result<()> {
+    let root = staging_root(rom_dir);
+    let mut entries = match tokio::fs::read_dir(&root).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let dir = entry.path();
+        let result = match Staging::load(rom_dir, dir.clone()).await {
+            Ok(staging) if staging.manifest.ready => {
+                tracing::info!(dir = ?dir, "Publishing interrupted import");
+                staging.publish().await
+            }
+            Ok(staging) => {
+                tracing::info!(dir = ?dir, "Rolling back interrupted import");
+                staging.rollback().await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::error!(dir = ?dir, "Failed to recover staged import: {}", e);
+        }
+    }
+
+    // Only removed when empty, anything that couldn't be recovered stays
+    let _ = tokio::fs::remove_dir(&root).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_staging_path() {
+        assert!(is_staging_path(Path::new(
+            "/games/.alumulemu-staging/01J/data/0100000000010000/a.nsp"
+        )));
+        assert!(!is_staging_path(Path::new("/games/0100000000010000/a.nsp")));
+    }
+
+    #[tokio::test]
+    async fn test_staging_publish_and_recover() {
+        let root = tempfile::tempdir().unwrap();
+        let rom_dir = root.path().join("games");
+        let source = root.path().join("a.nsp");
+        tokio::fs::write(&source, "aaaa").await.unwrap();
+        let dest = rom_dir.join("0100000000010000/a.nsp");
+
+        // Nothing shows up in the rom dir until the job is published
+        let mut staging = Staging::new(&rom_dir).await.unwrap();
+        staging.stage(&source, &dest).await.unwrap();
+        assert!(!source.exists());
+        assert!(!dest.exists());
+        staging.publish().await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "aaaa");
+
+        // A job that wasn't marked ready is rolled back on recovery
+        let source = root.path().join("b.nsp");
+        tokio::fs::write(&source, "bb").await.unwrap();
+        let mut staging = Staging::new(&rom_dir).await.unwrap();
+        staging
+            .stage(&source, &rom_dir.join("b.nsp"))
+            .await
+            .unwrap();
+        drop(staging);
+
+        recover(&rom_dir).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&source).await.unwrap(), "bb");
+        assert!(!rom_dir.join("b.nsp").exists());
+        assert!(!staging_root(&rom_dir).exists());
+    }
+}
@@ -0,0 +1,75 @@
+//! Tests for moving imported files into place
+#![cfg(test)]
+
+use super::*;
+
+#[tokio::test]
+async fn test_recursive_move_copies_dir() {
+    let root = tempfile::tempdir().unwrap();
+    let src = root.path().join("src");
+    tokio::fs::create_dir_all(src.join("sub")).await.unwrap();
+    tokio::fs::write(src.join("a.nsp"), "aaaa").await.unwrap();
+    tokio::fs::write(src.join("sub/b.nsp"), "bb").await.unwrap();
+
+    // The destination's parent doesn't exist, so the rename fast path fails
+    // and the files are moved one by one
+    let dest = root.path().join("missing/dest");
+    recursive_move(&src, &dest).await.unwrap();
+
+    assert!(!src.exists());
+    assert_eq!(
+        tokio::fs::read_to_string(dest.join("a.nsp")).await.unwrap(),
+        "aaaa"
+    );
+    assert_eq!(
+        tokio::fs::read_to_string(dest.join("sub/b.nsp"))
+            .await
+            .unwrap(),
+        "bb"
+    );
+}
+
+#[tokio::test]
+async fn test_recursive_move_reports_failure() {
+    let root = tempfile::tempdir().unwrap();
+    let src = root.path().join("missing.nsp");
+    let dest = root.path().join("dest.nsp");
+
+    match recursive_move(&src, &dest).await {
+        Err(ImportError::PartialMove {
+            failed,
+            moved,
+            remaining,
+            ..
+        }) => {
+            assert_eq!(failed, src);
+            assert!(moved.is_empty());
+            assert_eq!(remaining, vec![src]);
+        }
+        other => panic!("Expected a partial move error, got {other:?}"),
+    }
+    assert!(!dest.exists());
+}
+
+#[test]
+fn test_partial_copy_path() {
+    assert_eq!(
+        partial_copy_path(Path::new("/games/0100000000010000/game.nsp")),
+        Path::new("/games/0100000000010000/.game.nsp.alumulemu-part")
+    );
+}
+
+#[tokio::test]
+async fn test_move_file_copy_leaves_no_partial_file() {
+    let root = tempfile::tempdir().unwrap();
+    let src = root.path().join("game.nsp");
+    tokio::fs::write(&src, "game").await.unwrap();
+
+    // The destination's parent doesn't exist, so the file is copied
+    let dest = root.path().join("missing/game.nsp");
+    move_file(&src, &dest).await.unwrap();
+
+    assert!(!src.exists());
+    assert_eq!(tokio::fs::read_to_string(&dest).await.unwrap(), "game");
+    assert!(!partial_copy_path(&dest).exists());
+}
@@ -0,0 +1,100 @@
+//! Trash folder for deleted games
+//!
+//! Games deleted through the API with `trash=true` are moved into a hidden folder of the rom
+//! dir (`.alumulemu-trash/`) instead of being deleted, keeping their place relative to the
+//! rom dir. Moving them back restores them, emptying the folder deletes them for good. Being
+//! in the rom dir, the move never crosses a filesystem. The scanner skips hidden folders,
+//! and the watcher ignores the trash explicitly.
+
+use std::path::{Component, Path, PathBuf};
+
+use super::move_file;
+
+/// Name of the trash folder in the rom dir
+pub const TRASH_DIR: &str = ".alumulemu-trash";
+
+/// Check if a path is inside a trash folder
+pub fn is_trash_path(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == TRASH_DIR)
+}
+
+/// Path of a file relative to the rom dir. Files outside the rom dir only keep their name.
+pub(super) fn rom_relative_path(rom_dir: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(rom_dir)
+        .ok()
+        .filter(|relative| {
+            relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        })
+        .map(Path::to_path_buf)
+        .or_else(|| path.file_name().map(PathBuf::from))
+        .unwrap_or_default()
+}
+
+/// Where a file goes in the trash
+fn trash_path(rom_dir: &Path, path: &Path) -> PathBuf {
+    rom_dir
+        .join(TRASH_DIR)
+        .join(rom_relative_path(rom_dir, path))
+}
+
+/// Move a file into the trash and get where it ended up. If the trash already has a file of
+/// that name, the time of deletion is appended to the new one.
+pub async fn move_to_trash(rom_dir: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    let mut dest = trash_path(rom_dir, path);
+    if tokio::fs::try_exists(&dest).await? {
+        let mut name = dest.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", chrono::Utc::now().timestamp()));
+        dest.set_file_name(name);
+    }
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    move_file(path, &dest).await?;
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trash_path() {
+        let rom_dir = Path::new("/games");
+        assert_eq!(
+            trash_path(rom_dir, Path::new("/games/Some Game/game.nsp")),
+            Path::new("/games/.alumulemu-trash/Some Game/game.nsp")
+        );
+        assert_eq!(
+            trash_path(rom_dir, Path::new("/elsewhere/game.nsp")),
+            Path::new("/games/.alumulemu-trash/game.nsp")
+        );
+        assert_eq!(
+            trash_path(rom_dir, Path::new("/games/../game.nsp")),
+            Path::new("/games/.alumulemu-trash/game.nsp")
+        );
+
+        assert!(is_trash_path(Path::new("/games/.alumulemu-trash/game.nsp")));
+        assert!(!is_trash_path(Path::new("/games/game.nsp")));
+    }
+
+    #[tokio::test]
+    async fn test_move_to_trash() {
+        let rom_dir = tempfile::tempdir().unwrap();
+        let game = rom_dir.path().join("game.nsp");
+
+        std::fs::write(&game, b"first").unwrap();
+        let first = move_to_trash(rom_dir.path(), &game).await.unwrap();
+        assert_eq!(first, rom_dir.path().join(TRASH_DIR).join("game.nsp"));
+        assert!(!game.exists());
+
+        // A second file of the same name doesn't replace the first
+        std::fs::write(&game, b"second").unwrap();
+        let second = move_to_trash(rom_dir.path(), &game).await.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(std::fs::read(&first).unwrap(), b"first");
+        assert_eq!(std::fs::read(&second).unwrap(), b"second");
+    }
+}
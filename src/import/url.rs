@@ -23,6 +23,7 @@ impl Importer for UrlImporter {
         Ok(ImportSource::RemoteHttpAuto {
             url: request.url,
             headers: None,
+            expected_sha256: None,
         })
     }
 
@@ -37,6 +38,20 @@ impl Importer for UrlImporter {
     fn description(&self) -> &'static str {
         "Imports games from URLs"
     }
+
+    fn request_schema(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": "URL of the file, archives are extracted",
+                    "format": "uri"
+                }
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -57,9 +72,14 @@ mod tests {
             .unwrap();
 
         match result {
-            ImportSource::RemoteHttpAuto { url, headers } => {
+            ImportSource::RemoteHttpAuto {
+                url,
+                headers,
+                expected_sha256,
+            } => {
                 assert_eq!(url, "https://example.com/test.nsp");
                 assert!(headers.is_none(), "Headers should be None");
+                assert!(expected_sha256.is_none());
             }
             _ => panic!("Expected RemoteHttpAuto import source"),
         }
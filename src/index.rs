@@ -7,7 +7,9 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::secrets::SecretError;
 
 pub const EXTRA_INDEXES_LIST_TABLE: &str = "extra_indexes_list";
 
@@ -16,11 +18,36 @@ pub const EXTRA_INDEXES_LIST_TABLE: &str = "extra_indexes_list";
 pub struct ExtraIndexesImport {
     /// URL to download the index from
     pub url: String,
+    /// Headers to send when fetching the index, encrypted with [`crate::secrets`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<String>,
 }
 
 impl ExtraIndexesImport {
     pub fn new(url: String) -> Self {
-        ExtraIndexesImport { url }
+        ExtraIndexesImport { url, headers: None }
+    }
+
+    /// Set the headers sent when fetching the index, they're stored encrypted
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Result<Self, SecretError> {
+        self.headers = if headers.is_empty() {
+            None
+        } else {
+            let json = serde_json::to_vec(&headers).map_err(|_| SecretError::Malformed)?;
+            Some(crate::secrets::encrypt(&json)?)
+        };
+        Ok(self)
+    }
+
+    /// Decrypt the headers sent when fetching the index
+    pub fn headers(&self) -> Result<Option<HashMap<String, String>>, SecretError> {
+        let Some(stored) = &self.headers else {
+            return Ok(None);
+        };
+        let json = crate::secrets::decrypt(stored)?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|_| SecretError::Malformed)
     }
 
     pub async fn list() -> color_eyre::Result<Vec<Self>> {
@@ -46,6 +73,19 @@ impl ExtraIndexesImport {
         tracing::info!("Saved extra index to database");
         Ok(())
     }
+    /// Download the index and save it to the database
+    pub async fn load(&self) -> color_eyre::Result<()> {
+        let url = &self.url;
+        tracing::info!(%url, "Loading extra index");
+        let headers = self.headers()?;
+        // we will just name indexes after the URL
+        let idx = Index::load_index_url(url, headers.as_ref()).await?;
+
+        idx.save_extra_index(url).await?;
+        tracing::info!(%url, "Index loaded and saved");
+        Ok(())
+    }
+
     /// Deletes the extra index from the database.
     ///
     /// This does not actually delete the imported data itself, only the task to import it.
@@ -64,7 +104,7 @@ impl ExtraIndexesImport {
 
 use crate::db::DB;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct TinfoilTitleMeta {
     #[serde(rename = "id")]
@@ -80,6 +120,21 @@ pub struct TinfoilTitleMeta {
     pub description: String,
     pub size: u64,
     pub rank: u32,
+    /// Minimum system version needed to run the title, as packed in its CNMT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_system_version: Option<u32>,
+    /// Minimum firmware needed to run the title, such as `19.0.1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_firmware: Option<String>,
+}
+
+impl TinfoilTitleMeta {
+    /// Set the minimum system version the title needs
+    pub fn with_required_system_version(mut self, version: crate::nsp::SystemVersion) -> Self {
+        self.required_system_version = Some(version.0);
+        self.required_firmware = Some(version.to_string());
+        self
+    }
 }
 
 impl TryFrom<crate::titledb::Title> for TinfoilTitleMeta {
@@ -89,7 +144,10 @@ impl TryFrom<crate::titledb::Title> for TinfoilTitleMeta {
         Ok(TinfoilTitleMeta {
             title_id: title.title_id.unwrap_or_default(),
             name: title.name.unwrap_or_default(),
-            version: title.version.unwrap_or_default().parse().unwrap(),
+            version: title
+                .version
+                .and_then(|version| version.parse().ok())
+                .unwrap_or_default(),
             region: title.region.unwrap_or_default(),
             release_date: title.release_date.unwrap_or_default(),
             rating: title.rating.unwrap_or_default() as u8,
@@ -97,6 +155,8 @@ impl TryFrom<crate::titledb::Title> for TinfoilTitleMeta {
             description: title.description.unwrap_or_default(),
             size: title.size.unwrap_or_default(),
             rank: 0,
+            required_system_version: None,
+            required_firmware: None,
         })
     }
 }
@@ -161,6 +221,51 @@ pub enum TinfoilError {
     Failure(String),
 }
 
+/// Maximum number of characters of an unexpected response body to include in errors
+const BODY_SNIPPET_LEN: usize = 200;
+
+/// Errors that can occur while downloading an extra index
+#[derive(Debug, thiserror::Error)]
+pub enum IndexLoadError {
+    #[error("Failed to fetch index from {url}: {source}")]
+    Request { url: String, source: reqwest::Error },
+    #[error("Index at {url} returned {status}: {body}")]
+    Status {
+        url: String,
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    #[error("Index at {url} is encrypted, only plain JSON indexes can be imported")]
+    Encrypted { url: String },
+    #[error("Index at {url} is not a valid index ({source}), got: {body}")]
+    Parse {
+        url: String,
+        source: serde_json::Error,
+        body: String,
+    },
+}
+
+/// Shorten a response body for error messages, collapsing whitespace and dropping binary junk
+fn body_snippet(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(body);
+    let mut snippet: String = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(BODY_SNIPPET_LEN)
+        .collect();
+
+    if snippet.is_empty() {
+        return "<empty body>".to_string();
+    }
+    if text.chars().count() > BODY_SNIPPET_LEN {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
 // todo: something like this?
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -382,10 +487,57 @@ impl Index {
         self.files.push(file_link);
     }
 
-    pub async fn load_index_url(url: &str) -> color_eyre::Result<Self> {
-        let response = reqwest::get(url).await?;
-        let index: Index = response.json().await?;
-        Ok(index)
+    /// Download an index, sending the given headers along with the request
+    pub async fn load_index_url(
+        url: &str,
+        headers: Option<&HashMap<String, String>>,
+    ) -> Result<Self, IndexLoadError> {
+        let request_error = |source| IndexLoadError::Request {
+            url: url.to_string(),
+            source,
+        };
+        let mut request = crate::http_client::client().get(url);
+        for (key, value) in headers.into_iter().flatten() {
+            request = request.header(key, value);
+        }
+        let response = request.send().await.map_err(request_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(request_error)?;
+
+        Self::from_response_body(url, status, &body)
+    }
+
+    /// Parse the response to an index request, describing what was received instead if it
+    /// failed or isn't an index
+    pub fn from_response_body(
+        url: &str,
+        status: reqwest::StatusCode,
+        body: &[u8],
+    ) -> Result<Self, IndexLoadError> {
+        if !status.is_success() {
+            return Err(IndexLoadError::Status {
+                url: url.to_string(),
+                status,
+                body: body_snippet(body),
+            });
+        }
+
+        Self::parse_index_body(url, body)
+    }
+
+    /// Parse a downloaded index, describing what was received instead if it isn't one
+    fn parse_index_body(url: &str, body: &[u8]) -> Result<Self, IndexLoadError> {
+        if body.starts_with(crate::index_encryption::MAGIC) {
+            return Err(IndexLoadError::Encrypted {
+                url: url.to_string(),
+            });
+        }
+
+        serde_json::from_slice(body).map_err(|source| IndexLoadError::Parse {
+            url: url.to_string(),
+            source,
+            body: body_snippet(body),
+        })
     }
 
     /// Saves the extra index to the database table.
@@ -404,6 +556,12 @@ impl Index {
         Ok(())
     }
 
+    /// Deletes a saved extra index, so its files are no longer served
+    pub async fn delete_extra_index(src_name: &str) -> color_eyre::Result<()> {
+        let _: Option<Self> = DB.delete((EXTRA_INDEXES_TABLE, src_name)).await?;
+        Ok(())
+    }
+
     pub async fn get_extra_indexes() -> color_eyre::Result<Vec<Index>> {
         let db: Vec<Self> = DB.select(EXTRA_INDEXES_TABLE).await?.into_iter().collect();
         Ok(db)
@@ -426,6 +584,36 @@ impl IntoResponse for Index {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_index_body_errors() {
+        let url = "https://mirror.example/index.json";
+
+        let err = Index::parse_index_body(url, b"<html>\n  <body>Bad   gateway</body>\n</html>")
+            .unwrap_err();
+        assert!(matches!(err, IndexLoadError::Parse { .. }));
+        let message = err.to_string();
+        assert!(message.contains(url), "{message}");
+        assert!(
+            message.ends_with("got: <html> <body>Bad gateway</body> </html>"),
+            "{message}"
+        );
+
+        let err = Index::parse_index_body(url, b"TINFOIL\xfd\x00\x01").unwrap_err();
+        assert!(matches!(err, IndexLoadError::Encrypted { .. }));
+
+        assert!(Index::parse_index_body(url, br#"{"files": []}"#).is_ok());
+    }
+
+    #[test]
+    fn test_body_snippet() {
+        assert_eq!(body_snippet(b""), "<empty body>");
+        let long = "a".repeat(BODY_SNIPPET_LEN + 10);
+        assert_eq!(
+            body_snippet(long.as_bytes()),
+            format!("{}...", "a".repeat(BODY_SNIPPET_LEN))
+        );
+    }
+
     #[test]
     fn test_success_from_index() {
         let index = Index {
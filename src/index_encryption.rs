@@ -0,0 +1,228 @@
+//! Tinfoil encrypted index support
+//!
+//! Tinfoil can read indexes wrapped in its encrypted container (see the
+//! [Tinfoil DRM specification](https://blawar.github.io/tinfoil/drm/)), so a private shop's
+//! file list isn't sent over the wire in plaintext. The container layout is:
+//!
+//! | Offset  | Size    | Contents                                                              |
+//! |---------|---------|-----------------------------------------------------------------------|
+//! | `0x000` | 7       | Magic, `TINFOIL`                                                      |
+//! | `0x007` | 1       | Flags, `0xF0` (encrypted) OR'd with the compression (`0x0D`, zstd)    |
+//! | `0x008` | `0x100` | AES-128 session key, wrapped with RSA-OAEP (SHA-256) and Tinfoil's public key |
+//! | `0x108` | 8       | Size of the compressed index, little endian                           |
+//! | `0x110` | ...     | Compressed index, zero padded and encrypted with AES-128-ECB          |
+//!
+//! Only Tinfoil holds the private key, so the RSA public key published alongside the
+//! specification has to be configured with `ALU_TINFOIL_PUBLIC_KEY`.
+
+use std::{path::Path, sync::OnceLock};
+
+use aes::{
+    Aes128,
+    cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use rsa::{
+    Oaep, RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, rand_core::OsRng,
+    traits::PublicKeyParts,
+};
+use sha2::Sha256;
+
+use crate::index::Index;
+
+pub const MAGIC: &[u8; 7] = b"TINFOIL";
+const FLAG_ENCRYPTED: u8 = 0xF0;
+const FLAG_ZSTD: u8 = 0x0D;
+const ZSTD_LEVEL: i32 = 3;
+const AES_BLOCK_LEN: usize = 16;
+const SHA256_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IndexEncryptionError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid PEM file: {0}")]
+    Pem(#[from] pem::PemError),
+    #[error("Invalid RSA public key: {0}")]
+    InvalidKey(String),
+    #[error("Failed to wrap the session key: {0}")]
+    Rsa(#[from] rsa::Error),
+    #[error("Failed to serialize index: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, IndexEncryptionError>;
+
+/// RSA public key used to wrap the session key of encrypted indexes
+#[derive(Debug, Clone)]
+pub struct TinfoilPublicKey {
+    key: RsaPublicKey,
+}
+
+impl TinfoilPublicKey {
+    /// Load a key from a PEM file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_pem(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse a `PUBLIC KEY` (SPKI) or `RSA PUBLIC KEY` (PKCS#1) PEM block
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let pem = pem::parse(pem)?;
+        let key = match pem.tag() {
+            "PUBLIC KEY" => RsaPublicKey::from_public_key_der(pem.contents())
+                .map_err(|e| IndexEncryptionError::InvalidKey(e.to_string()))?,
+            "RSA PUBLIC KEY" => RsaPublicKey::from_pkcs1_der(pem.contents())
+                .map_err(|e| IndexEncryptionError::InvalidKey(e.to_string()))?,
+            _ => {
+                return Err(IndexEncryptionError::InvalidKey(
+                    "expected a PUBLIC KEY or RSA PUBLIC KEY PEM block".to_string(),
+                ));
+            }
+        };
+        if key.size() < 2 * SHA256_LEN + 2 + AES_BLOCK_LEN {
+            return Err(IndexEncryptionError::InvalidKey(
+                "key is too small".to_string(),
+            ));
+        }
+        Ok(Self { key })
+    }
+
+    /// Size of the modulus in bytes, which is also the size of a wrapped key
+    fn size(&self) -> usize {
+        self.key.size()
+    }
+
+    /// Encrypt a short message with RSA-OAEP, using SHA-256 and an empty label
+    fn wrap(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self
+            .key
+            .encrypt(&mut OsRng, Oaep::new::<Sha256>(), message)?)
+    }
+}
+
+/// Serialize an index and wrap it in Tinfoil's encrypted container
+pub fn encrypt_index(index: &Index, key: &TinfoilPublicKey) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(index)?;
+    let compressed = zstd::bulk::compress(&json, ZSTD_LEVEL)?;
+
+    // Like Tinfoil's own tooling, always pad, even if the data is already block aligned
+    let mut data = compressed.clone();
+    data.resize(
+        compressed.len() + AES_BLOCK_LEN - compressed.len() % AES_BLOCK_LEN,
+        0,
+    );
+
+    let session_key: [u8; AES_BLOCK_LEN] = rand::random();
+    let cipher = Aes128::new(&session_key.into());
+    for block in data.chunks_exact_mut(AES_BLOCK_LEN) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    let mut output = Vec::with_capacity(MAGIC.len() + 1 + key.size() + 8 + data.len());
+    output.extend_from_slice(MAGIC);
+    output.push(FLAG_ENCRYPTED | FLAG_ZSTD);
+    output.extend_from_slice(&key.wrap(&session_key)?);
+    output.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+    output.extend_from_slice(&data);
+
+    Ok(output)
+}
+
+/// Get the public key configured with `ALU_TINFOIL_PUBLIC_KEY`, if any.
+///
+/// The key is loaded once, an invalid key keeps failing until the server is restarted
+/// with a fixed configuration.
+pub fn configured_public_key() -> std::result::Result<Option<&'static TinfoilPublicKey>, String> {
+    static KEY: OnceLock<std::result::Result<Option<TinfoilPublicKey>, String>> = OnceLock::new();
+
+    KEY.get_or_init(|| {
+        crate::config::config()
+            .backend_config
+            .tinfoil_public_key
+            .map(|path| {
+                TinfoilPublicKey::load(&path)
+                    .map_err(|e| format!("Failed to load Tinfoil public key {path}: {e}"))
+            })
+            .transpose()
+    })
+    .as_ref()
+    .map(Option::as_ref)
+    .map_err(Clone::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1024-bit test key, Tinfoil's real key is 2048-bit but the container works the same
+    const TEST_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC52CKy9MM39EMcdaVAuez9sD0y
+qyQZ+7NDDQ7ggjNST0EXVVxrBR14uUe3yYxpxF33mm3WKZ64gWWhywYPbrxlcZ5v
++lU6owvWsPH5H8N4eLcjrA7PKiLKqfl/Z1Qy8451JcXw+c8exGLHn3OeyBQsJ6/S
+VO3/CsQDEVNtxsJsTQIDAQAB
+-----END PUBLIC KEY-----";
+
+    /// Private exponent of the test key, used to unwrap the session key
+    const TEST_PRIVATE_EXPONENT: &str = concat!(
+        "8e4f74dac6d54adeb01975f932646d972242eaaee4f944a5d3fd86dbe2b7fc1a",
+        "968490a831877c36fae27159543d95d2430dad7826ecd4eab971b8af0e5844c8",
+        "b78c16cce7e47eacc3f30e87d745ebb60d89493ed7e2ff30f51e9c2728934818",
+        "c65ef12d3c753520fd4d1c046c9a9e0e62570d79f60c12ed98140a70c9aca521"
+    );
+
+    fn test_key() -> TinfoilPublicKey {
+        TinfoilPublicKey::from_pem(TEST_PUBLIC_KEY).unwrap()
+    }
+
+    /// RSA-OAEP decryption, the inverse of [`TinfoilPublicKey::wrap`]
+    fn unwrap(key: &TinfoilPublicKey, wrapped: &[u8]) -> Vec<u8> {
+        let d = rsa::BigUint::parse_bytes(TEST_PRIVATE_EXPONENT.as_bytes(), 16).unwrap();
+        let private = rsa::RsaPrivateKey::from_components(
+            key.key.n().clone(),
+            key.key.e().clone(),
+            d,
+            vec![],
+        )
+        .unwrap();
+        private.decrypt(Oaep::new::<Sha256>(), wrapped).unwrap()
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = test_key();
+        assert_eq!(key.size(), 128);
+        assert_eq!(key.key.e(), &rsa::BigUint::from(65537u32));
+
+        assert!(TinfoilPublicKey::from_pem("not a key").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_index_roundtrip() {
+        use aes::cipher::BlockDecrypt;
+
+        let key = test_key();
+        let index = Index {
+            success: Some("Hello from an encrypted index".to_string()),
+            ..Default::default()
+        };
+
+        let encrypted = encrypt_index(&index, &key).unwrap();
+        assert_eq!(&encrypted[..7], MAGIC);
+        assert_eq!(encrypted[7], FLAG_ENCRYPTED | FLAG_ZSTD);
+
+        let (wrapped_key, rest) = encrypted[8..].split_at(key.size());
+        let (size, data) = rest.split_at(8);
+        let size = u64::from_le_bytes(size.try_into().unwrap()) as usize;
+        assert_eq!(data.len() % AES_BLOCK_LEN, 0);
+
+        let session_key = unwrap(&key, wrapped_key);
+        let cipher = Aes128::new_from_slice(&session_key).unwrap();
+        let mut data = data.to_vec();
+        for block in data.chunks_exact_mut(AES_BLOCK_LEN) {
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+
+        let json = zstd::bulk::decompress(&data[..size], 1 << 20).unwrap();
+        let decrypted: Index = serde_json::from_slice(&json).unwrap();
+        assert_eq!(decrypted.success, index.success);
+    }
+}
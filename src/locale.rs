@@ -0,0 +1,222 @@
+//! eShop regions and languages supported by TitleDB
+//!
+//! TitleDB publishes one file per region and language pair (e.g. `US.en.json`), and the
+//! imported titles end up in a `titles_<REGION>_<lang>` table. A typo in either half used to
+//! silently produce an empty table, so both are parsed into these types up front.
+
+use std::{fmt, str::FromStr};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum LocaleError {
+    #[error("unknown region '{0}', valid regions are: {valid}", valid = list(Region::ALL))]
+    UnknownRegion(String),
+    #[error("unknown language '{0}', valid languages are: {valid}", valid = list(Language::ALL))]
+    UnknownLanguage(String),
+    #[error("invalid locale '{0}', expected <REGION>_<lang> such as US_en or JP_ja")]
+    InvalidLocale(String),
+}
+
+fn list<T: fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Defines a code-backed enum, parsed case-insensitively and displayed as its canonical code
+macro_rules! locale_enum {
+    ($(#[$meta:meta])* $name:ident, $error:ident { $($variant:ident => $code:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub const ALL: &'static [Self] = &[$(Self::$variant),+];
+
+            pub fn code(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $code),+
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.code())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = LocaleError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let s = s.trim();
+                Self::ALL
+                    .iter()
+                    .find(|value| value.code().eq_ignore_ascii_case(s))
+                    .copied()
+                    .ok_or_else(|| LocaleError::$error(s.to_string()))
+            }
+        }
+    };
+}
+
+locale_enum!(
+    /// An eShop region, as an ISO 3166 country code
+    Region, UnknownRegion {
+        Ar => "AR", At => "AT", Au => "AU", Be => "BE", Bg => "BG", Br => "BR",
+        Ca => "CA", Ch => "CH", Cl => "CL", Cn => "CN", Co => "CO", Cy => "CY",
+        Cz => "CZ", De => "DE", Dk => "DK", Ee => "EE", Es => "ES", Fi => "FI",
+        Fr => "FR", Gb => "GB", Gr => "GR", Hk => "HK", Hr => "HR", Hu => "HU",
+        Ie => "IE", It => "IT", Jp => "JP", Kr => "KR", Lt => "LT", Lu => "LU",
+        Lv => "LV", Mt => "MT", Mx => "MX", Nl => "NL", No => "NO", Nz => "NZ",
+        Pe => "PE", Pl => "PL", Pt => "PT", Ro => "RO", Ru => "RU", Se => "SE",
+        Si => "SI", Sk => "SK", Us => "US", Za => "ZA",
+    }
+);
+
+locale_enum!(
+    /// An eShop language, as an ISO 639-1 code
+    Language, UnknownLanguage {
+        En => "en", Es => "es", Fr => "fr", De => "de", It => "it", Nl => "nl",
+        Pt => "pt", Ru => "ru", Ja => "ja", Ko => "ko", Zh => "zh",
+    }
+);
+
+impl Region {
+    /// Languages TitleDB publishes for this region
+    pub fn languages(&self) -> &'static [Language] {
+        use Language::*;
+        match self {
+            Region::Ar | Region::Cl | Region::Co | Region::Mx | Region::Pe | Region::Us => {
+                &[En, Es]
+            }
+            Region::At | Region::De => &[De],
+            Region::Be => &[Fr, Nl],
+            Region::Br => &[En, Pt],
+            Region::Ca => &[En, Fr],
+            Region::Ch => &[Fr, De, It],
+            Region::Cn | Region::Hk => &[Zh],
+            Region::Es => &[Es],
+            Region::Fr => &[Fr],
+            Region::It => &[It],
+            Region::Jp => &[Ja],
+            Region::Kr => &[Ko],
+            Region::Lu => &[Fr, De],
+            Region::Nl => &[Nl],
+            Region::Pt => &[Pt],
+            Region::Ru => &[Ru],
+            _ => &[En],
+        }
+    }
+}
+
+/// A region and language pair, written as `US_en`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Locale {
+    pub region: Region,
+    pub language: Language,
+}
+
+impl Locale {
+    pub fn new(region: Region, language: Language) -> Self {
+        Self { region, language }
+    }
+
+    /// Check if TitleDB publishes this language for this region
+    pub fn is_published(&self) -> bool {
+        self.region.languages().contains(&self.language)
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.region, self.language)
+    }
+}
+
+impl FromStr for Locale {
+    type Err = LocaleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (region, language) = s
+            .trim()
+            .split_once('_')
+            .ok_or_else(|| LocaleError::InvalidLocale(s.to_string()))?;
+        Ok(Self::new(region.parse()?, language.parse()?))
+    }
+}
+
+/// A comma-separated list of locales, such as `JP_ja,US_es`. Empty entries are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocaleList(pub Vec<Locale>);
+
+impl std::ops::Deref for LocaleList {
+    type Target = [Locale];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromStr for LocaleList {
+    type Err = LocaleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|locale| !locale.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_locale() {
+        let locale: Locale = "us_EN".parse().unwrap();
+        assert_eq!(locale, Locale::new(Region::Us, Language::En));
+        assert_eq!(locale.to_string(), "US_en");
+        assert!(locale.is_published());
+
+        assert!(!Locale::new(Region::Jp, Language::En).is_published());
+    }
+
+    #[test]
+    fn test_parse_locale_invalid() {
+        assert_eq!(
+            "XX_en".parse::<Locale>(),
+            Err(LocaleError::UnknownRegion("XX".to_string()))
+        );
+        assert_eq!(
+            "US_english".parse::<Locale>(),
+            Err(LocaleError::UnknownLanguage("english".to_string()))
+        );
+        assert_eq!(
+            "USen".parse::<Locale>(),
+            Err(LocaleError::InvalidLocale("USen".to_string()))
+        );
+
+        let message = "XX".parse::<Region>().unwrap_err().to_string();
+        assert!(message.contains("US, ZA"), "{message}");
+    }
+
+    #[test]
+    fn test_parse_locale_list() {
+        assert_eq!(
+            "JP_ja, ,us_es,".parse::<LocaleList>().unwrap().0,
+            vec![
+                Locale::new(Region::Jp, Language::Ja),
+                Locale::new(Region::Us, Language::Es)
+            ]
+        );
+        assert!("".parse::<LocaleList>().unwrap().is_empty());
+        assert!("JP_ja,XX_en".parse::<LocaleList>().is_err());
+    }
+}
@@ -1,10 +1,16 @@
 mod backend;
 mod config;
 mod db;
+mod http_client;
 mod import;
 mod index;
+mod index_encryption;
+mod locale;
 mod nsp;
 mod router;
+mod secrets;
+mod storage;
+mod title_kind;
 mod titledb;
 mod util;
 
@@ -14,7 +20,7 @@ use cron::Schedule;
 use db::init_database;
 use import::registry::init_registry;
 use index::ExtraIndexesImport;
-use reqwest::Client;
+use locale::Locale;
 use router::{create_router, watch_filesystem_for_changes};
 use std::str::FromStr;
 use std::sync::LazyLock;
@@ -26,24 +32,16 @@ use util::download_titledb;
 static LOCALE: LazyLock<String> =
     LazyLock::new(|| crate::config::config().backend_config.get_locale_string());
 
-pub fn games_dir() -> String {
-    let config = config::config();
-    tracing::debug!("Games directory: {}", config.backend_config.rom_dir);
-    config.backend_config.rom_dir
+/// Every directory with games, the primary `ALU_ROM_DIR` first
+pub fn games_dirs() -> Vec<String> {
+    config::config().backend_config.rom_dirs()
 }
 
 pub async fn romdir_inotify() {
-    if let Err(e) = watch_filesystem_for_changes(&games_dir()).await {
-        tracing::error!("Failed to start filesystem watcher: {}", e);
-    }
-}
-
-fn parse_secondary_locale_string(locale: &str) -> Result<(String, String)> {
-    let parts: Vec<&str> = locale.split('_').collect();
-    if parts.len() == 2 {
-        Ok((parts[0].to_uppercase(), parts[1].to_lowercase()))
-    } else {
-        Err(color_eyre::eyre::eyre!("Invalid locale string: {}", locale))
+    for dir in games_dirs() {
+        if let Err(e) = watch_filesystem_for_changes(&dir).await {
+            tracing::error!("Failed to start filesystem watcher for {}: {}", dir, e);
+        }
     }
 }
 
@@ -52,32 +50,27 @@ async fn import_extra_indexes() -> Result<()> {
     // Use the helper method to get only valid indexes
     let idx_to_addlist = config.backend_config.get_valid_extra_indexes();
 
-    if idx_to_addlist.is_empty() {
-        tracing::info!("No extra indexes to import");
-        return Ok(());
-    }
-
-    // Add indexes to list
+    // Add indexes to list, keeping the headers of ones that are already there
+    let existing = ExtraIndexesImport::list().await?;
     for url in idx_to_addlist {
+        if existing.iter().any(|i| i.url == url) {
+            continue;
+        }
         tracing::info!(%url, "Adding extra index to the list");
 
         let e_idx = ExtraIndexesImport::new(url);
         e_idx.add().await?;
     }
 
-    let idx_to_import = ExtraIndexesImport::list()
-        .await?
-        .iter()
-        .map(|i| i.url.clone())
-        .collect::<Vec<_>>();
+    // Indexes added through the API are in the list too
+    let idx_to_import = ExtraIndexesImport::list().await?;
+    if idx_to_import.is_empty() {
+        tracing::info!("No extra indexes to import");
+        return Ok(());
+    }
 
     for index in idx_to_import {
-        tracing::info!(%index, "Loading extra index");
-        // we will just name indexes after the URL
-        let idx = index::Index::load_index_url(&index).await?;
-
-        idx.save_extra_index(&index).await?;
-        tracing::info!(%index, "Index loaded and saved");
+        index.load().await?;
     }
     Ok(())
 }
@@ -116,83 +109,164 @@ async fn schedule_idx_downloads() -> Result<()> {
     }
 }
 
-async fn import_titledb(lang: &str, region: &str) -> Result<()> {
-    let client = Client::new();
-    let cache_dir = util::titledb_cache_dir();
-    let path = cache_dir.join(format!("{}.{}.json", region, lang));
+/// How often to check whether periodic rescans were enabled while they're disabled
+const RESCAN_DISABLED_POLL: Duration = Duration::from_secs(60 * 60);
 
-    let should_download = if let Ok(metadata) = std::fs::metadata(&path) {
-        if let Ok(modified) = metadata.modified() {
-            let age = modified.elapsed().unwrap_or_default();
-            age > Duration::from_secs(6 * 3600)
-        } else {
-            tracing::warn!(
-                "Could not get modification time for {:?}, will download again",
-                path
-            );
+fn rescan_interval_hours(config: Option<ExtraBackendConfig>) -> u64 {
+    config.unwrap_or_default().rescan_interval_hours
+}
+
+/// Periodically reconcile the rom dir with the database.
+///
+/// The filesystem watcher can miss events (network mounts, bulk moves, files added while
+/// alumulemu was down), so this catches anything it didn't pick up. The interval is read
+/// from [`ExtraBackendConfig`] every time, so changes apply without a restart.
+async fn schedule_rescans() {
+    loop {
+        let hours = rescan_interval_hours(ExtraBackendConfig::get().await.unwrap_or_default());
+        if hours == 0 {
+            tracing::debug!("Periodic rescans are disabled");
+            tokio::time::sleep(RESCAN_DISABLED_POLL).await;
+            continue;
+        }
+
+        tracing::info!("Next scheduled rescan in {} hours", hours);
+        tokio::time::sleep(Duration::from_secs(hours * 60 * 60)).await;
+
+        // Don't run if rescans were disabled in the meantime
+        if rescan_interval_hours(ExtraBackendConfig::get().await.unwrap_or_default()) == 0 {
+            continue;
+        }
+
+        tracing::info!("Scheduled rescan starting");
+        if let Err(e) = backend::admin::trigger_rescan(router::RescanOptions::default()).await {
+            tracing::error!("Scheduled rescan failed: {}", e);
+        }
+    }
+}
+
+/// How long to wait before retrying a TitleDB import whose download failed,
+/// instead of waiting for the next regular 6-hour slot.
+const TITLEDB_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Outcome of a TitleDB import for a single locale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TitleDbImportOutcome {
+    /// Fresh data was imported, or the cached data was recent enough to skip
+    UpToDate,
+    /// The download failed, so stale cached data (or nothing) was used and a retry is due
+    Degraded,
+}
+
+async fn import_titledb_file(path: &std::path::Path, locale: Locale) {
+    match util::open_titledb_cache(path) {
+        Ok(titledb_file) => {
+            let start = std::time::Instant::now();
+            let total_bytes = util::titledb_cache_size(path)
+                .inspect_err(|e| tracing::debug!("Failed to get size of {:?}: {}", path, e))
+                .ok();
+            let result = TitleDBImport::from_json_reader_streaming(
+                titledb_file,
+                &locale.to_string(),
+                total_bytes,
+            )
+            .await;
+
+            let duration = start.elapsed();
+
+            if let Err(e) = result {
+                tracing::error!("TitleDB import failed for {locale}: {}", e);
+            } else {
+                tracing::info!("TitleDB import for {locale} took: {:?}", duration);
+                tracing::info!("TitleDB import complete for {locale}");
+                // Index entries carry TitleDB names, grouping and filter facets
+                backend::api::invalidate_index_cache();
+            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to open TitleDB file {:?}: {}", path, e);
+        }
+    }
+}
+
+async fn import_titledb(locale: Locale) -> Result<TitleDbImportOutcome> {
+    let Locale { region, language } = locale;
+    let client = http_client::client();
+    // Either format counts, so existing plain caches aren't downloaded again right away
+    let cached = util::find_titledb_cache(region, language);
+
+    let should_download = match cached.as_ref().map(std::fs::metadata) {
+        Some(Ok(metadata)) => {
+            if let Ok(modified) = metadata.modified() {
+                let age = modified.elapsed().unwrap_or_default();
+                age > Duration::from_secs(6 * 3600)
+            } else {
+                tracing::warn!(
+                    "Could not get modification time for {:?}, will download again",
+                    cached
+                );
+                true
+            }
+        }
+        _ => {
+            tracing::debug!("No cached TitleDB for {locale}, will download");
             true
         }
-    } else {
-        tracing::debug!("File {:?} does not exist, will download", path);
-        true
     };
 
     if should_download {
-        match download_titledb(&client, region, lang).await {
-            Ok(path_str) => match std::fs::File::open(&path_str) {
-                Ok(titledb_file) => {
-                    if let Err(e) = TitleDBImport::from_json_reader_streaming(
-                        titledb_file,
-                        &format!("{region}_{lang}"),
-                    )
-                    .await
-                    {
-                        tracing::error!("Failed to import TitleDB: {}", e);
-                    } else {
-                        tracing::info!("TitleDB update complete!");
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Failed to open TitleDB file {}: {}", path_str, e);
-                }
-            },
+        match download_titledb(&client, region, language).await {
+            Ok(path) => {
+                import_titledb_file(&path, locale).await;
+                return Ok(TitleDbImportOutcome::UpToDate);
+            }
             Err(e) => {
-                tracing::error!("Failed to download TitleDB for {}-{}: {}", region, lang, e);
+                tracing::error!("Failed to download TitleDB for {}: {}", locale, e);
             }
         }
-        return Ok(());
+
+        // The download failed, fall back to whatever we have cached, even if it's stale
+        let Some(path) = cached.filter(|path| path.exists()) else {
+            tracing::error!(
+                "No cached TitleDB available for {locale}, metadata will be missing until the next retry"
+            );
+            return Ok(TitleDbImportOutcome::Degraded);
+        };
+
+        let age = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| m.elapsed().ok())
+            .unwrap_or_default();
+        tracing::warn!(
+            "Using STALE cached TitleDB for {locale} from {:?} ({} hours old)",
+            path,
+            age.as_secs() / 3600
+        );
+
+        // Only re-import the stale file if the table is empty, otherwise the existing
+        // data is already at least as fresh as the cache
+        match titledb::Title::count(&locale.to_string()).await {
+            Ok(0) => import_titledb_file(&path, locale).await,
+            Ok(_) => tracing::info!(
+                "TitleDB table for {locale} already has data, keeping it until the next retry"
+            ),
+            Err(e) => tracing::error!("Failed to get title count: {}", e),
+        }
+
+        return Ok(TitleDbImportOutcome::Degraded);
     }
 
+    let Some(path) = cached else {
+        return Ok(TitleDbImportOutcome::UpToDate);
+    };
+
     // Check if the Title table is empty
-    match titledb::Title::count(&format!("{region}_{lang}")).await {
+    match titledb::Title::count(&locale.to_string()).await {
         Ok(count) => {
             if count == 0 {
                 // Force import if table is empty, but don't re-download
-                match std::fs::File::open(&path) {
-                    Ok(titledb_file) => {
-                        let start = std::time::Instant::now();
-                        let result = TitleDBImport::from_json_reader_streaming(
-                            titledb_file,
-                            &format!("{region}_{lang}"),
-                        )
-                        .await;
-
-                        let duration = start.elapsed();
-
-                        if let Err(e) = result {
-                            tracing::error!("TitleDB import failed for {region}_{lang}: {}", e);
-                        } else {
-                            tracing::info!(
-                                "TitleDB import for {region}_{lang} took: {:?}",
-                                duration
-                            );
-                            tracing::info!("TitleDB import complete for {region}_{lang}");
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to open TitleDB file {:?}: {}", path, e);
-                    }
-                }
+                import_titledb_file(&path, locale).await;
             } else {
                 tracing::info!("TitleDB .json is recent and table has data, skipping...");
             }
@@ -202,52 +276,52 @@ async fn import_titledb(lang: &str, region: &str) -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(TitleDbImportOutcome::UpToDate)
 }
 
-async fn import_titledb_background(config: config::Config) -> Result<()> {
+/// Imports TitleDB for all configured locales.
+///
+/// Returns `true` if any locale could not be downloaded and fell back to stale data,
+/// in which case the caller should retry sooner than the regular schedule.
+async fn import_titledb_background(config: config::Config) -> Result<bool> {
     let span = tracing::info_span!("titledb_import");
     let _enter = span.enter();
 
     // Create primary import task
-    let primary_task: tokio::task::JoinHandle<Result<()>> = tokio::spawn({
-        let backend_config = config.backend_config.clone();
-        let lang = backend_config.primary_lang.clone();
-        let region = backend_config.primary_region.clone();
+    let primary_task: tokio::task::JoinHandle<bool> = tokio::spawn({
+        let locale = config.backend_config.primary_locale();
         async move {
-            if let Err(e) = import_titledb(&lang, &region).await {
-                tracing::error!("Primary TitleDB import failed: {}", e);
-            } else {
-                tracing::info!("TitleDB import complete for primary locale");
+            match import_titledb(locale).await {
+                Ok(outcome) => {
+                    tracing::info!("TitleDB import complete for primary locale");
+                    outcome == TitleDbImportOutcome::Degraded
+                }
+                Err(e) => {
+                    tracing::error!("Primary TitleDB import failed: {}", e);
+                    true
+                }
             }
-            Ok(())
         }
     });
 
     // Create secondary import tasks
     let secondary_tasks: Vec<_> = config
         .backend_config
-        .get_valid_secondary_locales()
-        .into_iter()
+        .secondary_locales
+        .iter()
+        .copied()
         .map(|locale| {
             tokio::spawn(async move {
-                match parse_secondary_locale_string(&locale) {
-                    Ok((region, lang)) => {
-                        if let Err(e) = import_titledb(&lang, &region).await {
-                            tracing::error!(
-                                "Secondary TitleDB import failed for {}: {}",
-                                locale,
-                                e
-                            );
-                        } else {
-                            tracing::info!("TitleDB import complete for {}", locale);
-                        }
+                match import_titledb(locale).await {
+                    Ok(outcome) => {
+                        tracing::info!("TitleDB import complete for {}", locale);
+                        outcome == TitleDbImportOutcome::Degraded
                     }
                     Err(e) => {
-                        tracing::error!("Invalid secondary locale '{}': {}", locale, e);
+                        tracing::error!("Secondary TitleDB import failed for {}: {}", locale, e);
+                        true
                     }
                 }
-                Ok(())
             })
         })
         .collect();
@@ -259,17 +333,31 @@ async fn import_titledb_background(config: config::Config) -> Result<()> {
     let results = futures::future::join_all(all_tasks).await;
 
     // Check for errors but don't fail the entire process
+    let mut degraded = false;
     for (i, result) in results.into_iter().enumerate() {
-        if let Err(e) = result {
-            tracing::error!("Import task {} failed: {}", i, e);
+        match result {
+            Ok(needs_retry) => degraded |= needs_retry,
+            Err(e) => {
+                tracing::error!("Import task {} failed: {}", i, e);
+                degraded = true;
+            }
         }
     }
 
-    tracing::info!("TitleDB import complete for all locales");
-    Ok(())
+    if degraded {
+        tracing::warn!("TitleDB import finished with stale or missing data for some locales");
+    } else {
+        tracing::info!("TitleDB import complete for all locales");
+    }
+
+    // The cached index keeps the TitleDB metadata it was generated with
+    if let Err(e) = backend::api::regenerate_index_cache().await {
+        tracing::error!("Failed to regenerate the tinfoil index: {}", e);
+    }
+    Ok(degraded)
 }
 
-async fn schedule_titledb_imports(config: config::Config) -> Result<()> {
+async fn schedule_titledb_imports(config: config::Config, mut retry_soon: bool) -> Result<()> {
     // Schedule for every 6 hours: midnight, 6am, noon, 6pm
     const EXPRESSION: &str = "0 0 0,6,12,18 * * * *";
     let schedule = match Schedule::from_str(EXPRESSION) {
@@ -288,14 +376,23 @@ async fn schedule_titledb_imports(config: config::Config) -> Result<()> {
         if let Some(next_time) = schedule.upcoming(chrono::Local).next() {
             // Calculate duration until the next run
             let duration_until_next = next_time - now;
-            let seconds_until_next = duration_until_next.num_seconds();
-
-            tracing::info!(
-                "Next scheduled TitleDB import at {} (in {} hours and {} minutes)",
-                next_time.format("%Y-%m-%d %H:%M:%S"),
-                seconds_until_next / 3600,
-                (seconds_until_next % 3600) / 60
-            );
+            let mut seconds_until_next = duration_until_next.num_seconds();
+
+            // The last import fell back to stale data, so don't wait for the next slot
+            if retry_soon && seconds_until_next > TITLEDB_RETRY_DELAY.as_secs() as i64 {
+                seconds_until_next = TITLEDB_RETRY_DELAY.as_secs() as i64;
+                tracing::warn!(
+                    "Last TitleDB download failed, retrying in {} minutes",
+                    seconds_until_next / 60
+                );
+            } else {
+                tracing::info!(
+                    "Next scheduled TitleDB import at {} (in {} hours and {} minutes)",
+                    next_time.format("%Y-%m-%d %H:%M:%S"),
+                    seconds_until_next / 3600,
+                    (seconds_until_next % 3600) / 60
+                );
+            }
 
             // Sleep until the next scheduled time
             if seconds_until_next > 0 {
@@ -304,9 +401,13 @@ async fn schedule_titledb_imports(config: config::Config) -> Result<()> {
 
             // Run the import task
             tracing::info!("Scheduled TitleDB import starting");
-            if let Err(e) = import_titledb_background(config.clone()).await {
-                tracing::error!("Scheduled TitleDB import failed: {}", e);
-            }
+            retry_soon = match import_titledb_background(config.clone()).await {
+                Ok(degraded) => degraded,
+                Err(e) => {
+                    tracing::error!("Scheduled TitleDB import failed: {}", e);
+                    true
+                }
+            };
         } else {
             // This should never happen with a valid cron expression
             tracing::error!("Failed to determine next schedule time");
@@ -373,26 +474,86 @@ async fn main() -> color_eyre::Result<()> {
 
     let config = config::config();
 
+    // Unknown regions and languages are rejected by the config parser, but TitleDB also
+    // doesn't publish every language for every region
+    for locale in std::iter::once(config.backend_config.primary_locale())
+        .chain(config.backend_config.secondary_locales.iter().copied())
+    {
+        if !locale.is_published() {
+            let languages: Vec<_> = locale.region.languages().iter().map(|l| l.code()).collect();
+            tracing::warn!(
+                "TitleDB does not publish {locale}, metadata for it will be missing. Languages available for {} are: {}",
+                locale.region,
+                languages.join(", ")
+            );
+        }
+    }
+
+    config
+        .backend_config
+        .check_anonymous_scopes()
+        .map_err(color_eyre::Report::msg)?;
+    config
+        .backend_config
+        .admin_credentials()
+        .map_err(color_eyre::Report::msg)?;
+    let anonymous_scopes = config.backend_config.anonymous_scopes();
+    if config.backend_config.public && anonymous_scopes.iter().any(|scope| scope.can_edit()) {
+        tracing::warn!(
+            "Public mode lets anyone who can reach the server act with the scopes {:?}",
+            anonymous_scopes
+        );
+    }
+
+    match index_encryption::configured_public_key() {
+        Ok(Some(_)) => tracing::info!("Tinfoil public key loaded, serving encrypted indexes"),
+        Ok(None) => {}
+        Err(e) => tracing::error!("{}, encrypted index requests will fail", e),
+    }
+
     // Initialize importer registry
     init_registry().await;
     tracing::info!("Importer registry initialized");
 
-    // create games directory
-    if !std::path::Path::new(&games_dir()).exists() {
-        match std::fs::create_dir(games_dir()) {
-            Ok(_) => tracing::info!("Directory '{}' created successfully", games_dir()),
-            Err(e) => {
-                tracing::error!("Failed to create directory '{}': {}", games_dir(), e);
-                // Continue anyway, failure will be handled when trying to access
+    for dir in games_dirs() {
+        // create games directory
+        if !std::path::Path::new(&dir).exists() {
+            match std::fs::create_dir(&dir) {
+                Ok(_) => tracing::info!("Directory '{}' created successfully", dir),
+                Err(e) => {
+                    tracing::error!("Failed to create directory '{}': {}", dir, e);
+                    // Continue anyway, failure will be handled when trying to access
+                }
             }
+        } else {
+            tracing::info!("Directory '{}' already exists, skipping...", dir);
+        }
+
+        // Finish or roll back imports that were interrupted while being moved into place
+        if let Err(e) = import::staging::recover(std::path::Path::new(&dir)).await {
+            tracing::error!("Failed to recover staged imports in {}: {}", dir, e);
         }
-    } else {
-        tracing::info!("Directory '{}' already exists, skipping...", games_dir());
     }
 
     // initialize database
     init_database().await?;
 
+    backend::user::bootstrap_admin().await?;
+
+    // Imports don't survive a restart, don't leave them listed as running forever
+    match import::job::ImportJob::fail_interrupted().await {
+        Ok(0) => {}
+        Ok(count) => tracing::warn!("Marked {} interrupted import jobs as failed", count),
+        Err(e) => tracing::error!("Failed to update interrupted import jobs: {}", e),
+    }
+
+    // Downloads pick up where they left off, and are imported once they're done
+    match import::resume_downloads().await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!("Resumed {} unfinished downloads", count),
+        Err(e) => tracing::error!("Failed to restore unfinished downloads: {}", e),
+    }
+
     // Run the initial TitleDB import and schedule future imports
     let config_clone = config.clone();
 
@@ -400,15 +561,20 @@ async fn main() -> color_eyre::Result<()> {
 
     tokio::spawn(async move {
         // Run immediately the first time
+        let mut retry_soon = false;
 
         if extra_cfg.import_titledb_on_start {
-            if let Err(e) = import_titledb_background(config_clone.clone()).await {
-                tracing::error!("Initial TitleDB import failed: {}", e);
-            }
+            retry_soon = match import_titledb_background(config_clone.clone()).await {
+                Ok(degraded) => degraded,
+                Err(e) => {
+                    tracing::error!("Initial TitleDB import failed: {}", e);
+                    true
+                }
+            };
         }
 
         // Then schedule recurring imports
-        if let Err(e) = schedule_titledb_imports(config_clone).await {
+        if let Err(e) = schedule_titledb_imports(config_clone, retry_soon).await {
             tracing::error!("TitleDB scheduling failed: {}", e);
         }
 
@@ -432,6 +598,22 @@ async fn main() -> color_eyre::Result<()> {
         }
     });
 
+    // Generate the index up front, so the first client doesn't wait for it
+    tokio::spawn(async {
+        if let Err(e) = backend::api::regenerate_index_cache().await {
+            tracing::error!("Failed to generate the tinfoil index: {}", e);
+        }
+    });
+
+    // Periodic safety-net rescan of the rom dir
+    tokio::spawn(schedule_rescans());
+
+    // Start queued downloads as others finish
+    tokio::spawn(import::downloader::run_scheduler());
+
+    // Hold back new downloads when the rom or cache volume is running out of space
+    tokio::spawn(storage::free_space_watchdog());
+
     let app = create_router();
 
     // Bind to the host address with proper error handling
@@ -454,6 +636,8 @@ async fn main() -> color_eyre::Result<()> {
 
     // Start the server with graceful shutdown
     tracing::info!("Starting server...");
+    // Connection info is used for the client IP in the access log
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
     if let Err(e) = axum::serve(listener, app)
         .with_graceful_shutdown(shutdown_signal())
         .await
@@ -1,11 +1,23 @@
 use nx_archive::{
-    formats::{Keyset, TitleKeys, cnmt::Cnmt, pfs0::Pfs0, xci::Xci},
+    formats::{
+        Keyset, TitleKeys,
+        cnmt::{Cnmt, ExtendedHeader, PackagedContentType},
+        nca::Nca,
+        pfs0::Pfs0,
+        xci::Xci,
+    },
     util::TitleDataExt,
 };
 use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fmt;
 use std::fs::File;
 use std::path::Path;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Semaphore;
+
+use crate::title_kind::TitleKind;
 
 // Lazy-loaded static keyset and title keys
 static KEYSET: Lazy<Result<Keyset, color_eyre::eyre::Error>> = Lazy::new(|| {
@@ -28,22 +40,102 @@ static TITLE_KEYS: Lazy<Result<TitleKeys, color_eyre::eyre::Error>> = Lazy::new(
     })
 });
 
+/// Limits CNMT reads for the whole server, as configured with `ALU_MAX_CNMT_READS`
+static CNMT_READS: Lazy<CnmtReadLimiter> =
+    Lazy::new(|| CnmtReadLimiter::new(crate::config::config().backend_config.max_cnmt_reads));
+
 const NSP_EXTENSIONS: &[&str] = &["nsp", "nsz"];
 const XCI_EXTENSIONS: &[&str] = &["xci", "xcz"];
 
-pub fn read_cnmts(path: &str) -> color_eyre::Result<Vec<Cnmt>> {
+/// Path of the NACP inside a control NCA's RomFS
+const NACP_PATH: &str = "/control.nacp";
+/// Size of one language's entry in a NACP: the application name, then the publisher
+const NACP_TITLE_SIZE: usize = 0x300;
+const NACP_NAME_SIZE: usize = 0x200;
+const NACP_LANGUAGES: usize = 16;
+
+/// The prod or title keys failed to load, so no file can be read
+#[derive(thiserror::Error, Debug)]
+#[error("keys are unavailable: {0}")]
+pub struct KeysUnavailable(String);
+
+fn keys() -> color_eyre::Result<(&'static Keyset, &'static TitleKeys)> {
     let keyset = KEYSET
         .as_ref()
-        .map_err(|e| color_eyre::eyre::eyre!("{}", e))?;
+        .map_err(|e| KeysUnavailable(e.to_string()))?;
     let title_keyset = TITLE_KEYS
         .as_ref()
-        .map_err(|e| color_eyre::eyre::eyre!("{}", e))?;
-    let path = Path::new(path);
-    let extension = path
-        .extension()
+        .map_err(|e| KeysUnavailable(e.to_string()))?;
+    Ok((keyset, title_keyset))
+}
+
+/// Check if the prod and title keys loaded, which reading any file needs
+pub fn keys_loaded() -> bool {
+    KEYSET.is_ok() && TITLE_KEYS.is_ok()
+}
+
+/// Why a file's CNMT couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CnmtErrorKind {
+    /// The file isn't a valid NSP or XCI, or its CNMT is broken
+    Format,
+    /// The keys are missing or too old for the file, or reading it failed. The file itself
+    /// may be fine, and reading it again can work
+    Environment,
+}
+
+impl CnmtErrorKind {
+    /// Tell format errors from the ones the file can't be blamed for
+    pub fn of(err: &color_eyre::Report) -> Self {
+        if err.downcast_ref::<KeysUnavailable>().is_some()
+            || err.downcast_ref::<std::io::Error>().is_some()
+            || err.downcast_ref::<tokio::task::JoinError>().is_some()
+            || err
+                .downcast_ref::<nx_archive::formats::title_keyset::KeyError>()
+                .is_some()
+        {
+            return Self::Environment;
+        }
+        match err.downcast_ref::<nx_archive::error::Error>() {
+            Some(
+                nx_archive::error::Error::Io(_)
+                | nx_archive::error::Error::KeyLookupError(_)
+                | nx_archive::error::Error::CryptoError(_)
+                | nx_archive::error::Error::PermissionDenied(_)
+                | nx_archive::error::Error::Timeout(_),
+            ) => Self::Environment,
+            _ => Self::Format,
+        }
+    }
+}
+
+/// A failed CNMT read, kept along with metadata that fell back to the filename
+#[derive(Debug, Clone)]
+pub struct CnmtReadError {
+    pub kind: CnmtErrorKind,
+    pub message: String,
+}
+
+impl From<&color_eyre::Report> for CnmtReadError {
+    fn from(err: &color_eyre::Report) -> Self {
+        Self {
+            kind: CnmtErrorKind::of(err),
+            message: err.to_string(),
+        }
+    }
+}
+
+fn lowercase_extension(path: &Path) -> color_eyre::Result<String> {
+    path.extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| ext.to_lowercase())
-        .ok_or_else(|| color_eyre::eyre::eyre!("Invalid file extension"))?;
+        .ok_or_else(|| color_eyre::eyre::eyre!("Invalid file extension"))
+}
+
+pub fn read_cnmts(path: &str) -> color_eyre::Result<Vec<Cnmt>> {
+    let (keyset, title_keyset) = keys()?;
+    let path = Path::new(path);
+    let extension = lowercase_extension(path)?;
 
     let file = File::open(path)?;
     let shared_reader = nx_archive::io::SharedReader::new(&file);
@@ -72,7 +164,7 @@ pub fn read_cnmt_merged(path: &str) -> color_eyre::Result<Cnmt> {
 
     let cnmt_base = cnmts_list
         .iter()
-        .find(|cnmt| cnmt.get_title_id_string().ends_with("000"));
+        .find(|cnmt| TitleKind::is_base(&cnmt.get_title_id_string()));
 
     let cnmt_latest = cnmts_list
         .iter()
@@ -91,10 +183,291 @@ pub fn read_cnmt_merged(path: &str) -> color_eyre::Result<Cnmt> {
     }
 }
 
+/// Read and merge the CNMTs of a file like [`read_cnmt_merged`], without blocking the
+/// async runtime and waiting for a turn if too many files are being read already
+pub async fn read_cnmt_limited(path: &str) -> color_eyre::Result<Cnmt> {
+    let path = path.to_string();
+    CNMT_READS.run(move || read_cnmt_merged(&path)).await?
+}
+
+/// Read the application name from the NACP of a file, `None` if it has none.
+///
+/// The name is read from the control NCA listed in the file's CNMT, so only applications
+/// and updates have one. The first language with a name wins, American English first.
+pub fn read_nacp_name(path: &str, cnmt: &Cnmt) -> color_eyre::Result<Option<String>> {
+    let Some(control) = cnmt
+        .content_entries
+        .iter()
+        .find(|content| content.info.content_type == PackagedContentType::Control)
+    else {
+        return Ok(None);
+    };
+    let nca_name = content_nca_name(&control.info.content_id);
+
+    let (keyset, title_keyset) = keys()?;
+    let path = Path::new(path);
+    let extension = lowercase_extension(path)?;
+    let file = File::open(path)?;
+    let shared_reader = nx_archive::io::SharedReader::new(&file);
+
+    let nca = if NSP_EXTENSIONS.contains(&extension.as_str()) {
+        let mut nsp = Pfs0::from_reader(shared_reader)?;
+        match nsp.get_file(&nca_name) {
+            Some(entry) => Some(nsp.read_to_vec(&entry)?),
+            None => None,
+        }
+    } else if XCI_EXTENSIONS.contains(&extension.as_str()) {
+        let mut xci = Xci::new(shared_reader)?;
+        match xci.open_secure_partition()? {
+            Some(mut secure) => match secure.get_file(&nca_name)? {
+                Some(entry) => Some(secure.read_to_vec(&entry)?),
+                None => None,
+            },
+            None => None,
+        }
+    } else {
+        return Err(color_eyre::eyre::eyre!("Unsupported file extension"));
+    };
+    // Compressed files may only have the control NCA compressed
+    let Some(nca) = nca else {
+        tracing::debug!("No {} in {}", nca_name, path.display());
+        return Ok(None);
+    };
+
+    let mut nca = Nca::from_reader(std::io::Cursor::new(nca), keyset, Some(title_keyset))?;
+    let mut romfs = nca.open_romfs_filesystem(0)?;
+    Ok(romfs
+        .read_to_vec(NACP_PATH)?
+        .and_then(|nacp| nacp_application_name(&nacp)))
+}
+
+/// Read the NACP name of a file like [`read_nacp_name`], taking turns with CNMT reads
+pub async fn read_nacp_name_limited(path: &str, cnmt: &Cnmt) -> color_eyre::Result<Option<String>> {
+    let path = path.to_string();
+    let cnmt = cnmt.clone();
+    CNMT_READS.run(move || read_nacp_name(&path, &cnmt)).await?
+}
+
+/// Name of the NCA file holding a content in NSPs and XCIs
+fn content_nca_name(content_id: &[u8; 16]) -> String {
+    let id: String = content_id.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{id}.nca")
+}
+
+/// Get the application name from a NACP, the first language that has one
+fn nacp_application_name(nacp: &[u8]) -> Option<String> {
+    nacp.chunks_exact(NACP_TITLE_SIZE)
+        .take(NACP_LANGUAGES)
+        .find_map(|title| {
+            let name = &title[..NACP_NAME_SIZE];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let name = String::from_utf8_lossy(&name[..end]).trim().to_string();
+            (!name.is_empty()).then_some(name)
+        })
+}
+
+/// Number of CNMT reads going on and waiting for their turn
+pub fn cnmt_read_stats() -> CnmtReadStats {
+    CNMT_READS.stats()
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CnmtReadStats {
+    /// Most CNMTs read at once
+    pub limit: usize,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+/// Caps how many CNMTs are read at once.
+///
+/// Reading a CNMT means seeking through and decrypting parts of a large file, many of them
+/// at once thrash the disk, spinning ones especially. This is separate from how many
+/// downloads may run at once.
+#[derive(Debug)]
+pub struct CnmtReadLimiter {
+    semaphore: Semaphore,
+    limit: usize,
+    in_flight: AtomicUsize,
+    waiting: AtomicUsize,
+}
+
+impl CnmtReadLimiter {
+    pub fn new(limit: usize) -> Self {
+        // A limit of 0 would never read anything
+        let limit = limit.max(1);
+        Self {
+            semaphore: Semaphore::new(limit),
+            limit,
+            in_flight: AtomicUsize::new(0),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Run a blocking read once there's a free slot
+    pub async fn run<T: Send + 'static>(
+        &self,
+        read: impl FnOnce() -> T + Send + 'static,
+    ) -> color_eyre::Result<T> {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let permit = self.semaphore.acquire().await;
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        let _permit = permit?;
+
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let result = tokio::task::spawn_blocking(read).await;
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        Ok(result?)
+    }
+
+    pub fn stats(&self) -> CnmtReadStats {
+        CnmtReadStats {
+            limit: self.limit,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            waiting: self.waiting.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A Switch system version, as packed into a `u32` by CNMTs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemVersion(pub u32);
+
+impl SystemVersion {
+    pub fn major(self) -> u32 {
+        self.0 >> 26
+    }
+
+    pub fn minor(self) -> u32 {
+        (self.0 >> 20) & 0x3f
+    }
+
+    pub fn micro(self) -> u32 {
+        (self.0 >> 16) & 0xf
+    }
+}
+
+impl fmt::Display for SystemVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major(), self.minor(), self.micro())
+    }
+}
+
+/// Get the minimum system version a title needs to run, `None` if it doesn't have one.
+///
+/// Only applications and patches carry one, DLC depend on the application version instead.
+pub fn required_system_version(cnmt: &Cnmt) -> Option<SystemVersion> {
+    let version = match &cnmt.extended_header {
+        ExtendedHeader::Application(header) => header.required_system_version,
+        ExtendedHeader::Patch(header) => header.required_system_version,
+        _ => return None,
+    };
+    (version != 0).then_some(SystemVersion(version))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_cnmt_error_kind() {
+        let format: color_eyre::Report =
+            nx_archive::error::Error::InvalidFormat("bad PFS0 magic".to_string()).into();
+        assert_eq!(CnmtErrorKind::of(&format), CnmtErrorKind::Format);
+        assert_eq!(
+            CnmtErrorKind::of(&color_eyre::eyre::eyre!("No valid CNMT found")),
+            CnmtErrorKind::Format
+        );
+
+        let keys: color_eyre::Report = KeysUnavailable("no prod.keys".to_string()).into();
+        assert_eq!(CnmtErrorKind::of(&keys), CnmtErrorKind::Environment);
+        let outdated: color_eyre::Report =
+            nx_archive::error::Error::KeyLookupError("key area".to_string()).into();
+        assert_eq!(CnmtErrorKind::of(&outdated), CnmtErrorKind::Environment);
+        let io: color_eyre::Report =
+            std::io::Error::from(std::io::ErrorKind::PermissionDenied).into();
+        assert_eq!(CnmtErrorKind::of(&io), CnmtErrorKind::Environment);
+    }
+
+    #[tokio::test]
+    async fn test_cnmt_read_limiter() {
+        let limiter = std::sync::Arc::new(CnmtReadLimiter::new(1));
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = std::sync::Arc::new(std::sync::Mutex::new(release_rx));
+
+        let reads: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let release_rx = release_rx.clone();
+                tokio::spawn(async move {
+                    limiter
+                        .run(move || release_rx.lock().unwrap().recv().unwrap())
+                        .await
+                })
+            })
+            .collect();
+
+        let expected = CnmtReadStats {
+            limit: 1,
+            in_flight: 1,
+            waiting: 1,
+        };
+        for _ in 0..100 {
+            if limiter.stats() == expected {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(limiter.stats(), expected);
+
+        release_tx.send(()).unwrap();
+        release_tx.send(()).unwrap();
+        for read in reads {
+            read.await.unwrap().unwrap();
+        }
+        assert_eq!(limiter.stats().in_flight, 0);
+        assert_eq!(limiter.stats().waiting, 0);
+    }
+
+    #[test]
+    fn test_nacp_application_name() {
+        let mut nacp = vec![0u8; 0x4000];
+        // No American English name, the British English one is used
+        let title = |language: usize| language * NACP_TITLE_SIZE;
+        nacp[title(1)..title(1) + 8].copy_from_slice(b"Homebrew");
+        nacp[title(1) + NACP_NAME_SIZE..title(1) + NACP_NAME_SIZE + 6].copy_from_slice(b"Author");
+        nacp[title(2)..title(2) + 5].copy_from_slice(b"Other");
+        assert_eq!(nacp_application_name(&nacp).as_deref(), Some("Homebrew"));
+
+        nacp[title(0)..title(0) + 9].copy_from_slice("Jeu \u{e9}t\u{e9}".as_bytes());
+        assert_eq!(
+            nacp_application_name(&nacp).as_deref(),
+            Some("Jeu \u{e9}t\u{e9}")
+        );
+
+        assert_eq!(nacp_application_name(&[0u8; 0x4000]), None);
+        assert_eq!(nacp_application_name(&[]), None);
+    }
+
+    #[test]
+    fn test_content_nca_name() {
+        let mut content_id = [0u8; 16];
+        content_id[0] = 0xab;
+        content_id[15] = 0x01;
+        assert_eq!(
+            content_nca_name(&content_id),
+            "ab000000000000000000000000000001.nca"
+        );
+    }
+
+    #[test]
+    fn test_system_version_display() {
+        // 19.0.1
+        assert_eq!(SystemVersion(1275133952).to_string(), "19.0.1");
+        assert_eq!(SystemVersion(0x0c10_0000).to_string(), "3.1.0");
+        assert_eq!(SystemVersion(0).to_string(), "0.0.0");
+    }
+
     #[test]
     fn test_read_cnmt() {
         read_cnmts(
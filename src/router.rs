@@ -1,14 +1,15 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
+use crate::backend::kv_config::FilenameConfig;
 use crate::backend::router::create_router as create_backend_router;
-use crate::db::NspMetadata;
-use crate::index::{Index, TinfoilResponse};
+use crate::db::{NspMetadata, UNIDENTIFIED_TITLE_ID, is_placeholder_title_id};
+use crate::index::{Index, TinfoilFileEntry, TinfoilResponse};
 use crate::titledb::GameFileDataNaive;
-use crate::util::format_download_id;
-use crate::util::format_game_name;
+use crate::util::file_download_id;
+use crate::util::{FilenameTemplate, format_game_name};
 use axum::{
     Json,
-    extract::Request,
+    extract::{DefaultBodyLimit, Request},
     http::{StatusCode, Uri},
     middleware::{self, Next},
     response::{IntoResponse, Redirect, Response},
@@ -31,18 +32,82 @@ impl IntoResponse for Error {
 
 pub type AlumRes<T> = Result<T, Error>;
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone)]
 pub struct RescanOptions {
     #[serde(default)]
     pub rescan: bool,
+    /// Only scan this directory of the rom dirs, either relative to them or an absolute path in one
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
-#[tracing::instrument]
+impl RescanOptions {
+    /// Whether only a directory of the rom dirs is scanned
+    pub fn is_scoped(&self) -> bool {
+        self.path
+            .as_deref()
+            .is_some_and(|path| !path.trim().is_empty())
+    }
+
+    /// Get the directories to scan out of the rom dirs `roots`, `None` if `path` leaves them.
+    ///
+    /// Relative paths are looked up in every rom dir, absolute ones in the rom dir they're in.
+    pub fn scan_dirs(&self, roots: &[String]) -> Option<Vec<PathBuf>> {
+        let Some(path) = self.path.as_deref().filter(|_| self.is_scoped()) else {
+            return Some(roots.iter().map(PathBuf::from).collect());
+        };
+        let path = Path::new(path);
+        if path.is_absolute() {
+            let root = roots.iter().find(|root| path.starts_with(root))?;
+            let relative = path.strip_prefix(root).ok()?;
+            return is_plain_relative(relative).then(|| vec![Path::new(root).join(relative)]);
+        }
+        is_plain_relative(path).then(|| {
+            roots
+                .iter()
+                .map(|root| Path::new(root).join(path))
+                .collect()
+        })
+    }
+}
+
+/// Check that a relative path stays in the directory it's relative to
+fn is_plain_relative(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Counters of a metadata rescan
+#[derive(serde::Serialize, Debug, Default, Clone)]
+pub struct RescanCounts {
+    /// Files and directories found in the scanned directory
+    pub total: usize,
+    pub processed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Metadata rows removed because their file is gone
+    pub deleted: usize,
+    pub delete_failed: usize,
+}
+
+#[tracing::instrument(skip(on_progress))]
 pub async fn update_metadata_from_filesystem(
-    path: &str,
+    roots: &[String],
     options: RescanOptions,
+    on_progress: impl Fn(&RescanCounts),
 ) -> color_eyre::eyre::Result<()> {
-    tracing::info!("Starting full metadata rescan of {}", path);
+    let scan_dirs = options.scan_dirs(roots).ok_or_else(|| {
+        color_eyre::eyre::eyre!("Rescan path {:?} is outside of {:?}", options.path, roots)
+    })?;
+    // Only a scan of every rom dir knows about every file that's gone
+    let scoped = options.is_scoped();
+    let scan_dirs = if scoped {
+        // A folder doesn't have to be in every rom dir
+        scan_dirs.into_iter().filter(|dir| dir.is_dir()).collect()
+    } else {
+        scan_dirs
+    };
+    tracing::info!("Starting metadata rescan of {:?}", scan_dirs);
 
     let rescan = options.rescan;
     tracing::debug!("Rescan option: {}", rescan);
@@ -63,6 +128,16 @@ pub async fn update_metadata_from_filesystem(
     let metadata_map: std::collections::HashMap<String, &NspMetadata> =
         all_metadata.iter().map(|m| (m.path.clone(), m)).collect();
 
+    // Files that failed to be read are read again until they work or get quarantined
+    let failing_paths: std::collections::HashSet<String> =
+        match crate::import::quarantine::failing_paths().await {
+            Ok(paths) => paths.into_iter().collect(),
+            Err(e) => {
+                tracing::warn!("Failed to get files that failed to be read: {}", e);
+                Default::default()
+            }
+        };
+
     // Define valid extensions once
     const VALID_EXTENSIONS: [&str; 5] = ["nsp", "xci", "nsz", "ncz", "xcz"];
 
@@ -70,34 +145,36 @@ pub async fn update_metadata_from_filesystem(
     let mut found_paths = std::collections::HashSet::new();
 
     // Track statistics
-    let mut total_files = 0;
-    let mut processed_files = 0;
-    let mut skipped_files = 0;
-    let mut failed_files = 0;
-
-    // Walk the directory and process each file
-    let walker = jwalk::WalkDir::new(path)
-        .skip_hidden(true)
-        .process_read_dir(move |_, _, _, dir_entry_results| {
-            // Sort entry results to process largest files first (optimization for typical use cases)
-            dir_entry_results.sort_by_cached_key(|entry_result| {
-                if let Ok(entry) = entry_result {
-                    let metadata = entry.metadata();
-                    if let Ok(metadata) = metadata {
-                        return std::cmp::Reverse(metadata.len());
+    let mut counts = RescanCounts::default();
+
+    // Walk everything first so progress can be reported against the total
+    let mut entries = Vec::new();
+    for scan_dir in &scan_dirs {
+        let walker = jwalk::WalkDir::new(scan_dir)
+            .skip_hidden(true)
+            .process_read_dir(move |_, _, _, dir_entry_results| {
+                // Sort entry results to process largest files first (optimization for typical use cases)
+                dir_entry_results.sort_by_cached_key(|entry_result| {
+                    if let Ok(entry) = entry_result {
+                        let metadata = entry.metadata();
+                        if let Ok(metadata) = metadata {
+                            return std::cmp::Reverse(metadata.len());
+                        }
                     }
-                }
-                std::cmp::Reverse(0)
+                    std::cmp::Reverse(0)
+                });
             });
-        });
+        entries.extend(walker);
+    }
+    counts.total = entries.len();
 
-    for entry in walker.into_iter() {
-        total_files += 1;
+    for entry in entries {
+        on_progress(&counts);
         let path = match entry {
             Ok(p) => p,
             Err(e) => {
                 tracing::error!("Failed to access file during scan: {}", e);
-                failed_files += 1;
+                counts.failed += 1;
                 continue; // Skip but don't abort entire operation
             }
         };
@@ -107,11 +184,11 @@ pub async fn update_metadata_from_filesystem(
         // Extract extension early for filtering
         if let Some(ext) = file_path.extension().and_then(|e| e.to_str()) {
             if !VALID_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                skipped_files += 1;
+                counts.skipped += 1;
                 continue;
             }
         } else {
-            skipped_files += 1;
+            counts.skipped += 1;
             continue;
         }
 
@@ -120,64 +197,104 @@ pub async fn update_metadata_from_filesystem(
 
         // Check if we need to update this file
         // Force rescan if the rescan option is true, otherwise only scan if no metadata exists
-        let needs_update = rescan || !metadata_map.contains_key(&file_path_str);
+        let needs_update = rescan
+            || !metadata_map.contains_key(&file_path_str)
+            || failing_paths.contains(&file_path_str);
 
         if needs_update {
             // Use the dedicated scan_file function instead of duplicating code
             match scan_file(&file_path, rescan).await {
                 Ok(_) => {
-                    processed_files += 1;
+                    counts.processed += 1;
                     tracing::debug!("Successfully processed file: {}", file_path_str);
                 }
                 Err(e) => {
-                    failed_files += 1;
+                    counts.failed += 1;
                     tracing::error!("Failed to scan file {}: {}", file_path_str, e);
                 }
             }
         } else {
-            skipped_files += 1;
+            counts.skipped += 1;
             tracing::trace!("Skipped file (already up to date): {}", file_path_str);
         }
     }
+    on_progress(&counts);
 
     // Delete metadata for files that no longer exist
-    let mut deleted_count = 0;
-    let mut delete_failed_count = 0;
-
-    for metadata in all_metadata
-        .iter()
-        .filter(|m| !found_paths.contains(&m.path))
-    {
+    for metadata in all_metadata.iter().filter(|m| {
+        !found_paths.contains(&m.path)
+            && (!scoped
+                || scan_dirs
+                    .iter()
+                    .any(|dir| Path::new(&m.path).starts_with(dir)))
+    }) {
         tracing::info!("Removing metadata for non-existent file: {}", metadata.path);
         match metadata.delete().await {
             Ok(_) => {
-                deleted_count += 1;
+                counts.deleted += 1;
                 tracing::debug!("Successfully deleted metadata for: {}", metadata.path);
             }
             Err(e) => {
-                delete_failed_count += 1;
+                counts.delete_failed += 1;
                 tracing::error!("Failed to delete metadata for {}: {}", metadata.path, e);
             }
         }
+        on_progress(&counts);
     }
 
     tracing::info!(
         "Metadata rescan complete. Results: total={}, processed={}, skipped={}, failed={}, deleted={}, delete_failed={}",
-        total_files,
-        processed_files,
-        skipped_files,
-        failed_files,
-        deleted_count,
-        delete_failed_count
+        counts.total,
+        counts.processed,
+        counts.skipped,
+        counts.failed,
+        counts.deleted,
+        counts.delete_failed
     );
 
-    if failed_files > 0 || delete_failed_count > 0 {
+    if counts.failed > 0 || counts.delete_failed > 0 {
         tracing::warn!("Some operations failed during metadata rescan. Check logs for details.");
     }
 
     Ok(())
 }
 
+/// Build the metadata of a scanned file, keeping the title name it already had.
+///
+/// Files without a usable title ID get [`UNIDENTIFIED_TITLE_ID`] and are flagged as
+/// unidentified, so they're not grouped with real titles.
+fn metadata_from_game_data(
+    path: &str,
+    game_data: GameFileDataNaive,
+    all_metadata: &[NspMetadata],
+) -> NspMetadata {
+    let title_id = game_data
+        .title_id
+        .unwrap_or_else(|| UNIDENTIFIED_TITLE_ID.to_string());
+    let unidentified = is_placeholder_title_id(&title_id);
+
+    // Get the title name from metadata or filename
+    let title_name = all_metadata
+        .iter()
+        .find(|m| m.path == path)
+        .and_then(|m| m.title_name.clone())
+        .unwrap_or_else(|| game_data.name.trim().trim_end_matches(".nsp").to_string());
+
+    let version = game_data.version.unwrap_or_else(|| "v0".to_string());
+    let extension = game_data.extension.unwrap_or_default();
+    let download_id = file_download_id(path, &title_id, &version, &extension);
+
+    NspMetadata {
+        path: path.to_string(),
+        title_id,
+        version,
+        title_name: Some(title_name),
+        download_id,
+        unidentified,
+        required_system_version: game_data.required_system_version,
+    }
+}
+
 pub async fn scan_file(path: &Path, rescan_files: bool) -> color_eyre::Result<()> {
     tracing::info!("Scanning file: {}", path.display());
     // Get all existing metadata with proper error handling
@@ -192,72 +309,49 @@ pub async fn scan_file(path: &Path, rescan_files: bool) -> color_eyre::Result<()
     let file_path_str = path.to_string_lossy().to_string();
     tracing::info!("Updating metadata for file: {}", file_path_str);
 
-    const MAX_RETRIES: usize = 3;
-    const RETRY_DELAY_MS: u64 = 500;
     tracing::debug!("Processing file: {}", file_path_str);
 
-    let mut attempt = 0;
-    let metadata_result = loop {
-        attempt += 1;
-        let naive = {
-            if rescan_files {
-                GameFileDataNaive::get(path).await
-            } else {
-                GameFileDataNaive::get_cached(path, &all_metadata).await
-            }
-        };
+    // The cached metadata of a file that failed to be read came from its filename
+    let rescan_files = rescan_files || crate::import::quarantine::has_failed(&file_path_str).await;
 
-        match naive {
-            Ok(game_data) => {
-                let title_id = game_data
-                    .title_id
-                    .unwrap_or_else(|| "00000000AAAA0000".to_string());
-
-                // Get the title name from metadata or filename
-                let title_name = all_metadata
-                    .iter()
-                    .find(|m| m.path == file_path_str)
-                    .and_then(|m| m.title_name.clone())
-                    .unwrap_or_else(|| {
-                        let name = game_data.name.trim();
-                        name.trim_end_matches(".nsp").to_string()
-                    });
-
-                let version = game_data.version.unwrap_or_else(|| "v0".to_string());
-                let extension = game_data.extension.unwrap_or_default();
-                let download_id = format_download_id(&title_id, &version, &extension);
-
-                break Some(NspMetadata {
-                    path: file_path_str.clone(),
-                    title_id,
-                    version,
-                    title_name: Some(title_name),
-                    download_id,
-                });
-            }
-            Err(e) => {
-                if e.to_string().contains("This transaction can be retried")
-                    && attempt < MAX_RETRIES
-                {
-                    tracing::info!(
-                        "Retryable error on attempt {}/{} for {}: {}. Retrying...",
-                        attempt,
-                        MAX_RETRIES,
-                        file_path_str,
-                        e
-                    );
-                    tokio::time::sleep(tokio::time::Duration::from_millis(RETRY_DELAY_MS)).await;
-                    continue;
+    let all_metadata = &all_metadata;
+    let naive = crate::db::with_retry("scan_file", || async move {
+        if rescan_files {
+            GameFileDataNaive::get(path).await
+        } else {
+            GameFileDataNaive::get_cached(path, all_metadata).await
+        }
+    })
+    .await;
+
+    if let Ok(game_data) = &naive {
+        match &game_data.cnmt_error {
+            // Missing keys or a failed read don't make the file broken
+            Some(error) if error.kind == crate::nsp::CnmtErrorKind::Format => {
+                match crate::import::quarantine::record_failure(path, &error.message).await {
+                    // Its metadata went with it
+                    Ok(Some(_)) => return Ok(()),
+                    Ok(None) => {}
+                    Err(e) => tracing::error!("Failed to quarantine {}: {}", file_path_str, e),
                 }
-
-                tracing::warn!(
-                    "Failed to get game data for {} after {} attempt(s): {}",
-                    file_path_str,
-                    attempt,
-                    e
-                );
-                break None;
             }
+            Some(_) => {}
+            None if rescan_files => {
+                crate::import::quarantine::clear_failures(&file_path_str).await;
+            }
+            None => {}
+        }
+    }
+
+    let metadata_result = match naive {
+        Ok(game_data) => Some(metadata_from_game_data(
+            &file_path_str,
+            game_data,
+            all_metadata,
+        )),
+        Err(e) => {
+            tracing::warn!("Failed to get game data for {}: {}", file_path_str, e);
+            None
         }
     };
 
@@ -313,6 +407,16 @@ async fn process_fs_events(rx: &mut tokio::sync::mpsc::Receiver<notify::Event>,
     // Define valid extensions
     const VALID_EXTENSIONS: [&str; 5] = ["nsp", "xci", "nsz", "ncz", "xcz"];
 
+    let backend_config = crate::config::config().backend_config;
+    let grace = std::time::Duration::from_millis(backend_config.watcher_remove_grace_ms);
+    let quiet = std::time::Duration::from_millis(backend_config.watcher_quiet_ms);
+    // Removals waiting out the grace period, by path
+    let mut pending_removals: std::collections::HashMap<String, tokio::task::JoinHandle<()>> =
+        std::collections::HashMap::new();
+    // Scans waiting for their file to settle, by path
+    let mut pending_scans: std::collections::HashMap<String, tokio::task::JoinHandle<()>> =
+        std::collections::HashMap::new();
+
     while let Some(event) = rx.recv().await {
         // Get the path from the event
         let event_path = match event.paths.first() {
@@ -320,6 +424,19 @@ async fn process_fs_events(rx: &mut tokio::sync::mpsc::Receiver<notify::Event>,
             _ => continue,
         };
 
+        // Imports in progress, they show up again once published
+        if crate::import::staging::is_staging_path(event_path) {
+            continue;
+        }
+        // Deleted games, moving them there is seen as their removal
+        if crate::import::trash::is_trash_path(event_path) {
+            continue;
+        }
+        // Files that couldn't be read, moving them there is seen as their removal
+        if crate::import::quarantine::is_quarantine_path(event_path) {
+            continue;
+        }
+
         // Check if the file has a valid extension
         if let Some(ext) = event_path.extension().and_then(|e| e.to_str()) {
             if !VALID_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
@@ -330,81 +447,57 @@ async fn process_fs_events(rx: &mut tokio::sync::mpsc::Receiver<notify::Event>,
         }
 
         let path_str = event_path.to_string_lossy().to_string();
+        pending_removals.retain(|_, removal| !removal.is_finished());
+        pending_scans.retain(|_, scan| !scan.is_finished());
 
         match event.kind {
             EventKind::Create(_) | EventKind::Modify(_) => {
-                tracing::info!("File created/modified: {}", path_str);
-
-                // Get all existing metadata with better error handling
-                let all_metadata = match NspMetadata::get_all().await {
-                    Ok(metadata) => std::sync::Arc::new(metadata),
-                    Err(e) => {
-                        tracing::error!("Failed to get metadata: {}", e);
-                        std::sync::Arc::new(Vec::new()) // Continue with empty metadata
-                    }
-                };
-
-                // Process the new/modified file
-                match GameFileDataNaive::get_cached(event_path, &all_metadata).await {
-                    Ok(game_data) => {
-                        let title_id = game_data
-                            .title_id
-                            .unwrap_or_else(|| "00000000AAAA0000".to_string());
-
-                        // Get the title name
-                        let title_name = all_metadata
-                            .iter()
-                            .find(|m| m.path == path_str)
-                            .and_then(|m| m.title_name.clone())
-                            .unwrap_or_else(|| {
-                                game_data.name.trim().trim_end_matches(".nsp").to_string()
-                            });
-
-                        let extension = game_data.extension.unwrap_or_default();
-                        let version = game_data.version.unwrap_or_else(|| "v0".to_string());
-                        let download_id = format_download_id(&title_id, &version, &extension);
-
-                        // Save the metadata
-                        let metadata = NspMetadata {
-                            path: path_str,
-                            title_id,
-                            version,
-                            title_name: Some(title_name),
-                            download_id,
-                        };
-
-                        if let Err(e) = metadata.save().await {
-                            tracing::error!("Failed to save metadata: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        tracing::warn!("Failed to get game data for {}: {}", path_str, e);
+                tracing::debug!("File created/modified: {}", path_str);
+
+                // Replaced through delete-then-create, the entry is updated by the scan instead
+                if let Some(removal) = pending_removals.remove(&path_str) {
+                    removal.abort();
+                    tracing::debug!("File came back within the grace period: {}", path_str);
+                }
+
+                if quiet.is_zero() {
+                    scan_watched_file(&path_str).await;
+                    continue;
+                }
+
+                // Every event restarts the wait, so a file being copied is only scanned once
+                // the copy is done
+                let path = path_str.clone();
+                let scan = tokio::spawn(async move {
+                    tokio::time::sleep(quiet).await;
+                    if wait_for_stable_size(Path::new(&path), SIZE_SAMPLE_INTERVAL).await {
+                        scan_watched_file(&path).await;
                     }
+                });
+                if let Some(previous) = pending_scans.insert(path_str, scan) {
+                    previous.abort();
                 }
             }
             EventKind::Remove(_) => {
                 tracing::info!("File removed: {}", path_str);
 
-                // Find and delete the metadata for this file, with proper error handling
-                match NspMetadata::get_all().await {
-                    Ok(all_metadata) => {
-                        if let Some(metadata) = all_metadata.iter().find(|m| m.path == path_str) {
-                            if let Err(e) = metadata.delete().await {
-                                tracing::error!(
-                                    "Failed to delete metadata for {}: {}",
-                                    path_str,
-                                    e
-                                );
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        tracing::error!(
-                            "Failed to get metadata when removing file {}: {}",
-                            path_str,
-                            e
-                        );
-                    }
+                if let Some(scan) = pending_scans.remove(&path_str) {
+                    scan.abort();
+                }
+
+                if grace.is_zero() {
+                    remove_file_metadata(&path_str).await;
+                    continue;
+                }
+
+                // Only forget the file if it doesn't come back in the meantime
+                let path = path_str.clone();
+                let removal = tokio::spawn(async move {
+                    tokio::time::sleep(grace).await;
+                    remove_file_metadata(&path).await;
+                });
+                if let Some(previous) = pending_removals.insert(path_str, removal) {
+                    previous.abort();
                 }
             }
             _ => {} // Ignore other event types
@@ -412,69 +505,196 @@ async fn process_fs_events(rx: &mut tokio::sync::mpsc::Receiver<notify::Event>,
     }
 }
 
-#[tracing::instrument]
-pub async fn index_from_existing_data() -> color_eyre::eyre::Result<Index> {
-    let mut idx = Index::default();
+/// Time between the two size checks of a file the watcher is about to scan
+const SIZE_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Wait until a file's size stays the same across two checks `interval` apart.
+///
+/// Returns `false` if the file went away in the meantime.
+async fn wait_for_stable_size(path: &Path, interval: std::time::Duration) -> bool {
+    async fn size(path: &Path) -> Option<u64> {
+        tokio::fs::metadata(path).await.map(|m| m.len()).ok()
+    }
+
+    let Some(mut previous) = size(path).await else {
+        return false;
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(current) = size(path).await else {
+            return false;
+        };
+        if current == previous {
+            return true;
+        }
+        tracing::debug!("{} is still being written, waiting", path.display());
+        previous = current;
+    }
+}
+
+/// Scan a file the watcher saw being created or modified and save its metadata
+async fn scan_watched_file(path: &str) {
+    tracing::info!("Scanning new or modified file: {}", path);
+
+    // Same as a rescan, so files that can't be read are counted towards their quarantine
+    if let Err(e) = scan_file(Path::new(path), false).await {
+        tracing::warn!("Failed to scan {}: {}", path, e);
+    }
+}
+
+/// Delete the metadata of a file that was removed from the rom dir
+async fn remove_file_metadata(path: &str) {
+    // Find and delete the metadata for this file, with proper error handling
+    match NspMetadata::get_by_path(path).await {
+        Ok(Some(metadata)) => {
+            if let Err(e) = metadata.delete().await {
+                tracing::error!("Failed to delete metadata for {}: {}", path, e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("Failed to get metadata when removing file {}: {}", path, e);
+        }
+    }
+}
+
+/// A slice of the rom dir that an index can be generated for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexScope {
+    /// Every file
+    All,
+    /// A single file, or every file in a directory
+    Path(String),
+    /// Every file whose title ID starts with this prefix, e.g. all versions and DLCs of a game
+    TitlePrefix(String),
+}
 
+impl IndexScope {
+    pub fn matches(&self, path: &str, title_id: &str) -> bool {
+        match self {
+            IndexScope::All => true,
+            IndexScope::Path(scope) => Path::new(path).starts_with(scope),
+            IndexScope::TitlePrefix(prefix) => {
+                title_id.to_uppercase().starts_with(&prefix.to_uppercase())
+            }
+        }
+    }
+}
+
+#[tracing::instrument]
+pub async fn index_from_existing_data(scope: &IndexScope) -> color_eyre::eyre::Result<Index> {
     // Get all metadata with proper error handling
-    let all_metadata = match NspMetadata::get_all().await {
+    let all_metadata = match NspMetadata::get_in_scope(scope).await {
         Ok(metadata) => metadata,
         Err(e) => {
             tracing::error!("Failed to get metadata for index generation: {}", e);
             return Err(color_eyre::eyre::eyre!("Failed to generate index: {}", e));
         }
     };
+    let filename_template = FilenameConfig::configured_template().await;
 
-    for metadata in all_metadata {
-        let path = std::path::Path::new(&metadata.path);
+    let entries: Vec<_> = all_metadata
+        .iter()
+        .filter_map(|m| {
+            Some((
+                m.path.as_str(),
+                index_entry_from_metadata(m, filename_template.as_ref())?,
+            ))
+        })
+        .collect();
 
-        // Handle potential missing filename more gracefully
-        let filename = match path.file_name() {
-            Some(name) => name.to_string_lossy().into_owned(),
-            _ => {
-                tracing::warn!("Skipping entry with invalid path: {}", metadata.path);
-                continue;
-            }
-        };
+    Ok(Index {
+        files: dedup_index_entries(entries.iter().map(|(path, entry)| (*path, entry))),
+        ..Default::default()
+    })
+}
 
-        // Handle potential missing extension more gracefully
-        let extension = path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("nsp");
-
-        // Use the refactored function to format the name
-        let formatted_name = format_game_name(&metadata, &filename, extension);
-
-        // Extract the version number without 'v' prefix
-        let version_num = metadata.version.trim_start_matches('v');
-
-        // Create a title ID with version and file extension appended
-        let versioned_title_id = format!("{}_v{}.{}", metadata.title_id, version_num, extension);
-
-        // Construct the URL for the download endpoint
-        let url = format!("/api/get_game/{}#{}", versioned_title_id, formatted_name);
-
-        // Get the file size from the filesystem metadata
-        // This still requires filesystem access, but avoids using the `add_file` method's
-        // internal filesystem call and path manipulation logic.
-        let size = match std::fs::metadata(path) {
-             Ok(meta) => meta.len(),
-             Err(e) => {
-             tracing::warn!(
-                 "Failed to get filesystem metadata for {}: {}. Skipping entry.",
-                 metadata.path,
-                 e
-             );
-             continue; // Skip this entry if we can't get its size
-             }
-        };
+/// Build the index entry for a single file, or `None` if the file can't be served
+pub fn index_entry_from_metadata(
+    metadata: &NspMetadata,
+    filename_template: Option<&FilenameTemplate>,
+) -> Option<TinfoilFileEntry> {
+    let path = std::path::Path::new(&metadata.path);
+
+    // Handle potential missing filename more gracefully
+    let Some(filename) = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+    else {
+        tracing::warn!("Skipping entry with invalid path: {}", metadata.path);
+        return None;
+    };
 
-        // Add the file entry using the pre-constructed URL and size
-        idx.naive_add_file(&url, size);
-    }
+    // Handle potential missing extension more gracefully
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("nsp");
+
+    // Use the refactored function to format the name
+    let formatted_name = format_game_name(metadata, &filename, extension, filename_template);
+
+    // Title ID with version and file extension appended, unique for unidentified files
+    let download_id = file_download_id(
+        &metadata.path,
+        &metadata.title_id,
+        &metadata.version,
+        extension,
+    );
 
-    Ok(idx)
+    // Construct the URL for the download endpoint
+    let url = format!("/api/get_game/{}#{}", download_id, formatted_name);
+
+    // Get the file size from the filesystem metadata
+    let size = match std::fs::metadata(path) {
+        Ok(meta) => meta.len(),
+        Err(e) => {
+            tracing::warn!(
+                "Failed to get filesystem metadata for {}: {}. Skipping entry.",
+                metadata.path,
+                e
+            );
+            return None;
+        }
+    };
+
+    Some(TinfoilFileEntry { url, size })
+}
+
+/// Key a local file is listed under in the index, `{title_id}_v{version}.{ext}`
+fn index_entry_key(entry: &TinfoilFileEntry) -> Option<&str> {
+    let download_id = entry.url.strip_prefix("/api/get_game/")?;
+    download_id.split('#').next()
+}
+
+/// Drop entries listed under the same key as an earlier one, so Tinfoil doesn't show a
+/// title twice when the same version and format is on disk more than once.
+///
+/// Entries are `(path, entry)` pairs, the first path (sorted) of a key is kept.
+pub fn dedup_index_entries<'a>(
+    entries: impl IntoIterator<Item = (&'a str, &'a TinfoilFileEntry)>,
+) -> Vec<TinfoilFileEntry> {
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    entries.sort_by_key(|(path, _)| *path);
+
+    let mut seen = std::collections::HashMap::new();
+    let mut files = Vec::with_capacity(entries.len());
+    for (path, entry) in entries {
+        if let Some(key) = index_entry_key(entry) {
+            if let Some(kept) = seen.get(key) {
+                tracing::warn!(
+                    "Duplicate file {} for {}, only listing {} in the index",
+                    path,
+                    key,
+                    kept
+                );
+                continue;
+            }
+            seen.insert(key, path);
+        }
+        files.push(entry.clone());
+    }
+    files
 }
 
 // Middleware to handle trailing slashes
@@ -543,6 +763,7 @@ async fn log_request(req: Request, next: Next) -> impl IntoResponse {
 
     let response = next.run(req).await;
     let duration = start.elapsed();
+    crate::backend::api::metrics::record_request(&method, response.status(), duration);
 
     tracing::trace!("Request completed: {} {} in {:?}", method, path, duration);
     response
@@ -550,10 +771,79 @@ async fn log_request(req: Request, next: Next) -> impl IntoResponse {
 
 pub fn create_router() -> axum::Router {
     let router = create_backend_router();
+    let max_body_size = crate::config::config().max_body_size.as_u64() as usize;
 
     // Apply middlewares - normalize paths first, then log requests
     router
+        // Bound buffered bodies (JSON, form, bytes extractors), oversized requests get a 413
+        .layer(DefaultBodyLimit::max(max_body_size))
         .layer(middleware::from_fn(log_request))
         .layer(middleware::from_fn(normalize_trailing_slash))
         .layer(middleware::from_fn(tinfoil_redirect))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_for_stable_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("game.nsp");
+        std::fs::write(&path, b"data").unwrap();
+
+        let interval = std::time::Duration::from_millis(10);
+        assert!(wait_for_stable_size(&path, interval).await);
+        assert!(!wait_for_stable_size(&dir.path().join("missing.nsp"), interval).await);
+    }
+
+    #[test]
+    fn test_rescan_scan_dirs() {
+        let options = |path: Option<&str>| RescanOptions {
+            rescan: false,
+            path: path.map(str::to_string),
+        };
+        let roots = ["/games".to_string(), "/mnt/games".to_string()];
+        let dirs = |dirs: &[&str]| Some(dirs.iter().map(PathBuf::from).collect::<Vec<_>>());
+
+        assert_eq!(
+            options(None).scan_dirs(&roots),
+            dirs(&["/games", "/mnt/games"])
+        );
+        assert_eq!(
+            options(Some("")).scan_dirs(&roots),
+            dirs(&["/games", "/mnt/games"])
+        );
+        assert_eq!(
+            options(Some("Mario")).scan_dirs(&roots),
+            dirs(&["/games/Mario", "/mnt/games/Mario"])
+        );
+        assert_eq!(
+            options(Some("/mnt/games/Mario/DLC")).scan_dirs(&roots),
+            dirs(&["/mnt/games/Mario/DLC"])
+        );
+        assert_eq!(options(Some("../etc")).scan_dirs(&roots), None);
+        assert_eq!(options(Some("Mario/../../etc")).scan_dirs(&roots), None);
+        assert_eq!(options(Some("/games/../etc")).scan_dirs(&roots), None);
+        assert_eq!(options(Some("/etc")).scan_dirs(&roots), None);
+        assert_eq!(options(Some("/gamesextra")).scan_dirs(&roots), None);
+    }
+
+    #[test]
+    fn test_metadata_without_title_id() {
+        let path = "/games/Homebrew App.nsp";
+        let game_data = GameFileDataNaive::parse_from_filename("Homebrew App.nsp");
+        assert!(game_data.title_id.is_none());
+
+        let metadata = metadata_from_game_data(path, game_data, &[]);
+        assert!(metadata.unidentified);
+        assert_eq!(metadata.title_id, UNIDENTIFIED_TITLE_ID);
+        assert_eq!(metadata.title_name.as_deref(), Some("Homebrew App"));
+
+        let game_data =
+            GameFileDataNaive::parse_from_filename("Some Game [0100000000010000][v0].nsp");
+        let metadata = metadata_from_game_data(path, game_data, &[]);
+        assert!(!metadata.unidentified);
+        assert_eq!(metadata.title_id, "0100000000010000");
+    }
+}
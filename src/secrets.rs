@@ -0,0 +1,105 @@
+//! Encryption for secrets stored in the database
+//!
+//! Credentials like the headers of private extra indexes are encrypted with AES-256-GCM
+//! before they're saved, using a key derived from `ALU_SECRET_KEY`. Stored values are
+//! `v1:` followed by the base64 encoded nonce and ciphertext, so anyone reading the
+//! database without the key only sees that a secret is there.
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecretError {
+    #[error("ALU_SECRET_KEY is not set, it's required to store secrets")]
+    NoKey,
+    #[error("Stored secret is malformed")]
+    Malformed,
+    #[error("Failed to decrypt secret, was ALU_SECRET_KEY changed?")]
+    Decrypt,
+    #[error("Failed to encrypt secret")]
+    Encrypt,
+}
+
+pub type Result<T> = std::result::Result<T, SecretError>;
+
+/// Derive the encryption key from the configured secret
+fn cipher_from(secret: &str) -> Aes256Gcm {
+    let key = Sha256::digest(secret.as_bytes());
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+fn configured_cipher() -> Result<Aes256Gcm> {
+    crate::config::config()
+        .backend_config
+        .secret_key
+        .filter(|key| !key.is_empty())
+        .map(|key| cipher_from(&key))
+        .ok_or(SecretError::NoKey)
+}
+
+fn encrypt_with(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| SecretError::Encrypt)?;
+
+    let mut data = nonce.to_vec();
+    data.extend(ciphertext);
+    Ok(format!("{PREFIX}{}", BASE64.encode(data)))
+}
+
+fn decrypt_with(cipher: &Aes256Gcm, stored: &str) -> Result<Vec<u8>> {
+    let data = stored
+        .strip_prefix(PREFIX)
+        .and_then(|data| BASE64.decode(data).ok())
+        .filter(|data| data.len() > NONCE_LEN)
+        .ok_or(SecretError::Malformed)?;
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| SecretError::Decrypt)
+}
+
+/// Encrypt a secret for storage
+pub fn encrypt(plaintext: &[u8]) -> Result<String> {
+    encrypt_with(&configured_cipher()?, plaintext)
+}
+
+/// Decrypt a secret stored with [`encrypt`]
+pub fn decrypt(stored: &str) -> Result<Vec<u8>> {
+    decrypt_with(&configured_cipher()?, stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_roundtrip() {
+        let cipher = cipher_from("correct horse battery staple");
+        let stored = encrypt_with(&cipher, b"Bearer hunter2").unwrap();
+        assert!(stored.starts_with(PREFIX));
+        assert!(!stored.contains("hunter2"));
+        assert_eq!(decrypt_with(&cipher, &stored).unwrap(), b"Bearer hunter2");
+
+        // Same secret, different nonce
+        assert_ne!(encrypt_with(&cipher, b"Bearer hunter2").unwrap(), stored);
+
+        assert!(matches!(
+            decrypt_with(&cipher_from("wrong key"), &stored),
+            Err(SecretError::Decrypt)
+        ));
+        assert!(matches!(
+            decrypt_with(&cipher, "Bearer hunter2"),
+            Err(SecretError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,364 @@
+//! Free-space watchdog for the rom and cache volumes
+//!
+//! A background task periodically checks the free space of the volumes that hold the
+//! rom directory and the download cache. When either drops below the configured
+//! threshold, new downloads are held back (downloads already running keep going) until
+//! enough space is available again.
+//!
+//! Downloads and extracted archives also check there's room for each file before writing
+//! it, leaving the same minimum free, so a full disk fails the import right away instead of
+//! in the middle of a file.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::Disks;
+use tokio::sync::watch;
+
+use crate::backend::kv_config::KvOptExt;
+
+/// Warning shown to users while new downloads are held back
+pub const LOW_SPACE_WARNING: &str =
+    "The server is running low on disk space, new downloads are paused until space is freed";
+
+/// How much of a file of unknown size is written between two free-space checks
+const SPACE_CHECK_INTERVAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Whether any watched volume is currently below the free-space threshold
+static LOW_SPACE: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Result of the most recent free-space check
+static LAST_REPORT: LazyLock<Mutex<Option<StorageReport>>> = LazyLock::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageGuardConfig {
+    /// Minimum free space in bytes on the rom and cache volumes, 0 disables the guard
+    #[serde(default)]
+    pub min_free_bytes: u64,
+    /// How often to check free space, in seconds
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+fn default_check_interval_secs() -> u64 {
+    60
+}
+
+impl Default for StorageGuardConfig {
+    fn default() -> Self {
+        Self {
+            min_free_bytes: 0,
+            check_interval_secs: default_check_interval_secs(),
+        }
+    }
+}
+
+impl KvOptExt for StorageGuardConfig {
+    const KEY_NAME: &'static str = "storage_guard";
+}
+
+/// Free space of a single watched directory
+#[derive(Debug, Clone, Serialize)]
+pub struct VolumeSpace {
+    pub path: PathBuf,
+    pub mount_point: Option<PathBuf>,
+    pub available_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageReport {
+    pub low_space: bool,
+    pub min_free_bytes: u64,
+    pub volumes: Vec<VolumeSpace>,
+    pub checked_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Check if new downloads are currently held back because of low disk space
+pub fn is_low_on_space() -> bool {
+    *LOW_SPACE.borrow()
+}
+
+/// Wait until there is enough free space to start new downloads
+pub async fn wait_for_space() {
+    let mut rx = LOW_SPACE.subscribe();
+    // The sender lives in a static, so this can't fail
+    let _ = rx.wait_for(|low| !*low).await;
+}
+
+/// Get the result of the most recent free-space check, if one has run yet
+pub fn last_report() -> Option<StorageReport> {
+    LAST_REPORT.lock().unwrap().clone()
+}
+
+/// Find the disk that holds `path`, by longest matching mount point
+fn volume_space(disks: &Disks, path: &Path) -> VolumeSpace {
+    let resolved = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let disk = disks
+        .list()
+        .iter()
+        .filter(|disk| resolved.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len());
+
+    VolumeSpace {
+        path: path.to_path_buf(),
+        mount_point: disk.map(|d| d.mount_point().to_path_buf()),
+        available_bytes: disk.map(|d| d.available_space()),
+        total_bytes: disk.map(|d| d.total_space()),
+    }
+}
+
+/// Check free space on the rom and cache volumes and update the low-space flag
+pub async fn check_free_space(config: &StorageGuardConfig) -> StorageReport {
+    let backend_config = crate::config::config().backend_config;
+    let paths = backend_config
+        .rom_dirs()
+        .into_iter()
+        .chain([backend_config.cache_dir])
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+    let volumes = tokio::task::spawn_blocking(move || {
+        let disks = Disks::new_with_refreshed_list();
+        paths
+            .iter()
+            .map(|path| volume_space(&disks, path))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    let low_space = config.min_free_bytes > 0
+        && volumes.iter().any(|volume| {
+            volume
+                .available_bytes
+                .is_some_and(|available| available < config.min_free_bytes)
+        });
+
+    let report = StorageReport {
+        low_space,
+        min_free_bytes: config.min_free_bytes,
+        volumes,
+        checked_at: chrono::Utc::now(),
+    };
+
+    let was_low = LOW_SPACE.send_replace(low_space);
+    if low_space != was_low {
+        // The warning is part of the index MOTD
+        crate::backend::api::invalidate_index_cache();
+    }
+
+    if low_space && !was_low {
+        tracing::warn!(
+            min_free_bytes = config.min_free_bytes,
+            volumes = ?report.volumes,
+            "Free disk space is below the threshold, holding back new downloads"
+        );
+    } else if !low_space && was_low {
+        tracing::info!("Free disk space recovered, resuming downloads");
+    }
+
+    *LAST_REPORT.lock().unwrap() = Some(report.clone());
+    report
+}
+
+/// Configured minimum of free space, kept free by imports too
+async fn min_free_bytes() -> u64 {
+    match StorageGuardConfig::get().await {
+        Ok(config) => config.unwrap_or_default().min_free_bytes,
+        Err(e) => {
+            tracing::error!("Failed to get storage guard config: {}", e);
+            StorageGuardConfig::default().min_free_bytes
+        }
+    }
+}
+
+/// Pick the first of `volumes` with room for `needed` more bytes on top of `min_free_bytes`
+fn first_with_room(volumes: &[VolumeSpace], needed: u64, min_free_bytes: u64) -> Option<&Path> {
+    volumes
+        .iter()
+        .find(|volume| {
+            volume
+                .available_bytes
+                .is_some_and(|available| available >= needed.saturating_add(min_free_bytes))
+        })
+        .map(|volume| volume.path.as_path())
+}
+
+/// Pick the rom dir to import `needed` bytes of games into.
+///
+/// That's the first rom dir with enough free space left afterwards, or the primary one if
+/// none has it.
+pub async fn pick_rom_dir(needed: u64) -> PathBuf {
+    let rom_dirs = crate::config::config().backend_config.rom_dirs();
+    if rom_dirs.len() == 1 {
+        return PathBuf::from(&rom_dirs[0]);
+    }
+
+    let min_free_bytes = min_free_bytes().await;
+    let paths = rom_dirs.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let volumes = tokio::task::spawn_blocking(move || {
+        let disks = Disks::new_with_refreshed_list();
+        paths
+            .iter()
+            .map(|path| volume_space(&disks, path))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    match first_with_room(&volumes, needed, min_free_bytes) {
+        Some(path) => path.to_path_buf(),
+        None => {
+            tracing::warn!(
+                needed,
+                "No games directory has enough free space, importing into {}",
+                rom_dirs[0]
+            );
+            PathBuf::from(&rom_dirs[0])
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Not enough disk space for {path:?}: {needed} bytes needed, only {available} available")]
+pub struct InsufficientSpaceError {
+    pub path: PathBuf,
+    /// Bytes needed, including the configured minimum of free space
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl From<InsufficientSpaceError> for io::Error {
+    fn from(e: InsufficientSpaceError) -> Self {
+        io::Error::new(io::ErrorKind::StorageFull, e)
+    }
+}
+
+/// Check `available` bytes leave room for `size` more bytes in `path` and `min_free_bytes`
+/// to spare. Unknown free space is let through, as not every filesystem reports it
+fn check_room(
+    path: &Path,
+    available: Option<u64>,
+    size: u64,
+    min_free_bytes: u64,
+) -> Result<(), InsufficientSpaceError> {
+    let needed = size.saturating_add(min_free_bytes);
+    match available {
+        Some(available) if available < needed => Err(InsufficientSpaceError {
+            path: path.to_path_buf(),
+            needed,
+            available,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Check there's room for `size` more bytes in `path`, a file or the directory it goes in
+pub async fn ensure_space(path: &Path, size: u64) -> Result<(), InsufficientSpaceError> {
+    // Files that don't exist yet are on the volume of their directory
+    let dir = match path.parent() {
+        Some(parent) if !path.is_dir() => parent.to_path_buf(),
+        _ => path.to_path_buf(),
+    };
+    let available = tokio::task::spawn_blocking(move || {
+        volume_space(&Disks::new_with_refreshed_list(), &dir).available_bytes
+    })
+    .await
+    .ok()
+    .flatten();
+    check_room(path, available, size, min_free_bytes().await)
+}
+
+/// Checks free space every so often while a file of unknown size is written
+#[derive(Debug)]
+pub struct SpaceWatch {
+    path: PathBuf,
+    unchecked: u64,
+}
+
+impl SpaceWatch {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            unchecked: 0,
+        }
+    }
+
+    /// Count bytes written to the file, failing once the disk is almost full
+    pub async fn wrote(&mut self, bytes: u64) -> Result<(), InsufficientSpaceError> {
+        self.unchecked += bytes;
+        if self.unchecked < SPACE_CHECK_INTERVAL_BYTES {
+            return Ok(());
+        }
+        self.unchecked = 0;
+        ensure_space(&self.path, 0).await
+    }
+}
+
+/// Periodically check free space, forever
+pub async fn free_space_watchdog() {
+    loop {
+        let config = match StorageGuardConfig::get().await {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                tracing::error!("Failed to get storage guard config: {}", e);
+                StorageGuardConfig::default()
+            }
+        };
+
+        check_free_space(&config).await;
+
+        tokio::time::sleep(Duration::from_secs(config.check_interval_secs.max(1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volume(path: &str, available_bytes: Option<u64>) -> VolumeSpace {
+        VolumeSpace {
+            path: PathBuf::from(path),
+            mount_point: None,
+            available_bytes,
+            total_bytes: None,
+        }
+    }
+
+    #[test]
+    fn test_first_with_room() {
+        let volumes = [
+            volume("/games", Some(100)),
+            volume("/mnt/unknown", None),
+            volume("/mnt/games", Some(1000)),
+        ];
+
+        assert_eq!(first_with_room(&volumes, 50, 10), Some(Path::new("/games")));
+        assert_eq!(
+            first_with_room(&volumes, 95, 10),
+            Some(Path::new("/mnt/games"))
+        );
+        assert_eq!(first_with_room(&volumes, 995, 10), None);
+    }
+
+    #[test]
+    fn test_check_room() {
+        let path = Path::new("/games/game.nsp");
+        let gib = 1024 * 1024 * 1024;
+        let margin = 256 * 1024 * 1024;
+        assert!(check_room(path, Some(2 * gib), gib, margin).is_ok());
+        assert!(check_room(path, None, 100 * gib, margin).is_ok());
+        assert!(check_room(path, Some(gib), gib, 0).is_ok());
+        // The minimum free space has to fit too
+        let e = check_room(path, Some(gib), gib, margin).unwrap_err();
+        assert_eq!(e.needed, gib + margin);
+        assert_eq!(e.available, gib);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::StorageFull);
+    }
+}
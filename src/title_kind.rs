@@ -0,0 +1,266 @@
+//! Classification of title IDs into base games, updates and DLC
+//!
+//! The last digits of a title ID tell what kind of content it is: base games end in `000`
+//! and updates in `800`, anything else is DLC. The suffixes can be changed with
+//! `ALU_BASE_TITLE_SUFFIXES` and `ALU_UPDATE_TITLE_SUFFIXES` for content that doesn't follow
+//! this scheme, and every listing, grouping and database query goes through the rules here
+//! instead of checking suffixes on its own.
+
+use std::{fmt, str::FromStr, sync::LazyLock};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+/// Rules in effect, as configured at startup
+static RULES: LazyLock<TitleKindRules> = LazyLock::new(|| {
+    // Fall back to the default scheme where the config can't be parsed, such as in tests
+    crate::config::Config::try_parse()
+        .map(|config| TitleKindRules::from_config(&config.backend_config))
+        .unwrap_or_default()
+});
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid title ID suffix '{0}', expected 1 to 16 hex digits")]
+pub struct TitleSuffixError(String);
+
+/// Hex digits a title ID of some kind ends with, stored in uppercase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleSuffix(String);
+
+impl TitleSuffix {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Check if a title ID ends with this suffix, ignoring case
+    fn matches(&self, title_id: &str) -> bool {
+        title_id.len() >= self.0.len()
+            && title_id.as_bytes()[title_id.len() - self.0.len()..]
+                .eq_ignore_ascii_case(self.0.as_bytes())
+    }
+
+    /// SurrealQL condition matching the values of `field` that end with this suffix
+    fn sql_condition(&self, field: &str) -> String {
+        // Title IDs aren't always stored in uppercase, which only matters for letters
+        if self.0.bytes().all(|byte| byte.is_ascii_digit()) {
+            format!("string::ends_with({field}, '{}')", self.0)
+        } else {
+            format!(
+                "string::ends_with(string::uppercase({field}), '{}')",
+                self.0
+            )
+        }
+    }
+}
+
+impl FromStr for TitleSuffix {
+    type Err = TitleSuffixError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // Only hex digits, so suffixes can be put into queries as they are
+        if s.is_empty() || s.len() > 16 || !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(TitleSuffixError(s.to_string()));
+        }
+        Ok(Self(s.to_ascii_uppercase()))
+    }
+}
+
+impl fmt::Display for TitleSuffix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What kind of content a title ID belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TitleKind {
+    Base,
+    Update,
+    Dlc,
+}
+
+impl TitleKind {
+    /// Classify a title ID with the configured rules
+    pub fn from_title_id(title_id: &str) -> Self {
+        RULES.classify(title_id)
+    }
+
+    /// Get the title ID of the base game a title ID belongs to, with the configured rules
+    pub fn base_title_id(title_id: &str) -> Option<String> {
+        RULES.base_title_id(title_id)
+    }
+
+    /// SurrealQL condition matching the values of `field` of this kind, with the configured
+    /// rules
+    pub fn sql_condition(self, field: &str) -> String {
+        RULES.sql_condition(self, field)
+    }
+
+    /// SurrealQL expression of the base title ID of an update in `field`, with the
+    /// configured rules
+    pub fn sql_base_title_id(field: &str) -> String {
+        RULES.sql_base_title_id(field)
+    }
+
+    pub fn is_base(title_id: &str) -> bool {
+        Self::from_title_id(title_id) == Self::Base
+    }
+
+    pub fn is_update(title_id: &str) -> bool {
+        Self::from_title_id(title_id) == Self::Update
+    }
+}
+
+/// Suffixes that tell the kind of a title ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleKindRules {
+    /// The first one is used when going from an update to its base game
+    pub base_suffixes: Vec<TitleSuffix>,
+    pub update_suffixes: Vec<TitleSuffix>,
+}
+
+impl Default for TitleKindRules {
+    fn default() -> Self {
+        Self {
+            base_suffixes: vec![TitleSuffix("000".to_string())],
+            update_suffixes: vec![TitleSuffix("800".to_string())],
+        }
+    }
+}
+
+impl TitleKindRules {
+    /// Rules configured with `ALU_BASE_TITLE_SUFFIXES` and `ALU_UPDATE_TITLE_SUFFIXES`,
+    /// keeping the default of a kind left empty
+    pub fn from_config(config: &crate::config::BackendConfig) -> Self {
+        let default = Self::default();
+        let or_default = |suffixes: &[TitleSuffix], default: Vec<TitleSuffix>| {
+            if suffixes.is_empty() {
+                default
+            } else {
+                suffixes.to_vec()
+            }
+        };
+        Self {
+            base_suffixes: or_default(&config.base_title_suffixes, default.base_suffixes),
+            update_suffixes: or_default(&config.update_title_suffixes, default.update_suffixes),
+        }
+    }
+
+    /// Classify a title ID, base suffixes taking precedence over update suffixes
+    pub fn classify(&self, title_id: &str) -> TitleKind {
+        let matches = |suffixes: &[TitleSuffix]| suffixes.iter().any(|s| s.matches(title_id));
+        if matches(&self.base_suffixes) {
+            TitleKind::Base
+        } else if matches(&self.update_suffixes) {
+            TitleKind::Update
+        } else {
+            TitleKind::Dlc
+        }
+    }
+
+    /// Get the title ID of the base game, by replacing the last digits with the base suffix
+    pub fn base_title_id(&self, title_id: &str) -> Option<String> {
+        let base = self.base_suffixes.first()?;
+        let end = title_id.len().checked_sub(base.0.len())?;
+        let prefix = title_id.get(..end)?;
+        Some(format!("{prefix}{base}"))
+    }
+
+    /// SurrealQL condition matching the values of `field` of a kind
+    pub fn sql_condition(&self, kind: TitleKind, field: &str) -> String {
+        let any = |suffixes: &[TitleSuffix]| {
+            let conditions: Vec<String> = suffixes.iter().map(|s| s.sql_condition(field)).collect();
+            format!("({})", conditions.join(" OR "))
+        };
+        match kind {
+            TitleKind::Base => any(&self.base_suffixes),
+            TitleKind::Update => format!(
+                "(!{} AND {})",
+                any(&self.base_suffixes),
+                any(&self.update_suffixes)
+            ),
+            TitleKind::Dlc => format!(
+                "(!{} AND !{})",
+                any(&self.base_suffixes),
+                any(&self.update_suffixes)
+            ),
+        }
+    }
+
+    /// SurrealQL expression of the base title ID of an update in `field`, `NONE` for
+    /// other kinds
+    pub fn sql_base_title_id(&self, field: &str) -> String {
+        let base = self
+            .base_suffixes
+            .first()
+            .map(TitleSuffix::as_str)
+            .unwrap_or_default();
+        format!(
+            "IF {} THEN string::concat(string::slice({field}, 0, string::len({field}) - {}), '{base}') END",
+            self.sql_condition(TitleKind::Update, field),
+            base.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_title_ids() {
+        let rules = TitleKindRules::default();
+        assert_eq!(rules.classify("0100000000010000"), TitleKind::Base);
+        assert_eq!(rules.classify("0100000000010800"), TitleKind::Update);
+        assert_eq!(rules.classify("0100000000011001"), TitleKind::Dlc);
+        assert_eq!(rules.classify("01000000000113E8"), TitleKind::Dlc);
+        // Only the suffix counts, even for a DLC that happens to be numbered 0x800
+        assert_eq!(rules.classify("0100000000011800"), TitleKind::Update);
+        assert_eq!(rules.classify(""), TitleKind::Dlc);
+
+        let rules = TitleKindRules {
+            base_suffixes: vec!["000".parse().unwrap(), "a00".parse().unwrap()],
+            update_suffixes: vec!["800".parse().unwrap()],
+        };
+        assert_eq!(rules.classify("0100000000010A00"), TitleKind::Base);
+        assert_eq!(rules.classify("0100000000010a00"), TitleKind::Base);
+    }
+
+    #[test]
+    fn test_base_title_id() {
+        let rules = TitleKindRules::default();
+        assert_eq!(
+            rules.base_title_id("0100000000010800").as_deref(),
+            Some("0100000000010000")
+        );
+        // Only the suffix is replaced, not other occurrences of it
+        assert_eq!(
+            rules.base_title_id("0100800000010800").as_deref(),
+            Some("0100800000010000")
+        );
+        assert_eq!(rules.base_title_id("00"), None);
+    }
+
+    #[test]
+    fn test_parse_title_suffix() {
+        assert_eq!("a00".parse::<TitleSuffix>().unwrap().as_str(), "A00");
+        assert!("".parse::<TitleSuffix>().is_err());
+        assert!("80g".parse::<TitleSuffix>().is_err());
+        assert!("0') OR true OR ('".parse::<TitleSuffix>().is_err());
+    }
+
+    #[test]
+    fn test_sql_condition() {
+        let rules = TitleKindRules::default();
+        assert_eq!(
+            rules.sql_condition(TitleKind::Base, "title_id"),
+            "(string::ends_with(title_id, '000'))"
+        );
+        assert_eq!(
+            rules.sql_condition(TitleKind::Dlc, "title_id"),
+            "(!(string::ends_with(title_id, '000')) AND !(string::ends_with(title_id, '800')))"
+        );
+    }
+}
@@ -1,21 +1,111 @@
 use crate::LOCALE;
-use crate::backend::api::metadata::SearchQuery;
+use crate::backend::api::metadata::{ListQuery, SearchQuery};
 use crate::db::{DB, NspMetadata, create_precomputed_metaview};
-use crate::util::format_download_id;
+use crate::router::IndexScope;
+use crate::title_kind::TitleKind;
+use crate::util::file_download_id;
 use color_eyre::Result;
+use nx_archive::formats::cnmt::Cnmt;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
 use struson::reader::{JsonReader, JsonStreamReader};
 use surrealdb::sql::Thing;
+
+/// When TitleDB was last imported for each locale since the server started
+static LAST_IMPORT: LazyLock<Mutex<HashMap<String, chrono::DateTime<chrono::Utc>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Get the time TitleDB was last successfully imported for a locale, if it was since startup
+pub fn last_import_time(locale: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    LAST_IMPORT.lock().unwrap().get(locale).copied()
+}
+
+/// Entries imported between two progress reports of a TitleDB import
+const IMPORT_PROGRESS_INTERVAL: u64 = 5000;
+
+/// TitleDB imports that are running, by locale
+static IMPORT_PROGRESS: LazyLock<Mutex<HashMap<String, TitleDbImportProgress>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Progress of a running TitleDB import
+#[derive(Debug, Clone, Serialize)]
+pub struct TitleDbImportProgress {
+    pub locale: String,
+    /// Entries read so far, including the failed ones
+    pub entries: u64,
+    /// Entries that couldn't be parsed or saved, and were skipped
+    pub failed: u64,
+    /// Rough percentage of the file that was read, if its size is known
+    pub percent: Option<f64>,
+    /// Rough number of entries in the file, extrapolated from the part that was read
+    pub estimated_total: Option<u64>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Get the progress of the TitleDB imports that are running
+pub fn import_progress() -> Vec<TitleDbImportProgress> {
+    let mut progress: Vec<_> = IMPORT_PROGRESS.lock().unwrap().values().cloned().collect();
+    progress.sort_by(|a, b| a.locale.cmp(&b.locale));
+    progress
+}
+
+/// Reader counting how many bytes were read through it
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Number of leading title ID characters shared by a base game and all of its updates and DLC
+pub const TITLE_GROUP_PREFIX_LEN: usize = 12;
+
+/// Get the prefix used to group a title ID with its base game, updates and DLC.
+///
+/// Returns `None` if the title ID is too short or contains non-hex characters in the prefix.
+pub fn title_group_prefix(title_id: &str) -> Option<&str> {
+    let prefix = title_id.get(..TITLE_GROUP_PREFIX_LEN)?;
+    prefix
+        .chars()
+        .all(|c| c.is_ascii_hexdigit())
+        .then_some(prefix)
+}
+
+/// Get the title ID of the base game of a title ID's group, in uppercase
+pub fn title_group_base_id(title_id: &str) -> Option<String> {
+    title_group_prefix(title_id).map(|prefix| format!("{}0000", prefix.to_uppercase()))
+}
+
+/// Whether a filename tag is a title ID, 16 hex characters
+fn is_title_id_tag(tag: &str) -> bool {
+    tag.len() == 16 && tag.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether a filename tag is a version, a `v` followed by digits
+fn is_version_tag(tag: &str) -> bool {
+    tag.strip_prefix('v')
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+}
+
 /// Represents a naive game data type, parsed with regex
 ///
 /// Example: `Video Game [TITLEID][v0][US].nsp`
 /// All fields between the file name and the extension (in square brackets) are optional.
 ///
 ///
-/// if tag contains exactly 16 characters it's a titleid, if it starts with a `v` (lowercase v) it's a version
-/// assume last tag is region
+/// if tag is exactly 16 hex characters it's a titleid, if it's a `v` (lowercase v) followed by
+/// digits it's a version
+/// assume last remaining tag is region
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct GameFileDataNaive {
@@ -25,6 +115,10 @@ pub struct GameFileDataNaive {
     pub region: Option<String>,
     pub other_tags: Vec<String>,
     pub extension: Option<String>,
+    /// Minimum system version from the CNMT, filenames don't carry one
+    pub required_system_version: Option<u32>,
+    /// Why the CNMT couldn't be read, when everything else came from the filename
+    pub cnmt_error: Option<crate::nsp::CnmtReadError>,
 }
 
 impl GameFileDataNaive {
@@ -45,8 +139,8 @@ impl GameFileDataNaive {
             .map(|cap| cap[1].to_string())
             .collect();
 
-        // Find the title_id (exactly 16 characters)
-        let title_id = tags.iter().find(|tag| tag.len() == 16).cloned();
+        // Find the title_id (exactly 16 hex characters)
+        let title_id = tags.iter().find(|tag| is_title_id_tag(tag)).cloned();
 
         // Remove title_id from tags if found
         if let Some(tid) = &title_id {
@@ -55,8 +149,8 @@ impl GameFileDataNaive {
             }
         }
 
-        // Find version (starts with 'v')
-        let version = tags.iter().find(|tag| tag.starts_with("v")).cloned();
+        // Find version ('v' followed by digits)
+        let version = tags.iter().find(|tag| is_version_tag(tag)).cloned();
 
         // Remove version from tags if found
         if let Some(ver) = &version {
@@ -65,10 +159,9 @@ impl GameFileDataNaive {
             }
         }
 
-        let other_tags = tags;
-
         // Assume the last remaining tag is the region, if any
-        let region = None;
+        let region = tags.pop();
+        let other_tags = tags;
 
         // Get the base name without tags
         let name = regex.replace_all(filename, "").trim().to_string();
@@ -80,6 +173,8 @@ impl GameFileDataNaive {
             region,
             extension,
             other_tags,
+            required_system_version: None,
+            cnmt_error: None,
         }
     }
 
@@ -109,14 +204,17 @@ impl GameFileDataNaive {
                 let mut naive = Self::parse_from_filename(filename);
                 naive.title_id = Some(existing_metadata.title_id.clone());
                 naive.version = Some(existing_metadata.version.clone());
+                naive.required_system_version = existing_metadata.required_system_version;
                 return Ok(naive);
             } else {
                 tracing::debug!("Reading NSP/NSZ/XCI file: {:?}", filename);
-                let cnmt = match crate::nsp::read_cnmt_merged(path_str) {
+                let cnmt = match crate::nsp::read_cnmt_limited(path_str).await {
                     Ok(cnmt) => cnmt,
                     Err(e) => {
                         tracing::warn!("Failed to read CNMT for {}: {}", path.display(), e);
-                        return Ok(Self::parse_from_filename(filename));
+                        let mut naive = Self::parse_from_filename(filename);
+                        naive.cnmt_error = Some((&e).into());
+                        return Ok(naive);
                     }
                 };
 
@@ -128,6 +226,8 @@ impl GameFileDataNaive {
 
                 let title_id = cnmt.get_title_id_string();
                 let version = cnmt.header.title_version.to_string();
+                let required_system_version =
+                    crate::nsp::required_system_version(&cnmt).map(|version| version.0);
 
                 tracing::debug!("Title ID: {:?}", title_id);
                 tracing::debug!("Version: {:?}", version);
@@ -137,7 +237,9 @@ impl GameFileDataNaive {
                     title_id: title_id.clone(),
                     version: version.clone(),
                     title_name: None,
-                    download_id: format_download_id(&title_id, &version, extension),
+                    download_id: file_download_id(path_str, &title_id, &version, extension),
+                    unidentified: false,
+                    required_system_version,
                 };
 
                 if let Err(e) = metadata.save().await {
@@ -166,7 +268,9 @@ impl GameFileDataNaive {
                         title_id: title_id.clone(),
                         version: version.clone(),
                         title_name: title_name.clone(),
-                        download_id: format_download_id(&title_id, &version, extension),
+                        download_id: file_download_id(path_str, &title_id, &version, extension),
+                        unidentified: false,
+                        required_system_version,
                     };
 
                     if let Err(e) = metadata.save().await {
@@ -180,11 +284,17 @@ impl GameFileDataNaive {
                         region: title.region,
                         other_tags: Vec::new(),
                         extension: Some(extension.to_string()),
+                        required_system_version,
+                        cnmt_error: None,
                     });
                 // else we got a title ID but no title, we can still return the title ID
                 } else {
                     let mut naive = Self::parse_from_filename(filename);
                     naive.title_id = Some(title_id.to_string());
+                    naive.required_system_version = required_system_version;
+                    if let Some(name) = nacp_name(path_str, &cnmt).await {
+                        naive.name = name;
+                    }
                     return Ok(naive);
                 }
             }
@@ -213,11 +323,13 @@ impl GameFileDataNaive {
                 .to_str()
                 .ok_or_else(|| color_eyre::eyre::eyre!("Path is not valid UTF-8"))?;
 
-            let cnmt = match crate::nsp::read_cnmt_merged(path_str) {
+            let cnmt = match crate::nsp::read_cnmt_limited(path_str).await {
                 Ok(cnmt) => cnmt,
                 Err(e) => {
                     tracing::warn!("Failed to read CNMT for {}: {}", path.display(), e);
-                    return Ok(Self::parse_from_filename(filename));
+                    let mut naive = Self::parse_from_filename(filename);
+                    naive.cnmt_error = Some((&e).into());
+                    return Ok(naive);
                 }
             };
 
@@ -229,6 +341,8 @@ impl GameFileDataNaive {
 
             let title_id = cnmt.get_title_id_string();
             let version = cnmt.header.title_version.to_string();
+            let required_system_version =
+                crate::nsp::required_system_version(&cnmt).map(|version| version.0);
 
             tracing::debug!("Title ID: {:?}", title_id);
             tracing::debug!("Version: {:?}", version);
@@ -257,12 +371,18 @@ impl GameFileDataNaive {
                     region: title.region,
                     other_tags: Vec::new(),
                     extension: Some(extension.to_string()),
+                    required_system_version,
+                    cnmt_error: None,
                 });
             // else we got a title ID but no title, we can still return the title ID
             } else {
                 let mut naive = Self::parse_from_filename(filename);
                 naive.title_id = Some(title_id.to_string());
                 naive.version = Some(version);
+                naive.required_system_version = required_system_version;
+                if let Some(name) = nacp_name(path_str, &cnmt).await {
+                    naive.name = name;
+                }
                 return Ok(naive);
             }
         }
@@ -271,6 +391,20 @@ impl GameFileDataNaive {
     }
 }
 
+/// Name of a title TitleDB doesn't know from the NACP in its file, if that's enabled
+pub async fn nacp_name(path: &str, cnmt: &Cnmt) -> Option<String> {
+    if !crate::config::config().backend_config.nacp_names {
+        return None;
+    }
+    match crate::nsp::read_nacp_name_limited(path, cnmt).await {
+        Ok(name) => name,
+        Err(e) => {
+            tracing::warn!("Failed to read the NACP of {}: {}", path, e);
+            None
+        }
+    }
+}
+
 use serde::Deserializer;
 
 fn deser_to_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
@@ -315,6 +449,56 @@ pub fn default_locale() -> String {
     LOCALE.to_string()
 }
 
+/// Field to sort title listings by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleSort {
+    Name,
+    ReleaseDate,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl TitleSort {
+    /// Field of a metaview row holding the value to sort by
+    fn field(self) -> &'static str {
+        match self {
+            TitleSort::Name => "title.name",
+            TitleSort::ReleaseDate => "title.releaseDate",
+            TitleSort::Size => "title.size",
+        }
+    }
+
+    /// Extra field to select for [`TitleSort::order_by`], flagging rows without a value
+    fn missing_field(self) -> String {
+        format!("!{} AS sort_missing", self.field())
+    }
+
+    /// `ORDER BY` clause, titles without a value come last in either order
+    fn order_by(self, order: SortOrder) -> String {
+        let collate = if self == TitleSort::Name {
+            " COLLATE"
+        } else {
+            ""
+        };
+        let direction = match order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        format!(
+            "ORDER BY sort_missing ASC, {}{collate} {direction}, id ASC",
+            self.field()
+        )
+    }
+}
+
 impl Metaview {
     pub async fn get_from_title_id(title_id: &str) -> Result<Option<Self>> {
         let locale = default_locale();
@@ -348,7 +532,8 @@ impl Metaview {
         let query = format!(
             "SELECT * FROM metaview_{locale}
             WHERE title_id
-            AND not(string::ends_with(title_id, '000'))"
+            AND !{}",
+            TitleKind::Base.sql_condition("title_id")
         );
         let mut query = DB.query(query).await?;
         let data: Vec<Metaview> = query.take(0)?;
@@ -358,7 +543,8 @@ impl Metaview {
     /// Get all download IDs of a give
     pub async fn get_download_ids(title_id: &str) -> Result<Vec<String>> {
         let locale = default_locale();
-        let title_id_prefix = &title_id[..12];
+        let title_id_prefix = title_group_prefix(title_id)
+            .ok_or_else(|| color_eyre::eyre::eyre!("Invalid title ID: {}", title_id))?;
 
         let query = format!(
             "SELECT * FROM metaview_{locale}
@@ -377,19 +563,113 @@ impl Metaview {
         Ok(title_ids)
     }
 
-    pub async fn get_base_games() -> Result<Vec<Self>> {
+    /// Get the title IDs of all local files that TitleDB flags as demos
+    pub async fn get_demo_title_ids() -> Result<std::collections::HashSet<String>> {
+        let locale = default_locale();
+        let query = format!("SELECT VALUE title_id FROM metaview_{locale} WHERE title.isDemo = true");
+        let mut query = DB.query(query).await?;
+        let data: Vec<Option<String>> = query.take(0)?;
+        Ok(data.into_iter().flatten().collect())
+    }
+
+    /// Get the languages supported by at least one local title, as TitleDB lists them
+    pub async fn get_languages() -> Result<std::collections::BTreeSet<String>> {
+        let locale = default_locale();
+        let query = format!("SELECT VALUE title.languages FROM metaview_{locale}");
+        let mut query = DB.query(query).await?;
+        let data: Vec<Option<Vec<String>>> = query.take(0)?;
+        Ok(data
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|language| language.to_lowercase())
+            .collect())
+    }
+
+    /// Count the local files that have TitleDB metadata
+    pub async fn count_matched() -> Result<usize> {
+        let locale = default_locale();
+        let query = format!("SELECT count() FROM metaview_{locale} WHERE title.titleId GROUP ALL");
+        let mut query = DB.query(query).await?;
+        take_count(&mut query)
+    }
+
+    /// Get the categories (genres) of the local titles
+    pub async fn get_categories() -> Result<std::collections::BTreeSet<String>> {
         let locale = default_locale();
+        let query = format!("SELECT VALUE title.category FROM metaview_{locale}");
+        let mut query = DB.query(query).await?;
+        let data: Vec<Option<Vec<String>>> = query.take(0)?;
+        Ok(data.into_iter().flatten().flatten().collect())
+    }
+
+    /// List base games matching the filters of a listing, `limit` of them from the `start`th on,
+    /// in the requested order or by ID.
+    pub async fn get_base_games(
+        list_query: &ListQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Title>> {
+        let locale = default_locale();
+        let (fields, order_by) = match list_query.sort {
+            Some(sort) => (
+                format!("*, {}", sort.missing_field()),
+                sort.order_by(list_query.order.unwrap_or_default()),
+            ),
+            None => ("*".to_string(), "ORDER BY id".to_string()),
+        };
         let query = format!(
-            "SELECT * FROM metaview_{locale} WHERE title.titleId AND string::ends_with(title.titleId, '000')"
+            "SELECT {fields} FROM metaview_{locale} WHERE {} {order_by} LIMIT $limit START $start",
+            Self::base_game_condition()
         );
-        let mut query = DB.query(query).await?;
+        let mut query = DB
+            .query(query)
+            .bind(("limit", limit))
+            .bind(("start", start))
+            .bind(("include_demos", list_query.include_demos()))
+            .bind(("language", list_query.language()))
+            .bind(("category", list_query.category()))
+            .bind(("publisher", list_query.publisher()))
+            .await?;
         let data: Vec<Metaview> = query.take(0)?;
-        Ok(data)
+        Ok(data.into_iter().filter_map(|m| m.title).collect())
+    }
+
+    /// Count the base games matching the filters of a listing
+    pub async fn count_base_games(list_query: &ListQuery) -> Result<usize> {
+        let locale = default_locale();
+        let query = format!(
+            "SELECT count() FROM metaview_{locale} WHERE {} GROUP ALL",
+            Self::base_game_condition()
+        );
+        let mut query = DB
+            .query(query)
+            .bind(("include_demos", list_query.include_demos()))
+            .bind(("language", list_query.language()))
+            .bind(("category", list_query.category()))
+            .bind(("publisher", list_query.publisher()))
+            .await?;
+        take_count(&mut query)
+    }
+
+    /// Condition for base games, filtered by `$include_demos`, `$language`, `$category` and
+    /// `$publisher`
+    fn base_game_condition() -> String {
+        format!(
+            "title.titleId
+            AND {}
+            AND ($include_demos OR title.isDemo != true)
+            AND (!$language OR title.languages CONTAINS $language)
+            AND (!$category OR title.category CONTAINS $category)
+            AND (!$publisher OR title.publisher = $publisher)",
+            TitleKind::Base.sql_condition("title.titleId")
+        )
     }
 
     pub async fn get_updates(locale: &str) -> Result<Vec<Self>> {
         let query = format!(
-            "SELECT * FROM metaview_{locale} WHERE title.titleId AND string::ends_with(title.titleId, '800')"
+            "SELECT * FROM metaview_{locale} WHERE title.titleId AND {}",
+            TitleKind::Update.sql_condition("title.titleId")
         );
         let mut query = DB.query(query).await?;
         let data: Vec<Metaview> = query.take(0)?;
@@ -402,30 +682,130 @@ impl Metaview {
         let query = format!(
             "SELECT * FROM metaview_{locale}
             WHERE title.titleId
-            AND not string::ends_with(title.titleId, '000')
-            AND not string::ends_with(title.titleId, '800')"
+            AND {}",
+            TitleKind::Dlc.sql_condition("title.titleId")
         );
         let mut query = DB.query(query).await?;
         let data: Vec<Metaview> = query.take(0)?;
         Ok(data)
     }
 
-    /// Search for all base game titles.
-    pub async fn search_base_game(search_query: &SearchQuery) -> Result<Vec<Title>> {
+    /// Search for base game titles, `limit` matches from the `start`th on.
+    ///
+    /// With `fuzzy` set, names are matched typo tolerantly if the full-text search finds
+    /// nothing at all.
+    pub async fn search_base_game(
+        search_query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Title>> {
+        let data = Self::search_base_game_exact(search_query, start, limit).await?;
+        if !data.is_empty()
+            || !search_query.fuzzy()
+            || (start > 0 && Self::count_base_game_exact(search_query).await? > 0)
+        {
+            return Ok(data);
+        }
+
+        let locale = default_locale();
+        let ids: Vec<String> = Self::search_base_game_fuzzy(search_query)
+            .await?
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut res = DB
+            .query(format!(
+                "SELECT * FROM metaview_{locale} WHERE title_id IN $ids"
+            ))
+            .bind(("ids", ids.clone()))
+            .await?;
+        let data: Vec<Self> = res.take(0)?;
+        let titles = data.into_iter().filter_map(|m| m.title).collect();
+        Ok(order_by_ids(titles, &ids))
+    }
+
+    /// Count the matches of a base game search, the same way [`Metaview::search_base_game`]
+    /// finds them
+    pub async fn count_base_game_search(search_query: &SearchQuery) -> Result<usize> {
+        let count = Self::count_base_game_exact(search_query).await?;
+        if count > 0 || !search_query.fuzzy() {
+            return Ok(count);
+        }
+        Ok(Self::search_base_game_fuzzy(search_query).await?.len())
+    }
+
+    /// Condition for base games matching a search, with `$query`, `$include_demos` and
+    /// `$language` bound
+    fn base_game_search_condition() -> String {
+        format!(
+            "{}
+            AND ($include_demos OR title.isDemo != true)
+            AND (!$language OR title.languages CONTAINS $language)",
+            TitleKind::Base.sql_condition("title_id")
+        )
+    }
+
+    async fn count_base_game_exact(search_query: &SearchQuery) -> Result<usize> {
+        let locale = default_locale();
+        let query = format!(
+            "SELECT count() FROM metaview_{locale}
+            WHERE {} AND title_name @@ $query GROUP ALL",
+            Self::base_game_search_condition()
+        );
+        let mut query = DB
+            .query(query)
+            .bind(("query", search_query.query.clone()))
+            .bind(("include_demos", search_query.include_demos()))
+            .bind(("language", search_query.language()))
+            .await?;
+        take_count(&mut query)
+    }
+
+    /// Title IDs of all base games fuzzily matching a search, best matches first
+    async fn search_base_game_fuzzy(search_query: &SearchQuery) -> Result<Arc<Vec<String>>> {
+        let locale = default_locale();
+        cached_fuzzy_rank("metaview", &locale, search_query, || async {
+            let query = format!(
+                "SELECT title_id, title_name AS name FROM metaview_{locale} WHERE {}",
+                Self::base_game_search_condition()
+            );
+            let mut res = DB
+                .query(query)
+                .bind(("include_demos", search_query.include_demos()))
+                .bind(("language", search_query.language()))
+                .await?;
+            let candidates: Vec<FuzzyCandidate> = res.take(0)?;
+            Ok(fuzzy_rank(&search_query.query, candidates))
+        })
+        .await
+    }
+
+    async fn search_base_game_exact(
+        search_query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Title>> {
         let locale = default_locale();
         let mut query = format!(
             "SELECT * FROM metaview_{locale}
-            WHERE string::ends_with(title_id, '000')
-            AND title_name @@ $query"
+            WHERE {} AND title_name @@ $query",
+            Self::base_game_search_condition()
         );
 
-        if search_query.limit.is_some() {
-            query.push_str(" LIMIT $limit");
-        }
+        query.push_str(" ORDER BY id LIMIT $limit START $start");
         let mut query = DB
             .query(query)
             .bind(("query", search_query.query.clone()))
-            .bind(("limit", search_query.limit.unwrap_or(100)))
+            .bind(("limit", limit))
+            .bind(("start", start))
+            .bind(("include_demos", search_query.include_demos()))
+            .bind(("language", search_query.language()))
             .await?;
         let data: Vec<Self> = query.take(0)?;
 
@@ -433,22 +813,30 @@ impl Metaview {
         Ok(data)
     }
 
-    /// Search for all titles, excluding updates.
-    pub async fn search_all(search_query: &SearchQuery) -> Result<Vec<Title>> {
+    /// Search for titles excluding updates, `limit` matches from the `start`th on.
+    pub async fn search_all(
+        search_query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Title>> {
         let locale = LOCALE.parse::<String>()?;
         let mut query = format!(
             "SELECT * FROM metaview_{locale}
-            WHERE not(string::ends_with(title_id, '800'))
-            AND title_name @@ $query"
+            WHERE !{}
+            AND ($include_demos OR title.isDemo != true)
+            AND (!$language OR title.languages CONTAINS $language)
+            AND title_name @@ $query",
+            TitleKind::Update.sql_condition("title_id")
         );
 
-        if search_query.limit.is_some() {
-            query.push_str(" LIMIT $limit");
-        }
+        query.push_str(" ORDER BY id LIMIT $limit START $start");
         let mut query = DB
             .query(query)
             .bind(("query", search_query.query.clone()))
-            .bind(("limit", search_query.limit.unwrap_or(100)))
+            .bind(("limit", limit))
+            .bind(("start", start))
+            .bind(("include_demos", search_query.include_demos()))
+            .bind(("language", search_query.language()))
             .await?;
         let data: Vec<Self> = query.take(0)?;
 
@@ -629,6 +1017,15 @@ pub struct Title {
 }
 
 impl Title {
+    /// Check if TitleDB lists a language as supported by the title. This is independent of
+    /// the locale the title was imported from, a US title can support Japanese
+    pub fn supports_language(&self, language: &str) -> bool {
+        self.languages
+            .iter()
+            .flatten()
+            .any(|supported| supported.eq_ignore_ascii_case(language))
+    }
+
     pub async fn count(locale: &str) -> Result<i64> {
         #[derive(Debug, Deserialize)]
         struct CountResult {
@@ -645,15 +1042,15 @@ impl Title {
     }
 
     pub async fn get_from_title_id(locale: &str, title_id: &str) -> Result<Option<Self>> {
-        // If the title ID ends with *800, it's an update for a game,
-        // So we can replace it with 000 to get the base game
-        let is_update = title_id.ends_with("800");
+        // Updates don't have metadata of their own, so use the base game's
+        let is_update = TitleKind::is_update(title_id);
 
-        let title_id_query = if is_update {
-            tracing::trace!("Fetching base game metadata for update");
-            title_id.replace("800", "000")
-        } else {
-            title_id.to_string()
+        let title_id_query = match TitleKind::base_title_id(title_id) {
+            Some(base_title_id) if is_update => {
+                tracing::trace!("Fetching base game metadata for update");
+                base_title_id
+            }
+            _ => title_id.to_string(),
         };
 
         let query =
@@ -684,38 +1081,240 @@ impl Title {
         Ok(data)
     }
 
-    pub async fn search(search_query: &SearchQuery) -> Result<Vec<Self>> {
-        let locale = crate::config::config().backend_config.get_locale_string();
-        let mut query = format!(
+    /// Get the TitleDB entries of titles in one query, keyed by their uppercase title ID.
+    /// Titles that aren't in TitleDB are left out.
+    pub async fn get_from_title_ids(
+        locale: &str,
+        title_ids: &[String],
+    ) -> Result<HashMap<String, Self>> {
+        let query = format!("SELECT * FROM titles_{locale} WHERE titleId IN $tids");
+        let mut res = DB.query(query).bind(("tids", title_ids.to_vec())).await?;
+        let titles: Vec<Self> = res.take(0)?;
+
+        Ok(titles
+            .into_iter()
+            .filter_map(|title| Some((title.title_id.as_ref()?.to_uppercase(), title)))
+            .collect())
+    }
+
+    /// Get the icon URLs of titles in one query, keyed by their uppercase title ID.
+    /// Titles that aren't in TitleDB or have no icon are left out.
+    pub async fn get_icon_urls(
+        locale: &str,
+        title_ids: &[String],
+    ) -> Result<HashMap<String, String>> {
+        #[derive(Debug, Deserialize)]
+        struct IconRow {
+            title_id: String,
+            icon_url: Option<String>,
+        }
+
+        let query = format!(
+            "SELECT titleId AS title_id, iconUrl AS icon_url FROM titles_{locale}
+            WHERE titleId IN $tids"
+        );
+        let mut res = DB.query(query).bind(("tids", title_ids.to_vec())).await?;
+        let rows: Vec<IconRow> = res.take(0)?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| Some((row.title_id.to_uppercase(), row.icon_url?)))
+            .collect())
+    }
+
+    /// Get the DLC TitleDB lists for the game whose title IDs start with `prefix`
+    pub async fn get_dlc_of(locale: &str, prefix: &str) -> Result<Vec<Self>> {
+        let prefix = prefix.to_uppercase();
+        // A range instead of a prefix match, so the title ID index can be used
+        let query = format!(
             "SELECT * FROM titles_{locale}
-            WHERE name @@ $query
-            AND titleId
-            AND string::ends_with(titleId, '000')
-            "
+            WHERE titleId >= $from AND titleId <= $to
+            AND {}",
+            TitleKind::Dlc.sql_condition("titleId")
         );
+        let mut res = DB
+            .query(query)
+            .bind(("from", format!("{prefix}0000")))
+            .bind(("to", format!("{prefix}FFFF")))
+            .await?;
+        let data: Vec<Self> = res.take(0)?;
+        Ok(data)
+    }
 
-        if search_query.limit.is_some() {
-            query.push_str(" LIMIT $limit");
+    /// Search for base games, `limit` matches from the `start`th on.
+    ///
+    /// With `fuzzy` set, names are matched typo tolerantly if the full-text search finds
+    /// nothing at all, best matches first.
+    pub async fn search(
+        search_query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Self>> {
+        let data = Self::search_exact(search_query, start, limit).await?;
+        if !data.is_empty()
+            || !search_query.fuzzy()
+            || (start > 0 && Self::count_exact(search_query).await? > 0)
+        {
+            return Ok(data);
         }
+
+        let locale = crate::config::config().backend_config.get_locale_string();
+        let ids: Vec<String> = Self::search_fuzzy(search_query)
+            .await?
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut res = DB
+            .query(format!(
+                "SELECT * FROM titles_{locale} WHERE titleId IN $ids"
+            ))
+            .bind(("ids", ids.clone()))
+            .await?;
+        let data: Vec<Self> = res.take(0)?;
+        Ok(order_by_ids(data, &ids))
+    }
+
+    /// Count the matches of a search, the same way [`Title::search`] finds them
+    pub async fn count_search(search_query: &SearchQuery) -> Result<usize> {
+        let count = Self::count_exact(search_query).await?;
+        if count > 0 || !search_query.fuzzy() {
+            return Ok(count);
+        }
+        Ok(Self::search_fuzzy(search_query).await?.len())
+    }
+
+    /// Condition for base games matching a search, with `$include_demos` and `$language`
+    /// bound
+    fn search_condition() -> String {
+        format!(
+            "titleId
+            AND {}
+            AND ($include_demos OR isDemo != true)
+            AND (!$language OR languages CONTAINS $language)",
+            TitleKind::Base.sql_condition("titleId")
+        )
+    }
+
+    async fn count_exact(search_query: &SearchQuery) -> Result<usize> {
+        let locale = crate::config::config().backend_config.get_locale_string();
+        let query = format!(
+            "SELECT count() FROM titles_{locale} WHERE name @@ $query AND {} GROUP ALL",
+            Self::search_condition()
+        );
+        let mut query = DB
+            .query(query)
+            .bind(("query", search_query.query.clone()))
+            .bind(("include_demos", search_query.include_demos()))
+            .bind(("language", search_query.language()))
+            .await?;
+        take_count(&mut query)
+    }
+
+    /// Title IDs of all base games fuzzily matching a search, best matches first
+    async fn search_fuzzy(search_query: &SearchQuery) -> Result<Arc<Vec<String>>> {
+        let locale = crate::config::config().backend_config.get_locale_string();
+        cached_fuzzy_rank("titles", &locale, search_query, || async {
+            let query = format!(
+                "SELECT titleId AS title_id, name FROM titles_{locale} WHERE {}",
+                Self::search_condition()
+            );
+            let mut res = DB
+                .query(query)
+                .bind(("include_demos", search_query.include_demos()))
+                .bind(("language", search_query.language()))
+                .await?;
+            let candidates: Vec<FuzzyCandidate> = res.take(0)?;
+            Ok(fuzzy_rank(&search_query.query, candidates))
+        })
+        .await
+    }
+
+    async fn search_exact(
+        search_query: &SearchQuery,
+        start: usize,
+        limit: usize,
+    ) -> Result<Vec<Self>> {
+        let locale = crate::config::config().backend_config.get_locale_string();
+        let mut query = format!(
+            "SELECT * FROM titles_{locale} WHERE name @@ $query AND {}",
+            Self::search_condition()
+        );
+
+        query.push_str(" ORDER BY id LIMIT $limit START $start");
         let mut query = DB
             .query(query)
             .bind(("query", search_query.query.clone()))
-            .bind(("limit", search_query.limit.unwrap_or(100)))
+            .bind(("limit", limit))
+            .bind(("start", start))
+            .bind(("include_demos", search_query.include_demos()))
+            .bind(("language", search_query.language()))
             .await?;
         let data: Vec<Self> = query.take(0)?;
 
         Ok(data)
     }
 
+    /// Suggest base games whose name has a word starting with `prefix`, for search-as-you-type.
+    ///
+    /// This goes through the same edge n-gram search index as [`Title::search`], but only
+    /// fetches the ID and name of a handful of titles.
+    pub async fn suggest(
+        locale: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<TitleSuggestion>> {
+        #[derive(Debug, Deserialize)]
+        struct SuggestRow {
+            title_id: Option<String>,
+            name: Option<String>,
+        }
+
+        let query = format!(
+            "SELECT titleId AS title_id, name, search::score(1) AS score FROM titles_{locale}
+            WHERE name @1@ $query
+            AND titleId
+            AND {}
+            ORDER BY score DESC
+            LIMIT $limit",
+            TitleKind::Base.sql_condition("titleId")
+        );
+        // Over-fetch a bit so names that start with the prefix can be ranked first
+        let mut res = DB
+            .query(query)
+            .bind(("query", prefix.to_string()))
+            .bind(("limit", limit * 4))
+            .await?;
+        let rows: Vec<SuggestRow> = res.take(0)?;
+
+        let suggestions = rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(TitleSuggestion {
+                    title_id: row.title_id?,
+                    name: row.name?,
+                })
+            })
+            .collect();
+
+        Ok(rank_suggestions(prefix, suggestions, limit))
+    }
+
     pub async fn get_from_metaview_cache(title_id: &str) -> Result<Option<Self>> {
-        let is_update = title_id.ends_with("800");
+        let is_update = TitleKind::is_update(title_id);
         let locale = crate::config::config().backend_config.get_locale_string();
 
-        let title_id_query = if is_update {
-            tracing::trace!("Fetching base game metadata for update");
-            title_id.replace("800", "000")
-        } else {
-            title_id.to_string()
+        let title_id_query = match TitleKind::base_title_id(title_id) {
+            Some(base_title_id) if is_update => {
+                tracing::trace!("Fetching base game metadata for update");
+                base_title_id
+            }
+            _ => title_id.to_string(),
         };
 
         tracing::trace!("Fetching title metadata for {title_id_query}");
@@ -739,6 +1338,299 @@ impl Title {
     }
 }
 
+/// A DLC of a game that isn't in the library
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MissingDlc {
+    pub title_id: String,
+    pub name: Option<String>,
+}
+
+/// What TitleDB knows of a game in the library that the library doesn't have
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MissingContent {
+    /// Title ID of the base game
+    pub title_id: String,
+    /// Newest version of the game in the library, counting updates
+    pub owned_version: u32,
+    /// Newest version of the game TitleDB knows of
+    pub latest_version: Option<u32>,
+    pub update_available: bool,
+    pub missing_dlc: Vec<MissingDlc>,
+}
+
+/// Parse a version as stored in file metadata or TitleDB, with or without the leading `v`
+fn parse_version(version: &str) -> Option<u32> {
+    let version = version.trim();
+    version.strip_prefix('v').unwrap_or(version).parse().ok()
+}
+
+impl MissingContent {
+    /// Find what's missing of a game in the library, by any title ID of the game. `None` if
+    /// the library doesn't have the base game.
+    pub async fn find(title_id: &str) -> Result<Option<Self>> {
+        let prefix = title_group_prefix(title_id)
+            .ok_or_else(|| color_eyre::eyre::eyre!("Invalid title ID: {}", title_id))?;
+        let owned = NspMetadata::get_in_scope(&IndexScope::TitlePrefix(prefix.to_string())).await?;
+        let Some(base_title_id) = owned
+            .iter()
+            .find(|file| TitleKind::is_base(&file.title_id))
+            .map(|file| file.title_id.to_uppercase())
+        else {
+            return Ok(None);
+        };
+
+        let locale = default_locale();
+        let title = Title::get_from_title_id(&locale, &base_title_id).await?;
+        let dlc = Title::get_dlc_of(&locale, prefix).await?;
+        Ok(Some(Self::compare(
+            base_title_id,
+            &owned,
+            title.as_ref(),
+            dlc,
+        )))
+    }
+
+    /// Compare the files of a game in the library with TitleDB's entries of the game and its
+    /// DLC
+    fn compare(
+        base_title_id: String,
+        owned: &[NspMetadata],
+        title: Option<&Title>,
+        dlc: Vec<Title>,
+    ) -> Self {
+        let is_dlc = |title_id: &str| TitleKind::from_title_id(title_id) == TitleKind::Dlc;
+        let owned_version = owned
+            .iter()
+            .filter(|file| !is_dlc(&file.title_id))
+            .filter_map(|file| parse_version(&file.version))
+            .max()
+            .unwrap_or_default();
+        let owned_dlc: std::collections::HashSet<String> = owned
+            .iter()
+            .filter(|file| is_dlc(&file.title_id))
+            .map(|file| file.title_id.to_uppercase())
+            .collect();
+
+        let latest_version = title
+            .and_then(|title| title.version.as_deref())
+            .and_then(parse_version);
+        let mut missing_dlc: Vec<MissingDlc> = dlc
+            .into_iter()
+            .filter_map(|title| {
+                let title_id = title.title_id?.to_uppercase();
+                (is_dlc(&title_id) && !owned_dlc.contains(&title_id)).then_some(MissingDlc {
+                    title_id,
+                    name: title.name,
+                })
+            })
+            .collect();
+        missing_dlc.sort_by(|a, b| a.title_id.cmp(&b.title_id));
+        missing_dlc.dedup_by(|a, b| a.title_id == b.title_id);
+
+        Self {
+            title_id: base_title_id,
+            owned_version,
+            latest_version,
+            update_available: latest_version.is_some_and(|latest| latest > owned_version),
+            missing_dlc,
+        }
+    }
+}
+
+/// A title ID and name pair, returned by autocomplete
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TitleSuggestion {
+    pub title_id: String,
+    pub name: String,
+}
+
+/// Order suggestions so names starting with `prefix` come first, then names containing it,
+/// keeping the search score order otherwise. Duplicate title IDs are dropped.
+pub fn rank_suggestions(
+    prefix: &str,
+    mut suggestions: Vec<TitleSuggestion>,
+    limit: usize,
+) -> Vec<TitleSuggestion> {
+    let prefix = prefix.to_lowercase();
+    let mut seen = std::collections::HashSet::new();
+    suggestions.retain(|s| seen.insert(s.title_id.clone()));
+    suggestions.sort_by_key(|s| {
+        let name = s.name.to_lowercase();
+        if name.starts_with(&prefix) {
+            0
+        } else if name.contains(&prefix) {
+            1
+        } else {
+            2
+        }
+    });
+    suggestions.truncate(limit);
+    suggestions
+}
+
+/// Minimum [`fuzzy_score`] of a fuzzy search match
+const FUZZY_MIN_SCORE: f64 = 0.7;
+
+/// How long a fuzzy ranking is reused, so counting the matches of a search and paging
+/// through them doesn't rank every title again
+const FUZZY_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+const FUZZY_CACHE_CAPACITY: usize = 64;
+
+/// Table, locale, query, whether demos are included and the language of a fuzzy search
+type FuzzyKey = (&'static str, String, String, bool, Option<String>);
+
+/// Fuzzy rankings of recent searches, with the time they were made
+type FuzzyCache = HashMap<FuzzyKey, (std::time::Instant, Arc<Vec<String>>)>;
+
+static FUZZY_CACHE: LazyLock<Mutex<FuzzyCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Get the fuzzy ranking of a search of a table, reusing the ranking of the same search
+/// made shortly before. `rank` only runs when there's none
+async fn cached_fuzzy_rank<F, Fut>(
+    table: &'static str,
+    locale: &str,
+    search_query: &SearchQuery,
+    rank: F,
+) -> Result<Arc<Vec<String>>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Vec<String>>>,
+{
+    let key = (
+        table,
+        locale.to_string(),
+        search_query.query.clone(),
+        search_query.include_demos(),
+        search_query.language(),
+    );
+    let cached = FUZZY_CACHE
+        .lock()
+        .unwrap()
+        .get(&key)
+        .filter(|(ranked_at, _)| ranked_at.elapsed() < FUZZY_CACHE_TTL)
+        .map(|(_, ids)| ids.clone());
+    if let Some(ids) = cached {
+        return Ok(ids);
+    }
+
+    let ids = Arc::new(rank().await?);
+    let mut cache = FUZZY_CACHE.lock().unwrap();
+    if cache.len() >= FUZZY_CACHE_CAPACITY {
+        cache.retain(|_, (ranked_at, _)| ranked_at.elapsed() < FUZZY_CACHE_TTL);
+        if cache.len() >= FUZZY_CACHE_CAPACITY {
+            cache.clear();
+        }
+    }
+    cache.insert(key, (std::time::Instant::now(), ids.clone()));
+    Ok(ids)
+}
+
+/// Title ID and name of a title, matched against a fuzzy search
+#[derive(Debug, Deserialize)]
+struct FuzzyCandidate {
+    title_id: Option<String>,
+    name: Option<String>,
+}
+
+/// Rank fuzzy search candidates by how well their name matches, and get the title IDs of
+/// the matches, best first
+fn fuzzy_rank(query: &str, candidates: Vec<FuzzyCandidate>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut matches: Vec<(f64, String)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let score = fuzzy_score(query, candidate.name.as_deref()?);
+            (score >= FUZZY_MIN_SCORE).then_some((score, candidate.title_id?))
+        })
+        .filter(|(_, title_id)| seen.insert(title_id.clone()))
+        .collect();
+    // Stable, so equally good matches keep the database order
+    matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+    matches.into_iter().map(|(_, title_id)| title_id).collect()
+}
+
+/// Take the result of a `SELECT count() ... GROUP ALL` query, which has no rows at all
+/// when nothing matches
+fn take_count(response: &mut surrealdb::Response) -> Result<usize> {
+    #[derive(Debug, Deserialize)]
+    struct CountRow {
+        count: usize,
+    }
+
+    let row: Option<CountRow> = response.take(0)?;
+    Ok(row.map(|row| row.count).unwrap_or_default())
+}
+
+/// Put titles in the order of `ids`
+fn order_by_ids(mut titles: Vec<Title>, ids: &[String]) -> Vec<Title> {
+    titles.sort_by_key(|title| {
+        ids.iter()
+            .position(|id| title.title_id.as_ref() == Some(id))
+            .unwrap_or(usize::MAX)
+    });
+    titles
+}
+
+/// How well a name matches a search query, from 0 to 1, tolerating typos.
+///
+/// Every word of the query is compared to the most similar word of the name, the score is
+/// the average similarity. Words the name has a word starting with count as a full match.
+pub fn fuzzy_score(query: &str, name: &str) -> f64 {
+    fn words(text: &str) -> Vec<Vec<char>> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase().chars().collect())
+            .collect()
+    }
+
+    let query_words = words(query);
+    let name_words = words(name);
+    if query_words.is_empty() || name_words.is_empty() {
+        return 0.0;
+    }
+
+    let total: f64 = query_words
+        .iter()
+        .map(|query_word| {
+            name_words
+                .iter()
+                .map(|name_word| {
+                    if name_word.starts_with(query_word) {
+                        return 1.0;
+                    }
+                    let distance = edit_distance(query_word, name_word);
+                    1.0 - distance as f64 / query_word.len().max(name_word.len()) as f64
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+    total / query_words.len() as f64
+}
+
+/// Edit distance counting insertions, deletions, substitutions and swaps of neighbouring
+/// characters as one edit each (optimal string alignment)
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // Rows for the previous two prefixes of `a`
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
 #[tracing::instrument(skip(title), fields(
     title_id = title.title_id.clone(),
     nsuid = title.nsu_id.unwrap_or_default(),
@@ -753,42 +1645,29 @@ async fn import_entry_to_db(title: TitleDbEntry, locale: &str) -> Result<()> {
     let table_name = format!("titles_{}", locale);
     let nsuid = title.nsu_id.unwrap_or_default();
     let nsuid_str = nsuid.to_string(); // Convert u64 to String because surrealdb doesnt like numbered indexes
-    let max_retries = 5;
-    let mut retry_count = 0;
-    let mut last_error = None;
 
-    while retry_count < max_retries {
-        let upsert_result: Result<Option<Title>> = DB
-            .upsert((&table_name, &nsuid_str))
+    let _: Option<Title> = crate::db::with_retry("titledb.import_entry", || async {
+        DB.upsert((&table_name, &nsuid_str))
             .content(title.clone())
             .await
-            .map_err(|e| color_eyre::eyre::eyre!(e));
-
-        if let Ok(_ent) = upsert_result {
-            tracing::trace!("Title imported");
-            return Ok(());
-        } else if let Err(e) = upsert_result {
-            last_error = Some(e);
-            retry_count += 1;
-
-            if retry_count < max_retries {
-                // Exponential backoff: wait longer after each failure
-                let delay = std::time::Duration::from_millis(50 * 2u64.pow(retry_count as u32));
-                tracing::warn!(
-                    "Database lock error during import, retrying in {:?} (attempt {}/{})",
-                    delay,
-                    retry_count,
-                    max_retries
-                );
-                tokio::time::sleep(delay).await;
-            }
-        }
-    }
+    })
+    .await
+    .map_err(|e| color_eyre::eyre::eyre!("Database error during import: {}", e))?;
 
-    // If we get here, all retries failed
-    Err(last_error
-        .map(|e| color_eyre::eyre::eyre!("Database error during import: {}", e))
-        .unwrap_or_else(|| color_eyre::eyre::eyre!("Unknown database error during import")))
+    tracing::trace!("Title imported");
+    Ok(())
+}
+
+/// Read the next key and entry of a TitleDB file.
+///
+/// The entry is read as plain JSON first, so one that doesn't match [`TitleDbEntry`] is
+/// returned as an error while the reader moves on to the next key.
+fn read_entry<R: std::io::Read>(
+    reader: &mut JsonStreamReader<R>,
+) -> Result<(String, std::result::Result<TitleDbEntry, serde_json::Error>)> {
+    let key = reader.next_name_owned()?;
+    let value: serde_json::Value = reader.deserialize_next()?;
+    Ok((key, serde_json::from_value(value)))
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -804,10 +1683,14 @@ impl TitleDBImport {
     //     }
     // }
 
-    #[tracing::instrument(skip(reader))]
+    /// Import a TitleDB JSON file, reading it entry by entry.
+    ///
+    /// `total_bytes` is the size of the JSON, if known, to report how far along the import is.
+    #[tracing::instrument(skip(reader), fields(entries = 0u64, percent = tracing::field::Empty))]
     pub async fn from_json_reader_streaming<R: std::io::Read>(
         reader: R,
         locale: &str,
+        total_bytes: Option<u64>,
     ) -> color_eyre::Result<()> {
         tracing::info!("Importing TitleDB data for {locale} (Streamed)");
 
@@ -816,28 +1699,95 @@ impl TitleDBImport {
         let _q = DB.query(schema).await?;
         create_precomputed_metaview(locale).await?;
 
-        let mut reader = JsonStreamReader::new(reader);
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let mut progress = TitleDbImportProgress {
+            locale: locale.to_string(),
+            entries: 0,
+            failed: 0,
+            percent: total_bytes.map(|_| 0.0),
+            estimated_total: None,
+            started_at: chrono::Utc::now(),
+        };
+        IMPORT_PROGRESS
+            .lock()
+            .unwrap()
+            .insert(locale.to_string(), progress.clone());
+        // Forget the import however it ends
+        struct ProgressGuard<'a>(&'a str);
+        impl Drop for ProgressGuard<'_> {
+            fn drop(&mut self) {
+                IMPORT_PROGRESS.lock().unwrap().remove(self.0);
+            }
+        }
+        let _guard = ProgressGuard(locale);
 
-        reader.begin_object().unwrap();
+        let mut reader = JsonStreamReader::new(CountingReader {
+            inner: reader,
+            count: bytes_read.clone(),
+        });
 
-        while reader.has_next().unwrap() {
-            // Skip the key
-            reader.next_name().unwrap();
-            // let key = key_og.clone();
-            // drop(key_og);
-
-            let entry: TitleDbEntry = reader.deserialize_next().unwrap();
-            // tracing::info!("Read key: {:#?}", entry);
-            //
-            // let nsuid = entry.nsu_id.unwrap_or_default();
-            import_entry_to_db(entry.clone(), locale).await.unwrap();
-
-            // db.titles.insert(nsuid.to_string(), entry);
-        }
+        reader.begin_object()?;
+
+        while reader.has_next()? {
+            // Malformed entries are skipped, only broken JSON stops the import
+            let (key, entry) = read_entry(&mut reader)?;
+            progress.entries += 1;
+            match entry {
+                Ok(entry) => {
+                    if let Err(e) = import_entry_to_db(entry, locale).await {
+                        tracing::warn!(key, "Failed to save TitleDB entry: {}", e);
+                        progress.failed += 1;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(key, "Skipping malformed TitleDB entry: {}", e);
+                    progress.failed += 1;
+                }
+            }
 
-        reader.end_object().unwrap();
+            if progress.entries % IMPORT_PROGRESS_INTERVAL == 0 {
+                let read = bytes_read.load(Ordering::Relaxed);
+                if let Some(total) = total_bytes.filter(|&total| total > 0 && read > 0) {
+                    let fraction = (read as f64 / total as f64).min(1.0);
+                    progress.percent = Some((fraction * 1000.0).round() / 10.0);
+                    progress.estimated_total = Some((progress.entries as f64 / fraction) as u64);
+                }
 
-        tracing::info!("Successfully imported TitleDB data for {locale}");
+                let span = tracing::Span::current();
+                span.record("entries", progress.entries);
+                if let Some(percent) = progress.percent {
+                    span.record("percent", percent);
+                }
+                tracing::info!(
+                    "Importing TitleDB data for {locale}: {} entries{}",
+                    progress.entries,
+                    progress
+                        .percent
+                        .map(|percent| format!(" (~{percent}%)"))
+                        .unwrap_or_default()
+                );
+                IMPORT_PROGRESS
+                    .lock()
+                    .unwrap()
+                    .insert(locale.to_string(), progress.clone());
+            }
+        }
+
+        reader.end_object()?;
+
+        LAST_IMPORT
+            .lock()
+            .unwrap()
+            .insert(locale.to_string(), chrono::Utc::now());
+        tracing::info!(
+            total = progress.entries,
+            imported = progress.entries - progress.failed,
+            failed = progress.failed,
+            "Successfully imported TitleDB data for {locale}: {} of {} entries, {} failed",
+            progress.entries - progress.failed,
+            progress.entries,
+            progress.failed
+        );
         Ok(())
     }
 
@@ -854,3 +1804,273 @@ impl TitleDBImport {
     //     Ok(())
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cached_fuzzy_rank() {
+        let query = |q: &str| -> SearchQuery {
+            serde_json::from_value(serde_json::json!({ "q": q })).unwrap()
+        };
+        let ranked = AtomicU64::new(0);
+        let rank = || async {
+            ranked.fetch_add(1, Ordering::Relaxed);
+            Ok(vec!["0100000000010000".to_string()])
+        };
+
+        // Counting and every page of the same search share one ranking
+        let first = cached_fuzzy_rank("test", "US_en", &query("zelda"), rank)
+            .await
+            .unwrap();
+        let second = cached_fuzzy_rank("test", "US_en", &query("zelda"), rank)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(ranked.load(Ordering::Relaxed), 1);
+
+        cached_fuzzy_rank("test", "US_en", &query("mario"), rank)
+            .await
+            .unwrap();
+        cached_fuzzy_rank("test", "JP_ja", &query("zelda"), rank)
+            .await
+            .unwrap();
+        assert_eq!(ranked.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_title_group_prefix() {
+        assert_eq!(title_group_prefix("0100ABCD12345000"), Some("0100ABCD1234"));
+        assert_eq!(title_group_prefix("0100abcd12345800"), Some("0100abcd1234"));
+        assert_eq!(title_group_prefix("0100ABCD1234"), Some("0100ABCD1234"));
+    }
+
+    #[test]
+    fn test_parse_from_filename() {
+        let data =
+            GameFileDataNaive::parse_from_filename("Game [0100ABCD12345000][v65536][US].nsp");
+        assert_eq!(data.title_id.as_deref(), Some("0100ABCD12345000"));
+        assert_eq!(data.version.as_deref(), Some("v65536"));
+        assert_eq!(data.region.as_deref(), Some("US"));
+        assert!(data.other_tags.is_empty());
+        assert_eq!(data.extension.as_deref(), Some("nsp"));
+
+        let data =
+            GameFileDataNaive::parse_from_filename("Game [0100ABCD12345000][Rev 1][v0][EU].xci");
+        assert_eq!(data.version.as_deref(), Some("v0"));
+        assert_eq!(data.region.as_deref(), Some("EU"));
+        assert_eq!(data.other_tags, vec!["Rev 1".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_from_filename_no_tags() {
+        let data = GameFileDataNaive::parse_from_filename("Game.nsp");
+        assert_eq!(data.title_id, None);
+        assert_eq!(data.version, None);
+        assert_eq!(data.region, None);
+        assert!(data.other_tags.is_empty());
+    }
+
+    #[test]
+    fn test_parse_from_filename_not_hex() {
+        let data = GameFileDataNaive::parse_from_filename("Game [PUBLISHERCODE123][vDemo][JP].nsp");
+        assert_eq!(data.title_id, None);
+        assert_eq!(data.version, None);
+        assert_eq!(data.region.as_deref(), Some("JP"));
+        assert_eq!(
+            data.other_tags,
+            vec!["PUBLISHERCODE123".to_string(), "vDemo".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_read_entry_skips_malformed() {
+        let json = r#"{
+            "1": {"id": "0100000000010000", "name": "Good"},
+            "2": {"id": 42, "name": ["Bad"]},
+            "3": {"id": "0100000000020000", "name": "Also good"}
+        }"#;
+        let mut reader = JsonStreamReader::new(json.as_bytes());
+        reader.begin_object().unwrap();
+
+        let mut entries = Vec::new();
+        while reader.has_next().unwrap() {
+            entries.push(read_entry(&mut reader).unwrap());
+        }
+        reader.end_object().unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0].1.as_ref().unwrap().title_id.as_deref(),
+            Some("0100000000010000")
+        );
+        assert_eq!(entries[1].0, "2");
+        assert!(entries[1].1.is_err());
+        assert_eq!(
+            entries[2].1.as_ref().unwrap().title_id.as_deref(),
+            Some("0100000000020000")
+        );
+    }
+
+    #[test]
+    fn test_missing_content_compare() {
+        let file = |title_id: &str, version: &str| NspMetadata {
+            path: format!("/roms/{title_id}_{version}.nsp"),
+            title_id: title_id.to_string(),
+            version: version.to_string(),
+            title_name: None,
+            download_id: format!("{title_id}_{version}.nsp"),
+            unidentified: false,
+            required_system_version: None,
+        };
+        let title = |title_id: &str, version: Option<&str>| -> Title {
+            serde_json::from_value(serde_json::json!({
+                "titleId": title_id,
+                "name": format!("Title {title_id}"),
+                "version": version,
+            }))
+            .unwrap()
+        };
+
+        let owned = vec![
+            file("0100000000010000", "v0"),
+            file("0100000000010800", "v65536"),
+            file("0100000000011001", "v0"),
+        ];
+        let base = title("0100000000010000", Some("131072"));
+        let dlc = vec![
+            title("0100000000011001", None),
+            title("0100000000011002", None),
+            title("0100000000011002", None),
+        ];
+
+        let missing =
+            MissingContent::compare("0100000000010000".to_string(), &owned, Some(&base), dlc);
+        assert_eq!(missing.owned_version, 65536);
+        assert_eq!(missing.latest_version, Some(131072));
+        assert!(missing.update_available);
+        assert_eq!(
+            missing.missing_dlc,
+            vec![MissingDlc {
+                title_id: "0100000000011002".to_string(),
+                name: Some("Title 0100000000011002".to_string()),
+            }]
+        );
+
+        // Up to date, and TitleDB doesn't know the game at all
+        let missing =
+            MissingContent::compare("0100000000010000".to_string(), &owned, None, Vec::new());
+        assert_eq!(missing.latest_version, None);
+        assert!(!missing.update_available);
+        assert!(missing.missing_dlc.is_empty());
+    }
+
+    #[test]
+    fn test_title_sort_order_by() {
+        assert_eq!(
+            TitleSort::Name.order_by(SortOrder::Asc),
+            "ORDER BY sort_missing ASC, title.name COLLATE ASC, id ASC"
+        );
+        assert_eq!(
+            TitleSort::ReleaseDate.order_by(SortOrder::Desc),
+            "ORDER BY sort_missing ASC, title.releaseDate DESC, id ASC"
+        );
+        assert_eq!(
+            TitleSort::Size.missing_field(),
+            "!title.size AS sort_missing"
+        );
+
+        let sort: TitleSort = serde_json::from_str("\"release_date\"").unwrap();
+        assert_eq!(sort, TitleSort::ReleaseDate);
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(edit_distance(&chars("zlda"), &chars("zelda")), 1);
+        assert_eq!(edit_distance(&chars("zelad"), &chars("zelda")), 1);
+        assert_eq!(edit_distance(&chars("mario"), &chars("kart")), 3);
+
+        let name = "The Legend of Zelda: Breath of the Wild";
+        assert_eq!(fuzzy_score("zelda breath", name), 1.0);
+        assert!(fuzzy_score("zlda", name) >= FUZZY_MIN_SCORE);
+        assert!(fuzzy_score("legnd of zelad", name) >= FUZZY_MIN_SCORE);
+        assert!(fuzzy_score("mario", name) < FUZZY_MIN_SCORE);
+        assert_eq!(fuzzy_score("", name), 0.0);
+    }
+
+    #[test]
+    fn test_fuzzy_rank() {
+        let candidate = |title_id: &str, name: &str| FuzzyCandidate {
+            title_id: Some(title_id.to_string()),
+            name: Some(name.to_string()),
+        };
+        let candidates = || {
+            vec![
+                candidate("0100000000010000", "Super Mario Odyssey"),
+                candidate("0100000000020000", "Zelda Tennis"),
+                candidate("0100000000030000", "The Legend of Zelda"),
+                candidate("0100000000020000", "Zelda Tennis"),
+            ]
+        };
+
+        let ids = fuzzy_rank("legend of zlda", candidates());
+        assert_eq!(ids, vec!["0100000000030000"]);
+        let ids = fuzzy_rank("zlda", candidates());
+        assert_eq!(ids, vec!["0100000000020000", "0100000000030000"]);
+    }
+
+    #[test]
+    fn test_title_group_prefix_invalid() {
+        assert_eq!(title_group_prefix("0100ABCD123"), None);
+        assert_eq!(title_group_prefix(""), None);
+        assert_eq!(title_group_prefix("0100ABCD123Z4000"), None);
+        assert_eq!(title_group_prefix("0100ABCD12ü4000"), None);
+    }
+
+    #[test]
+    fn test_supports_language() {
+        let title: Title = serde_json::from_value(serde_json::json!({
+            "titleId": "0100000000010000",
+            "name": "Some Game",
+            "languages": ["en", "ja", "zh"],
+        }))
+        .unwrap();
+
+        assert!(title.supports_language("en"));
+        assert!(title.supports_language("JA"));
+        assert!(!title.supports_language("fr"));
+
+        let unknown: Title = serde_json::from_value(serde_json::json!({
+            "titleId": "0100000000020000",
+        }))
+        .unwrap();
+        assert!(!unknown.supports_language("en"));
+    }
+
+    #[test]
+    fn test_rank_suggestions() {
+        let suggestion = |title_id: &str, name: &str| TitleSuggestion {
+            title_id: title_id.to_string(),
+            name: name.to_string(),
+        };
+        let suggestions = vec![
+            suggestion("0100000000001000", "Super Mario Odyssey"),
+            suggestion("0100000000002000", "Mario Kart 8 Deluxe"),
+            suggestion("0100000000003000", "Paper Mario"),
+            suggestion("0100000000002000", "Mario Kart 8 Deluxe"),
+            suggestion("0100000000004000", "Marionette"),
+        ];
+
+        let ranked = rank_suggestions("mario", suggestions, 3);
+        assert_eq!(
+            ranked,
+            vec![
+                suggestion("0100000000002000", "Mario Kart 8 Deluxe"),
+                suggestion("0100000000004000", "Marionette"),
+                suggestion("0100000000001000", "Super Mario Odyssey"),
+            ]
+        );
+    }
+}
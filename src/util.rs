@@ -1,7 +1,14 @@
 use crate::db::NspMetadata;
+use crate::locale::{Language, Region};
 use color_eyre::Result;
 use reqwest::Client;
-use std::{fs::File, io, path::PathBuf};
+use sha2::Digest;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use tempfile::TempDir;
 
 const TITLEDB_BASEURL: &str = "https://github.com/blawar/titledb/raw/refs/heads/master";
@@ -67,31 +74,86 @@ pub fn titledb_cache_dir() -> PathBuf {
     cache_dir
 }
 
+/// Path of a locale's TitleDB in the cache directory, plain or gzip-compressed
+fn titledb_cache_file(region: Region, lang: Language, compressed: bool) -> PathBuf {
+    let extension = if compressed { "json.gz" } else { "json" };
+    titledb_cache_dir().join(format!("{region}.{lang}.{extension}"))
+}
+
+/// Get the path a locale's TitleDB is downloaded to, which is gzip-compressed when
+/// `ALU_TITLEDB_COMPRESS` is enabled
+pub fn titledb_cache_path(region: Region, lang: Language) -> PathBuf {
+    let compress = crate::config::config().backend_config.titledb_compress;
+    titledb_cache_file(region, lang, compress)
+}
+
+/// Find the cached TitleDB of a locale in either format, preferring the configured one.
+///
+/// Caches in the other format, such as plain JSON from before compression was enabled,
+/// are still used until the next download replaces them.
+pub fn find_titledb_cache(region: Region, lang: Language) -> Option<PathBuf> {
+    let preferred = titledb_cache_path(region, lang);
+    let compressed = preferred.extension().is_some_and(|ext| ext == "gz");
+    [preferred, titledb_cache_file(region, lang, !compressed)]
+        .into_iter()
+        .find(|path| path.exists())
+}
+
+/// Open a cached TitleDB file, decompressing it on the fly if it's gzipped
+pub fn open_titledb_cache(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(flate2::read::GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Size of a cached TitleDB once decompressed, read from the gzip trailer for gzipped caches.
+///
+/// The trailer only has the size modulo 4 GiB, which TitleDB files stay well below.
+pub fn titledb_cache_size(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut size = [0u8; 4];
+        file.seek(io::SeekFrom::End(-4))?;
+        file.read_exact(&mut size)?;
+        Ok(u32::from_le_bytes(size) as u64)
+    } else {
+        Ok(file.metadata()?.len())
+    }
+}
+
+/// Write a downloaded TitleDB to its cache path, through a temporary file so an
+/// interrupted write never replaces a good cache
+fn write_titledb_cache(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let file = File::create(&partial)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(bytes)?;
+        encoder.finish()?.sync_all()?;
+    } else {
+        let mut file = file;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&partial, path)
+}
+
 /// Downloads a TitleDB file from the internet
-pub async fn download_titledb(client: &Client, region: &str, lang: &str) -> Result<String> {
+pub async fn download_titledb(client: &Client, region: Region, lang: Language) -> Result<PathBuf> {
     let url = format!("{TITLEDB_BASEURL}/{}.{}.json", region, lang);
-    let cache_dir = titledb_cache_dir();
-    let file_path = cache_dir
-        .join(format!("{}.{}.json", region, lang))
-        .to_str()
-        .ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid path for TitleDB file: {}/{}.{}.json",
-                    cache_dir.display(),
-                    region,
-                    lang
-                ),
-            )
-        })?
-        .to_string();
+    let file_path = titledb_cache_path(region, lang);
 
     tracing::info!(
         "Downloading TitleDB for {} {} to {}",
         region,
         lang,
-        file_path
+        file_path.display()
     );
 
     let resp = client.get(&url).send().await?;
@@ -103,12 +165,34 @@ pub async fn download_titledb(client: &Client, region: &str, lang: &str) -> Resu
     }
 
     let bytes = resp.bytes().await?;
-    std::fs::write(&file_path, bytes)?;
+    let target = file_path.clone();
+    tokio::task::spawn_blocking(move || write_titledb_cache(&target, &bytes)).await??;
+
+    // The cache in the other format is outdated now, this is also how plain caches
+    // get migrated once compression is enabled
+    let compressed = file_path.extension().is_some_and(|ext| ext == "gz");
+    let outdated = titledb_cache_file(region, lang, !compressed);
+    match std::fs::remove_file(&outdated) {
+        Ok(()) => tracing::info!("Removed outdated TitleDB cache {}", outdated.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!(
+            "Failed to remove outdated TitleDB cache {}: {}",
+            outdated.display(),
+            e
+        ),
+    }
+
     Ok(file_path)
 }
 
-/// Formats a game name for display with title ID and version information
-pub fn format_game_name(metadata: &NspMetadata, filename: &str, extension: &str) -> String {
+/// Formats a game name for display with title ID and version information, with the
+/// configured template if there's one
+pub fn format_game_name(
+    metadata: &NspMetadata,
+    filename: &str,
+    extension: &str,
+    template: Option<&FilenameTemplate>,
+) -> String {
     let name = match &metadata.title_name {
         Some(n) => n.clone(),
         None => filename.trim().trim_end_matches(extension).to_string(),
@@ -119,10 +203,123 @@ pub fn format_game_name(metadata: &NspMetadata, filename: &str, extension: &str)
         .strip_prefix('v')
         .unwrap_or(&metadata.version);
 
-    format!(
-        "{} [{}][v{}].{}",
-        name, metadata.title_id, version, extension
-    )
+    match template {
+        Some(template) => template.render(&name, &metadata.title_id, version, extension),
+        None => format!(
+            "{} [{}][v{}].{}",
+            name, metadata.title_id, version, extension
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Name,
+    TitleId,
+    Version,
+    Ext,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum FilenameTemplateError {
+    #[error(
+        "unknown placeholder '{{{0}}}', expected {{name}}, {{title_id}}, {{version}} or {{ext}}"
+    )]
+    UnknownPlaceholder(String),
+    #[error("unclosed '{{' in filename template")]
+    Unclosed,
+    #[error("unmatched '}}' in filename template, write '}}}}' for a literal brace")]
+    Unmatched,
+    #[error("filename template can't contain path separators")]
+    PathSeparator,
+}
+
+/// Template of the names files are served with, such as `{name} [{title_id}][{version}].{ext}`,
+/// which is also the default format.
+///
+/// `{version}` includes the leading `v`. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenameTemplate(Vec<TemplatePart>);
+
+impl FromStr for FilenameTemplate {
+    type Err = FilenameTemplateError;
+
+    fn from_str(template: &str) -> std::result::Result<Self, Self::Err> {
+        if template.contains(['/', '\\']) {
+            return Err(FilenameTemplateError::PathSeparator);
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(FilenameTemplateError::Unmatched),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(FilenameTemplateError::Unclosed),
+                        }
+                    }
+                    let placeholder = match placeholder.trim() {
+                        "name" => Placeholder::Name,
+                        "title_id" => Placeholder::TitleId,
+                        "version" => Placeholder::Version,
+                        "ext" => Placeholder::Ext,
+                        _ => return Err(FilenameTemplateError::UnknownPlaceholder(placeholder)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Placeholder(placeholder));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl FilenameTemplate {
+    /// Fill in the template in a single pass, so values are never read as placeholders.
+    /// Path separators in values are replaced, the name has to stay a single file name.
+    pub fn render(&self, name: &str, title_id: &str, version: &str, extension: &str) -> String {
+        let mut rendered = String::new();
+        for part in &self.0 {
+            let value = match part {
+                TemplatePart::Literal(text) => {
+                    rendered.push_str(text);
+                    continue;
+                }
+                TemplatePart::Placeholder(Placeholder::Name) => name.to_string(),
+                TemplatePart::Placeholder(Placeholder::TitleId) => title_id.to_string(),
+                TemplatePart::Placeholder(Placeholder::Version) => format!("v{version}"),
+                TemplatePart::Placeholder(Placeholder::Ext) => extension.to_string(),
+            };
+            rendered.push_str(&value.replace(['/', '\\'], "_"));
+        }
+        rendered
+    }
 }
 
 /// Creates a download ID for a game based on the title ID, extension and version information
@@ -131,3 +328,103 @@ pub fn format_download_id(title_id: &str, version: &str, ext: &str) -> String {
     let version = version.strip_prefix('v').unwrap_or(version);
     format!("{}_v{}.{}", title_id, version, ext)
 }
+
+/// Download ID of a file on disk. Unidentified files all share a placeholder title ID, so
+/// theirs gets a hash of the path as well, or they'd all be served as the same file.
+// example: 00000000AAAA0000-1a2b3c4d_v0.nsp
+pub fn file_download_id(path: &str, title_id: &str, version: &str, ext: &str) -> String {
+    if !crate::db::is_placeholder_title_id(title_id) {
+        return format_download_id(title_id, version, ext);
+    }
+    let hash: String = sha2::Sha256::digest(path.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format_download_id(&format!("{title_id}-{hash}"), version, ext)
+}
+
+/// Split a download ID into its title ID and numeric version, the reverse of
+/// [`format_download_id`]
+pub fn parse_download_id(download_id: &str) -> Option<(&str, u32)> {
+    let (title_id, rest) = download_id.rsplit_once("_v")?;
+    let version = rest.split('.').next()?.parse().ok()?;
+    Some((title_id, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filename_template() {
+        let metadata = NspMetadata {
+            path: "/roms/game.nsp".to_string(),
+            title_id: "0100000000010000".to_string(),
+            version: "v65536".to_string(),
+            title_name: Some("Some/Game".to_string()),
+            download_id: "0100000000010000_v65536.nsp".to_string(),
+            unidentified: false,
+            required_system_version: None,
+        };
+        let default = format_game_name(&metadata, "game.nsp", "nsp", None);
+        assert_eq!(default, "Some/Game [0100000000010000][v65536].nsp");
+
+        // The default format as a template, except for the path separator
+        let template: FilenameTemplate = "{name} [{title_id}][{version}].{ext}".parse().unwrap();
+        assert_eq!(
+            format_game_name(&metadata, "game.nsp", "nsp", Some(&template)),
+            "Some_Game [0100000000010000][v65536].nsp"
+        );
+
+        let template: FilenameTemplate = "{{{title_id}}} {name}.{ext}".parse().unwrap();
+        assert_eq!(
+            template.render("{name}", "0100000000010000", "0", "xci"),
+            "{0100000000010000} {name}.xci"
+        );
+    }
+
+    #[test]
+    fn test_filename_template_invalid() {
+        assert_eq!(
+            "{name} {region}.{ext}".parse::<FilenameTemplate>(),
+            Err(FilenameTemplateError::UnknownPlaceholder(
+                "region".to_string()
+            ))
+        );
+        assert_eq!(
+            "{name.{ext}".parse::<FilenameTemplate>(),
+            Err(FilenameTemplateError::UnknownPlaceholder(
+                "name.{ext".to_string()
+            ))
+        );
+        assert_eq!(
+            "{name} [{title_id".parse::<FilenameTemplate>(),
+            Err(FilenameTemplateError::Unclosed)
+        );
+        assert_eq!(
+            "{name}}.{ext}".parse::<FilenameTemplate>(),
+            Err(FilenameTemplateError::Unmatched)
+        );
+        assert_eq!(
+            "games/{name}.{ext}".parse::<FilenameTemplate>(),
+            Err(FilenameTemplateError::PathSeparator)
+        );
+    }
+
+    #[test]
+    fn test_file_download_id() {
+        assert_eq!(
+            file_download_id("/roms/game.nsp", "0100000000010000", "v0", "nsp"),
+            "0100000000010000_v0.nsp"
+        );
+
+        let first = file_download_id("/roms/a.nsp", "00000000AAAA0000", "v0", "nsp");
+        let second = file_download_id("/roms/b.nsp", "00000000AAAA0000", "v0", "nsp");
+        assert_ne!(first, second);
+        assert!(first.starts_with("00000000AAAA0000-"));
+        assert_eq!(
+            parse_download_id(&first).map(|(_, version)| version),
+            Some(0)
+        );
+    }
+}